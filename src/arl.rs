@@ -0,0 +1,156 @@
+//! ARRL numbered radiogram texts, sent as `ARL` followed by the spelled-out number.
+//! Fill-in blanks are marked with `___`.
+
+use crate::Traffic;
+
+const TEXTS: &[(u16, &str)] = &[
+    (1, "Everyone safe here. Please don't worry."),
+    (2, "Coming home as soon as possible."),
+    (3, "Am in ___ hospital. Receiving excellent care and recovering fine."),
+    (4, "Only slight property damage here. Do not be concerned about disaster reports."),
+    (5, "Am moving to new location. Send no further mail or communication. Will inform you of new address when relocated."),
+    (6, "Will contact you as soon as possible."),
+    (7, "Please reply by Amateur Radio through the amateur delivering this message. This is a free public service."),
+    (8, "Need additional ___ mobile or portable equipment for immediate emergency use."),
+    (9, "Additional ___ radio operators needed to assist with emergency at this location."),
+    (10, "Please contact ___. Advise to standby and provide further emergency information, instructions or assistance."),
+    (11, "Establish Amateur Radio emergency communications with ___ on ___ MHz."),
+    (12, "Anxious to hear from you. No word in some time. Please contact me as soon as possible."),
+    (13, "Medical emergency situation exists here."),
+    (14, "Situation here becoming critical. Losses and damage from ___ increasing."),
+    (15, "Please advise your condition and what help is needed."),
+    (16, "Property damage very severe in this area."),
+    (17, "REACT communications services also available. Establish REACT communication with ___ on channel ___."),
+    (18, "Please contact me as soon as possible at ___."),
+    (19, "Request health and welfare report on ___."),
+    (20, "Temporarily stranded. Will need some assistance. Please contact me at ___."),
+    (21, "Search and Rescue assistance is needed by local authorities here. Advise availability."),
+    (22, "Need accurate information on the extent and type of conditions now existing at your location. Please furnish this information and reply without delay."),
+    (23, "Report at once the accessibility and best way to reach your location."),
+    (24, "Evacuation of residents from this area urgently needed. Advise plans for help."),
+    (25, "Furnish as soon as possible the weather conditions at your location."),
+    (26, "Help and care for evacuation of sick and injured from this location needed at once."),
+    (46, "Greetings on your birthday and best wishes for many more to come."),
+    (47, "Reply to your message number ___ of ___ delivered on ___ at ___ UTC."),
+    (50, "Greetings by Amateur Radio."),
+    (51, "Greetings by Amateur Radio. This message is sent as a free public service by ham radio operators at ___. Am having a wonderful time."),
+    (52, "Really enjoyed being with you. Looking forward to getting together again."),
+    (53, "Received your ___. It's appreciated; many thanks."),
+    (54, "Many thanks for your good wishes."),
+    (55, "Good news is always welcome. Very delighted to hear about yours."),
+    (56, "Congratulations on your ___, a most worthy and deserved achievement."),
+    (57, "Wish we could be together."),
+    (58, "Have a wonderful time. Let us know when you return."),
+    (59, "Congratulations on the new arrival. Hope mother and child are well."),
+    (60, "Wishing you the best of everything on ___."),
+    (61, "Wishing you a very Merry Christmas and a Happy New Year."),
+    (62, "Greetings and best wishes to you for a pleasant ___ holiday season."),
+    (63, "Victory or defeat, our best regards to you."),
+    (64, "Arrived safely at ___."),
+    (65, "Arriving ___ on ___. Please arrange to meet me there."),
+    (66, "DX QSLs are on hand for you at the ___ QSL Bureau. Send ___ self addressed envelopes."),
+    (67, "Your message number ___ undeliverable because of ___. Please advise."),
+];
+
+const ONES: [&str; 19] = [
+    "ONE", "TWO", "THREE", "FOUR", "FIVE", "SIX", "SEVEN", "EIGHT", "NINE", "TEN",
+    "ELEVEN", "TWELVE", "THIRTEEN", "FOURTEEN", "FIFTEEN", "SIXTEEN", "SEVENTEEN", "EIGHTEEN", "NINETEEN",
+];
+const TENS: [&str; 8] = ["TWENTY", "THIRTY", "FORTY", "FIFTY", "SIXTY", "SEVENTY", "EIGHTY", "NINETY"];
+
+pub fn expand(number: u16) -> Option<&'static str> {
+    TEXTS.binary_search_by_key(&number, |&(n, _)| n)
+        .ok()
+        .map(|i| TEXTS[i].1)
+}
+
+fn ones(group: &str) -> Option<u16> {
+    ONES.iter().position(|&g| g == group).map(|i| i as u16 + 1)
+}
+
+fn tens(group: &str) -> Option<u16> {
+    TENS.iter().position(|&g| g == group).map(|i| (i as u16 + 2) * 10)
+}
+
+/// Reads a spelled-out number, such as `FORTY SIX` or `FORTY-SIX`, from the start of
+/// `groups`. Returns the number and how many groups it took up.
+pub(crate) fn spelled_number(groups: &[&str]) -> Option<(u16, usize)> {
+    let first = *groups.first()?;
+    if let Some((t, o)) = first.split_once('-') {
+        let number = tens(t)? + ones(o).filter(|&o| o < 10)?;
+        return Some((number, 1));
+    }
+
+    if let Some(number) = ones(first) {
+        return Some((number, 1));
+    }
+
+    let t = tens(first)?;
+    match groups.get(1).and_then(|&g| ones(g)).filter(|&o| o < 10) {
+        Some(o) => Some((t + o, 2)),
+        None => Some((t, 1)),
+    }
+}
+
+impl Traffic {
+    /// The body with each ARL number replaced by its text.
+    pub fn expand_arl(&self) -> String {
+        let groups: Vec<&str> = self.body.split_whitespace().collect();
+        let mut expanded = Vec::with_capacity(groups.len());
+        let mut i = 0;
+        while i < groups.len() {
+            if groups[i] == "ARL" {
+                if let Some((text, used)) = spelled_number(&groups[i + 1..])
+                    .and_then(|(number, used)| Some((expand(number)?, used)))
+                {
+                    expanded.push(text);
+                    i += 1 + used;
+                    continue;
+                }
+            }
+            expanded.push(groups[i]);
+            i += 1;
+        }
+        expanded.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_table() {
+        assert_eq!(expand(46), Some("Greetings on your birthday and best wishes for many more to come."));
+        assert_eq!(expand(60), Some("Wishing you the best of everything on ___."));
+        assert_eq!(expand(27), None);
+        assert_eq!(expand(0), None);
+        assert!(TEXTS.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn spelled_numbers() {
+        assert_eq!(spelled_number(&["ONE"]), Some((1, 1)));
+        assert_eq!(spelled_number(&["NINETEEN", "SEVEN"]), Some((19, 1)));
+        assert_eq!(spelled_number(&["FORTY", "SIX", "HAPPY"]), Some((46, 2)));
+        assert_eq!(spelled_number(&["SIXTY-SEVEN"]), Some((67, 1)));
+        assert_eq!(spelled_number(&["FIFTY", "TWELVE"]), Some((50, 1)));
+        assert_eq!(spelled_number(&["SIXTY-TWELVE"]), None);
+        assert_eq!(spelled_number(&["HAPPY"]), None);
+        assert_eq!(spelled_number(&[]), None);
+    }
+
+    #[test]
+    fn expand_body() {
+        let mut traffic = crate::test();
+        traffic.body = String::from("ARL FORTY SIX ARL SIXTY YOUR GRADUATION LOVE MOM");
+        assert_eq!(
+            traffic.expand_arl(),
+            "Greetings on your birthday and best wishes for many more to come. \
+             Wishing you the best of everything on ___. YOUR GRADUATION LOVE MOM",
+        );
+
+        traffic.body = String::from("ARL THIRTY ARL");
+        assert_eq!(traffic.expand_arl(), "ARL THIRTY ARL");
+    }
+}
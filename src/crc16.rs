@@ -1,5 +1,12 @@
+//! The CRC16 that guards a B2F message's compressed content.
+//!
+//! This is CRC-16/XMODEM: the CCITT polynomial `0x1021`, processed most significant bit
+//! first, with an initial value of `0x0000`, no reflection, and no final XOR. It differs from
+//! the "CCITT-FALSE" variant only in starting from `0x0000` instead of `0xFFFF`.
+
 use std::fmt;
 
+#[derive(Clone)]
 pub struct Crc16 {
     state: u16
 }
@@ -12,15 +19,37 @@ impl fmt::Debug for Crc16 {
     }
 }
 
+impl Default for Crc16 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Crc16 {
     pub const fn new() -> Self {
         Self { state: 0 }
     }
 
+    /// The CRC of `data` in one go.
+    pub fn checksum(data: &[u8]) -> u16 {
+        let mut crc = Self::new();
+        crc.update_slice(data);
+        crc.finish()
+    }
+
     pub fn update(&mut self, c: u8) {
         self.state = (self.state << 8) ^ CRC_TABLE[(c ^ (self.state >> 8) as u8) as usize]
     }
 
+    pub fn update_slice(&mut self, data: &[u8]) {
+        data.iter().for_each(|&c| self.update(c))
+    }
+
+    /// The CRC of the bytes seen so far, leaving the calculation open for more.
+    pub fn value(&self) -> u16 {
+        self.state
+    }
+
     pub fn finish(self) -> u16 {
         self.state
     }
@@ -72,4 +101,18 @@ mod tests {
         assert_eq!(result.finish(), u16::from_le_bytes([input[0x29], input[0x2A]]));
 
     }
+
+    #[test]
+    fn checksum_matches_sample() {
+        let input = include_bytes!("../samples/winlink.raw");
+        let declared = u16::from_le_bytes([input[0x29], input[0x2A]]);
+        assert_eq!(Crc16::checksum(&input[0x2B..=0x10B]), declared);
+    }
+
+    #[test]
+    fn checksum_is_xmodem() {
+        // The standard check value for CRC-16/XMODEM.
+        assert_eq!(Crc16::checksum(b"123456789"), 0x31C3);
+        assert_eq!(Crc16::checksum(b""), 0x0000);
+    }
 }
@@ -1,5 +1,6 @@
 use std::fmt;
 use std::fmt::Formatter;
+use std::time::SystemTime;
 use aliri_braid::braid;
 use nom::branch::alt;
 use nom::{IResult, Parser};
@@ -10,10 +11,21 @@ use nom::combinator::{all_consuming, map, map_res, not, opt, value, verify};
 use nom::error::VerboseError;
 use nom::number::streaming::be_u8;
 use nom::sequence::{delimited, preceded, separated_pair, terminated, tuple};
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use crate::crc16::Crc16;
-use crate::lzhuf::Decoder;
+use crate::lzhuf::{encode_with_header, Decoder, IncrementalDecoder};
+pub use crate::lzhuf::DecodeError;
 use crate::{StationId, StationIdRef};
 
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+
+/// Winlink clients send full blocks of 250 bytes, although the length byte allows up to 256.
+const MAX_BLOCK_SIZE: usize = 250;
+const MAX_TITLE_SIZE: usize = 80;
+
 fn soh(data: &[u8]) -> IResult<&[u8], &[u8], VerboseError<&[u8]>> {
     tag(&[0x01])(data)
 }
@@ -31,62 +43,616 @@ fn eot(data: &[u8]) -> IResult<&[u8], &[u8], VerboseError<&[u8]>> {
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
-struct CompressedMessage<'a> {
-    title: &'a str,
+pub struct CompressedMessage<'a> {
+    title: &'a MessageTitleRef,
     offset: u32,
     crc16: u16,
     uncompressed_size: u32,
     blocks: Vec<&'a [u8]>,
+    checksum: u8,
+    encoding: BlockEncoding,
+}
+
+/// The title from a message block's header. Winlink fills it with the message's subject,
+/// so it carries no message id; that comes from the proposal. Only printable ASCII and
+/// spaces are allowed, as in the header. Titles longer than 80 characters are truncated
+/// when framed.
+#[braid(validator)]
+pub struct MessageTitle;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("message titles may only contain printable ASCII and spaces")]
+pub struct InvalidMessageTitle;
+
+impl aliri_braid::Validator for MessageTitle {
+    type Error = InvalidMessageTitle;
+
+    fn validate(s: &str) -> Result<(), Self::Error> {
+        if s.bytes().all(is_title_byte) {
+            Ok(())
+        } else {
+            Err(InvalidMessageTitle)
+        }
+    }
+}
+
+fn is_title_byte(c: u8) -> bool {
+    c.is_ascii_graphic() || c == b' '
+}
+
+/// How the content of a message's STX blocks is encoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum BlockEncoding {
+    /// A CRC16 and little-endian length, followed by LZHUF-compressed content.
+    #[default]
+    Lzhuf,
+    /// The content itself, with no CRC16 or length prefix.
+    Uncompressed,
+}
+
+impl BlockEncoding {
+    /// Chooses the encoding from the feature flags of a peer's SID, such as `B2FHM$`
+    /// in `[RMS-1.2-B2FHM$]`. Peers without the `B` flag can't decompress.
+    pub fn from_sid_features(features: &str) -> Self {
+        if features.contains('B') {
+            Self::Lzhuf
+        } else {
+            Self::Uncompressed
+        }
+    }
+}
+
+/// A well-formed B2F message block whose contents were corrupted in transit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum IntegrityError {
+    #[error("checksum mismatch: declared {declared:#04X}, computed {computed:#04X}")]
+    Checksum { declared: u8, computed: u8 },
+    #[error("CRC16 mismatch: declared {declared:#06X}, computed {computed:#06X}")]
+    Crc16 { declared: u16, computed: u16 },
+}
+
+/// The most a message may declare it decompresses to before [`CompressedMessage::decompress`]
+/// refuses it. Winlink messages, attachments included, are far smaller.
+pub const MAX_UNCOMPRESSED_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecompressError {
+    #[error("message declares {size} bytes uncompressed, more than the limit of {limit}")]
+    TooLarge { size: u32, limit: usize },
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
 }
 
 impl<'a> CompressedMessage<'a> {
-    fn decompress(self) -> Result<Vec<u8>, crate::lzhuf::UnexpectedEof> {
+    pub fn title(&self) -> &'a str {
+        self.title.as_str()
+    }
+
+    /// The title as a checked [`MessageTitle`].
+    pub fn message_title(&self) -> &'a MessageTitleRef {
+        self.title
+    }
+
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// The CRC16 sent in the first data block, covering the size and compressed data.
+    /// Uncompressed messages have none and report `0`.
+    pub fn declared_crc16(&self) -> u16 {
+        self.crc16
+    }
+
+    pub fn encoding(&self) -> BlockEncoding {
+        self.encoding
+    }
+
+    pub fn uncompressed_size(&self) -> u32 {
+        self.uncompressed_size
+    }
+
+    /// Checks the EOT checksum, and then the CRC16 when the content is compressed.
+    pub fn verify(&self) -> Result<(), IntegrityError> {
+        let mut verifier = BlockVerifier::new(self.encoding);
+        if self.encoding == BlockEncoding::Lzhuf {
+            verifier.update(&self.crc16.to_le_bytes());
+            verifier.update(&self.uncompressed_size.to_le_bytes());
+        }
+        self.blocks.iter().for_each(|block| verifier.update(block));
+        verifier.finish(self.checksum)
+    }
+
+    /// Decompresses the message, refusing any that claim to be larger than
+    /// [`MAX_UNCOMPRESSED_SIZE`].
+    pub fn decompress(self) -> Result<Vec<u8>, DecompressError> {
+        self.decompress_with_limit(MAX_UNCOMPRESSED_SIZE)
+    }
+
+    /// Decompresses the message unless its declared size, which comes from the peer, is
+    /// over `max`. The size is checked before anything is allocated.
+    pub fn decompress_with_limit(self, max: usize) -> Result<Vec<u8>, DecompressError> {
+        if usize::try_from(self.uncompressed_size).map_or(true, |size| size > max) {
+            return Err(DecompressError::TooLarge { size: self.uncompressed_size, limit: max });
+        }
+
         let mut buffer = vec![0; self.uncompressed_size as usize];
-        let mut decoder = Decoder::new(self.blocks.into_iter().flatten().copied());
-        decoder.decode(&mut buffer)?;
+        self.decompress_to(&mut buffer)?;
         Ok(buffer)
     }
-}
 
-fn b2_message_block(data: &[u8]) -> IResult<&[u8], CompressedMessage, VerboseError<&[u8]>> {
-    let (rest, (title, offset)) = header(data)?;
-    let (rest, (crc16, uncompressed_size, blocks, _)) =
-        verify(
-            data_blocks,
-            |(crc16, uncompressed_size, blocks, checksum)| {
-                // println!("checksum: {:#0X}", checksum); //A1
-                // println!("just blocks: {:#0X}", (&blocks[..]).iter().copied().flatten().fold(0u8, |x, &y| x.wrapping_add(y)));
-                // println!("header bytes: {:#0X}", h_bytes.iter().fold(0u8, |x, &y| x.wrapping_add(y)));
-                // println!("all bytes: {:#0X}", data.iter().rev().skip(2).fold(0u8, |x, &y| x.wrapping_add(y)));
-                // println!("total: {:#0X}", bytes.iter().rev().skip(2).fold(0u8, |x, &y| x.wrapping_add(y)));
-                let check =
-                    ((crc16.wrapping_add(crc16 >> 8)) as u32)
-                        .wrapping_add(
-                            uncompressed_size
-                                .wrapping_add(uncompressed_size >> 8)
-                                .wrapping_add(uncompressed_size >> 16)
-                                .wrapping_add(uncompressed_size >> 24)
-                        );
-                let check = blocks[..].iter().copied().flatten().copied().fold((check & 0xff) as u8, |x, y| x.wrapping_add(y));
-                let checksum_ok = checksum.wrapping_add(check) == 0;
-
-                if !checksum_ok {
-                    tracing::warn!(sum = format!("{:#04X}", check).as_str(), expected = format!("{:#04X}", checksum).as_str(), actual = format!("{:#04X}", -(check as i8)).as_str(), "checksum mismatch");
+    /// Decodes into the start of `buffer`, which must hold at least [`uncompressed_size`](Self::uncompressed_size) bytes.
+    pub fn decompress_to(&self, buffer: &mut [u8]) -> Result<(), DecodeError> {
+        let buffer = buffer.get_mut(..self.uncompressed_size as usize).ok_or(DecodeError::Overrun)?;
+        match self.encoding {
+            BlockEncoding::Lzhuf => {
+                let mut decoder = Decoder::new(self.blocks.iter().copied().flatten().copied());
+                decoder.decode(buffer)
+            }
+            BlockEncoding::Uncompressed => {
+                let mut rest = buffer;
+                for block in &self.blocks {
+                    let (head, tail) = rest.split_at_mut(block.len());
+                    head.copy_from_slice(block);
+                    rest = tail;
                 }
+                Ok(())
+            }
+        }
+    }
+
+    /// The content still to be sent when a peer accepts this message at `offset`, skipping
+    /// that many bytes across block boundaries. For compressed messages, the offset counts
+    /// LZHUF data after the CRC16 and size, which are always resent.
+    pub fn blocks_from_offset(&self, offset: u16) -> impl Iterator<Item = &'a [u8]> + '_ {
+        let mut skip = usize::from(offset);
+        self.blocks.iter().filter_map(move |&block| {
+            if skip >= block.len() {
+                skip -= block.len();
+                None
+            } else {
+                let rest = &block[skip..];
+                skip = 0;
+                Some(rest)
+            }
+        })
+    }
+
+    /// Frames the rest of this message for a transfer resumed at `offset`, as accepted with
+    /// [`MessageChoice::Accept`]. See [`blocks_from_offset`](Self::blocks_from_offset).
+    pub fn encode_from_offset(&self, offset: u16) -> Vec<u8> {
+        let mut data = Vec::new();
+        if self.encoding == BlockEncoding::Lzhuf {
+            data.extend_from_slice(&self.crc16.to_le_bytes());
+            data.extend_from_slice(&self.uncompressed_size.to_le_bytes());
+        }
+        self.blocks_from_offset(offset).for_each(|block| data.extend_from_slice(block));
+        let checksum = match self.encoding {
+            BlockEncoding::Lzhuf => b2_checksum(self.crc16, self.uncompressed_size, &self.blocks_from_offset(offset).collect::<Vec<_>>()),
+            BlockEncoding::Uncompressed => negated_sum(&data),
+        };
+        frame(self.title.as_str(), u32::from(offset), &data, checksum)
+    }
 
-                let mut crc = Crc16::new();
-                uncompressed_size.to_le_bytes().into_iter().for_each(|b| crc.update(b));
-                blocks[..].iter().copied().flatten().copied().for_each(|b| crc.update(b));
-                let actual_crc = crc.finish();
-                let crc16_ok = *crc16 == actual_crc;
+    /// The `FC EM` line proposing this message to a peer, as [`winlink_proposal`] parses it.
+    /// The compressed size counts the CRC16 and length along with the blocks held, so it
+    /// only describes the whole message when [`offset`](Self::offset) is `0`.
+    pub fn winlink_proposal_line(&self, message_id: &MessageIdRef) -> String {
+        let prefix = if self.encoding == BlockEncoding::Lzhuf { PREFIX_SIZE } else { 0 };
+        let compressed_size = prefix + self.blocks.iter().map(|block| block.len()).sum::<usize>();
+        format!("FC EM {} {} {} 0\r", message_id, self.uncompressed_size, compressed_size)
+    }
 
-                if !crc16_ok {
-                    tracing::warn!(expected = format!("{:#06X}", crc16).as_str(), actual = format!("{:#06X}", actual_crc).as_str(), "CRC16 mismatch");
-                }
+    /// Compresses `body` into a complete B2F message block, ready to be parsed by [`b2_message_block`].
+    ///
+    /// Titles are limited to 80 characters and truncated beyond that, and anything but
+    /// printable ASCII and spaces is sent as `?`. The offset is only recorded in the header;
+    /// the whole body is always sent.
+    pub fn encode(title: &str, offset: u32, body: &[u8]) -> Vec<u8> {
+        let data = compress(body);
+        frame(title, offset, &data, compressed_checksum(&data))
+    }
+
+    /// Frames `body` as-is, for peers that don't support compression. Parse the result
+    /// with [`message_block`] and [`BlockEncoding::Uncompressed`].
+    pub fn encode_uncompressed(title: &str, offset: u32, body: &[u8]) -> Vec<u8> {
+        frame(title, offset, body, negated_sum(body))
+    }
+}
+
+/// The CRC16, length, and LZHUF-compressed content carried by a message's STX blocks.
+fn compress(body: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(body.len() + 6);
+    data.extend_from_slice(&[0; 2]);
+    data.extend_from_slice(&encode_with_header(body));
+
+    let mut crc = Crc16::new();
+    data[2..].iter().for_each(|&b| crc.update(b));
+    data[..2].copy_from_slice(&crc.finish().to_le_bytes());
+    data
+}
+
+/// The EOT checksum for `data` laid out as [`compress`] leaves it: the CRC16 and length,
+/// then the compressed content.
+fn compressed_checksum(data: &[u8]) -> u8 {
+    let crc16 = u16::from_le_bytes([data[0], data[1]]);
+    let uncompressed_size = u32::from_le_bytes([data[2], data[3], data[4], data[5]]);
+    b2_checksum(crc16, uncompressed_size, &[&data[PREFIX_SIZE..]])
+}
 
-                checksum_ok && crc16_ok
+/// Checks a message's integrity as its STX blocks arrive, rather than after the whole
+/// message has been buffered.
+///
+/// Feed each block's content, including the CRC16 and length prefix at the start of
+/// the first block, and then the checksum following EOT.
+#[derive(Clone, Debug)]
+pub struct BlockVerifier {
+    encoding: BlockEncoding,
+    declared_crc16: [u8; 2],
+    seen: usize,
+    crc: Crc16,
+    sum: u8,
+}
+
+impl Default for BlockVerifier {
+    fn default() -> Self {
+        Self::new(BlockEncoding::default())
+    }
+}
+
+impl BlockVerifier {
+    pub fn new(encoding: BlockEncoding) -> Self {
+        Self {
+            encoding,
+            declared_crc16: [0; 2],
+            seen: 0,
+            crc: Crc16::new(),
+            sum: 0,
+        }
+    }
+
+    pub fn update(&mut self, block: &[u8]) {
+        self.sum = add_bytes(self.sum, block);
+
+        let mut block = block;
+        if self.encoding == BlockEncoding::Lzhuf {
+            while self.seen < 2 {
+                let Some((&b, rest)) = block.split_first() else { break };
+                self.declared_crc16[self.seen] = b;
+                self.seen += 1;
+                block = rest;
+            }
+            self.crc.update_slice(block);
+        }
+        self.seen += block.len();
+    }
+
+    /// The CRC16 declared by the sender, once enough of the first block has arrived.
+    pub fn declared_crc16(&self) -> Option<u16> {
+        (self.encoding == BlockEncoding::Lzhuf && self.seen >= 2).then(|| u16::from_le_bytes(self.declared_crc16))
+    }
+
+    /// The CRC16 over the length and compressed data seen so far.
+    pub fn crc16(&self) -> u16 {
+        self.crc.value()
+    }
+
+    /// The EOT checksum that would close the blocks seen so far, as [`b2_checksum`] gives
+    /// for a whole message.
+    pub fn checksum(&self) -> u8 {
+        negated_sum(&[self.sum])
+    }
+
+    /// Checks the EOT checksum, and then the CRC16 when the content is compressed.
+    pub fn finish(self, checksum: u8) -> Result<(), IntegrityError> {
+        compare_checksum(checksum, self.checksum())?;
+
+        if let Some(declared) = self.declared_crc16() {
+            let computed = self.crc.finish();
+            if declared != computed {
+                return Err(IntegrityError::Crc16 { declared, computed });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The CRC16 and little-endian uncompressed length at the start of the first block.
+const PREFIX_SIZE: usize = 6;
+
+/// Decompresses an LZHUF-encoded B2F message as its STX blocks arrive, without first
+/// buffering the whole message.
+#[derive(Debug, Default)]
+pub struct StreamingDecompressor {
+    prefix: Vec<u8>,
+    uncompressed_size: Option<u32>,
+    produced: usize,
+    decoder: IncrementalDecoder,
+    decoded: Vec<u8>,
+    verifier: BlockVerifier,
+}
+
+impl StreamingDecompressor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds the content of the next STX block, returning whatever it allowed to be decoded.
+    ///
+    /// Blocks may also be split further, as when they straddle reads from a stream.
+    pub fn push_block(&mut self, block: &[u8]) -> Result<&[u8], DecodeError> {
+        self.verifier.update(block);
+
+        let mut block = block;
+        if self.uncompressed_size.is_none() {
+            let needed = (PREFIX_SIZE - self.prefix.len()).min(block.len());
+            self.prefix.extend_from_slice(&block[..needed]);
+            block = &block[needed..];
+            if self.prefix.len() == PREFIX_SIZE {
+                let size = u32::from_le_bytes(self.prefix[2..].try_into().unwrap());
+                self.uncompressed_size = Some(size);
             }
-        )(rest)?;
+        }
+
+        self.decoded.clear();
+        if let Some(size) = self.uncompressed_size {
+            self.decoder.push(block);
+            self.produced += self.decoder.decode(&mut self.decoded, size as usize - self.produced)?;
+        }
+        Ok(&self.decoded)
+    }
+
+    /// The length of the decompressed message, once the first block has arrived.
+    pub fn uncompressed_size(&self) -> Option<u32> {
+        self.uncompressed_size
+    }
+
+    /// Whether the whole message has been decoded.
+    pub fn is_finished(&self) -> bool {
+        self.uncompressed_size.is_some_and(|size| self.produced == size as usize)
+    }
+
+    /// Checks the blocks fed so far against the checksum following EOT, and the CRC16.
+    pub fn verify(self, checksum: u8) -> Result<(), IntegrityError> {
+        self.verifier.finish(checksum)
+    }
+}
+
+/// A compressed message whose transfer was cut short, kept so that a later session can ask
+/// for just the rest with `FS !<offset>`. With the `serde` feature, it can be saved to
+/// resume after a restart.
+///
+/// Call [`start_transfer`](Self::start_transfer) when each transfer's header arrives, and
+/// then [`append_blocks`](Self::append_blocks) with the content of its STX blocks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PartialMessage {
+    message_id: MessageId,
+    uncompressed_size: UncompressedSize,
+    compressed_size: CompressedSize,
+    /// The CRC16 and size from the first transfer, and then the LZHUF data so far.
+    received: Vec<u8>,
+    /// How much of the CRC16 and size, which every transfer starts with, the current
+    /// transfer has yet to resend.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    resent_prefix: usize,
+}
+
+/// A transfer that does not pick up where a [`PartialMessage`] left off.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("transfer starts at offset {offset}, but the message is missing everything from {missing}")]
+pub struct UnexpectedOffset {
+    pub offset: u32,
+    pub missing: u16,
+}
+
+impl PartialMessage {
+    /// Starts a message with the sizes declared in its proposal.
+    pub fn new(message_id: MessageId, uncompressed_size: UncompressedSize, compressed_size: CompressedSize) -> Self {
+        Self {
+            message_id,
+            uncompressed_size,
+            compressed_size,
+            received: Vec::new(),
+            resent_prefix: 0,
+        }
+    }
+
+    pub fn from_proposal(proposal: &WinlinkProposal<'_>) -> Self {
+        Self::new(proposal.message_id.to_owned(), proposal.uncompressed_message_size, proposal.compressed_message_size)
+    }
+
+    pub fn message_id(&self) -> &MessageIdRef {
+        &self.message_id
+    }
+
+    pub fn uncompressed_size(&self) -> UncompressedSize {
+        self.uncompressed_size
+    }
+
+    pub fn compressed_size(&self) -> CompressedSize {
+        self.compressed_size
+    }
+
+    /// The offset to ask for with `FS !<offset>`: how much LZHUF data, after the CRC16 and
+    /// size, has arrived so far.
+    pub fn missing_offset(&self) -> u16 {
+        self.received.len().saturating_sub(PREFIX_SIZE) as u16
+    }
+
+    /// Prepares for a transfer whose header declared `offset`, which must be the
+    /// [`missing_offset`](Self::missing_offset).
+    pub fn start_transfer(&mut self, offset: u32) -> Result<(), UnexpectedOffset> {
+        let missing = self.missing_offset();
+        if offset != u32::from(missing) {
+            return Err(UnexpectedOffset { offset, missing });
+        }
+
+        if self.received.len() < PREFIX_SIZE {
+            // Cut off before the CRC16 and size were complete, so take them afresh.
+            self.received.clear();
+            self.resent_prefix = 0;
+        } else {
+            self.resent_prefix = PREFIX_SIZE;
+        }
+        Ok(())
+    }
+
+    /// Adds the content of the current transfer's next STX blocks, skipping the CRC16 and
+    /// size when a resumed transfer sends them again.
+    pub fn append_blocks<'b>(&mut self, blocks: impl IntoIterator<Item = &'b [u8]>) {
+        for block in blocks {
+            let skip = self.resent_prefix.min(block.len());
+            self.resent_prefix -= skip;
+            self.received.extend_from_slice(&block[skip..]);
+        }
+    }
+
+    /// Whether everything in the proposal's compressed size has arrived.
+    pub fn is_complete(&self) -> bool {
+        self.received.len() >= usize::from(self.compressed_size.0)
+    }
+
+    /// Checks the CRC16 sent at the start of the first transfer against everything received.
+    pub fn verify(&self) -> Result<(), IntegrityError> {
+        let (declared, covered) = match self.received.split_first_chunk::<2>() {
+            Some((declared, covered)) => (u16::from_le_bytes(*declared), covered),
+            None => (0, &[][..]),
+        };
+        let mut crc = Crc16::new();
+        crc.update_slice(covered);
+        let computed = crc.finish();
+        if declared != computed {
+            return Err(IntegrityError::Crc16 { declared, computed });
+        }
+        Ok(())
+    }
+
+    /// Decompresses the message once it is complete, refusing any that claim to be larger
+    /// than [`MAX_UNCOMPRESSED_SIZE`].
+    pub fn decompress(&self) -> Result<Vec<u8>, DecompressError> {
+        let (_, (_, size)) = first_data_block(&self.received).map_err(|_| DecodeError::UnexpectedEof)?;
+        if usize::try_from(size).map_or(true, |size| size > MAX_UNCOMPRESSED_SIZE) {
+            return Err(DecompressError::TooLarge { size, limit: MAX_UNCOMPRESSED_SIZE });
+        }
+
+        let mut buffer = vec![0; size as usize];
+        Decoder::new(self.received[PREFIX_SIZE..].iter().copied()).decode(&mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+/// The byte following EOT in a compressed message, which brings the sum of the CRC16,
+/// uncompressed size, and block contents to zero.
+pub fn b2_checksum(crc16: u16, uncompressed_size: u32, blocks: &[&[u8]]) -> u8 {
+    negated_sum(crc16.to_le_bytes().iter().chain(&uncompressed_size.to_le_bytes()).chain(blocks.iter().copied().flatten()))
+}
+
+/// Adds `bytes` to a running sum, modulo 256.
+fn add_bytes<'a>(sum: u8, bytes: impl IntoIterator<Item = &'a u8>) -> u8 {
+    bytes.into_iter().fold(sum, |x, &y| x.wrapping_add(y))
+}
+
+fn negated_sum<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> u8 {
+    add_bytes(0, bytes).wrapping_neg()
+}
+
+fn compare_checksum(declared: u8, computed: u8) -> Result<(), IntegrityError> {
+    if declared != computed {
+        return Err(IntegrityError::Checksum { declared, computed });
+    }
+    Ok(())
+}
+
+/// The checksum sent after `F>` to close a batch of proposals: the sum of every byte of the
+/// proposal lines, `\r` terminators included, modulo 256 and then negated in two's
+/// complement, so that the lines and the checksum together sum to zero.
+pub fn proposal_checksum(proposals: &[u8]) -> u8 {
+    negated_sum(proposals)
+}
+
+/// Checks the checksum from an `F>` line against the proposal lines it followed.
+pub fn verify_proposal_checksum(proposals: &[u8], declared: u8) -> Result<(), IntegrityError> {
+    compare_checksum(declared, proposal_checksum(proposals))
+}
+
+/// `title` as a header can carry it: the first 80 characters, with each one that a
+/// [`MessageTitle`] can't hold replaced by `?`.
+fn title_bytes(title: &str) -> Vec<u8> {
+    title.chars()
+        .take(MAX_TITLE_SIZE)
+        .map(|c| u8::try_from(c).ok().filter(|&b| is_title_byte(b)).unwrap_or(b'?'))
+        .collect()
+}
+
+/// Wraps `data` in SOH, STX, and EOT framing, closing with `checksum`.
+fn frame(title: &str, offset: u32, data: &[u8], checksum: u8) -> Vec<u8> {
+    let title = &title_bytes(title);
+    let offset = offset.to_string();
+
+    let mut message = Vec::with_capacity(title.len() + offset.len() + data.len() + data.len() / MAX_BLOCK_SIZE * 2 + 8);
+    message.push(SOH);
+    message.push((title.len() + offset.len() + 2) as u8);
+    message.extend_from_slice(title);
+    message.push(0);
+    message.extend_from_slice(offset.as_bytes());
+    message.push(0);
+
+    for block in data.chunks(MAX_BLOCK_SIZE) {
+        message.push(STX);
+        message.push(block.len() as u8);
+        message.extend_from_slice(block);
+    }
+
+    message.push(EOT);
+    message.push(checksum);
+    message
+}
+
+/// Parses a complete B2F compressed message: the SOH header, STX data blocks,
+/// and EOT checksum. Blocks failing their CRC16 or checksum are rejected; use
+/// [`unverified_b2_message_block`] and [`CompressedMessage::verify`] to tell
+/// corruption apart from malformed framing.
+///
+/// ```
+/// let input = include_bytes!("../samples/winlink.raw");
+/// let (rest, message) = plusendi::fbb::b2_message_block(&input[..]).unwrap();
+/// assert!(rest.is_empty());
+///
+/// let body = message.decompress().unwrap();
+/// assert!(body.starts_with(b"MID: 3W9QQU3V3XVV\r\n"));
+/// ```
+pub fn b2_message_block(data: &[u8]) -> IResult<&[u8], CompressedMessage<'_>, VerboseError<&[u8]>> {
+    message_block(BlockEncoding::Lzhuf, data)
+}
+
+/// Parses a complete message with the given block encoding, rejecting it if its integrity checks fail.
+pub fn message_block(encoding: BlockEncoding, data: &[u8]) -> IResult<&[u8], CompressedMessage<'_>, VerboseError<&[u8]>> {
+    verify(|data| unverified_message_block(encoding, data), |message| {
+        if let Err(error) = message.verify() {
+            tracing::warn!(%error, "rejecting B2F message block");
+            false
+        } else {
+            true
+        }
+    })(data)
+}
+
+/// Parses the framing of a B2F compressed message without checking its CRC16 or checksum.
+pub fn unverified_b2_message_block(data: &[u8]) -> IResult<&[u8], CompressedMessage<'_>, VerboseError<&[u8]>> {
+    unverified_message_block(BlockEncoding::Lzhuf, data)
+}
+
+pub fn unverified_message_block(encoding: BlockEncoding, data: &[u8]) -> IResult<&[u8], CompressedMessage<'_>, VerboseError<&[u8]>> {
+    let (rest, (title, offset)) = header(data)?;
+    let (rest, (crc16, uncompressed_size, blocks, checksum)) = match encoding {
+        BlockEncoding::Lzhuf => data_blocks(rest)?,
+        BlockEncoding::Uncompressed => {
+            let (rest, (blocks, checksum)) = nom::multi::many_till(data_block, checksum)(rest)?;
+            let size = blocks.iter().map(|b| b.len() as u32).sum();
+            (rest, (0, size, blocks, checksum))
+        }
+    };
 
     Ok((rest, CompressedMessage {
         title,
@@ -94,17 +660,19 @@ fn b2_message_block(data: &[u8]) -> IResult<&[u8], CompressedMessage, VerboseErr
         crc16,
         uncompressed_size,
         blocks,
+        checksum,
+        encoding,
     }))
 }
 
-fn header(data: &[u8]) -> IResult<&[u8], (&str, u32), VerboseError<&[u8]>> {
+fn header(data: &[u8]) -> IResult<&[u8], (&MessageTitleRef, u32), VerboseError<&[u8]>> {
     let (rest, data) = preceded(soh, verify(be_u8, |&v| v <= 88))(data)?;
     let (rest, header) = take(data)(rest)?;
     let (_, (title, offset)) = all_consuming(terminated(
         separated_pair(
-            nom::combinator::map(
-                nom::bytes::complete::take_while(|c: u8| c.is_ascii_graphic() || c == b' '),
-                |bytes: &[u8]| unsafe { std::str::from_utf8_unchecked(bytes) }
+            nom::combinator::map_res(
+                nom::bytes::complete::take_while(is_title_byte),
+                |bytes: &[u8]| MessageTitleRef::from_str(unsafe { std::str::from_utf8_unchecked(bytes) })
             ),
             nul,
             nom::combinator::map_res(
@@ -125,13 +693,13 @@ fn checksum(data: &[u8]) -> IResult<&[u8], u8, VerboseError<&[u8]>> {
 fn data_blocks(data: &[u8]) -> IResult<&[u8], (u16, u32, Vec<&[u8]>, u8), VerboseError<&[u8]>> {
     let (rest, (mut blocks, checksum)) = nom::multi::many_till(data_block, checksum)(data)?;
 
-    let (crc16checksum, uncompressed_size) = if let Some(block) = blocks.get_mut(0) {
-        let (data, (crc16checksum, uncompressed_size)) = first_data_block(&*block)?;
-        *block = data;
-        (crc16checksum, uncompressed_size)
-    } else {
-        (0, 0)
+    // Even an empty body is sent as a block carrying the CRC16 and length, so a message
+    // without one is malformed rather than empty.
+    let Some(block) = blocks.get_mut(0) else {
+        return Err(nom::Err::Error(nom::error::ParseError::from_error_kind(data, nom::error::ErrorKind::Verify)));
     };
+    let (first, (crc16checksum, uncompressed_size)) = first_data_block(block)?;
+    *block = first;
 
     Ok((
         rest,
@@ -172,6 +740,9 @@ fn select_tag(data: &[u8]) -> IResult<&[u8], &[u8], VerboseError<&[u8]>> {
     tag("FS")(data)
 }
 
+/// The most proposals B2F allows before an `F>`.
+pub const MAX_PROPOSALS: usize = 5;
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum MessageChoice {
     Accept { offset: u16 },
@@ -195,7 +766,7 @@ fn selection_element(data: &[u8]) -> IResult<&[u8], MessageChoice, VerboseError<
     ))(data)
 }
 
-fn selection<const P: usize>(data: &[u8]) -> IResult<&[u8], [MessageChoice; P], VerboseError<&[u8]>> {
+pub fn selection<const P: usize>(data: &[u8]) -> IResult<&[u8], [MessageChoice; P], VerboseError<&[u8]>> {
     let (mut data, _) = terminated(select_tag, tag(" "))(data)?;
     let mut responses = [MessageChoice::Defer; P];
     for response in &mut responses {
@@ -206,6 +777,39 @@ fn selection<const P: usize>(data: &[u8]) -> IResult<&[u8], [MessageChoice; P],
     Ok((data, responses))
 }
 
+/// What a [`selection_n`] parser yields: one choice per proposal.
+pub type SelectionResult<'a> = IResult<&'a [u8], Vec<MessageChoice>, VerboseError<&'a [u8]>>;
+
+/// Like [`selection`], for a number of proposals only known at run time, such as the
+/// count of `FA`/`FB`/`FC` lines just sent. A bare `FS` answers zero proposals.
+pub fn selection_n(count: usize) -> impl Fn(&[u8]) -> SelectionResult<'_> {
+    move |data| {
+        if count == 0 {
+            return map(select_tag, |_| Vec::new())(data);
+        }
+        preceded(terminated(select_tag, tag(" ")), nom::multi::count(selection_element, count))(data)
+    }
+}
+
+/// Renders an `FS` line answering a peer's proposals, in the same order as they were proposed.
+///
+/// The line terminator is left to the caller.
+pub fn render_selection(choices: &[MessageChoice]) -> String {
+    let mut line = String::from("FS ");
+    for choice in choices {
+        match choice {
+            MessageChoice::Accept { offset: 0 } => line.push('+'),
+            MessageChoice::Accept { offset } => {
+                line.push('!');
+                line.push_str(&offset.to_string());
+            }
+            MessageChoice::Defer => line.push('='),
+            MessageChoice::Reject => line.push('-'),
+        }
+    }
+    line
+}
+
 fn no_more(data: &[u8]) -> IResult<&[u8], &[u8], VerboseError<&[u8]>> {
     tag("FF")(data)
 }
@@ -230,21 +834,22 @@ fn message_type(data: &[u8]) -> IResult<&[u8], MessageType, VerboseError<&[u8]>>
 }
 
 #[braid]
-struct Sender;
+pub struct Sender;
 
 fn sender(data: &[u8]) -> IResult<&[u8], &SenderRef, VerboseError<&[u8]>> {
     map(map_res(take_while_m_n(1, 6, |x| !delimiter(x)), std::str::from_utf8), SenderRef::from_str)(data)
 }
 
 #[braid]
-struct Recipient;
+pub struct Recipient;
 
 #[braid]
-struct AtBbs;
+pub struct AtBbs;
 
-struct Addressee<'a> {
-    recipient: &'a RecipientRef,
-    mbo: &'a AtBbsRef,
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Addressee<'a> {
+    pub recipient: &'a RecipientRef,
+    pub mbo: &'a AtBbsRef,
 }
 
 fn recipient(data: &[u8]) -> IResult<&[u8], Addressee, VerboseError<&[u8]>> {
@@ -255,8 +860,9 @@ fn recipient(data: &[u8]) -> IResult<&[u8], Addressee, VerboseError<&[u8]>> {
     ), |(mbo, recipient)| Addressee { recipient, mbo })(data)
 }
 
-#[braid]
-struct MessageId;
+#[cfg_attr(feature = "serde", braid(serde))]
+#[cfg_attr(not(feature = "serde"), braid)]
+pub struct MessageId;
 
 fn message_id(data: &[u8]) -> IResult<&[u8], &MessageIdRef, VerboseError<&[u8]>> {
     map(map_res(take_while_m_n(1, 12, |x| !delimiter(x)), std::str::from_utf8), MessageIdRef::from_str)(data)
@@ -266,7 +872,7 @@ fn message_size(data: &[u8]) -> IResult<&[u8], u16, VerboseError<&[u8]>> {
     map_res(map_res(take_while_m_n(1, 6, is_digit), std::str::from_utf8), |s| u16::from_str_radix(s, 10))(data)
 }
 
-fn fbb_proposal(data: &[u8]) -> IResult<&[u8], Proposal, VerboseError<&[u8]>> {
+pub fn fbb_proposal(data: &[u8]) -> IResult<&[u8], Proposal<'_>, VerboseError<&[u8]>> {
     delimited(
         alt((fa_tag, fb_tag)),
         tuple((
@@ -280,12 +886,13 @@ fn fbb_proposal(data: &[u8]) -> IResult<&[u8], Proposal, VerboseError<&[u8]>> {
     )(data)
 }
 
-struct Proposal<'a> {
-    message_type: MessageType,
-    sender: &'a SenderRef,
-    addressee: Addressee<'a>,
-    message_id: &'a MessageIdRef,
-    message_size: u16,
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proposal<'a> {
+    pub message_type: MessageType,
+    pub sender: &'a SenderRef,
+    pub addressee: Addressee<'a>,
+    pub message_id: &'a MessageIdRef,
+    pub message_size: u16,
 }
 
 impl<'a> Proposal<'a> {
@@ -300,14 +907,15 @@ impl<'a> Proposal<'a> {
     }
 }
 
-fn winlink_proposal(data: &[u8]) -> IResult<&[u8], WinlinkProposal, VerboseError<&[u8]>> {
+pub fn winlink_proposal(data: &[u8]) -> IResult<&[u8], WinlinkProposal<'_>, VerboseError<&[u8]>> {
     delimited(
         fc_tag,
         map(tuple((
             preceded(tag(" "), tag("EM")),
             preceded(tag(" "), message_id),
-            preceded(tag(" "), message_size),
-            preceded(tag(" "), message_size),
+            preceded(tag(" "), map(message_size, UncompressedSize)),
+            preceded(tag(" "), map(message_size, CompressedSize)),
+            opt(preceded(tag(" "), tag("0"))),
             opt(
                 map(tuple((
                     preceded(tag(" "), sender),
@@ -319,15 +927,31 @@ fn winlink_proposal(data: &[u8]) -> IResult<&[u8], WinlinkProposal, VerboseError
     )(data)
 }
 
-struct WinlinkProposal<'a> {
-    message_id: &'a MessageIdRef,
-    compressed_message_size: u16,
-    uncompressed_message_size: u16,
-    bqp_extension: Option<BqpProposalExtension<'a>>
+/// The size of a message once compressed, including the CRC16 and length that precede
+/// the LZHUF data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CompressedSize(pub u16);
+
+/// The size of a message before compression.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UncompressedSize(pub u16);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WinlinkProposal<'a> {
+    pub message_id: &'a MessageIdRef,
+    pub compressed_message_size: CompressedSize,
+    pub uncompressed_message_size: UncompressedSize,
+    pub bqp_extension: Option<BqpProposalExtension<'a>>
 }
 
+/// The fields of an `FC` line in the order `winlink_proposal` parses them.
+type WinlinkProposalParts<'a> = (&'a [u8], &'a MessageIdRef, UncompressedSize, CompressedSize, Option<&'a [u8]>, Option<BqpProposalExtension<'a>>);
+
 impl<'a> WinlinkProposal<'a> {
-    fn from_parts((_, message_id, uncompressed_message_size, compressed_message_size, bqp_extension): (&[u8], &'a MessageIdRef, u16, u16, Option<BqpProposalExtension<'a>>)) -> Self {
+    /// `FC EM` lines give the uncompressed size before the compressed one.
+    fn from_parts((_, message_id, uncompressed_message_size, compressed_message_size, _, bqp_extension): WinlinkProposalParts<'a>) -> Self {
         Self {
             message_id,
             uncompressed_message_size,
@@ -337,9 +961,10 @@ impl<'a> WinlinkProposal<'a> {
     }
 }
 
-struct BqpProposalExtension<'a> {
-    sender: &'a SenderRef,
-    addressee: Addressee<'a>,
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BqpProposalExtension<'a> {
+    pub sender: &'a SenderRef,
+    pub addressee: Addressee<'a>,
 }
 
 impl<'a> BqpProposalExtension<'a> {
@@ -351,27 +976,1192 @@ impl<'a> BqpProposalExtension<'a> {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use test_log::test;
-    use super::*;
+/// One proposal line from a batch, in either the FBB (`FA`/`FB`) or Winlink (`FC`) form.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProposalKind<'a> {
+    Fbb(Proposal<'a>),
+    Winlink(WinlinkProposal<'a>),
+}
 
-    #[test]
-    fn try_it_out() -> color_eyre::Result<()> {
-        let input = include_bytes!("../samples/winlink.raw");
-        let (_, data) = all_consuming(b2_message_block)(&input[..])?;
-        data.decompress();
-        Ok(())
-        // Err(color_eyre::eyre::eyre!("just need a forced failure"))
+fn proposal_kind(data: &[u8]) -> IResult<&[u8], ProposalKind<'_>, VerboseError<&[u8]>> {
+    alt((
+        map(fbb_proposal, ProposalKind::Fbb),
+        map(winlink_proposal, ProposalKind::Winlink),
+    ))(data)
+}
+
+/// The `F>` line closing a batch of proposals, with the checksum if one was sent. See
+/// [`verify_proposal_checksum`].
+pub fn end_of_proposals(data: &[u8]) -> IResult<&[u8], Option<u8>, VerboseError<&[u8]>> {
+    terminated(
+        preceded(
+            end_of_proposal_tag,
+            opt(preceded(tag(" "), map_res(
+                map_res(take_while_m_n(2, 2, nom::character::is_hex_digit), std::str::from_utf8),
+                |hex| u8::from_str_radix(hex, 16),
+            ))),
+        ),
+        tag("\r"),
+    )(data)
+}
+
+/// A run of proposal lines through the closing `F>`, rejected if its checksum doesn't match.
+/// There is one proposal for each [`MessageChoice`] expected in the `FS` answer.
+pub fn proposal_batch(data: &[u8]) -> IResult<&[u8], Vec<ProposalKind<'_>>, VerboseError<&[u8]>> {
+    let (rest, (lines, proposals)) = nom::combinator::consumed(nom::multi::many1(proposal_kind))(data)?;
+    let (after, declared) = end_of_proposals(rest)?;
+
+    if let Some(declared) = declared {
+        if verify_proposal_checksum(lines, declared).is_err() {
+            return Err(nom::Err::Error(nom::error::ParseError::from_error_kind(rest, nom::error::ErrorKind::Verify)));
+        }
     }
+    Ok((after, proposals))
+}
 
-    #[test]
-    fn try_it_out2() -> color_eyre::Result<()> {
-        let input = include_bytes!("../samples/winlink2.raw");
-        let (_, data) = all_consuming(b2_message_block)(&input[..])?;
-        println!("{}", crate::parser::StrOrByteSlice::Bytes(&data.decompress().unwrap()));
-        Ok(())
-        // Err(color_eyre::eyre::eyre!("just need a forced failure"))
+/// A Winlink message's B2F headers and plain-text body, before compression.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WinlinkMessage {
+    pub message_id: MessageId,
+    pub date: SystemTime,
+    pub from: StationId,
+    pub to: StationId,
+    pub subject: String,
+    pub body: String,
+}
+
+impl WinlinkMessage {
+    /// Renders the headers and body with CRLF line endings, as Winlink expects.
+    pub fn render(&self) -> Vec<u8> {
+        let body = self.body.replace("\r\n", "\n").replace('\n', "\r\n");
+        format!(
+            "MID: {}\r\nDate: {}\r\nType: Private\r\nFrom: {}\r\nTo: {}\r\nSubject: {}\r\nMbo: {}\r\nBody: {}\r\n\r\n{}",
+            self.message_id,
+            format_date(self.date),
+            self.from,
+            self.to,
+            self.subject,
+            self.from,
+            body.len(),
+            body,
+        ).into_bytes()
+    }
+}
+
+/// Formats a time as the `YYYY/MM/DD HH:MM` UTC used by B2F `Date` headers.
+fn format_date(date: SystemTime) -> String {
+    let secs = date.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = ((secs / 86_400) as i64, secs % 86_400);
+
+    // Converts days since the epoch to a proleptic Gregorian date, counting years from March.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}/{:02}/{:02} {:02}:{:02}", year, month, day, secs / 3_600, secs % 3_600 / 60)
+}
+
+/// A message compressed and framed, ready to be proposed to a peer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutboundMessage {
+    message_id: MessageId,
+    uncompressed_size: usize,
+    compressed_size: usize,
+    crc16: u16,
+    block: Vec<u8>,
+}
+
+impl OutboundMessage {
+    /// Compresses `body`, which should already begin with its B2F headers.
+    pub fn new(message_id: MessageId, title: &str, body: &[u8]) -> Self {
+        let data = compress(body);
+        Self {
+            message_id,
+            uncompressed_size: body.len(),
+            compressed_size: data.len(),
+            crc16: u16::from_le_bytes([data[0], data[1]]),
+            block: frame(title, 0, &data, compressed_checksum(&data)),
+        }
+    }
+
+    pub fn message_id(&self) -> &MessageIdRef {
+        &self.message_id
+    }
+
+    fn proposal(&self) -> String {
+        format!("FC EM {} {} {} 0", self.message_id, self.uncompressed_size, self.compressed_size)
+    }
+
+    /// The message block to send when the peer accepts at `offset`, if the offset lies
+    /// within the compressed data.
+    fn block_from_offset(&self, offset: u16) -> Option<Vec<u8>> {
+        if offset == 0 {
+            return Some(self.block.clone());
+        }
+        if usize::from(offset) >= self.compressed_size - PREFIX_SIZE {
+            return None;
+        }
+        let (_, message) = unverified_b2_message_block(&self.block).ok()?;
+        Some(message.encode_from_offset(offset))
+    }
+}
+
+impl From<&WinlinkMessage> for OutboundMessage {
+    fn from(message: &WinlinkMessage) -> Self {
+        Self::new(message.message_id.clone(), &message.subject, &message.render())
+    }
+}
+
+/// Our SID, announcing support for B2F compressed forwarding.
+pub fn local_sid() -> String {
+    format!("[{}-{}-B2FWIHJM$]", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+/// The feature flags of a SID line such as `[RMS-1.2-B2FHM$]`.
+fn sid_features(line: &str) -> Option<&str> {
+    let sid = line.strip_prefix('[')?.strip_suffix(']')?;
+    let (_, features) = sid.rsplit_once('-')?;
+    Some(features)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SessionError {
+    #[error("I/O error during B2F session")]
+    Io(#[from] std::io::Error),
+    #[error("connection closed by peer")]
+    Closed,
+    #[error("peer reported an error: {0}")]
+    Peer(String),
+    #[error("unexpected line from peer: {0:?}")]
+    UnexpectedLine(String),
+    #[error("peer asked to resume at offset {0}, beyond the end of the message")]
+    UnsupportedOffset(u16),
+}
+
+/// A step in a [`Session`], as written by a [`SessionRecorder`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// The handshake finished, with the feature flags from the peer's SID if it sent one.
+    Handshake { peer_features: Option<String> },
+    /// A batch of proposals went out, closed by `F>` with `checksum`.
+    Proposed { message_ids: Vec<MessageId>, checksum: u8 },
+    /// The peer answered one of our proposals.
+    Selected { message_id: MessageId, choice: MessageChoice },
+    /// An accepted message went out from `offset`, with `crc16` declared in its first block.
+    Sent { message_id: MessageId, offset: u16, bytes: usize, crc16: u16 },
+    /// The peer proposed messages of its own, which were deferred. `checksum_valid` is
+    /// `None` when its `F>` carried no checksum.
+    PeerProposed { count: usize, checksum_valid: Option<bool> },
+    /// Both sides were done and the session ended with `FQ`.
+    Finished,
+}
+
+impl SessionEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Handshake { .. } => "handshake",
+            Self::Proposed { .. } => "proposed",
+            Self::Selected { .. } => "selected",
+            Self::Sent { .. } => "sent",
+            Self::PeerProposed { .. } => "peer_proposed",
+            Self::Finished => "finished",
+        }
+    }
+
+    /// The event as a single line of JSON, without the line terminator.
+    fn to_json(&self, time: SystemTime) -> String {
+        let time = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        let mut json = format!(r#"{{"time":{:.3},"event":"{}""#, time, self.name());
+        match self {
+            Self::Handshake { peer_features } => {
+                let features = peer_features.as_deref().map_or_else(|| String::from("null"), json_string);
+                json.push_str(&format!(r#","peer_features":{}"#, features));
+            }
+            Self::Proposed { message_ids, checksum } => {
+                let ids: Vec<_> = message_ids.iter().map(|id| json_string(id.as_str())).collect();
+                json.push_str(&format!(r#","message_ids":[{}],"checksum":{}"#, ids.join(","), checksum));
+            }
+            Self::Selected { message_id, choice } => {
+                let (choice, offset) = match choice {
+                    MessageChoice::Accept { offset } => ("accept", Some(offset)),
+                    MessageChoice::Defer => ("defer", None),
+                    MessageChoice::Reject => ("reject", None),
+                };
+                json.push_str(&format!(r#","message_id":{},"choice":"{}""#, json_string(message_id.as_str()), choice));
+                if let Some(offset) = offset {
+                    json.push_str(&format!(r#","offset":{}"#, offset));
+                }
+            }
+            Self::Sent { message_id, offset, bytes, crc16 } => {
+                json.push_str(&format!(r#","message_id":{},"offset":{},"bytes":{},"crc16":{}"#, json_string(message_id.as_str()), offset, bytes, crc16));
+            }
+            Self::PeerProposed { count, checksum_valid } => {
+                let valid = checksum_valid.map_or("null", |valid| if valid { "true" } else { "false" });
+                json.push_str(&format!(r#","count":{},"checksum_valid":{}"#, count, valid));
+            }
+            Self::Finished => {}
+        }
+        json.push('}');
+        json
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Writes each [`SessionEvent`] as a line of JSON with the time it happened, for a
+/// transcript of forwarding sessions. [`Session::record_to`] makes one around the writer
+/// it is given.
+pub struct SessionRecorder<W> {
+    writer: W,
+}
+
+impl<W: std::io::Write> SessionRecorder<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Writes `event` and flushes, so the transcript is complete even if the process dies.
+    pub fn record(&mut self, event: &SessionEvent) -> std::io::Result<()> {
+        writeln!(self.writer, "{}", event.to_json(SystemTime::now()))?;
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W> fmt::Debug for SessionRecorder<W> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionRecorder").finish_non_exhaustive()
+    }
+}
+
+/// Where a [`Session`] stands in the exchange of proposals.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionState {
+    /// Waiting on the peer's greeting.
+    Handshake,
+    /// Our turn, to propose messages or to say with `FF` that we have none.
+    Sending,
+    /// Waiting on the peer's proposals, or on its `FF` or `FQ`.
+    Receiving,
+    /// One side sent `FQ`.
+    Closed,
+}
+
+/// How the peer ended its turn without proposing anything.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Turnover {
+    /// `FF`: it has nothing more to send, so it is our turn.
+    NoMore,
+    /// `FQ`: it is ending the session.
+    Quit,
+}
+
+fn turnover(line: &str) -> Option<Turnover> {
+    alt((value(Turnover::NoMore, no_more), value(Turnover::Quit, all_done)))(line.as_bytes()).ok().map(|(_, turnover)| turnover)
+}
+
+/// The calling side of a B2F forwarding session, over an already connected stream.
+#[derive(Debug)]
+pub struct Session<S> {
+    stream: S,
+    buffer: BytesMut,
+    peer_features: Option<String>,
+    state: SessionState,
+    peer_finished: bool,
+    recorder: Option<SessionRecorder<Box<dyn std::io::Write + Send>>>,
+}
+
+fn session_line(data: &[u8]) -> IResult<&[u8], &[u8], VerboseError<&[u8]>> {
+    terminated(nom::bytes::streaming::take_until("\r"), tag("\r"))(data)
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Session<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            buffer: BytesMut::new(),
+            peer_features: None,
+            state: SessionState::Handshake,
+            peer_finished: false,
+            recorder: None,
+        }
+    }
+
+    /// Records every step of the session to `writer` from now on. A failure to record is
+    /// logged rather than ending the session.
+    pub fn record_to<W: std::io::Write + Send + 'static>(&mut self, writer: W) -> &mut Self {
+        self.recorder = Some(SessionRecorder::new(Box::new(writer)));
+        self
+    }
+
+    fn record(&mut self, event: SessionEvent) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(error) = recorder.record(&event) {
+                tracing::warn!(%error, event = event.name(), "failed to record session event");
+            }
+        }
+    }
+
+    /// The feature flags from the peer's SID, once the handshake has seen it.
+    pub fn peer_features(&self) -> Option<&str> {
+        self.peer_features.as_deref()
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    /// Whether the peer has said, with `FF` or `FQ`, that it has no more messages to send.
+    /// It is cleared whenever the peer proposes again.
+    pub fn peer_finished(&self) -> bool {
+        self.peer_finished
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    async fn read_line(&mut self) -> Result<String, SessionError> {
+        loop {
+            match session_line(&self.buffer) {
+                Ok((rest, line)) => {
+                    let line = String::from_utf8_lossy(line).trim_start_matches('\n').to_owned();
+                    let consumed = self.buffer.len() - rest.len();
+                    self.buffer.advance(consumed);
+                    tracing::trace!(line = line.as_str(), "received line");
+                    if let Some(message) = line.strip_prefix("***") {
+                        return Err(SessionError::Peer(message.trim().to_owned()));
+                    }
+                    return Ok(line);
+                }
+                Err(_) => {
+                    if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                        return Err(SessionError::Closed);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn write_line(&mut self, line: &str) -> Result<(), SessionError> {
+        tracing::trace!(line, "sending line");
+        self.stream.write_all(format!("{}\r", line).as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Reads the peer's greeting through its `>` prompt, and answers with our SID.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn handshake(&mut self) -> Result<(), SessionError> {
+        loop {
+            let line = self.read_line().await?;
+            if let Some(features) = sid_features(&line) {
+                self.peer_features = Some(features.to_owned());
+            }
+            if line.ends_with('>') {
+                break;
+            }
+        }
+        self.write_line(&local_sid()).await?;
+        self.state = SessionState::Sending;
+        self.record(SessionEvent::Handshake { peer_features: self.peer_features.clone() });
+        Ok(())
+    }
+
+    /// Proposes `messages`, sending each one the peer accepts, until both sides have
+    /// nothing more to send. Returns the peer's answer to each message, in order.
+    ///
+    /// Anything the peer proposes in return is deferred.
+    #[tracing::instrument(skip(self, messages), fields(messages = messages.len()), err)]
+    pub async fn exchange(&mut self, messages: &[OutboundMessage]) -> Result<Vec<MessageChoice>, SessionError> {
+        let mut choices = Vec::with_capacity(messages.len());
+        let mut batches = messages.chunks(MAX_PROPOSALS);
+        loop {
+            match batches.next() {
+                Some(batch) => choices.extend(self.propose(batch).await?),
+                None if self.peer_finished => {
+                    self.write_line("FQ").await?;
+                    self.state = SessionState::Closed;
+                    self.record(SessionEvent::Finished);
+                    return Ok(choices);
+                }
+                None => self.write_line("FF").await?,
+            }
+            self.state = SessionState::Receiving;
+
+            let mut proposed = 0;
+            let mut proposals = String::new();
+            loop {
+                let line = self.read_line().await?;
+                match turnover(&line) {
+                    Some(Turnover::Quit) => {
+                        self.peer_finished = true;
+                        self.state = SessionState::Closed;
+                        self.record(SessionEvent::Finished);
+                        return Ok(choices);
+                    }
+                    Some(Turnover::NoMore) => {
+                        self.peer_finished = true;
+                        self.state = SessionState::Sending;
+                        break;
+                    }
+                    None => {}
+                }
+                match line.get(..2) {
+                    Some("FA" | "FB" | "FC") => {
+                        proposed += 1;
+                        proposals.push_str(&line);
+                        proposals.push('\r');
+                    }
+                    Some("F>") => {
+                        let declared = end_of_proposals(format!("{}\r", line).as_bytes()).ok().and_then(|(_, declared)| declared);
+                        let checksum_valid = declared.map(|declared| verify_proposal_checksum(proposals.as_bytes(), declared).is_ok());
+                        self.record(SessionEvent::PeerProposed { count: proposed, checksum_valid });
+                        let deferred = vec![MessageChoice::Defer; proposed];
+                        self.write_line(&render_selection(&deferred)).await?;
+                        self.peer_finished = false;
+                        self.state = SessionState::Sending;
+                        break;
+                    }
+                    _ if line.is_empty() || line.starts_with(';') => {}
+                    _ => return Err(SessionError::UnexpectedLine(line)),
+                }
+            }
+        }
+    }
+
+    async fn propose(&mut self, batch: &[OutboundMessage]) -> Result<Vec<MessageChoice>, SessionError> {
+        let mut proposals = String::new();
+        for message in batch {
+            proposals.push_str(&message.proposal());
+            proposals.push('\r');
+        }
+        let checksum = proposal_checksum(proposals.as_bytes());
+        proposals.push_str(&format!("F> {:02X}\r", checksum));
+        self.stream.write_all(proposals.as_bytes()).await?;
+        self.record(SessionEvent::Proposed {
+            message_ids: batch.iter().map(|message| message.message_id.clone()).collect(),
+            checksum,
+        });
+
+        let line = loop {
+            let line = self.read_line().await?;
+            if !line.is_empty() && !line.starts_with(';') {
+                break line;
+            }
+        };
+        let terminated_line = format!("{}\r", line);
+        let choices = match all_consuming(terminated(selection_n(batch.len()), tag("\r")))(terminated_line.as_bytes()) {
+            Ok((_, choices)) => choices,
+            Err(_) => return Err(SessionError::UnexpectedLine(line)),
+        };
+
+        for (message, choice) in batch.iter().zip(&choices) {
+            self.record(SessionEvent::Selected { message_id: message.message_id.clone(), choice: *choice });
+            match *choice {
+                MessageChoice::Accept { offset } => {
+                    tracing::debug!(message_id = message.message_id.as_str(), offset, "sending accepted message");
+                    let block = message.block_from_offset(offset).ok_or(SessionError::UnsupportedOffset(offset))?;
+                    self.stream.write_all(&block).await?;
+                    self.record(SessionEvent::Sent { message_id: message.message_id.clone(), offset, bytes: block.len(), crc16: message.crc16 });
+                }
+                MessageChoice::Defer | MessageChoice::Reject => {}
+            }
+        }
+        self.stream.flush().await?;
+        Ok(choices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_log::test;
+    use super::*;
+
+    #[test]
+    fn try_it_out() -> color_eyre::Result<()> {
+        let input = include_bytes!("../samples/winlink.raw");
+        let (_, data) = all_consuming(b2_message_block)(&input[..])?;
+        data.decompress();
+        Ok(())
+        // Err(color_eyre::eyre::eyre!("just need a forced failure"))
+    }
+
+    #[test]
+    fn empty_body_differs_from_missing_blocks() -> color_eyre::Result<()> {
+        let input = CompressedMessage::encode("Empty", 0, b"");
+        let (_, message) = all_consuming(b2_message_block)(&input[..]).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert_eq!(message.decompress()?, b"");
+
+        let input = frame("No blocks", 0, b"", negated_sum(b""));
+        assert!(matches!(all_consuming(unverified_b2_message_block)(&input[..]), Err(nom::Err::Error(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn first_data_block_reads_encoded_header() -> color_eyre::Result<()> {
+        let body = include_bytes!("../samples/winlink.txt");
+        let mut block = vec![0x34, 0x12];
+        block.extend_from_slice(&encode_with_header(body));
+
+        let (stream, (crc16, uncompressed_size)) = first_data_block(&block).unwrap();
+        assert_eq!(crc16, 0x1234);
+        assert_eq!(uncompressed_size as usize, body.len());
+
+        let mut decoded = vec![0; body.len()];
+        Decoder::new(stream.iter().copied()).decode(&mut decoded)?;
+        assert_eq!(decoded, body);
+        Ok(())
+    }
+
+    #[test]
+    fn try_it_out2() -> color_eyre::Result<()> {
+        let input = include_bytes!("../samples/winlink2.raw");
+        let (_, data) = all_consuming(b2_message_block)(&input[..])?;
+        println!("{}", crate::parser::StrOrByteSlice::Bytes(&data.decompress().unwrap()));
+        Ok(())
+        // Err(color_eyre::eyre::eyre!("just need a forced failure"))
+    }
+
+    #[test]
+    fn message_titles_from_samples() -> color_eyre::Result<()> {
+        let (_, message) = all_consuming(b2_message_block)(&include_bytes!("../samples/winlink.raw")[..])?;
+        assert_eq!(message.message_title(), MessageTitleRef::from_str("Sending myself an outgoing message")?);
+        let (_, message) = all_consuming(b2_message_block)(&include_bytes!("../samples/winlink2.raw")[..])?;
+        assert_eq!(message.message_title().as_str(), "Need a test message");
+
+        assert_eq!(MessageTitle::new(String::from("Re: QSL?"))?.as_str(), "Re: QSL?");
+        assert_eq!(MessageTitleRef::from_str("tab\there"), Err(InvalidMessageTitle));
+        assert_eq!(MessageTitleRef::from_str("caf\u{e9}"), Err(InvalidMessageTitle));
+        Ok(())
+    }
+
+    #[test]
+    fn encoded_titles_are_sanitized() -> color_eyre::Result<()> {
+        let encoded = CompressedMessage::encode("caf\u{e9}\tmenu", 0, b"Bonjour");
+        let (_, message) = all_consuming(b2_message_block)(&encoded[..]).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert_eq!(message.message_title().as_str(), "caf??menu");
+
+        let long = "\u{e9}".repeat(100);
+        let encoded = CompressedMessage::encode_uncompressed(&long, 0, b"Bonjour");
+        let (_, message) = all_consuming(unverified_b2_message_block)(&encoded[..]).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert_eq!(message.message_title().as_str(), "?".repeat(MAX_TITLE_SIZE));
+        Ok(())
+    }
+
+    #[test]
+    fn inspect_before_decompressing() -> color_eyre::Result<()> {
+        let input = include_bytes!("../samples/winlink2.raw");
+        let (_, message) = all_consuming(b2_message_block)(&input[..])?;
+        assert_eq!(message.title(), "Need a test message");
+        assert_eq!(message.offset(), 0);
+        assert_eq!(message.declared_crc16(), 0x6418);
+        assert_eq!(message.uncompressed_size(), 418);
+
+        let mut buffer = [0; 512];
+        message.decompress_to(&mut buffer)?;
+        assert_eq!(&buffer[..418], &message.clone().decompress()?[..]);
+        assert!(matches!(message.decompress_to(&mut buffer[..417]), Err(DecodeError::Overrun)));
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_block_is_an_integrity_error() -> color_eyre::Result<()> {
+        let mut input = include_bytes!("../samples/winlink.raw").to_vec();
+        let flipped = input.len() / 2;
+        input[flipped] ^= 0x20;
+
+        let (_, message) = all_consuming(unverified_b2_message_block)(&input[..]).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert!(matches!(message.verify(), Err(IntegrityError::Checksum { .. })));
+        assert!(matches!(all_consuming(b2_message_block)(&input[..]), Err(nom::Err::Error(_))));
+
+        // Patch the trailing checksum so only the CRC16 disagrees.
+        let last = input.len() - 1;
+        input[last] = input[last].wrapping_sub(0x20);
+        let (_, message) = all_consuming(unverified_b2_message_block)(&input[..]).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert!(matches!(message.verify(), Err(IntegrityError::Crc16 { .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn sample_checksum_matches_b2_checksum() -> color_eyre::Result<()> {
+        for input in [&include_bytes!("../samples/winlink.raw")[..], &include_bytes!("../samples/winlink2.raw")[..]] {
+            let (_, message) = all_consuming(b2_message_block)(input)?;
+            assert_eq!(b2_checksum(message.crc16, message.uncompressed_size, &message.blocks), *input.last().unwrap());
+            let sum = message.blocks.iter().copied().flatten().fold(0u8, |x, &y| x.wrapping_add(y));
+            let sum = message.crc16.to_le_bytes().iter().chain(&message.uncompressed_size.to_le_bytes()).fold(sum, |x, &y| x.wrapping_add(y));
+            assert_eq!(message.checksum.wrapping_add(sum), 0);
+
+            let mut verifier = BlockVerifier::new(BlockEncoding::Lzhuf);
+            verifier.update(&message.crc16.to_le_bytes());
+            verifier.update(&message.uncompressed_size.to_le_bytes());
+            message.blocks.iter().for_each(|block| verifier.update(block));
+            assert_eq!(verifier.checksum(), b2_checksum(message.crc16, message.uncompressed_size, &message.blocks));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn decompress_refuses_oversized_message() -> color_eyre::Result<()> {
+        let mut data = vec![0; 2];
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        data.extend_from_slice(&[0xEC, 0x80]);
+        let encoded = frame("Huge", 0, &data, compressed_checksum(&data));
+        let (_, message) = all_consuming(unverified_b2_message_block)(&encoded[..]).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert_eq!(message.uncompressed_size(), u32::MAX);
+        assert!(matches!(message.clone().decompress(), Err(DecompressError::TooLarge { size: u32::MAX, limit: MAX_UNCOMPRESSED_SIZE })));
+
+        let body = b"Small enough";
+        let encoded = CompressedMessage::encode("Small", 0, body);
+        let (_, message) = all_consuming(b2_message_block)(&encoded[..]).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert!(matches!(message.clone().decompress_with_limit(body.len() - 1), Err(DecompressError::TooLarge { .. })));
+        assert_eq!(message.decompress_with_limit(body.len())?, body);
+        Ok(())
+    }
+
+    #[test]
+    fn uncompressed_sample() -> color_eyre::Result<()> {
+        let input = include_bytes!("../samples/uncompressed.raw");
+        let (_, message) = all_consuming(|d| message_block(BlockEncoding::Uncompressed, d))(&input[..])?;
+        assert_eq!(message.title(), "Uncompressed test");
+        assert_eq!(message.declared_crc16(), 0);
+
+        let body = message.decompress()?;
+        assert!(body.starts_with(b"MID: 7Q2X8KZ4N1PA\r\n"));
+        assert_eq!(CompressedMessage::encode_uncompressed("Uncompressed test", 0, &body), input);
+
+        assert!(all_consuming(b2_message_block)(&input[..]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn uncompressed_round_trips() -> color_eyre::Result<()> {
+        let body: Vec<u8> = (0..600u32).map(|i| (i % 251) as u8).collect();
+        let encoded = CompressedMessage::encode_uncompressed("Spans three blocks", 0, &body);
+        let (_, message) = all_consuming(|d| message_block(BlockEncoding::Uncompressed, d))(&encoded[..]).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert_eq!(message.decompress()?, body);
+
+        let mut corrupt = encoded.clone();
+        corrupt[40] ^= 1;
+        let (_, message) = all_consuming(|d| unverified_message_block(BlockEncoding::Uncompressed, d))(&corrupt[..]).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert!(matches!(message.verify(), Err(IntegrityError::Checksum { .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn encoding_from_sid() {
+        assert_eq!(BlockEncoding::from_sid_features("B2FHM$"), BlockEncoding::Lzhuf);
+        assert_eq!(BlockEncoding::from_sid_features("FHM$"), BlockEncoding::Uncompressed);
+    }
+
+    #[test]
+    fn incremental_crc_matches_one_shot() -> color_eyre::Result<()> {
+        let input = include_bytes!("../samples/winlink2.raw");
+        let (_, message) = all_consuming(b2_message_block)(&input[..])?;
+        let (mut rest, _) = header(&input[..])?;
+
+        let mut verifier = BlockVerifier::new(BlockEncoding::Lzhuf);
+        let mut received = Vec::new();
+        while let Ok((r, block)) = data_block(rest) {
+            verifier.update(block);
+            received.extend_from_slice(block);
+            let mut one_shot = Crc16::new();
+            one_shot.update_slice(&received[2..]);
+            assert_eq!(verifier.crc16(), one_shot.finish());
+            rest = r;
+        }
+
+        assert_eq!(verifier.declared_crc16(), Some(message.declared_crc16()));
+        assert_eq!(verifier.crc16(), message.declared_crc16());
+        let (_, sum) = all_consuming(checksum)(rest)?;
+        verifier.finish(sum)?;
+        Ok(())
+    }
+
+    #[test]
+    fn resume_at_offset() -> color_eyre::Result<()> {
+        let input = include_bytes!("../samples/winlink2.raw");
+        let (_, message) = all_consuming(b2_message_block)(&input[..])?;
+        let expected = message.clone().decompress()?;
+
+        let content: Vec<u8> = message.blocks_from_offset(0).flatten().copied().collect();
+        let resumed: Vec<u8> = message.blocks_from_offset(100).flatten().copied().collect();
+        assert_eq!(resumed, content[100..]);
+        assert!(message.blocks_from_offset(content.len() as u16).next().is_none());
+
+        // The resumed transfer resends the CRC16 and size, then picks up where the first stopped.
+        let framed = message.encode_from_offset(100);
+        let (_, second) = all_consuming(unverified_b2_message_block)(&framed[..]).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert_eq!(second.offset(), 100);
+        assert_eq!(second.declared_crc16(), message.declared_crc16());
+        assert_eq!(second.blocks_from_offset(0).flatten().copied().collect::<Vec<u8>>(), resumed);
+
+        let mut decompressor = StreamingDecompressor::new();
+        let mut decoded = Vec::new();
+        let mut first = Vec::new();
+        first.extend_from_slice(&message.declared_crc16().to_le_bytes());
+        first.extend_from_slice(&message.uncompressed_size().to_le_bytes());
+        first.extend_from_slice(&content[..100]);
+        decoded.extend_from_slice(decompressor.push_block(&first)?);
+        for block in second.blocks_from_offset(0) {
+            decoded.extend_from_slice(decompressor.push_block(block)?);
+        }
+        assert!(decompressor.is_finished());
+        assert_eq!(decoded, expected);
+
+        let outbound = OutboundMessage::new(MessageId::new(String::from("3W9QQU3V3XVV")), "Test", &expected);
+        assert_eq!(outbound.block_from_offset(0), Some(outbound.block.clone()));
+        assert!(outbound.block_from_offset(u16::MAX).is_none());
+        let block = outbound.block_from_offset(10).unwrap();
+        let (_, resumed) = all_consuming(unverified_b2_message_block)(&block[..]).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert_eq!(resumed.offset(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn partial_message_resumes_after_saving() -> color_eyre::Result<()> {
+        let input = include_bytes!("../samples/winlink2.raw");
+        let (_, message) = all_consuming(b2_message_block)(&input[..])?;
+        let mut prefix = message.declared_crc16().to_le_bytes().to_vec();
+        prefix.extend_from_slice(&message.uncompressed_size().to_le_bytes());
+        let content: Vec<u8> = message.blocks_from_offset(0).flatten().copied().collect();
+        let expected = message.decompress()?;
+
+        let mut partial = PartialMessage::new(
+            MessageId::new(String::from("3W9QQU3V3XVV")),
+            UncompressedSize(expected.len() as u16),
+            CompressedSize((prefix.len() + content.len()) as u16),
+        );
+        partial.start_transfer(0)?;
+        let first: Vec<u8> = prefix.iter().chain(&content[..content.len() / 2]).copied().collect();
+        partial.append_blocks(first.chunks(MAX_BLOCK_SIZE));
+        assert!(!partial.is_complete());
+        assert_eq!(usize::from(partial.missing_offset()), content.len() / 2);
+
+        #[cfg(feature = "serde")]
+        let mut partial: PartialMessage = serde_json::from_str(&serde_json::to_string(&partial)?)?;
+
+        let offset = partial.missing_offset();
+        assert_eq!(partial.start_transfer(0), Err(UnexpectedOffset { offset: 0, missing: offset }));
+        partial.start_transfer(u32::from(offset))?;
+        let resent: Vec<u8> = prefix.iter().chain(&content[usize::from(offset)..]).copied().collect();
+        partial.append_blocks(resent.chunks(MAX_BLOCK_SIZE));
+        assert!(partial.is_complete());
+        partial.verify()?;
+        assert_eq!(partial.decompress()?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn streaming_decompression_in_chunks() -> color_eyre::Result<()> {
+        let input = include_bytes!("../samples/winlink2.raw");
+        let (_, message) = all_consuming(b2_message_block)(&input[..])?;
+        let expected = message.decompress()?;
+
+        let (mut rest, _) = header(&input[..])?;
+        let mut content = Vec::new();
+        while let Ok((r, block)) = data_block(rest) {
+            content.extend_from_slice(block);
+            rest = r;
+        }
+        let (_, sum) = all_consuming(checksum)(rest)?;
+
+        let mut decompressor = StreamingDecompressor::new();
+        let mut decoded = Vec::new();
+        for chunk in content.chunks(64) {
+            assert!(!decompressor.is_finished());
+            decoded.extend_from_slice(decompressor.push_block(chunk)?);
+        }
+        assert!(decompressor.is_finished());
+        assert_eq!(decompressor.uncompressed_size(), Some(expected.len() as u32));
+        assert_eq!(decoded, expected);
+        decompressor.verify(sum)?;
+        Ok(())
+    }
+
+    #[test]
+    fn streaming_back_references_cross_blocks() -> color_eyre::Result<()> {
+        let words = ["ALPHA ", "BRAVO ", "CHARLIE ", "DELTA ", "ECHO ", "FOXTROT ", "GOLF ", "HOTEL "];
+        let mut seed = 1u32;
+        let body: Vec<u8> = (0..800).flat_map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            words[(seed >> 16) as usize % words.len()].bytes()
+        }).collect();
+        let encoded = CompressedMessage::encode("Repetitive", 0, &body);
+        let (mut rest, _) = header(&encoded[..]).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+
+        let mut decompressor = StreamingDecompressor::new();
+        let mut decoded = Vec::new();
+        let mut blocks = 0;
+        while let Ok((r, block)) = data_block(rest) {
+            decoded.extend_from_slice(decompressor.push_block(block)?);
+            blocks += 1;
+            rest = r;
+        }
+        assert!(blocks > 1);
+        assert_eq!(decoded, body);
+        Ok(())
+    }
+
+    #[test]
+    fn b2f_dates() {
+        assert_eq!(format_date(SystemTime::UNIX_EPOCH), "1970/01/01 00:00");
+        let date = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_638_568_740);
+        assert_eq!(format_date(date), "2021/12/03 21:59");
+        let leap_day = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(951_782_400);
+        assert_eq!(format_date(leap_day), "2000/02/29 00:00");
+    }
+
+    #[test]
+    fn sid_feature_flags() {
+        assert_eq!(sid_features("[WL2K-5.0-B2FWIHJM$]"), Some("B2FWIHJM$"));
+        assert_eq!(sid_features("[RMS Express-1.5.1.0-B2FHM$]"), Some("B2FHM$"));
+        assert_eq!(sid_features("KW1U >"), None);
+    }
+
+    #[test(tokio::test)]
+    async fn session_defers_peer_proposals() -> color_eyre::Result<()> {
+        use tokio::io::AsyncBufReadExt;
+
+        let message = WinlinkMessage {
+            message_id: MessageId::new(String::from("3W9QQU3V3XVV")),
+            date: SystemTime::UNIX_EPOCH,
+            from: StationId::new("KC1GSL")?,
+            to: StationId::new("KW1U")?,
+            subject: String::from("Test"),
+            body: String::from("Hello\nthere"),
+        };
+        let outbound = OutboundMessage::from(&message);
+
+        let (client, server) = tokio::io::duplex(1024);
+        let mut peer = tokio::io::BufReader::new(server);
+        let (choices, script) = tokio::join!(
+            async {
+                let mut session = Session::new(client);
+                session.handshake().await?;
+                assert_eq!(session.peer_features(), Some("B2FHM$"));
+                session.exchange(&[outbound]).await
+            },
+            async {
+                let mut line = Vec::new();
+                peer.get_mut().write_all(b";PQ: 12345678\r\n[RMS-1.2-B2FHM$]\rKW1U >\r").await?;
+                for expected in [local_sid(), String::from("FC EM 3W9QQU3V3XVV")] {
+                    line.clear();
+                    peer.read_until(b'\r', &mut line).await?;
+                    assert!(line.starts_with(expected.as_bytes()));
+                }
+                peer.read_until(b'\r', &mut line).await?;
+                peer.get_mut().write_all(b"FS =\r").await?;
+
+                peer.get_mut().write_all(b"FC EM ABCDEFGHIJKL 100 80 0\rF> 00\r").await?;
+                line.clear();
+                peer.read_until(b'\r', &mut line).await?;
+                assert_eq!(line, b"FS =\r");
+
+                line.clear();
+                peer.read_until(b'\r', &mut line).await?;
+                assert_eq!(line, b"FF\r");
+                peer.get_mut().write_all(b"FQ\r").await?;
+                color_eyre::Result::<()>::Ok(())
+            },
+        );
+        script?;
+        assert_eq!(choices?, [MessageChoice::Defer]);
+        Ok(())
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn session_recorder_transcript() -> color_eyre::Result<()> {
+        let message = WinlinkMessage {
+            message_id: MessageId::new(String::from("3W9QQU3V3XVV")),
+            date: SystemTime::UNIX_EPOCH,
+            from: StationId::new("KC1GSL")?,
+            to: StationId::new("KW1U")?,
+            subject: String::from("Test"),
+            body: String::from("Hello\nthere"),
+        };
+        let outbound = OutboundMessage::from(&message);
+        let crc16 = unverified_b2_message_block(&outbound.block).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?.1.declared_crc16();
+
+        let (client, mut server) = tokio::io::duplex(8192);
+        server.write_all(b"[RMS-1.2-B2FHM$]\rKW1U >\rFS +\rFC EM ABCDEFGHIJKL 100 80 0\rF> 00\rFQ\r").await?;
+
+        let transcript = SharedBuffer::default();
+        let mut session = Session::new(client);
+        session.record_to(transcript.clone());
+        session.handshake().await?;
+        assert_eq!(session.exchange(std::slice::from_ref(&outbound)).await?, [MessageChoice::Accept { offset: 0 }]);
+
+        let transcript = String::from_utf8(transcript.0.lock().unwrap().clone())?;
+        let mut events = Vec::new();
+        for line in transcript.lines() {
+            let mut event: serde_json::Value = serde_json::from_str(line)?;
+            assert!(event["time"].as_f64().is_some());
+            event.as_object_mut().unwrap().remove("time");
+            events.push(event);
+        }
+        assert_eq!(events, [
+            serde_json::json!({"event": "handshake", "peer_features": "B2FHM$"}),
+            serde_json::json!({"event": "proposed", "message_ids": ["3W9QQU3V3XVV"], "checksum": proposal_checksum(format!("{}\r", outbound.proposal()).as_bytes())}),
+            serde_json::json!({"event": "selected", "message_id": "3W9QQU3V3XVV", "choice": "accept", "offset": 0}),
+            serde_json::json!({"event": "sent", "message_id": "3W9QQU3V3XVV", "offset": 0, "bytes": outbound.block.len(), "crc16": crc16}),
+            serde_json::json!({"event": "peer_proposed", "count": 1, "checksum_valid": false}),
+            serde_json::json!({"event": "finished"}),
+        ]);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn session_turns_over_on_ff_and_closes_on_fq() -> color_eyre::Result<()> {
+        let messages: Vec<_> = (0..6)
+            .map(|n| OutboundMessage::new(MessageId::new(format!("MSG{:09}", n)), "Test", b"Hello"))
+            .collect();
+        let (client, mut server) = tokio::io::duplex(8192);
+        server.write_all(b"[RMS-1.2-B2FHM$]\rKW1U >\rFS -----\rFF\rFS -\rFQ\r").await?;
+
+        let mut session = Session::new(client);
+        assert_eq!(session.state(), SessionState::Handshake);
+        session.handshake().await?;
+        assert_eq!(session.state(), SessionState::Sending);
+        assert!(!session.peer_finished());
+
+        assert_eq!(session.exchange(&messages).await?, [MessageChoice::Reject; 6]);
+        assert_eq!(session.state(), SessionState::Closed);
+        assert!(session.peer_finished());
+
+        // The last proposal went out after the peer's FF, and the peer's FQ went unanswered.
+        drop(session);
+        let mut sent = String::new();
+        server.read_to_string(&mut sent).await?;
+        let last = messages[5].proposal();
+        assert!(sent.contains(&last));
+        assert!(sent.ends_with(&format!("{}\rF> {:02X}\r", last, proposal_checksum(format!("{}\r", last).as_bytes()))));
+        Ok(())
+    }
+
+    #[test]
+    fn session_events_escape_strings() -> color_eyre::Result<()> {
+        let event = SessionEvent::Handshake { peer_features: Some(String::from("B2F\"\\\r\u{1}")) };
+        let json: serde_json::Value = serde_json::from_str(&event.to_json(SystemTime::UNIX_EPOCH))?;
+        assert_eq!(json, serde_json::json!({"time": 0.0, "event": "handshake", "peer_features": "B2F\"\\\r\u{1}"}));
+        Ok(())
+    }
+
+    #[test]
+    fn winlink_message_renders_like_sample() {
+        let message = WinlinkMessage {
+            message_id: MessageId::new(String::from("3W9QQU3V3XVV")),
+            date: SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_638_568_740),
+            from: StationId::new("KC1GSL").unwrap(),
+            to: StationId::new("KW1U").unwrap(),
+            subject: String::from("Sending myself an outgoing message"),
+            body: String::from("Hi\n"),
+        };
+        let rendered = message.render();
+        let sample = include_bytes!("../samples/winlink.txt");
+        let up_to_recipient = &sample[..sample.windows(4).position(|w| w == b"To: ").unwrap()];
+        assert!(rendered.starts_with(up_to_recipient));
+        assert!(rendered.ends_with(b"Body: 4\r\n\r\nHi\r\n"));
+    }
+
+    #[test]
+    fn render_selection_with_offset() -> color_eyre::Result<()> {
+        let choices = [
+            MessageChoice::Accept { offset: 0 },
+            MessageChoice::Accept { offset: 1500 },
+            MessageChoice::Defer,
+            MessageChoice::Reject,
+        ];
+        let line = render_selection(&choices);
+        assert_eq!(line, "FS +!1500=-");
+        let (_, parsed) = selection::<4>(line.as_bytes()).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert_eq!(parsed, choices);
+        Ok(())
+    }
+
+    #[test]
+    fn selection_n_parses_runtime_count() -> color_eyre::Result<()> {
+        let parse = |count, line: &'static str| selection_n(count)(line.as_bytes())
+            .map_err(|e| color_eyre::eyre::eyre!("{e:?}"));
+
+        let (rest, parsed) = parse(0, "FS\r")?;
+        assert_eq!(rest, b"\r");
+        assert!(parsed.is_empty());
+
+        let (rest, parsed) = parse(1, "FS Y\r")?;
+        assert_eq!(rest, b"\r");
+        assert_eq!(parsed, [MessageChoice::Accept { offset: 0 }]);
+
+        let (rest, parsed) = parse(4, "FS +!1500=-\r")?;
+        assert_eq!(rest, b"\r");
+        assert_eq!(parsed, [
+            MessageChoice::Accept { offset: 0 },
+            MessageChoice::Accept { offset: 1500 },
+            MessageChoice::Defer,
+            MessageChoice::Reject,
+        ]);
+
+        assert!(parse(3, "FS +-\r").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn proposal_batch_mixes_fbb_and_winlink() -> color_eyre::Result<()> {
+        let lines = "FB P KC1GSL W1AW KW1U 1234_KC1GSL 1500\rFC EM ABCDEFGHIJKL 100 80 0\r";
+        let checksum = negated_sum(lines.as_bytes());
+        let batch = format!("{}F> {:02X}\rFS", lines, checksum);
+
+        let (rest, proposals) = proposal_batch(batch.as_bytes()).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert_eq!(rest, b"FS");
+        assert_eq!(proposals.len(), 2);
+        assert!(matches!(&proposals[0], ProposalKind::Fbb(p) if p.message_id.as_str() == "1234_KC1GSL" && p.message_size == 1500));
+        assert!(matches!(&proposals[1], ProposalKind::Winlink(p) if p.message_id.as_str() == "ABCDEFGHIJKL" && p.compressed_message_size == CompressedSize(80)));
+
+        let wrong = format!("{}F> {:02X}\r", lines, checksum.wrapping_add(1));
+        assert!(proposal_batch(wrong.as_bytes()).is_err());
+        let unchecked = format!("{}F>\r", lines);
+        assert_eq!(proposal_batch(unchecked.as_bytes()).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?.1, proposals);
+        Ok(())
+    }
+
+    #[test]
+    fn winlink_proposal_sizes() -> color_eyre::Result<()> {
+        let (_, proposal) = all_consuming(winlink_proposal)(b"FC EM 3W9QQU3V3XVV 1324 712 0\r").map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert_eq!(proposal.message_id.as_str(), "3W9QQU3V3XVV");
+        assert_eq!(proposal.uncompressed_message_size, UncompressedSize(1324));
+        assert_eq!(proposal.compressed_message_size, CompressedSize(712));
+
+        let outbound = OutboundMessage::new(MessageId::new(String::from("3W9QQU3V3XVV")), "Test", &[b'x'; 500]);
+        let line = format!("{}\r", outbound.proposal());
+        let (_, proposal) = all_consuming(winlink_proposal)(line.as_bytes()).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert_eq!(proposal.uncompressed_message_size, UncompressedSize(500));
+        assert_eq!(proposal.compressed_message_size, CompressedSize(outbound.compressed_size as u16));
+        Ok(())
+    }
+
+    #[test]
+    fn proposal_line_from_message() -> color_eyre::Result<()> {
+        let body = include_bytes!("../samples/winlink.txt");
+        let outbound = OutboundMessage::new(MessageId::new(String::from("3W9QQU3V3XVV")), "Test", body);
+        let (_, message) = all_consuming(b2_message_block)(&outbound.block[..]).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+
+        let line = message.winlink_proposal_line(outbound.message_id());
+        assert_eq!(line, format!("{}\r", outbound.proposal()));
+        let (_, proposal) = all_consuming(winlink_proposal)(line.as_bytes()).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert_eq!(proposal, WinlinkProposal {
+            message_id: outbound.message_id(),
+            compressed_message_size: CompressedSize(outbound.compressed_size as u16),
+            uncompressed_message_size: UncompressedSize(body.len() as u16),
+            bqp_extension: None,
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn proposal_checksum_line() -> color_eyre::Result<()> {
+        let block = b"FC EM TJKYEIMMHSRB 527 123 0\rFC EM 3W9QQU3V3XVV 1324 712 0\rF> 65\r";
+        let lines = &block[..block.len() - 6];
+        let (rest, declared) = end_of_proposals(&block[lines.len()..]).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert!(rest.is_empty());
+        assert_eq!(declared, Some(0x65));
+        assert_eq!(proposal_checksum(lines), 0x65);
+        verify_proposal_checksum(lines, 0x65)?;
+
+        let mut corrupt = lines.to_vec();
+        corrupt[10] = b'X';
+        assert!(matches!(verify_proposal_checksum(&corrupt, 0x65), Err(IntegrityError::Checksum { declared: 0x65, .. })));
+
+        assert_eq!(end_of_proposals(b"F>\r").map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?.1, None);
+        assert!(end_of_proposals(b"F> G1\r").is_err());
+        Ok(())
+    }
+
+    fn message_choice() -> impl proptest::strategy::Strategy<Value = MessageChoice> {
+        use proptest::prelude::*;
+        prop_oneof![
+            any::<u16>().prop_map(|offset| MessageChoice::Accept { offset }),
+            Just(MessageChoice::Defer),
+            Just(MessageChoice::Reject),
+        ]
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn render_selection_round_trips(choices in proptest::array::uniform5(message_choice())) {
+            let line = render_selection(&choices) + "\r";
+            let (rest, parsed) = selection::<5>(line.as_bytes()).expect("rendered selection should parse");
+            proptest::prop_assert_eq!(rest, b"\r");
+            proptest::prop_assert_eq!(parsed, choices);
+        }
+    }
+
+    #[test]
+    fn encode_reproduces_sample() -> color_eyre::Result<()> {
+        let input = &include_bytes!("../samples/winlink.raw")[..];
+        let (_, data) = all_consuming(b2_message_block)(input)?;
+        let (title, offset) = (data.title(), data.offset);
+        let body = data.decompress()?;
+        assert_eq!(CompressedMessage::encode(title, offset, &body), input);
+        Ok(())
+    }
+
+    #[test]
+    fn encode_round_trips() -> color_eyre::Result<()> {
+        let body: Vec<u8> = include_bytes!("../samples/winlink.txt").iter().copied().cycle().take(3000).collect();
+        let encoded = CompressedMessage::encode("Round trip", 0, &body);
+        let (_, data) = all_consuming(b2_message_block)(&encoded[..]).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert_eq!(data.title(), "Round trip");
+        assert!(data.blocks.len() > 1);
+        assert_eq!(data.decompress()?, body);
+
+        let (_, data) = all_consuming(b2_message_block)(&include_bytes!("../samples/winlink2.raw")[..])?;
+        let body = data.decompress()?;
+        let encoded = CompressedMessage::encode("Need a test message", 0, &body);
+        let (_, data) = all_consuming(b2_message_block)(&encoded[..]).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert_eq!(data.decompress()?, body);
+
+        let encoded = CompressedMessage::encode("Empty", 0, b"");
+        let (_, data) = all_consuming(b2_message_block)(&encoded[..]).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert_eq!(data.decompress()?, b"");
+        Ok(())
     }
 
     #[test]
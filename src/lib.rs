@@ -1,17 +1,22 @@
 use std::collections::hash_set::HashSet;
+use std::hash::Hash;
 
+pub mod arl;
+pub mod crc16;
 pub mod fbb;
+pub mod lzhuf;
 pub mod modem;
 pub mod rig;
-mod crc16;
-mod lzhuf;
 mod types;
-mod parser;
+pub mod parser;
+mod radiogram;
 
 pub use modem::Modem;
-pub use types::{StationId, StationIdRef};
+pub use radiogram::{CheckMismatch, InvalidHxCode, InvalidPrecedence, TrafficParseError};
+pub use types::{Country, InvalidStationId, StationId, StationIdRef, ValidationLevel};
 
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Traffic {
     pub header: TrafficHeader,
     pub destination: Destination,
@@ -20,22 +25,58 @@ pub struct Traffic {
 }
 
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Destination {
     pub addressee: String,
-    pub station: Option<StationId>,
+    pub station: Option<Addressable>,
     pub address: Vec<String>,
     pub phone: Option<String>,
     pub email: Option<String>,
     pub op_note: Option<String>,
 }
 
+/// The station a radiogram is addressed to: either a call sign, or a tactical designator
+/// such as `EOC` or `SHELTER1` used during a net.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Addressable {
+    Station(StationId),
+    Tactical(String),
+}
+
+impl Addressable {
+    pub fn as_station(&self) -> Option<&StationIdRef> {
+        match self {
+            Self::Station(station) => Some(station),
+            Self::Tactical(_) => None,
+        }
+    }
+}
+
+impl From<StationId> for Addressable {
+    fn from(station: StationId) -> Self {
+        Self::Station(station)
+    }
+}
+
+impl std::fmt::Display for Addressable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Station(station) => f.write_str(station.as_str()),
+            Self::Tactical(designator) => f.write_str(designator),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Signature {
     pub signed_by: String,
     pub op_note: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TrafficHeader {
     pub service: ServiceType,
     pub number: u16,
@@ -50,18 +91,21 @@ pub struct TrafficHeader {
 }
 
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ServiceType {
     Normal,
     Service,
 }
 
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TrafficType {
     Normal,
     Test,
 }
 
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Precedence {
     Routine,
     Welfare,
@@ -69,7 +113,7 @@ pub enum Precedence {
     Emergency,
 }
 
-#[derive(Debug, Default, PartialEq, Eq)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Handling {
     directives: HashSet<HandlingDirective>,
 }
@@ -82,7 +126,26 @@ impl Handling {
     }
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+/// Serialized as an array in a stable order, regardless of the set's iteration order.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Handling {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut directives: Vec<_> = self.directives.iter().collect();
+        directives.sort();
+        serializer.collect_seq(directives)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Handling {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let directives = Vec::<HandlingDirective>::deserialize(deserializer)?;
+        Ok(Handling::with_directives(directives))
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum HandlingDirective {
     LandlineCollect {
         distance: u16,
@@ -100,17 +163,254 @@ pub enum HandlingDirective {
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Check {
     content: ContentType,
     count: u16,
+    /// Marked with an `R` before the count, as when a relay station corrected it.
+    #[cfg_attr(feature = "serde", serde(default))]
+    revised: bool,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ContentType {
     Standard,
     Arl,
 }
 
+/// A radiogram built with [`Traffic::builder`] lacked a field that has no default.
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("radiogram is missing its {0}")]
+pub struct MissingField(pub &'static str);
+
+/// Assembles a [`Traffic`] one field at a time. Unless set, the service and traffic type
+/// are normal, the precedence is routine, there are no handling instructions, and the
+/// check is counted from the body.
+#[derive(Clone, Debug, Default)]
+pub struct TrafficBuilder {
+    service: Option<ServiceType>,
+    number: Option<u16>,
+    traffic_type: Option<TrafficType>,
+    precedence: Option<Precedence>,
+    handling: Handling,
+    originator: Option<StationId>,
+    check: Option<Check>,
+    origin: Option<String>,
+    time_filed: Option<String>,
+    date: Option<String>,
+    addressee: Option<String>,
+    station: Option<Addressable>,
+    address: Vec<String>,
+    phone: Option<String>,
+    email: Option<String>,
+    destination_note: Option<String>,
+    body: Option<String>,
+    signed_by: Option<String>,
+    signature_note: Option<String>,
+}
+
+impl TrafficBuilder {
+    pub fn service(&mut self, service: ServiceType) -> &mut Self {
+        self.service = Some(service);
+        self
+    }
+
+    pub fn number(&mut self, number: u16) -> &mut Self {
+        self.number = Some(number);
+        self
+    }
+
+    pub fn traffic_type(&mut self, traffic_type: TrafficType) -> &mut Self {
+        self.traffic_type = Some(traffic_type);
+        self
+    }
+
+    pub fn precedence(&mut self, precedence: Precedence) -> &mut Self {
+        self.precedence = Some(precedence);
+        self
+    }
+
+    pub fn handling(&mut self, handling: Handling) -> &mut Self {
+        self.handling = handling;
+        self
+    }
+
+    /// Adds one handling instruction to those already set.
+    pub fn directive(&mut self, directive: HandlingDirective) -> &mut Self {
+        self.handling.directives.insert(directive);
+        self
+    }
+
+    pub fn originator(&mut self, originator: StationId) -> &mut Self {
+        self.originator = Some(originator);
+        self
+    }
+
+    /// States the check rather than counting it from the body.
+    pub fn check(&mut self, content: ContentType, count: u16) -> &mut Self {
+        self.check = Some(Check { content, count, revised: false });
+        self
+    }
+
+    pub fn origin(&mut self, origin: impl Into<String>) -> &mut Self {
+        self.origin = Some(origin.into());
+        self
+    }
+
+    pub fn time_filed(&mut self, time_filed: impl Into<String>) -> &mut Self {
+        self.time_filed = Some(time_filed.into());
+        self
+    }
+
+    pub fn date(&mut self, date: impl Into<String>) -> &mut Self {
+        self.date = Some(date.into());
+        self
+    }
+
+    pub fn addressee(&mut self, addressee: impl Into<String>) -> &mut Self {
+        self.addressee = Some(addressee.into());
+        self
+    }
+
+    pub fn station(&mut self, station: impl Into<Addressable>) -> &mut Self {
+        self.station = Some(station.into());
+        self
+    }
+
+    pub fn address_line(&mut self, line: impl Into<String>) -> &mut Self {
+        self.address.push(line.into());
+        self
+    }
+
+    pub fn phone(&mut self, phone: impl Into<String>) -> &mut Self {
+        self.phone = Some(phone.into());
+        self
+    }
+
+    pub fn email(&mut self, email: impl Into<String>) -> &mut Self {
+        self.email = Some(email.into());
+        self
+    }
+
+    /// An operator note for the delivering station, following the address.
+    pub fn destination_note(&mut self, note: impl Into<String>) -> &mut Self {
+        self.destination_note = Some(note.into());
+        self
+    }
+
+    pub fn body(&mut self, body: impl Into<String>) -> &mut Self {
+        self.body = Some(body.into());
+        self
+    }
+
+    pub fn signed_by(&mut self, signed_by: impl Into<String>) -> &mut Self {
+        self.signed_by = Some(signed_by.into());
+        self
+    }
+
+    /// An operator note following the signature.
+    pub fn signature_note(&mut self, note: impl Into<String>) -> &mut Self {
+        self.signature_note = Some(note.into());
+        self
+    }
+
+    pub fn build(&self) -> Result<Traffic, MissingField> {
+        let body = self.body.clone().ok_or(MissingField("body"))?;
+        let mut traffic = Traffic {
+            header: TrafficHeader {
+                service: self.service.unwrap_or(ServiceType::Normal),
+                number: self.number.ok_or(MissingField("number"))?,
+                traffic_type: self.traffic_type.unwrap_or(TrafficType::Normal),
+                precedence: self.precedence.unwrap_or(Precedence::Routine),
+                handling: self.handling.clone(),
+                originator: self.originator.clone().ok_or(MissingField("originator"))?,
+                check: Check { content: ContentType::Standard, count: 0, revised: false },
+                origin: self.origin.clone().ok_or(MissingField("place of origin"))?,
+                time_filed: self.time_filed.clone(),
+                date: self.date.clone().ok_or(MissingField("date"))?,
+            },
+            destination: Destination {
+                addressee: self.addressee.clone().ok_or(MissingField("addressee"))?,
+                station: self.station.clone(),
+                address: self.address.clone(),
+                phone: self.phone.clone(),
+                email: self.email.clone(),
+                op_note: self.destination_note.clone(),
+            },
+            body,
+            signature: Signature {
+                signed_by: self.signed_by.clone().ok_or(MissingField("signature"))?,
+                op_note: self.signature_note.clone(),
+            },
+        };
+
+        traffic.header.check = match &self.check {
+            Some(check) => check.clone(),
+            None => {
+                let content = if traffic.body.split_whitespace().next() == Some("ARL") { ContentType::Arl } else { ContentType::Standard };
+                Check { content, count: traffic.computed_check(), revised: false }
+            }
+        };
+        Ok(traffic)
+    }
+}
+
+impl Traffic {
+    pub fn builder() -> TrafficBuilder {
+        TrafficBuilder::default()
+    }
+
+    /// A Winlink message ID derived from what identifies a radiogram: its originator,
+    /// number, date filed, and text. Sending the same radiogram again reuses the ID, so a
+    /// peer that already has it can turn it down.
+    ///
+    /// The fields are hashed with 64-bit FNV-1a, each followed by a zero byte, rather than
+    /// with `DefaultHasher`, whose output may change between Rust releases.
+    fn message_id(&self) -> fbb::MessageId {
+        let fields: [&[u8]; 4] = [
+            self.header.originator.as_str().as_bytes(),
+            &self.header.number.to_le_bytes(),
+            self.header.date.as_bytes(),
+            self.body.as_bytes(),
+        ];
+        let mut hash = 0xCBF2_9CE4_8422_2325u64;
+        for &b in fields.iter().flat_map(|field| field.iter().chain(&[0])) {
+            hash ^= u64::from(b);
+            hash = hash.wrapping_mul(0x0100_0000_01B3);
+        }
+        let mut id = String::with_capacity(12);
+        for _ in 0..12 {
+            id.push(char::from_digit((hash % 36) as u32, 36).unwrap().to_ascii_uppercase());
+            hash /= 36;
+        }
+        fbb::MessageId::new(id)
+    }
+}
+
+/// Connects to `to` and delivers `traffic` as a Winlink peer-to-peer message.
+///
+/// Returns the peer's answer to the proposal; the message was only sent if it was accepted.
+#[tracing::instrument(skip(tnc, traffic), fields(number = traffic.header.number), err)]
+pub async fn send_traffic(tnc: &modem::vara::VaraTnc, from: StationId, to: StationId, traffic: &Traffic) -> color_eyre::Result<fbb::MessageChoice> {
+    let message = fbb::WinlinkMessage {
+        message_id: traffic.message_id(),
+        date: std::time::SystemTime::now(),
+        from: from.clone(),
+        to: to.clone(),
+        subject: format!("Radiogram NR {}", traffic.header.number),
+        body: traffic.to_string(),
+    };
+
+    let stream = tnc.connect(from, to).await?;
+    let mut session = fbb::Session::new(stream);
+    session.handshake().await?;
+    let choices = session.exchange(&[fbb::OutboundMessage::from(&message)]).await?;
+    session.into_inner().disconnect().await?;
+
+    Ok(choices[0])
+}
+
 fn test() -> Traffic {
     Traffic {
         header: TrafficHeader {
@@ -123,6 +423,7 @@ fn test() -> Traffic {
             check: Check {
                 content: ContentType::Standard,
                 count: 21,
+                revised: false,
             },
             originator: StationId::new("KC1GSL").unwrap(),
             origin: String::from("BILLERICA MA"),
@@ -132,7 +433,7 @@ fn test() -> Traffic {
         },
         destination: Destination {
             addressee: String::from("BOB SPARKES"),
-            station: Some(StationId::new("KC1KVY").unwrap()),
+            station: Some(Addressable::Station(StationId::new("KC1KVY").unwrap())),
             address: Vec::new(),
             phone: None,
             email: None,
@@ -145,3 +446,84 @@ fn test() -> Traffic {
         body: String::from("THIS IS A TEST OF A PROGRAM I WROTE TO ASSIST ME IN PUSHING TRAFFIC INTO THE DIGITAL TRAFFIC NETWORK 73"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_reproduces_sample() -> color_eyre::Result<()> {
+        let traffic = Traffic::builder()
+            .number(22)
+            .directive(HandlingDirective::ReportDelivery)
+            .originator(StationId::new("KC1GSL")?)
+            .origin("BILLERICA MA")
+            .date("DEC 3")
+            .addressee("BOB SPARKES")
+            .station(StationId::new("KC1KVY")?)
+            .body(test().body)
+            .signed_by("MARCUS KC1GSL")
+            .build()?;
+        assert_eq!(traffic, test());
+        Ok(())
+    }
+
+    #[test]
+    fn builder_requires_fields_and_counts_arl_checks() -> color_eyre::Result<()> {
+        let mut builder = Traffic::builder();
+        builder.number(107).originator(StationId::new("W1AW")?).origin("NEWINGTON CT").date("JUN 26");
+        assert_eq!(builder.build(), Err(MissingField("body")));
+
+        builder.body("ARL FIFTY ARL SIXTY").signed_by("FIELD DAY TEAM");
+        assert_eq!(builder.build(), Err(MissingField("addressee")));
+
+        builder.addressee("JOHN SMITH");
+        let traffic = builder.build()?;
+        assert_eq!(traffic.header.check, Check { content: ContentType::Arl, count: 4, revised: false });
+        traffic.verify_check()?;
+
+        let traffic = builder.check(ContentType::Standard, 5).build()?;
+        assert_eq!(traffic.header.check, Check { content: ContentType::Standard, count: 5, revised: false });
+        Ok(())
+    }
+
+    #[test]
+    fn message_id_is_stable() {
+        assert_eq!(test().message_id().as_str(), "CB142C4YSVJ0");
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod serde_tests {
+    use super::*;
+
+    #[test]
+    fn serde_round_trip() -> color_eyre::Result<()> {
+        let traffic = test();
+        let json = serde_json::to_string(&traffic)?;
+        assert_eq!(serde_json::from_str::<Traffic>(&json)?, traffic);
+        Ok(())
+    }
+
+    #[test]
+    fn serde_station_id_is_normalized() -> color_eyre::Result<()> {
+        assert_eq!(serde_json::to_string(&StationId::new("kc1gsl")?)?, r#""KC1GSL""#);
+        assert_eq!(serde_json::from_str::<StationId>(r#""kc1gsl-7""#)?, StationId::new("KC1GSL-7")?);
+        assert!(serde_json::from_str::<StationId>(r#""12""#).is_err());
+        assert_eq!(serde_json::from_str::<&StationIdRef>(r#""KC1GSL""#)?.as_str(), "KC1GSL");
+        Ok(())
+    }
+
+    #[test]
+    fn serde_handling_is_stable() -> color_eyre::Result<()> {
+        let handling = Handling::with_directives([
+            HandlingDirective::RequestReply,
+            HandlingDirective::DeliverWithin { hours: 12 },
+            HandlingDirective::ReportDelivery,
+        ]);
+        let json = serde_json::to_string(&handling)?;
+        assert_eq!(json, r#"[{"DeliverWithin":{"hours":12}},"ReportDelivery","RequestReply"]"#);
+        assert_eq!(serde_json::from_str::<Handling>(&json)?, handling);
+        Ok(())
+    }
+}
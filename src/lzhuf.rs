@@ -1,38 +1,123 @@
 use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
 
-const N: u16 = 2048;
-const F: u16 = 60;
-const THRESHOLD: u16 = 2;
-const NIL: u16 = N; // 2048
-const N_CHAR: u16 = 256 - THRESHOLD + F; // 314
-const T: u16 = N_CHAR * 2 - 1; // 627
-const R: u16 = T - 1; // 313
 const MAX_FREQ: u16 = 0x8000;
 
+/// Window and match-length parameters for an LZHUF stream.
+///
+/// The defaults are the 2 KiB window, 60-byte lookahead, and threshold of 2 that
+/// Winlink B2F uses. Match positions are sent as up to six high bits coded through
+/// the static position tables followed by the remaining low bits verbatim, so
+/// windows beyond 4 KiB carry extra raw bits rather than needing other tables.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LzhufParams {
+    window_bits: u8,
+    lookahead: u16,
+    threshold: u16,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid LZHUF parameters")]
+pub struct InvalidParams;
+
+impl LzhufParams {
+    pub const B2F: Self = Self {
+        window_bits: 11,
+        lookahead: 60,
+        threshold: 2,
+    };
+
+    /// Windows may be 2^8 through 2^15 bytes; the lookahead must exceed the
+    /// threshold, fit within 256 bytes, and be no more than half the window.
+    pub fn new(window_bits: u8, lookahead: u16, threshold: u16) -> Result<Self, InvalidParams> {
+        if !(8..=15).contains(&window_bits) || threshold == 0 || lookahead <= threshold || lookahead > 256 {
+            return Err(InvalidParams);
+        }
+
+        let params = Self {
+            window_bits,
+            lookahead,
+            threshold,
+        };
+        if params.window_size() < 2 * lookahead {
+            return Err(InvalidParams);
+        }
+
+        Ok(params)
+    }
+
+    pub fn window_bits(&self) -> u8 {
+        self.window_bits
+    }
+
+    pub fn lookahead(&self) -> u16 {
+        self.lookahead
+    }
+
+    pub fn threshold(&self) -> u16 {
+        self.threshold
+    }
+
+    fn window_size(&self) -> u16 {
+        1 << self.window_bits
+    }
+
+    fn n_char(&self) -> u16 {
+        256 - self.threshold + self.lookahead
+    }
+
+    fn table_size(&self) -> u16 {
+        self.n_char() * 2 - 1
+    }
+
+    fn root(&self) -> u16 {
+        self.table_size() - 1
+    }
+
+    fn position_low_bits(&self) -> u8 {
+        self.window_bits.saturating_sub(6).max(6)
+    }
+}
+
+impl Default for LzhufParams {
+    fn default() -> Self {
+        Self::B2F
+    }
+}
+
 #[derive(Debug)]
 struct LzHufState {
-    frequency_table: [u16; T as usize + 1],
-    parents: [u16; (T + N_CHAR) as usize],
-    children: [u16; T as usize],
-    text_buffer: [u8; (N + F - 1) as usize],
+    params: LzhufParams,
+    frequency_table: Box<[u16]>,
+    parents: Box<[u16]>,
+    children: Box<[u16]>,
+    text_buffer: Box<[u8]>,
     r: u16,
     // bit_buffer: u16,
     // bit_buf_len: u8,
 }
 
 impl LzHufState {
-    fn new() -> Self {
-        let mut frequency_table = [0; T as usize + 1];
-        let mut parents = [0; (T + N_CHAR) as usize];
-        let mut children = [0; T as usize];
-        for i in 0..N_CHAR {
+    fn new(params: LzhufParams) -> Self {
+        let n = params.window_size();
+        let f = params.lookahead;
+        let n_char = params.n_char();
+        let t = params.table_size();
+
+        let mut frequency_table = vec![0; t as usize + 1].into_boxed_slice();
+        let mut parents = vec![0; (t + n_char) as usize].into_boxed_slice();
+        let mut children = vec![0; t as usize].into_boxed_slice();
+        for i in 0..n_char {
             frequency_table[i as usize] = 1;
-            children[i as usize] = i + T;
-            parents[(i + T) as usize] = i;
+            children[i as usize] = i + t;
+            parents[(i + t) as usize] = i;
         }
 
         let mut i = 0;
-        let mut j = N_CHAR;
+        let mut j = n_char;
         while i + 1 < j {
             frequency_table[j as usize] = frequency_table[i as usize] + frequency_table[i as usize + 1];
             j += 1;
@@ -40,8 +125,8 @@ impl LzHufState {
         }
 
         let mut i = 0;
-        let mut j = N_CHAR;
-        while i < T - 1 {
+        let mut j = n_char;
+        while i < t - 1 {
             children[j as usize] = i;
             parents[i as usize] = j;
             parents[i as usize + 1] = j;
@@ -49,30 +134,34 @@ impl LzHufState {
             i += 2;
         }
 
-        frequency_table[T as usize] = 0xffff;
-        parents[R as usize] = 0;
+        frequency_table[t as usize] = 0xffff;
+        parents[params.root() as usize] = 0;
 
         LzHufState {
+            params,
             frequency_table,
             parents,
             children,
-            text_buffer: [0x20; (N + F - 1) as usize],
-            r: N - F,
+            text_buffer: vec![0x20; (n + f - 1) as usize].into_boxed_slice(),
+            r: n - f,
         }
     }
 
     #[tracing::instrument(skip(self))]
     fn reconstruct(&mut self) {
+        let n_char = self.params.n_char();
+        let t = self.params.table_size();
+
         let mut j = 0;
-        for i in 0..T {
-            if self.children[i as usize] >= T {
+        for i in 0..t {
+            if self.children[i as usize] >= t {
                 self.frequency_table[j as usize] = (self.frequency_table[i as usize] + 1) / 2;
                 self.children[j as usize] = self.children[i as usize];
                 j += 1;
             }
         }
 
-        for (j, i) in (N_CHAR..T).zip((0..).step_by(2)) {
+        for (j, i) in (n_char..t).zip((0..).step_by(2)) {
             let k = i + 1;
             self.frequency_table[j as usize] = self.frequency_table[i as usize] + self.frequency_table[k as usize];
             let mut k = j - 1;
@@ -88,9 +177,9 @@ impl LzHufState {
             self.children[k as usize] = i;
         }
 
-        for i in 0..T {
+        for i in 0..t {
             let k = self.children[i as usize];
-            if k >= T {
+            if k >= t {
                 self.parents[k as usize] = i;
             } else {
                 self.parents[k as usize + 1] = i;
@@ -101,11 +190,13 @@ impl LzHufState {
 
     #[tracing::instrument(skip(self))]
     fn update(&mut self, c: u16) {
-        if self.frequency_table[R as usize] == MAX_FREQ {
+        let t = self.params.table_size();
+
+        if self.frequency_table[self.params.root() as usize] == MAX_FREQ {
             self.reconstruct()
         }
 
-        let mut c = self.parents[(c + T) as usize];
+        let mut c = self.parents[(c + t) as usize];
         loop {
             self.frequency_table[c as usize] += 1;
             let k = self.frequency_table[c as usize];
@@ -121,7 +212,7 @@ impl LzHufState {
 
                 let i = self.children[c as usize];
                 self.parents[i as usize] = l;
-                if i < T {
+                if i < t {
                     self.parents[i as usize + 1] = l;
                 }
 
@@ -129,7 +220,7 @@ impl LzHufState {
                 self.children[l as usize] = i;
 
                 self.parents[j as usize] = c;
-                if j < T {
+                if j < t {
                     self.parents[j as usize + 1] = c;
                 }
                 self.children[c as usize] = j;
@@ -147,13 +238,14 @@ impl LzHufState {
     fn update_text_buffer(&mut self, c: u8) {
         self.text_buffer[self.r as usize] = c;
         self.r += 1;
-        self.r &= N - 1;
+        self.r &= self.params.window_size() - 1;
     }
 }
 
 struct Bitbuffer<'a> {
     bit_buffer: u16,
     bit_pos: u8,
+    codesize: usize,
     output: &'a mut Vec<u8>,
 }
 
@@ -162,6 +254,7 @@ impl<'a> Bitbuffer<'a> {
         Self {
             bit_pos: 0,
             bit_buffer: 0,
+            codesize: 0,
             output,
         }
     }
@@ -174,24 +267,31 @@ impl<'a> Bitbuffer<'a> {
             self.bit_pos -= 8;
             if self.bit_pos >= 8 {
                 self.output.push(self.bit_buffer as u8);
-                // self.codesize += 2;
+                self.codesize += 2;
                 self.bit_pos -= 8;
                 self.bit_buffer = c << (l - self.bit_pos) as usize;
             } else {
                 self.bit_buffer = self.bit_buffer << 8;
-                // self.codesize += 1;
+                self.codesize += 1;
             }
         }
     }
 
-    fn finish(self) {}
+    /// Writes out any partial final byte and returns the total number of bytes written.
+    fn flush(&mut self) -> usize {
+        if self.bit_pos > 0 {
+            self.output.push((self.bit_buffer >> 8) as u8);
+            self.codesize += 1;
+            self.bit_pos = 0;
+            self.bit_buffer = 0;
+        }
+        self.codesize
+    }
 }
 
 impl<'a> Drop for Bitbuffer<'a> {
     fn drop(&mut self) {
-        if self.bit_pos > 0 {
-            self.output.push((self.bit_buffer >> 8) as u8);
-        }
+        self.flush();
     }
 }
 
@@ -271,6 +371,59 @@ mod tests {
     use super::*;
     use test_log::test;
 
+    #[test(tokio::test)]
+    async fn async_decoder_over_duplex() -> color_eyre::Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let body = include_bytes!("../samples/winlink.txt");
+        let mut compressed = Vec::new();
+        let mut encoder = Encoder::new(&mut compressed);
+        encoder.encode(body.iter().copied());
+        encoder.finish();
+
+        let (mut writer, reader) = tokio::io::duplex(16);
+        let sender = tokio::spawn(async move {
+            for chunk in compressed.chunks(5) {
+                writer.write_all(chunk).await?;
+                tokio::task::yield_now().await;
+            }
+            std::io::Result::Ok(())
+        });
+
+        let mut decoder = AsyncDecoder::new(reader, body.len());
+        let mut decoded = Vec::new();
+        let mut buffer = [0; 7];
+        loop {
+            let n = decoder.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            decoded.extend_from_slice(&buffer[..n]);
+        }
+        sender.await??;
+
+        assert_eq!(&decoded[..], &body[..]);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn async_decoder_truncated_input() -> color_eyre::Result<()> {
+        use tokio::io::AsyncReadExt;
+
+        let body = include_bytes!("../samples/winlink.txt");
+        let mut compressed = Vec::new();
+        let mut encoder = Encoder::new(&mut compressed);
+        encoder.encode(body.iter().copied());
+        encoder.finish();
+        compressed.truncate(compressed.len() / 2);
+
+        let mut decoder = AsyncDecoder::new(&compressed[..], body.len());
+        let mut decoded = Vec::new();
+        let error = decoder.read_to_end(&mut decoded).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+
     #[test]
     fn get_bytes() {
         let input: [u8; 6] = [0xFA, 0x50, 0xFF, 0x00, 0x96, 0xC3];
@@ -363,41 +516,52 @@ pub struct Encoder<'a> {
     match_length: u16,
     match_position: u16,
     output: Bitbuffer<'a>,
-    lson: [u16; N as usize + 1],
-    rson: [u16; N as usize + 257],
-    dad: [u16; N as usize + 1],
+    lson: Box<[u16]>,
+    rson: Box<[u16]>,
+    dad: Box<[u16]>,
 }
 
 impl<'a> Encoder<'a> {
-    fn new(output: &'a mut Vec<u8>) -> Self {
-        let mut rson = [0; N as usize + 257];
-        for i in (N + 1)..(N + 257) {
-            rson[i as usize] = NIL;
+    pub fn new(output: &'a mut Vec<u8>) -> Self {
+        Self::with_params(output, LzhufParams::default())
+    }
+
+    pub fn with_params(output: &'a mut Vec<u8>, params: LzhufParams) -> Self {
+        let n = params.window_size();
+        let nil = n;
+
+        let mut rson = vec![0; n as usize + 257].into_boxed_slice();
+        for i in (n + 1)..(n + 257) {
+            rson[i as usize] = nil;
         }
 
-        let mut dad = [NIL; N as usize + 1];
-        dad[N as usize] = 0;
+        let mut dad = vec![nil; n as usize + 1].into_boxed_slice();
+        dad[n as usize] = 0;
         Self {
-            state: LzHufState::new(),
+            state: LzHufState::new(params),
             match_length: 0,
             match_position: 0,
             output: Bitbuffer::new(output),
-            lson: [0; N as usize + 1],
+            lson: vec![0; n as usize + 1].into_boxed_slice(),
             rson,
             dad,
         }
     }
 
     fn insert_node(&mut self, r: u16) {
+        let n = self.state.params.window_size();
+        let f = self.state.params.lookahead;
+        let nil = n;
+
         let mut cmp = 1;
         let key = &self.state.text_buffer[r as usize..];
-        let mut p = N + 1 + key[0] as u16;
-        self.lson[r as usize] = NIL;
-        self.rson[r as usize] = NIL;
+        let mut p = n + 1 + key[0] as u16;
+        self.lson[r as usize] = nil;
+        self.rson[r as usize] = nil;
         self.match_length = 0;
         loop {
             if cmp >= 0 {
-                if self.rson[p as usize] != NIL {
+                if self.rson[p as usize] != nil {
                     p = self.rson[p as usize];
                 } else {
                     self.rson[p as usize] = r;
@@ -405,7 +569,7 @@ impl<'a> Encoder<'a> {
                     return;
                 }
             } else {
-                if self.lson[p as usize] != NIL {
+                if self.lson[p as usize] != nil {
                     p = self.lson[p as usize];
                 } else {
                     self.lson[p as usize] = r;
@@ -415,7 +579,7 @@ impl<'a> Encoder<'a> {
             }
 
             let mut i = 1;
-            while i < F {
+            while i < f {
                 cmp = key[i as usize].wrapping_sub(self.state.text_buffer[(p + i) as usize]);
                 if cmp != 0 {
                     break;
@@ -423,16 +587,16 @@ impl<'a> Encoder<'a> {
                 i += 1;
             }
 
-            if i > THRESHOLD {
+            if i > self.state.params.threshold {
                 if i > self.match_length {
-                    self.match_position = ((r.wrapping_sub(p)) & (N - 1)) - 1;
+                    self.match_position = ((r.wrapping_sub(p)) & (n - 1)) - 1;
                     self.match_length = i;
-                    if i >= F {
+                    if i >= f {
                         break;
                     }
                 }
                 if i == self.match_length {
-                    let c = ((r.wrapping_sub(p)) & (N - 1)) - 1;
+                    let c = ((r.wrapping_sub(p)) & (n - 1)) - 1;
                     if c < self.match_position {
                         self.match_position = c;
                     }
@@ -452,25 +616,27 @@ impl<'a> Encoder<'a> {
             self.lson[self.dad[p as usize] as usize] = r;
         }
 
-        self.dad[p as usize] = NIL;
+        self.dad[p as usize] = nil;
     }
 
     fn delete_node(&mut self, p: u16) {
-        if self.dad[p as usize] == NIL {
+        let nil = self.state.params.window_size();
+
+        if self.dad[p as usize] == nil {
             return;
         }
 
         let mut q;
-        if self.rson[p as usize] == NIL {
+        if self.rson[p as usize] == nil {
             q = self.lson[p as usize];
-        } else if self.lson[p as usize] == NIL {
+        } else if self.lson[p as usize] == nil {
             q = self.rson[p as usize];
         } else {
             q = self.lson[p as usize];
-            if self.rson[q as usize] != NIL {
+            if self.rson[q as usize] != nil {
                 loop {
                     q = self.rson[q as usize];
-                    if self.rson[q as usize] == NIL {
+                    if self.rson[q as usize] == nil {
                         break;
                     }
                 }
@@ -493,13 +659,16 @@ impl<'a> Encoder<'a> {
             self.lson[self.dad[p as usize] as usize] = q;
         }
 
-        self.dad[p as usize] = NIL;
+        self.dad[p as usize] = nil;
     }
 
     fn encode_char(&mut self, c: u16) {
+        let t = self.state.params.table_size();
+        let root = self.state.params.root();
+
         let mut i = 0u16;
         let mut j = 0u8;
-        let mut k = self.state.parents[(c + T) as usize];
+        let mut k = self.state.parents[(c + t) as usize];
         loop {
             i = i >> 1;
             if k & 1 != 0 {
@@ -508,7 +677,7 @@ impl<'a> Encoder<'a> {
             j += 1;
 
             k = self.state.parents[k as usize];
-            if k == R {
+            if k == root {
                 break;
             }
         }
@@ -518,17 +687,25 @@ impl<'a> Encoder<'a> {
     }
 
     fn encode_position(&mut self, c: u16) {
-        let i = c >> 6;
+        let low_bits = self.state.params.position_low_bits();
+        let i = c >> low_bits;
         self.output.put_code(p_len[i as usize], (p_code[i as usize] as u16) << 8);
-        self.output.put_code(6, (c & 0x3f) << 10);
+        self.output.put_code(low_bits, (c & ((1 << low_bits) - 1)) << (16 - low_bits));
     }
 
-    fn encode<I: IntoIterator<IntoIter=Y, Item = u8>, Y: Iterator<Item = u8>>(&mut self, input: I) {
-        let mut iterator = input.into_iter();
+    pub fn encode<I: IntoIterator<IntoIter=Y, Item = u8>, Y: Iterator<Item = u8>>(&mut self, input: I) {
+        let n = self.state.params.window_size();
+        let f = self.state.params.lookahead;
+        let threshold = self.state.params.threshold;
+
+        let mut iterator = input.into_iter().peekable();
+        if iterator.peek().is_none() {
+            return;
+        }
         let mut s = 0;
-        let mut r = N - F;
+        let mut r = n - f;
         let mut len = 0;
-        while len < F {
+        while len < f {
             if let Some(b) = iterator.next() {
                 self.state.text_buffer[(r + len) as usize] = b;
             } else {
@@ -536,7 +713,7 @@ impl<'a> Encoder<'a> {
             }
             len += 1;
         }
-        for i in 1..=F {
+        for i in 1..=f {
             self.insert_node(r - i);
         }
         self.insert_node(r);
@@ -544,11 +721,11 @@ impl<'a> Encoder<'a> {
             if self.match_length > len {
                 self.match_length = len;
             }
-            if self.match_length <= THRESHOLD {
+            if self.match_length <= threshold {
                 self.match_length = 1;
                 self.encode_char(self.state.text_buffer[r as usize] as u16);
             } else {
-                self.encode_char(255 - THRESHOLD + self.match_length);
+                self.encode_char(255 - threshold + self.match_length);
                 self.encode_position(self.match_position);
             }
             let last_match_len = self.match_length;
@@ -558,11 +735,11 @@ impl<'a> Encoder<'a> {
                 if let Some(c) = iterator.next() {
                     self.delete_node(s);
                     self.state.text_buffer[s as usize] = c;
-                    if s < F - 1 {
-                        self.state.text_buffer[(s + N) as usize] = c;
+                    if s < f - 1 {
+                        self.state.text_buffer[(s + n) as usize] = c;
                     }
-                    s = (s + 1) & (N - 1);
-                    r = (r + 1) & (N - 1);
+                    s = (s + 1) & (n - 1);
+                    r = (r + 1) & (n - 1);
                     self.insert_node(r);
                 } else {
                     break;
@@ -573,8 +750,8 @@ impl<'a> Encoder<'a> {
             while i < last_match_len {
                 i += 1;
                 self.delete_node(s);
-                s = (s.wrapping_add(1)) & (N - 1);
-                r = (r.wrapping_add(1)) & (N - 1);
+                s = (s.wrapping_add(1)) & (n - 1);
+                r = (r.wrapping_add(1)) & (n - 1);
                 len -= 1;
                 if len > 0 {
                     self.insert_node(r)
@@ -587,7 +764,22 @@ impl<'a> Encoder<'a> {
         }
     }
 
-    fn finish(self) {}
+    /// Flushes the final partial byte and returns the number of compressed bytes written
+    /// to the output.
+    pub fn finish(mut self) -> usize {
+        self.output.flush()
+    }
+}
+
+/// Compresses `input` as B2F carries it: the uncompressed length as a little-endian `u32`,
+/// followed by the LZHUF stream.
+pub fn encode_with_header(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len() + 4);
+    output.extend_from_slice(&(input.len() as u32).to_le_bytes());
+    let mut encoder = Encoder::new(&mut output);
+    encoder.encode(input.iter().copied());
+    encoder.finish();
+    output
 }
 
 pub struct Decoder<I> {
@@ -605,64 +797,306 @@ impl<I> fmt::Debug for Decoder<I> {
 }
 
 #[derive(Debug, thiserror::Error)]
-#[error("unexpected end of data")]
-pub struct UnexpectedEof;
+pub enum DecodeError {
+    #[error("unexpected end of data")]
+    UnexpectedEof,
+    #[error("invalid Huffman code in compressed data")]
+    InvalidCode,
+    #[error("decoded data overruns the expected length")]
+    Overrun,
+}
 
 impl<I: Iterator<Item = u8>> Decoder<I> {
     pub fn new<X: IntoIterator<IntoIter = I, Item = u8>>(input: X) -> Self {
+        Self::with_params(input, LzhufParams::default())
+    }
+
+    pub fn with_params<X: IntoIterator<IntoIter = I, Item = u8>>(input: X, params: LzhufParams) -> Self {
         Self {
-            state: LzHufState::new(),
+            state: LzHufState::new(params),
             stream: Biterator::new(input),
         }
     }
 
     #[tracing::instrument(skip(self, buffer))]
-    pub fn decode(&mut self, buffer: &mut [u8]) -> Result<(), UnexpectedEof> {
+    pub fn decode(&mut self, buffer: &mut [u8]) -> Result<(), DecodeError> {
         let mut count = 0;
         while count < buffer.len() {
-            let c = self.decode_char().ok_or(UnexpectedEof)?;
-            if c < 256 {
-                let c = c as u8;
-                buffer[count] = c;
-                self.state.update_text_buffer(c);
-                count += 1;
-            } else {
-                let i = (self.state.r.wrapping_sub(self.decode_position().ok_or(UnexpectedEof)?).wrapping_sub(1)) & (N - 1);
-                let j = c - 255 + THRESHOLD;
-                for k in 0..j {
-                    let c = self.state.text_buffer[((i + k) & (N - 1)) as usize];
-                    buffer[count] = c;
-                    self.state.update_text_buffer(c);
-                    count += 1;
+            let symbol = self.state.read_symbol(&mut self.stream)?;
+            let len = symbol.len();
+            let out = buffer.get_mut(count..count + len).ok_or(DecodeError::Overrun)?;
+            let mut out = out.iter_mut();
+            self.state.expand(symbol, |c| *out.next().unwrap() = c);
+            count += len;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes LZHUF data pushed in as it arrives, rather than pulled from an iterator.
+///
+/// A symbol split across pushes is held back, along with any partial bits, until the
+/// rest of it arrives. The window and Huffman tree carry over from one push to the next,
+/// so back-references may reach into data decoded from earlier input.
+pub struct IncrementalDecoder {
+    state: LzHufState,
+    bit_buffer: u32,
+    bit_pos: u8,
+    pending: Vec<u8>,
+}
+
+impl fmt::Debug for IncrementalDecoder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IncrementalDecoder")
+            .field("state", &self.state)
+            .field("bit_buffer", &format_args!("{:#034b}", self.bit_buffer))
+            .field("bit_pos", &self.bit_pos)
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+impl Default for IncrementalDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IncrementalDecoder {
+    pub fn new() -> Self {
+        Self::with_params(LzhufParams::default())
+    }
+
+    pub fn with_params(params: LzhufParams) -> Self {
+        Self {
+            state: LzHufState::new(params),
+            bit_buffer: 0,
+            bit_pos: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, input: &[u8]) {
+        self.pending.extend_from_slice(input);
+    }
+
+    /// Decodes every complete symbol available, appending at most `limit` bytes to `output`
+    /// and returning how many were appended.
+    ///
+    /// Stopping short of `limit` means more input is needed. A back-reference that would
+    /// carry past `limit` is an [`DecodeError::Overrun`].
+    #[tracing::instrument(skip(self, output))]
+    pub fn decode(&mut self, output: &mut Vec<u8>, limit: usize) -> Result<usize, DecodeError> {
+        let mut produced = 0;
+        let mut consumed = 0;
+        let result = loop {
+            if produced >= limit {
+                break Ok(produced);
+            }
+
+            let mut stream = Biterator {
+                bit_buffer: self.bit_buffer,
+                bit_pos: self.bit_pos,
+                input: self.pending[consumed..].iter().copied(),
+            };
+            let symbol = match self.state.read_symbol(&mut stream) {
+                Ok(symbol) => symbol,
+                Err(DecodeError::UnexpectedEof) => break Ok(produced),
+                Err(e) => break Err(e),
+            };
+            consumed = self.pending.len() - stream.input.len();
+            self.bit_buffer = stream.bit_buffer;
+            self.bit_pos = stream.bit_pos;
+
+            let len = symbol.len();
+            if produced + len > limit {
+                break Err(DecodeError::Overrun);
+            }
+            self.state.expand(symbol, |c| output.push(c));
+            produced += len;
+        };
+        self.pending.drain(..consumed);
+        result
+    }
+}
+
+/// Decompresses an LZHUF stream read from `R`, such as a connected modem.
+///
+/// LZHUF data doesn't mark its own end, so the decompressed length must be known up front,
+/// as it is from a B2F proposal or the prefix of a B2F message's first block. Once that
+/// many bytes have been produced, reads return end-of-file.
+#[pin_project::pin_project]
+pub struct AsyncDecoder<R> {
+    #[pin]
+    reader: R,
+    decoder: IncrementalDecoder,
+    remaining: usize,
+    staged: Vec<u8>,
+    staged_pos: usize,
+    input: Box<[u8]>,
+    reader_eof: bool,
+}
+
+impl<R> fmt::Debug for AsyncDecoder<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AsyncDecoder")
+            .field("decoder", &self.decoder)
+            .field("remaining", &self.remaining)
+            .field("staged", &(self.staged.len() - self.staged_pos))
+            .field("reader_eof", &self.reader_eof)
+            .finish()
+    }
+}
+
+impl<R: AsyncRead> AsyncDecoder<R> {
+    const INPUT_SIZE: usize = 256;
+
+    pub fn new(reader: R, uncompressed_size: usize) -> Self {
+        Self::with_params(reader, uncompressed_size, LzhufParams::default())
+    }
+
+    pub fn with_params(reader: R, uncompressed_size: usize, params: LzhufParams) -> Self {
+        Self {
+            reader,
+            decoder: IncrementalDecoder::with_params(params),
+            remaining: uncompressed_size,
+            staged: Vec::new(),
+            staged_pos: 0,
+            input: vec![0; Self::INPUT_SIZE].into_boxed_slice(),
+            reader_eof: false,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for AsyncDecoder<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            if *this.staged_pos < this.staged.len() {
+                let staged = &this.staged[*this.staged_pos..];
+                let n = staged.len().min(buf.remaining());
+                buf.put_slice(&staged[..n]);
+                *this.staged_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            if *this.remaining == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            // Anything held back by the decoder, including partial bits, stays there until
+            // more input arrives, so returning `Pending` below loses nothing.
+            this.staged.clear();
+            *this.staged_pos = 0;
+            let produced = this.decoder.decode(this.staged, *this.remaining)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            *this.remaining -= produced;
+            if produced > 0 {
+                continue;
+            }
+
+            if *this.reader_eof {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::UnexpectedEof, DecodeError::UnexpectedEof)));
+            }
+
+            let mut input = ReadBuf::new(this.input);
+            match this.reader.as_mut().poll_read(cx, &mut input) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Ready(Ok(())) if input.filled().is_empty() => *this.reader_eof = true,
+                Poll::Ready(Ok(())) => this.decoder.push(input.filled()),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Symbol {
+    Literal(u8),
+    Copy { position: u16, length: u16 },
+}
+
+impl Symbol {
+    fn len(&self) -> usize {
+        match *self {
+            Symbol::Literal(_) => 1,
+            Symbol::Copy { length, .. } => length as usize,
+        }
+    }
+}
+
+impl LzHufState {
+    /// Reads a whole symbol before adapting the Huffman tree, so that running out of
+    /// input part way through leaves the state as it was.
+    #[tracing::instrument(skip(self, stream))]
+    fn read_symbol<I: Iterator<Item = u8>>(&mut self, stream: &mut Biterator<I>) -> Result<Symbol, DecodeError> {
+        let c = self.read_char(stream)?;
+        let symbol = if c < 256 {
+            Symbol::Literal(c as u8)
+        } else {
+            Symbol::Copy {
+                position: self.read_position(stream)?,
+                length: c - 255 + self.params.threshold,
+            }
+        };
+        self.update(c);
+        Ok(symbol)
+    }
+
+    fn expand(&mut self, symbol: Symbol, mut output: impl FnMut(u8)) {
+        match symbol {
+            Symbol::Literal(c) => {
+                output(c);
+                self.update_text_buffer(c);
+            }
+            Symbol::Copy { position, length } => {
+                let n = self.params.window_size();
+                let i = (self.r.wrapping_sub(position).wrapping_sub(1)) & (n - 1);
+                for k in 0..length {
+                    let c = self.text_buffer[((i + k) & (n - 1)) as usize];
+                    output(c);
+                    self.update_text_buffer(c);
                 }
             }
         }
-        Ok(())
     }
 
-    #[tracing::instrument(skip(self))]
-    fn decode_char(&mut self) -> Option<u16> {
-        let mut c = self.state.children[R as usize];
-        while c < T {
-            c += self.stream.get_bit()? as u16;
-            c = self.state.children[c as usize];
+    #[tracing::instrument(skip(self, stream))]
+    fn read_char<I: Iterator<Item = u8>>(&self, stream: &mut Biterator<I>) -> Result<u16, DecodeError> {
+        let t = self.params.table_size();
+
+        let mut c = self.children[self.params.root() as usize];
+        while c < t {
+            c += stream.get_bit().ok_or(DecodeError::UnexpectedEof)? as u16;
+            c = *self.children.get(c as usize).ok_or(DecodeError::InvalidCode)?;
+        }
+        c -= t;
+        if c >= self.params.n_char() {
+            return Err(DecodeError::InvalidCode);
         }
-        c -= T;
-        self.state.update(c);
-        Some(c)
+        Ok(c)
     }
 
-    #[tracing::instrument(skip(self))]
-    fn decode_position(&mut self) -> Option<u16> {
-        let mut i = self.stream.get_byte()? as u16;
-        let c = DECODE_CODE[i as usize] << 6;
-        let mut j = DECODE_LEN[i as usize];
+    #[tracing::instrument(skip(self, stream))]
+    fn read_position<I: Iterator<Item = u8>>(&self, stream: &mut Biterator<I>) -> Result<u16, DecodeError> {
+        let low_bits = self.params.position_low_bits();
+
+        let mut i = stream.get_byte().ok_or(DecodeError::UnexpectedEof)? as u32;
+        let c = (DECODE_CODE[i as usize] as u16) << low_bits;
+        let j = DECODE_LEN[i as usize] + low_bits - 8;
 
-        j -= 2;
-        for _ in (1..=j).rev() {
-            i = (i << 1) + self.stream.get_bit()? as u16;
+        for _ in 0..j {
+            i = (i << 1) + stream.get_bit().ok_or(DecodeError::UnexpectedEof)? as u32;
         }
-        Some(c as u16 | (i & 0x3f))
+        Ok(c | (i as u16 & ((1 << low_bits) - 1)))
     }
 }
 
@@ -785,6 +1219,17 @@ mod tests2 {
         Ok(())
     }
 
+    #[test]
+    fn test_finish_returns_codesize() {
+        let input: &str = include_str!("../samples/winlink.txt");
+        let mut output = vec![0xFF; 6];
+        let mut encoder = Encoder::new(&mut output);
+        encoder.encode(input.as_bytes().iter().copied());
+        let codesize = encoder.finish();
+        assert_eq!(codesize, output.len() - 6);
+        assert_eq!(&output[6..], &include_bytes!("../samples/winlink.raw")[0x2F..0x10C]);
+    }
+
     #[test]
     fn test_decode_single_byte() -> color_eyre::Result<()> {
         let input: [u8; 2] = [0xEC, 0x80];
@@ -847,4 +1292,134 @@ mod tests2 {
         assert_eq!(&output, &[0xEC, 0xD4, 0x00, 0x00]);
         Ok(())
     }
+
+    fn round_trip(params: LzhufParams, input: &[u8]) -> color_eyre::Result<()> {
+        let mut output = Vec::new();
+        let mut encoder = Encoder::with_params(&mut output, params);
+        encoder.encode(input.iter().copied());
+        encoder.finish();
+
+        let mut decoder = Decoder::with_params(output.iter().copied(), params);
+        let mut data_spot = vec![0u8; input.len()];
+        decoder.decode(&mut data_spot)?;
+        assert_eq!(data_spot, input);
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip_with_params() -> color_eyre::Result<()> {
+        let input: Vec<u8> = include_bytes!("../samples/winlink.txt").iter()
+            .chain(b"ZULU ALPHA".iter().cycle().take(700))
+            .chain(include_bytes!("../samples/winlink.txt").iter())
+            .copied()
+            .collect();
+
+        round_trip(LzhufParams::default(), &input)?;
+        round_trip(LzhufParams::new(10, 60, 2)?, &input)?;
+        round_trip(LzhufParams::new(12, 60, 2)?, &input)?;
+        round_trip(LzhufParams::new(13, 256, 3)?, &input)?;
+        round_trip(LzhufParams::new(15, 18, 2)?, &input)?;
+        Ok(())
+    }
+
+    fn round_trip_default(input: &[u8]) -> Result<Vec<u8>, DecodeError> {
+        round_trip_with(LzhufParams::default(), input)
+    }
+
+    fn round_trip_with(params: LzhufParams, input: &[u8]) -> Result<Vec<u8>, DecodeError> {
+        let mut output = Vec::new();
+        let mut encoder = Encoder::with_params(&mut output, params);
+        encoder.encode(input.iter().copied());
+        encoder.finish();
+
+        let mut decoder = Decoder::with_params(output.iter().copied(), params);
+        let mut data_spot = vec![0u8; input.len()];
+        decoder.decode(&mut data_spot)?;
+        Ok(data_spot)
+    }
+
+    fn compressible_bytes() -> impl proptest::strategy::Strategy<Value = Vec<u8>> {
+        use proptest::prelude::*;
+        // Arbitrary bytes rarely repeat, so also draw from a tiny alphabet to get long
+        // matches that wrap around the ring buffer.
+        prop_oneof![
+            proptest::collection::vec(any::<u8>(), 0..2048),
+            proptest::collection::vec(proptest::sample::select(&b"AB \r"[..]), 0..4096),
+        ]
+    }
+
+    /// Any parameters `LzhufParams::new` accepts, down to the smallest windows.
+    fn valid_params() -> impl proptest::strategy::Strategy<Value = LzhufParams> {
+        use proptest::prelude::*;
+        (8u8..=15, 1u16..=16).prop_flat_map(|(window_bits, threshold)| {
+            let max_lookahead = ((1u16 << window_bits) / 2).min(256);
+            (threshold + 1..=max_lookahead).prop_map(move |lookahead| LzhufParams::new(window_bits, lookahead, threshold).unwrap())
+        })
+    }
+
+    proptest::proptest! {
+        // A fixed seed keeps failures reproducible unless PROPTEST_RNG_SEED asks for another.
+        #![proptest_config(proptest::test_runner::Config {
+            cases: 64,
+            rng_seed: match proptest::test_runner::Config::default().rng_seed {
+                proptest::test_runner::RngSeed::Random => proptest::test_runner::RngSeed::Fixed(0x4C5A_4855_4621),
+                seed => seed,
+            },
+            ..proptest::test_runner::Config::default()
+        })]
+
+        #[test]
+        fn round_trips_arbitrary_bytes(input in compressible_bytes()) {
+            let decoded = round_trip_default(&input);
+            proptest::prop_assert!(decoded.is_ok(), "decoding failed: {:?}", decoded);
+            proptest::prop_assert_eq!(decoded.unwrap(), input);
+        }
+
+        #[test]
+        fn round_trips_with_arbitrary_params(params in valid_params(), input in compressible_bytes()) {
+            let decoded = round_trip_with(params, &input);
+            proptest::prop_assert!(decoded.is_ok(), "decoding with {:?} failed: {:?}", params, decoded);
+            proptest::prop_assert_eq!(decoded.unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn test_invalid_params() {
+        assert!(LzhufParams::new(7, 60, 2).is_err());
+        assert!(LzhufParams::new(16, 60, 2).is_err());
+        assert!(LzhufParams::new(11, 2, 2).is_err());
+        assert!(LzhufParams::new(11, 257, 2).is_err());
+        assert!(LzhufParams::new(11, 60, 0).is_err());
+        assert!(LzhufParams::new(8, 256, 2).is_err());
+        assert!(LzhufParams::new(8, 200, 2).is_err());
+        assert!(LzhufParams::new(8, 128, 2).is_ok());
+    }
+
+    #[test]
+    fn test_decode_match_overrunning_buffer() {
+        let input: [u8; 4] = [0xEC, 0xD4, 0x00, 0x00];
+        let mut decoder = Decoder::new(input);
+        let data_spot = &mut [0u8; 16];
+        assert!(matches!(decoder.decode(data_spot), Err(DecodeError::Overrun)));
+    }
+
+    #[test]
+    fn test_decode_random_bytes() {
+        // xorshift32, so that failures are reproducible without a dependency on `rand`
+        let mut seed = 0x2545_F491u32;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+
+        for _ in 0..256 {
+            let len = (next() % 64) as usize;
+            let input: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            let mut decoder = Decoder::new(input.iter().copied());
+            let data_spot = &mut [0u8; 4096];
+            assert!(decoder.decode(data_spot).is_err(), "decoding {:02X?} should have failed", input);
+        }
+    }
 }
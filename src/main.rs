@@ -1,17 +1,60 @@
 use std::num::NonZeroU16;
+use std::path::PathBuf;
 use tracing_subscriber::EnvFilter;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use plusendi::StationId;
+use plusendi::modem::vara::{BandwidthMode, CompressionMode, VaraTnc};
 use structopt::StructOpt;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
 
 #[derive(Debug, StructOpt)]
 #[structopt(about, author)]
 struct Opt {
-    /// The station to connect to
-    target: StationId,
+    #[structopt(flatten)]
+    common: CommonOpt,
 
+    /// The station to connect to, when no subcommand is given
+    target: Option<StationId>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+impl Opt {
+    /// The subcommand to run. A bare target, as accepted before there were subcommands,
+    /// means `connect`.
+    fn command(&self) -> color_eyre::Result<Command> {
+        match (&self.command, &self.target) {
+            (Some(command), None) => Ok(command.clone()),
+            (None, Some(target)) => Ok(Command::Connect { target: target.clone() }),
+            (Some(_), Some(_)) => Err(color_eyre::eyre::eyre!("give the target to the subcommand")),
+            (None, None) => Err(color_eyre::eyre::eyre!("a subcommand or target station is required")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, StructOpt)]
+enum Command {
+    /// Connects to a station and shows its greeting
+    Connect {
+        /// The station to connect to
+        target: StationId,
+    },
+    /// Sends a radiogram to a station
+    Send {
+        /// The station to deliver to
+        target: StationId,
+        /// A file holding the radiogram
+        message: PathBuf,
+    },
+    /// Waits for stations to connect and shows what they propose
+    Listen,
+}
+
+/// Options shared by every subcommand, for reaching the modem and rig.
+#[derive(Debug, StructOpt)]
+struct CommonOpt {
     /// My station
     #[structopt(short = "d", long = "de", display_order = 1)]
     my_call: StationId,
@@ -31,6 +74,14 @@ struct Opt {
     #[structopt(long, possible_values(&["4800", "9600", "19200", "38400"]))]
     rig_baud: u32,
 
+    /// Modem bandwidth in hertz
+    #[structopt(long, default_value = "2300", possible_values(&["500", "2300", "2750"]))]
+    bandwidth: BandwidthMode,
+
+    /// What the modem compresses
+    #[structopt(long, default_value = "TEXT", possible_values(&["OFF", "TEXT", "FILES"]), case_insensitive = true)]
+    compression: CompressionMode,
+
     /// Configures internal logging
     #[structopt(short, long, env = "RUST_LOG", default_value = "info", global = true)]
     log: String,
@@ -43,7 +94,7 @@ async fn main() -> color_eyre::Result<()> {
 
     let opt: Opt = Opt::from_args();
 
-    let env_filter = EnvFilter::try_new(&opt.log).unwrap();
+    let env_filter = EnvFilter::try_new(&opt.common.log).unwrap();
     let fmt_layer = tracing_subscriber::fmt::layer();
 
     tracing_subscriber::registry()
@@ -52,6 +103,17 @@ async fn main() -> color_eyre::Result<()> {
         .with(tracing_error::ErrorLayer::default())
         .init();
 
+    let command = opt.command()?;
+    let mut tnc = start_station(&opt.common).await?;
+    match command {
+        Command::Connect { target } => connect(&mut tnc, opt.common.my_call, target).await,
+        Command::Send { target, message } => send(&mut tnc, opt.common.my_call, target, &message).await,
+        Command::Listen => listen(&mut tnc, &opt.common.my_call).await,
+    }
+}
+
+/// Sets up the modem and hands its PTT commands to the rig.
+async fn start_station(opt: &CommonOpt) -> color_eyre::Result<VaraTnc> {
     // let mut rig = serialport::new("COM3", 38400).open()?;
     // rig.write_all(b"ID;")?;
     // std::thread::sleep_ms(100);
@@ -59,87 +121,57 @@ async fn main() -> color_eyre::Result<()> {
     // read_response(&mut rig)?;
     // return Ok(());
 
-    let mut builder = plusendi::modem::vara::VaraTnc::builder();
+    let mut builder = VaraTnc::builder();
 
     builder.host(opt.modem_address)
-        .control_port(opt.modem_control_port);
+        .control_port(opt.modem_control_port)
+        .my_call(opt.my_call.clone())
+        .default_compression(opt.compression)
+        .default_bandwidth(opt.bandwidth);
 
     if let Some(port) = opt.modem_data_port {
         builder.data_port(port);
     }
 
-    let mut tnc = builder.build().await?;
+    let tnc = builder.build().await?;
 
-    tnc.send_callsign(opt.my_call.clone()).await?;
-    tnc.send_compression(plusendi::modem::vara::CompressionMode::Text).await?;
-    tnc.send_bandwidth(plusendi::modem::vara::BandwidthMode::Wide).await?;
-    let mut transceiver_cmd = tnc.subscribe_rig_command();
+    tnc.send_version().await?;
+    tracing::info!(version = tnc.version().as_deref(), "connected to VARA");
+
+    let transceiver_cmd = tnc.subscribe_rig_command();
 
     let (rig_tx, rig_rx) = tokio::sync::mpsc::channel(1);
 
-    let mut rig = tokio_serial::SerialStream::open(&tokio_serial::new(opt.rig_control, opt.rig_baud))?;
+    let mut rig = tokio_serial::SerialStream::open(&tokio_serial::new(&opt.rig_control, opt.rig_baud))?;
     #[cfg(unix)]
         rig.set_exclusive(true)?;
 
-    let _thread2 = tokio::spawn(plusendi::rig::elecraft::kx3::manage_rig_thread(rig_rx, rig));
+    let (rig_updates, rig_update_rx) = tokio::sync::broadcast::channel(16);
+    let _thread2 = tokio::spawn(plusendi::rig::elecraft::kx3::manage_rig_thread(rig_rx, rig_updates, rig));
+    let _ptt_monitor = tokio::spawn(plusendi::rig::monitor_ptt(
+        std::time::Duration::from_secs(2),
+        tnc.subscribe_rig_command(),
+        rig_update_rx,
+        plusendi::rig::elecraft::kx3::Update::transmit_state,
+    ));
+    let kx3 = plusendi::rig::elecraft::kx3::Kx3::new(rig_tx);
     let _thread3 = tokio::spawn(async move {
-        while let Ok(()) = transceiver_cmd.changed().await {
-            let request = {
-                let cmd = *transceiver_cmd.borrow();
-                tracing::trace!(?cmd, "received automated rig control request");
-                match cmd {
-                    plusendi::modem::vara::TransceiverCommand::Transmit => plusendi::rig::elecraft::kx3::TransmitState::Transmit,
-                    plusendi::modem::vara::TransceiverCommand::Receive => plusendi::rig::elecraft::kx3::TransmitState::Receive,
-                }
-            };
-            rig_tx.send(plusendi::rig::elecraft::kx3::Command::SetTransmitState(request)).await?;
-        }
-        tracing::info!("all done with automatic rig control");
-        color_eyre::Result::<_, color_eyre::Report>::Ok(())
+        plusendi::rig::follow_transceiver_commands(&kx3, transceiver_cmd).await
     });
 
-    let mut vara_stream = tnc.connect(opt.my_call, opt.target).await?;
+    Ok(tnc)
+}
+
+async fn connect(tnc: &mut VaraTnc, my_call: StationId, target: StationId) -> color_eyre::Result<()> {
+    let mut vara_stream = tnc.connect(my_call, target).await?;
 
     tracing::info!("sleep time");
-    let mut read = bytes::BytesMut::new();
-
-    fn line(data: &[u8]) -> nom::IResult<&[u8], &[u8]> {
-        nom::sequence::terminated(nom::bytes::streaming::take_until1("\r"), nom::bytes::streaming::tag("\r"))(data)
-    }
+    let mut lines = plusendi::modem::parser_iterator::BufferProcessor::new(plusendi::modem::vara::line);
 
-    'out: loop {
-        vara_stream.read_buf(&mut read).await?;
-        let retain_after = {
-            let mut data = &read[..];
-            while data.len() > 0 {
-                match line(&data) {
-                    Ok((remaining, line)) => {
-                        tracing::trace!(line = std::str::from_utf8(line).unwrap(), remaining = std::str::from_utf8(remaining).unwrap(), "received complete line");
-                        data = remaining;
-                        println!("{}", String::from_utf8_lossy(line));
-                        if line.ends_with(&[b'>']) {
-                            break 'out;
-                        }
-                    },
-                    Err(err) if err.is_incomplete() => {
-                        tracing::trace!(buffer = std::str::from_utf8(data).unwrap(), "incomplete");
-                        break
-                    },
-                    Err(err) => {
-                        return Err(err.to_owned().into())
-                    },
-                }
-            }
-            read.len() - data.len()
-        };
-        if retain_after == read.len() {
-            read.clear();
-        } else if retain_after > 0 {
-            let new = read.split_off(retain_after);
-            read = new;
-            tracing::trace!(bytes = read.len(), "retained incomplete parts");
-        }
-    }
+    lines.read_until(&mut vara_stream, |line| {
+        println!("{}", String::from_utf8_lossy(line));
+        line.ends_with(b">")
+    }).await?;
     // loop {
     //     match std::io::stdin().read_line(&mut to_send) {
     //         Ok(0) => break,
@@ -154,40 +186,10 @@ async fn main() -> color_eyre::Result<()> {
     let ident = format!("{}-{}", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION"));
     let to_be_sent = format!("[{}-B2FWIHJM$]\rFF\r", ident);
     vara_stream.write_all(to_be_sent.as_bytes()).await?;
-    read.clear();
-    loop {
-        let count = vara_stream.read_buf(&mut read).await?;
-        if count == 0 {
-            break;
-        }
-        let retain_after = {
-            let mut data = &read[..];
-            while data.len() > 0 {
-                match line(&data) {
-                    Ok((remaining, line)) => {
-                        tracing::trace!(line = std::str::from_utf8(line).unwrap(), remaining = std::str::from_utf8(remaining).unwrap(), "received complete line");
-                        data = remaining;
-                        println!("{}", String::from_utf8_lossy(line));
-                    },
-                    Err(err) if err.is_incomplete() => {
-                        tracing::trace!(buffer = std::str::from_utf8(data).unwrap(), "incomplete");
-                        break
-                    },
-                    Err(err) => {
-                        return Err(err.to_owned().into())
-                    },
-                }
-            }
-            read.len() - data.len()
-        };
-        if retain_after == read.len() {
-            read.clear();
-        } else if retain_after > 0 {
-            let new = read.split_off(retain_after);
-            read = new;
-            tracing::trace!(bytes = read.len(), "retained incomplete parts");
-        }
-    }
+    lines.read_until(&mut vara_stream, |line| {
+        println!("{}", String::from_utf8_lossy(line));
+        false
+    }).await?;
 
     // data.write_all(b"[Plusendi-0.0.1-B2FWIHJM$]\rFF\r");
 
@@ -198,3 +200,94 @@ async fn main() -> color_eyre::Result<()> {
 
     Ok(())
 }
+
+async fn send(tnc: &mut VaraTnc, my_call: StationId, target: StationId, message: &std::path::Path) -> color_eyre::Result<()> {
+    let text = tokio::fs::read_to_string(message).await?;
+    let traffic = plusendi::Traffic::parse(&text)?;
+    let choice = plusendi::send_traffic(tnc, my_call, target.clone(), &traffic).await?;
+    println!("{} answered {:?}", target, choice);
+    Ok(())
+}
+
+/// Answers each inbound connection, printing whatever the caller proposes. Every proposal is
+/// deferred, so nothing is received yet.
+async fn listen(tnc: &mut VaraTnc, my_call: &StationId) -> color_eyre::Result<()> {
+    tnc.send_listen(plusendi::modem::vara::ListenMode::Enable).await?;
+    loop {
+        let mut stream = tnc.accept().await?;
+        let peer = stream.peer().map(ToOwned::to_owned);
+        tracing::info!(peer = peer.as_ref().map(|peer| peer.as_str()), "accepted connection");
+
+        stream.write_all(format!("{}\r{} de {} >\r", plusendi::fbb::local_sid(), peer.as_ref().map_or("", |peer| peer.as_str()), my_call).as_bytes()).await?;
+
+        let mut lines = plusendi::modem::parser_iterator::BufferProcessor::new(plusendi::modem::vara::line);
+        let mut proposed = 0;
+        let mut done = false;
+        let ended = !lines.read_until(&mut stream, |line| {
+            let line = String::from_utf8_lossy(line);
+            let line = line.trim_start_matches('\n');
+            match line.get(..2) {
+                Some("FA" | "FB" | "FC") => {
+                    println!("{}", line);
+                    proposed += 1;
+                }
+                Some("F>") => return true,
+                Some("FF" | "FQ") => {
+                    done = true;
+                    return true;
+                }
+                _ => {}
+            }
+            false
+        }).await?;
+
+        if !ended && !done {
+            let deferred = vec![plusendi::fbb::MessageChoice::Defer; proposed];
+            stream.write_all(format!("{}\rFQ\r", plusendi::fbb::render_selection(&deferred)).as_bytes()).await?;
+        }
+        stream.close().await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMON: [&str; 7] = ["plusendi", "-d", "KC1GSL", "--rig-control", "/dev/ttyUSB0", "--rig-baud", "38400"];
+
+    fn parse(args: &[&str]) -> color_eyre::Result<Command> {
+        Opt::from_iter_safe(COMMON.iter().chain(args))?.command()
+    }
+
+    #[test]
+    fn parses_subcommands() -> color_eyre::Result<()> {
+        assert_eq!(parse(&["connect", "KW1U"])?, Command::Connect { target: StationId::new("KW1U")? });
+        assert_eq!(parse(&["send", "KW1U", "radiogram.txt"])?, Command::Send {
+            target: StationId::new("KW1U")?,
+            message: PathBuf::from("radiogram.txt"),
+        });
+        assert_eq!(parse(&["listen"])?, Command::Listen);
+        Ok(())
+    }
+
+    #[test]
+    fn parses_modem_modes() -> color_eyre::Result<()> {
+        let opt = Opt::from_iter_safe(COMMON.iter().chain(&["listen"]))?;
+        assert_eq!(opt.common.bandwidth, BandwidthMode::Wide);
+        assert_eq!(opt.common.compression, CompressionMode::Text);
+
+        let opt = Opt::from_iter_safe(COMMON.iter().chain(&["--bandwidth", "500", "--compression", "files", "listen"]))?;
+        assert_eq!(opt.common.bandwidth, BandwidthMode::Narrow);
+        assert_eq!(opt.common.compression, CompressionMode::Files);
+
+        assert!(Opt::from_iter_safe(COMMON.iter().chain(&["--bandwidth", "2000", "listen"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bare_target_connects() -> color_eyre::Result<()> {
+        assert_eq!(parse(&["KW1U"])?, Command::Connect { target: StationId::new("KW1U")? });
+        assert!(parse(&[]).is_err());
+        Ok(())
+    }
+}
@@ -1,10 +1,63 @@
+use std::future::Future;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use crate::StationIdRef;
 
+pub mod ardop;
+pub mod loopback;
+pub mod parser_iterator;
 pub mod vara;
 
-pub trait Modem<'a> {
-    type Connection;
-    type ConnectionError: std::error::Error + Send + Sync + 'static;
+/// Anything that can open a byte stream to another station, so that sessions can be
+/// driven without knowing which TNC carries them.
+pub trait Modem {
+    /// The open link, which borrows the modem until it is dropped.
+    type Connection<'a>: AsyncRead + AsyncWrite + Unpin + 'a where Self: 'a;
+    type ConnectionError: Into<color_eyre::Report> + Send + Sync + 'static;
 
-    fn connect(&'a mut self, station: &StationIdRef) -> Result<Self::Connection, Self::ConnectionError>;
+    /// Calls `to` as `from`, resolving once the link is up.
+    fn connect(&mut self, from: &StationIdRef, to: &StationIdRef) -> impl Future<Output = Result<Self::Connection<'_>, Self::ConnectionError>>;
+}
+
+/// Caps the number of simultaneous sessions, shared between any number of modems.
+#[derive(Clone, Debug)]
+pub struct SessionLimit {
+    semaphore: Arc<Semaphore>,
+    max_sessions: usize,
+}
+
+impl SessionLimit {
+    pub fn new(max_sessions: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max_sessions)),
+            max_sessions,
+        }
+    }
+
+    pub fn max_sessions(&self) -> usize {
+        self.max_sessions
+    }
+
+    pub fn active_sessions(&self) -> usize {
+        self.max_sessions - self.semaphore.available_permits()
+    }
+
+    pub fn try_acquire(&self) -> Option<SessionPermit> {
+        Arc::clone(&self.semaphore).try_acquire_owned().ok().map(|_permit| SessionPermit { _permit })
+    }
+}
+
+impl PartialEq for SessionLimit {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.semaphore, &other.semaphore)
+    }
+}
+
+impl Eq for SessionLimit {}
+
+/// Held for the lifetime of a session; dropping it frees a slot in the [`SessionLimit`].
+#[derive(Debug)]
+pub struct SessionPermit {
+    _permit: OwnedSemaphorePermit,
 }
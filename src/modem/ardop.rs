@@ -0,0 +1,898 @@
+//! A back end for the open-source ARDOP TNC, which, like VARA, takes commands on one TCP port
+//! and carries session data on the next.
+//!
+//! How VARA features map onto ARDOP:
+//!
+//! | VARA                       | ARDOP                                              |
+//! |----------------------------|----------------------------------------------------|
+//! | `MYCALL`                   | `MYCALL`, one callsign only                        |
+//! | `CONNECT <from> <to>`      | `ARQCALL <to> <repeats>`, calling as `MYCALL`      |
+//! | `DISCONNECT` / `ABORT`     | the same                                           |
+//! | `LISTEN ON`/`OFF`          | `LISTEN TRUE`/`FALSE`                              |
+//! | `OK` / `WRONG` replies     | the command echoed back / `FAULT <reason>`         |
+//! | `PTT ON`/`OFF`             | `PTT TRUE`/`FALSE`                                 |
+//! | `BUSY ON`/`OFF`            | `BUSY TRUE`/`FALSE`                                |
+//! | `BUFFER <n>`               | the same                                           |
+//! | `CONNECTED <me> <peer>`    | `CONNECTED <peer> <bandwidth> [<grid>]`            |
+//! | —                          | `NEWSTATE <state>`, tracked as [`State`]           |
+//! | raw bytes on the data port | length-prefixed frames; see [`data_frame`]         |
+//!
+//! VARA's compression, public mode, `CLEANTXBUFFER`, and registration have no
+//! counterpart here.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Error;
+use std::num::NonZeroU16;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use nom::{Finish, IResult};
+use nom::error::VerboseError;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::mpsc::{Receiver, Sender};
+use crate::modem::parser_iterator::BufferProcessor;
+use crate::modem::vara::{line, poll_remote_disconnect, BusyState, CommandResult, ControlLine, TransceiverCommand};
+use crate::parser::{StrOrByteSlice, ToOwnedError};
+use crate::{StationId, StationIdRef};
+
+/// How many times `ARQCALL` repeats the call before giving up.
+pub const DEFAULT_CALL_REPEATS: u8 = 5;
+
+/// The most data sent to the TNC in a single data port frame.
+pub const MAX_DATA_FRAME: usize = 2000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Initialize,
+    SetCall(StationId),
+    Listen(bool),
+    ArqCall { target: StationId, repeats: u8 },
+    Disconnect,
+    Abort,
+}
+
+impl Command {
+    /// The word ARDOP echoes back to acknowledge the command.
+    fn verb(&self) -> &'static str {
+        match self {
+            Self::Initialize => "INITIALIZE",
+            Self::SetCall(_) => "MYCALL",
+            Self::Listen(_) => "LISTEN",
+            Self::ArqCall { .. } => "ARQCALL",
+            Self::Disconnect => "DISCONNECT",
+            Self::Abort => "ABORT",
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.verb())?;
+        match self {
+            Self::SetCall(call) => write!(f, " {}", call),
+            Self::Listen(on) => f.write_str(if *on { " TRUE" } else { " FALSE" }),
+            Self::ArqCall { target, repeats } => write!(f, " {} {}", target, repeats),
+            Self::Initialize | Self::Disconnect | Self::Abort => Ok(()),
+        }
+    }
+}
+
+/// The ARDOP protocol state, as reported by `NEWSTATE`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Offline,
+    Disconnected,
+    /// Information sending station: this end has the data flow.
+    Iss,
+    /// Information receiving station.
+    Irs,
+    Idle,
+    IrsToIss,
+    FecSend,
+    FecReceive,
+}
+
+fn state(data: &[u8]) -> IResult<&[u8], State, VerboseError<&[u8]>> {
+    use nom::bytes::complete::tag;
+    use nom::combinator::value;
+    nom::branch::alt((
+        value(State::Offline, tag("OFFLINE")),
+        value(State::Disconnected, tag("DISC")),
+        value(State::IrsToIss, tag("IRStoISS")),
+        value(State::Iss, tag("ISS")),
+        value(State::Irs, tag("IRS")),
+        value(State::Idle, tag("IDLE")),
+        value(State::FecSend, tag("FECSend")),
+        value(State::FecReceive, tag("FECRcv")),
+    ))(data)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Disconnected,
+    Pending,
+    Connected {
+        other_station: StationId,
+        bandwidth: u16,
+    },
+}
+
+impl ConnectionState {
+    pub fn is_connected(&self) -> bool {
+        matches!(self, Self::Connected { .. })
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        matches!(self, Self::Disconnected)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Update<'a> {
+    TransceiverControl(TransceiverCommand),
+    Buffer { bytes_remaining: usize },
+    /// Both the reply to a `STATE` query and the `NEWSTATE` notification.
+    State(State),
+    Connected { other_station: &'a StationIdRef, bandwidth: u16 },
+    Disconnected,
+    Busy(BusyState),
+    /// An inbound call has been heard but not yet answered.
+    Pending,
+    CancelPending,
+    Status(&'a str),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum TncResponse<'a> {
+    Update(Update<'a>),
+    /// A command accepted by echoing its verb, with whatever ARDOP appended.
+    Reply { verb: &'a str },
+    Fault(&'a str),
+}
+
+fn tnc_response(data: &[u8]) -> IResult<&[u8], TncResponse<'_>, VerboseError<&[u8]>> {
+    nom::branch::alt((
+        nom::combinator::map(update, TncResponse::Update),
+        nom::combinator::map(nom::sequence::preceded(nom::bytes::complete::tag("FAULT "), text), TncResponse::Fault),
+        reply,
+    ))(data)
+}
+
+fn update(data: &[u8]) -> IResult<&[u8], Update<'_>, VerboseError<&[u8]>> {
+    use nom::bytes::complete::tag;
+    use nom::combinator::{map, value};
+    use nom::sequence::preceded;
+    nom::branch::alt((
+        map(preceded(tag("PTT "), true_or_false), |transmit| Update::TransceiverControl(if transmit { TransceiverCommand::Transmit { delay: None } } else { TransceiverCommand::Receive { delay: None } })),
+        map(preceded(tag("BUFFER "), nom::character::complete::u32), |bytes| Update::Buffer { bytes_remaining: bytes as usize }),
+        map(preceded(nom::branch::alt((tag("NEWSTATE "), tag("STATE "))), state), Update::State),
+        connected,
+        value(Update::Disconnected, tag("DISCONNECTED")),
+        map(preceded(tag("BUSY "), true_or_false), |busy| Update::Busy(if busy { BusyState::Busy } else { BusyState::NotBusy })),
+        value(Update::CancelPending, tag("CANCELPENDING")),
+        value(Update::Pending, tag("PENDING")),
+        map(preceded(tag("STATUS "), text), Update::Status),
+    ))(data)
+}
+
+fn connected(data: &[u8]) -> IResult<&[u8], Update<'_>, VerboseError<&[u8]>> {
+    let (rest, (other_station, bandwidth)) = nom::sequence::preceded(
+        nom::bytes::complete::tag("CONNECTED "),
+        nom::sequence::separated_pair(crate::types::callsign, nom::bytes::complete::tag(" "), nom::character::complete::u16),
+    )(data)?;
+    // Newer versions append the peer's grid square, which isn't tracked.
+    let (rest, _) = nom::combinator::opt(nom::sequence::preceded(
+        nom::bytes::complete::tag(" "),
+        nom::bytes::complete::take_while1(|c: u8| c.is_ascii_alphanumeric()),
+    ))(rest)?;
+    Ok((rest, Update::Connected { other_station, bandwidth }))
+}
+
+fn reply(data: &[u8]) -> IResult<&[u8], TncResponse<'_>, VerboseError<&[u8]>> {
+    let (rest, verb) = nom::combinator::map_res(nom::bytes::complete::take_while1(|c: u8| c.is_ascii_uppercase()), std::str::from_utf8)(data)?;
+    let (rest, _) = nom::combinator::opt(nom::sequence::preceded(nom::bytes::complete::tag(" "), nom::combinator::rest))(rest)?;
+    Ok((rest, TncResponse::Reply { verb }))
+}
+
+fn true_or_false(data: &[u8]) -> IResult<&[u8], bool, VerboseError<&[u8]>> {
+    nom::branch::alt((nom::combinator::value(true, nom::bytes::complete::tag("TRUE")), nom::combinator::value(false, nom::bytes::complete::tag("FALSE"))))(data)
+}
+
+fn text(data: &[u8]) -> IResult<&[u8], &str, VerboseError<&[u8]>> {
+    nom::combinator::map_res(nom::combinator::rest, std::str::from_utf8)(data)
+}
+
+/// One frame from the data port: a big-endian length, then that many bytes, of which the
+/// first three say what kind of data follows (`ARQ`, `FEC`, `IDF`, or `ERR`).
+pub fn data_frame(data: &[u8]) -> IResult<&[u8], &[u8], VerboseError<&[u8]>> {
+    nom::multi::length_data(nom::number::streaming::be_u16)(data)
+}
+
+type DataFrameParser = fn(&[u8]) -> IResult<&[u8], &[u8], VerboseError<&[u8]>>;
+
+type PendingReply = (&'static str, tokio::sync::oneshot::Sender<CommandResult>);
+
+#[tracing::instrument(skip(rx, tx, stream), err)]
+async fn manage_modem_thread(mut rx: Receiver<(Command, tokio::sync::oneshot::Sender<CommandResult>)>, tx: TncStatusSender, stream: TcpStream) -> color_eyre::Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    let mut lines = BufferProcessor::with_capacity(line, 64);
+    let mut response_queue = VecDeque::with_capacity(4);
+    let mut command_active = true;
+
+    while command_active || !response_queue.is_empty() {
+        tokio::select!(
+            recv = rx.recv(), if command_active => {
+                if let Some((command, reply)) = recv {
+                    send_command(&mut writer, &command, &tx).await?;
+                    if let Command::ArqCall { .. } = command {
+                        tx.connection.send_replace(ConnectionState::Pending);
+                    }
+                    response_queue.push_back((command.verb(), reply));
+                } else {
+                    command_active = false
+                }
+            },
+            read = reader.read_buf(&mut lines) => {
+                if read? == 0 {
+                    tracing::info!("control port closed");
+                    break;
+                }
+                for line in lines.iter() {
+                    handle_line(&line?, &tx, &mut response_queue);
+                }
+            }
+        );
+    }
+    tracing::info!("exiting command loop");
+    Ok(())
+}
+
+async fn send_command(writer: &mut OwnedWriteHalf, command: &Command, tx: &TncStatusSender) -> std::io::Result<()> {
+    let command = command.to_string();
+    tracing::trace!(command = command.as_str(), "sending command");
+    writer.write_all(format!("{}\r", command).as_bytes()).await?;
+    let _ = tx.control_trace.send(ControlLine::Sent(Bytes::from(command)));
+    Ok(())
+}
+
+fn handle_line(line: &[u8], tx: &TncStatusSender, response_queue: &mut VecDeque<PendingReply>) {
+    tracing::trace!(line = StrOrByteSlice::Bytes(line).to_lossy_string(), "received complete line");
+    let _ = tx.control_trace.send(ControlLine::Received(Bytes::copy_from_slice(line)));
+
+    let response = match nom::combinator::all_consuming(tnc_response)(line).map_err(ToOwnedError::to_owned_error).finish() {
+        Ok((_, response)) => response,
+        Err(err) => {
+            // Likely something a newer ARDOP sends; not worth dropping the link over.
+            tracing::warn!(line = StrOrByteSlice::Bytes(line).to_lossy_string(), %err, "ignoring unrecognized line from tnc");
+            return;
+        }
+    };
+    tracing::debug!(?response, "received tnc data");
+    match response {
+        TncResponse::Update(update) => match update {
+            Update::TransceiverControl(control) => {
+                tx.transceiver_control.send_replace(control);
+            }
+            Update::Buffer { bytes_remaining } => {
+                tx.buffer.send_replace(bytes_remaining);
+            }
+            Update::State(state) => {
+                tx.state.send_replace(state);
+                // A failed call only shows up as the TNC falling back to disconnected.
+                if state == State::Disconnected {
+                    tx.connection.send_replace(ConnectionState::Disconnected);
+                }
+            }
+            Update::Connected { other_station, bandwidth } => {
+                tx.connection.send_replace(ConnectionState::Connected { other_station: other_station.to_owned(), bandwidth });
+            }
+            Update::Disconnected => {
+                tx.connection.send_replace(ConnectionState::Disconnected);
+            }
+            Update::Busy(busy) => {
+                tx.busy_state.send_replace(busy);
+            }
+            Update::Pending => {
+                tx.connection.send_replace(ConnectionState::Pending);
+            }
+            Update::CancelPending => {
+                tx.connection.send_if_modified(|connection| {
+                    let pending = *connection == ConnectionState::Pending;
+                    if pending {
+                        *connection = ConnectionState::Disconnected;
+                    }
+                    pending
+                });
+            }
+            Update::Status(status) => {
+                tracing::info!(status, "tnc status");
+            }
+        },
+        TncResponse::Reply { verb } => match response_queue.front() {
+            Some((expected, _)) if *expected == verb => {
+                let (_, reply) = response_queue.pop_front().unwrap();
+                let _ = reply.send(CommandResult::Ok);
+            }
+            _ => tracing::warn!(verb, "unexpected reply"),
+        },
+        TncResponse::Fault(fault) => {
+            tracing::warn!(fault, "tnc reported a fault");
+            if let Some((verb, reply)) = response_queue.pop_front() {
+                if verb == "ARQCALL" {
+                    tx.connection.send_replace(ConnectionState::Disconnected);
+                }
+                let _ = reply.send(CommandResult::Wrong);
+            }
+        }
+    }
+}
+
+fn channel() -> (TncStatusSender, TncStatusReceiver) {
+    use tokio::sync::watch::channel;
+    let (state_tx, state_rx) = channel(State::Offline);
+    let (busy_tx, busy_rx) = channel(BusyState::NotBusy);
+    let (buffer_tx, buffer_rx) = channel(0);
+    let (connection_tx, connection_rx) = channel(ConnectionState::Disconnected);
+    let (transceiver_tx, transceiver_rx) = channel(TransceiverCommand::Receive { delay: None });
+    let (control_trace, _) = tokio::sync::broadcast::channel(64);
+
+    let sender = TncStatusSender {
+        state: state_tx,
+        busy_state: busy_tx,
+        buffer: buffer_tx,
+        connection: connection_tx,
+        transceiver_control: transceiver_tx,
+        control_trace: control_trace.clone(),
+    };
+
+    let receiver = TncStatusReceiver {
+        state: state_rx,
+        busy_state: busy_rx,
+        buffer: buffer_rx,
+        connection: connection_rx,
+        transceiver_control: transceiver_rx,
+        control_trace,
+    };
+
+    (sender, receiver)
+}
+
+#[derive(Debug)]
+struct TncStatusSender {
+    state: tokio::sync::watch::Sender<State>,
+    busy_state: tokio::sync::watch::Sender<BusyState>,
+    buffer: tokio::sync::watch::Sender<usize>,
+    connection: tokio::sync::watch::Sender<ConnectionState>,
+    transceiver_control: tokio::sync::watch::Sender<TransceiverCommand>,
+    control_trace: tokio::sync::broadcast::Sender<ControlLine>,
+}
+
+#[derive(Debug)]
+struct TncStatusReceiver {
+    state: tokio::sync::watch::Receiver<State>,
+    busy_state: tokio::sync::watch::Receiver<BusyState>,
+    buffer: tokio::sync::watch::Receiver<usize>,
+    connection: tokio::sync::watch::Receiver<ConnectionState>,
+    transceiver_control: tokio::sync::watch::Receiver<TransceiverCommand>,
+    control_trace: tokio::sync::broadcast::Sender<ControlLine>,
+}
+
+pub struct ArdopTnc {
+    data: TcpStream,
+    frames: BufferProcessor<DataFrameParser>,
+    control_channel: Sender<(Command, tokio::sync::oneshot::Sender<CommandResult>)>,
+    status: TncStatusReceiver,
+    managing_thread: tokio::task::JoinHandle<color_eyre::Result<()>>,
+    stream_tasks: Vec<tokio::task::JoinHandle<()>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArdopTncBuilder {
+    host: std::net::IpAddr,
+    control_port: NonZeroU16,
+    data_port: Option<NonZeroU16>,
+}
+
+impl ArdopTncBuilder {
+    pub async fn build(&mut self) -> std::io::Result<ArdopTnc> {
+        let data_port = self.validate()?;
+
+        let control = TcpStream::connect((self.host, self.control_port.get())).await?;
+        let data = TcpStream::connect((self.host, data_port.get())).await?;
+
+        let (control_tx, control_rx) = tokio::sync::mpsc::channel(1);
+        let (status_tx, status_rx) = channel();
+
+        let managing_thread = tokio::spawn(manage_modem_thread(control_rx, status_tx, control));
+
+        Ok(ArdopTnc {
+            data,
+            frames: BufferProcessor::with_capacity(data_frame, MAX_DATA_FRAME),
+            control_channel: control_tx,
+            status: status_rx,
+            managing_thread,
+            stream_tasks: Vec::new(),
+        })
+    }
+
+    /// Checks that the control and data ports can be used together, returning the data port
+    /// that `build` will connect to. When no data port is set, it is the one after the
+    /// control port.
+    pub fn validate(&self) -> std::io::Result<NonZeroU16> {
+        let data_port = match self.data_port {
+            Some(port) => port,
+            None => self.control_port.get().checked_add(1).and_then(NonZeroU16::new).ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("no data port follows control port {}; set the data port explicitly", self.control_port))
+            })?,
+        };
+
+        if data_port == self.control_port {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("control and data ports must differ, but both are {}", data_port)));
+        }
+
+        Ok(data_port)
+    }
+
+    pub fn host(&mut self, host: std::net::IpAddr) -> &mut Self {
+        self.host = host;
+        self
+    }
+
+    pub fn control_port(&mut self, port: NonZeroU16) -> &mut Self {
+        self.control_port = port;
+        self
+    }
+
+    pub fn data_port(&mut self, port: NonZeroU16) -> &mut Self {
+        self.data_port = Some(port);
+        self
+    }
+}
+
+impl ArdopTnc {
+    pub fn builder() -> ArdopTncBuilder {
+        ArdopTncBuilder {
+            host: std::net::Ipv4Addr::LOCALHOST.into(),
+            control_port: 8515.try_into().unwrap(),
+            data_port: None,
+        }
+    }
+
+    async fn send_command(&self, command: Command) -> color_eyre::Result<()> {
+        let (cmd_tx, cmd_rx) = tokio::sync::oneshot::channel();
+        let verb = command.verb();
+
+        self.control_channel.send((command, cmd_tx)).await?;
+
+        if cmd_rx.await? == CommandResult::Wrong {
+            return Err(color_eyre::eyre::eyre!("tnc reported a fault for {}", verb));
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    pub async fn send_initialize(&self) -> color_eyre::Result<()> {
+        self.send_command(Command::Initialize).await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    pub async fn send_callsign(&self, call: StationId) -> color_eyre::Result<()> {
+        self.send_command(Command::SetCall(call)).await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    pub async fn send_listen(&self, on: bool) -> color_eyre::Result<()> {
+        self.send_command(Command::Listen(on)).await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    pub async fn send_disconnect(&self) -> color_eyre::Result<()> {
+        self.send_command(Command::Disconnect).await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    pub async fn send_abort(&self) -> color_eyre::Result<()> {
+        self.send_command(Command::Abort).await
+    }
+
+    pub fn state(&self) -> State {
+        *self.status.state.borrow()
+    }
+
+    pub fn subscribe_state(&self) -> tokio::sync::watch::Receiver<State> {
+        self.status.state.clone()
+    }
+
+    pub fn buffer(&self) -> usize {
+        *self.status.buffer.borrow()
+    }
+
+    pub fn busy_state(&self) -> BusyState {
+        *self.status.busy_state.borrow()
+    }
+
+    pub fn connection(&self) -> ConnectionState {
+        self.status.connection.borrow().clone()
+    }
+
+    pub fn subscribe_rig_command(&self) -> tokio::sync::watch::Receiver<TransceiverCommand> {
+        self.status.transceiver_control.clone()
+    }
+
+    /// Receives every line sent or received on the control port from now on.
+    pub fn subscribe_control_trace(&self) -> tokio::sync::broadcast::Receiver<ControlLine> {
+        self.status.control_trace.subscribe()
+    }
+
+    pub fn is_managing_thread_finished(&self) -> bool {
+        self.managing_thread.is_finished()
+    }
+
+    /// Calls `to` as the callsign last set with `send_callsign`, resolving once the link is
+    /// up or the TNC gives up.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn connect(&mut self, to: StationId) -> color_eyre::Result<ArdopStream<'_>> {
+        self.send_command(Command::ArqCall { target: to, repeats: DEFAULT_CALL_REPEATS }).await?;
+
+        let connection = self.status.connection.wait_for(|connection| *connection != ConnectionState::Pending).await?.clone();
+        if connection.is_connected() {
+            Ok(self.open_stream())
+        } else {
+            Err(color_eyre::eyre::eyre!("failed to connect"))
+        }
+    }
+
+    fn open_stream(&mut self) -> ArdopStream<'_> {
+        let mut subscriber = self.status.connection.clone();
+        let (remote_dc, remote_disconnect) = tokio::sync::oneshot::channel();
+        let remote_dc_task = tokio::spawn(async move {
+            if subscriber.wait_for(ConnectionState::is_disconnected).await.is_ok() {
+                let _ = remote_dc.send(());
+            }
+        });
+
+        self.stream_tasks.retain(|task| !task.is_finished());
+        self.stream_tasks.push(remote_dc_task);
+
+        ArdopStream {
+            tnc: self,
+            received: Bytes::new(),
+            unsent: BytesMut::new(),
+            remote_disconnect: Some(remote_disconnect),
+        }
+    }
+}
+
+impl crate::modem::Modem for ArdopTnc {
+    type Connection<'a> = ArdopStream<'a>;
+    type ConnectionError = color_eyre::Report;
+
+    async fn connect(&mut self, from: &StationIdRef, to: &StationIdRef) -> color_eyre::Result<ArdopStream<'_>> {
+        self.send_callsign(from.to_owned()).await?;
+        ArdopTnc::connect(self, to.to_owned()).await
+    }
+}
+
+/// An ARQ session's data, with the data port's framing taken care of.
+pub struct ArdopStream<'a> {
+    tnc: &'a mut ArdopTnc,
+    received: Bytes,
+    unsent: BytesMut,
+    remote_disconnect: Option<tokio::sync::oneshot::Receiver<()>>,
+}
+
+impl<'a> ArdopStream<'a> {
+    /// Disconnects and waits for the TNC to confirm.
+    pub async fn disconnect(self) -> color_eyre::Result<()> {
+        if !self.tnc.status.connection.borrow().is_disconnected() {
+            self.tnc.send_disconnect().await?;
+        }
+        self.tnc.status.connection.wait_for(ConnectionState::is_disconnected).await?;
+        Ok(())
+    }
+
+    fn poll_send_unsent(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.unsent.is_empty() {
+            let written = std::task::ready!(Pin::new(&mut self.tnc.data).poll_write(cx, &self.unsent))?;
+            if written == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.unsent.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<'a> Drop for ArdopStream<'a> {
+    fn drop(&mut self) {
+        if self.tnc.status.connection.borrow().is_disconnected() {
+            return;
+        }
+
+        let (reply, _) = tokio::sync::oneshot::channel();
+        if let Err(err) = self.tnc.control_channel.try_send((Command::Disconnect, reply)) {
+            let control = self.tnc.control_channel.clone();
+            let command = match err {
+                tokio::sync::mpsc::error::TrySendError::Full(command) | tokio::sync::mpsc::error::TrySendError::Closed(command) => command,
+            };
+            let task = tokio::spawn(async move {
+                let _ = control.send(command).await;
+            });
+            self.tnc.stream_tasks.push(task);
+        }
+    }
+}
+
+impl<'a> AsyncRead for ArdopStream<'a> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.received.is_empty() {
+                let n = this.received.len().min(buf.remaining());
+                buf.put_slice(&this.received.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+
+            if let Some(frame) = this.tnc.frames.iter().next() {
+                let frame = frame.map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+                if frame.starts_with(b"ARQ") {
+                    this.received = frame.slice(3..);
+                } else {
+                    tracing::debug!(frame = StrOrByteSlice::Bytes(&frame).to_lossy_string(), "skipping non-ARQ data");
+                }
+                continue;
+            }
+
+            if poll_remote_disconnect(&mut this.remote_disconnect, cx).is_ready() {
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut chunk = [0; 512];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            std::task::ready!(Pin::new(&mut this.tnc.data).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            this.tnc.frames.put_slice(chunk.filled());
+        }
+    }
+}
+
+impl<'a> AsyncWrite for ArdopStream<'a> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        if poll_remote_disconnect(&mut this.remote_disconnect, cx).is_ready() {
+            return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "connection closed on remote end")));
+        }
+
+        std::task::ready!(this.poll_send_unsent(cx))?;
+        let n = buf.len().min(MAX_DATA_FRAME);
+        this.unsent.put_u16(n as u16);
+        this.unsent.put_slice(&buf[..n]);
+        // Start sending right away; whatever doesn't fit goes out on the next write or flush.
+        if let Poll::Ready(Err(err)) = this.poll_send_unsent(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        std::task::ready!(this.poll_send_unsent(cx))?;
+        Pin::new(&mut this.tnc.data).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        // The data port outlives the session, so only flush it.
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_log::test;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::TcpListener;
+    use super::*;
+
+    fn parse(line: &[u8]) -> color_eyre::Result<TncResponse<'_>> {
+        let (_, response) = nom::combinator::all_consuming(tnc_response)(line).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        Ok(response)
+    }
+
+    #[test]
+    fn status_lines() -> color_eyre::Result<()> {
+        assert_eq!(parse(b"PTT TRUE")?, TncResponse::Update(Update::TransceiverControl(TransceiverCommand::Transmit { delay: None })));
+        assert_eq!(parse(b"PTT FALSE")?, TncResponse::Update(Update::TransceiverControl(TransceiverCommand::Receive { delay: None })));
+        assert_eq!(parse(b"BUFFER 1432")?, TncResponse::Update(Update::Buffer { bytes_remaining: 1432 }));
+        assert_eq!(parse(b"BUSY TRUE")?, TncResponse::Update(Update::Busy(BusyState::Busy)));
+        assert_eq!(parse(b"DISCONNECTED")?, TncResponse::Update(Update::Disconnected));
+        assert_eq!(parse(b"PENDING")?, TncResponse::Update(Update::Pending));
+        assert_eq!(parse(b"CANCELPENDING")?, TncResponse::Update(Update::CancelPending));
+        assert_eq!(parse(b"STATUS CONNECT TO KW1U FAILED!")?, TncResponse::Update(Update::Status("CONNECT TO KW1U FAILED!")));
+        // Anything unrecognized is taken for a command echo, not a status change.
+        assert_eq!(parse(b"PTT ON")?, TncResponse::Reply { verb: "PTT" });
+        Ok(())
+    }
+
+    #[test]
+    fn state_lines() -> color_eyre::Result<()> {
+        for (line, state) in [
+            (&b"NEWSTATE OFFLINE"[..], State::Offline),
+            (b"NEWSTATE DISC", State::Disconnected),
+            (b"NEWSTATE ISS", State::Iss),
+            (b"NEWSTATE IRS", State::Irs),
+            (b"NEWSTATE IRStoISS", State::IrsToIss),
+            (b"NEWSTATE IDLE", State::Idle),
+            (b"NEWSTATE FECSend", State::FecSend),
+            (b"STATE FECRcv", State::FecReceive),
+        ] {
+            assert_eq!(parse(line)?, TncResponse::Update(Update::State(state)));
+        }
+        assert_eq!(parse(b"NEWSTATE SLEEPING")?, TncResponse::Reply { verb: "NEWSTATE" });
+        Ok(())
+    }
+
+    #[test]
+    fn connected_lines() -> color_eyre::Result<()> {
+        for line in [&b"CONNECTED KW1U 500"[..], b"CONNECTED KW1U 500 FN42"] {
+            let TncResponse::Update(Update::Connected { other_station, bandwidth }) = parse(line)? else {
+                color_eyre::eyre::bail!("not a connection: {:?}", line);
+            };
+            assert_eq!(other_station.as_str(), "KW1U");
+            assert_eq!(bandwidth, 500);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn replies_and_faults() -> color_eyre::Result<()> {
+        assert_eq!(parse(b"MYCALL now KC1GSL")?, TncResponse::Reply { verb: "MYCALL" });
+        assert_eq!(parse(b"ARQCALL KW1U 5")?, TncResponse::Reply { verb: "ARQCALL" });
+        assert_eq!(parse(b"DISCONNECT")?, TncResponse::Reply { verb: "DISCONNECT" });
+        assert_eq!(parse(b"FAULT Not from state DISC")?, TncResponse::Fault("Not from state DISC"));
+        Ok(())
+    }
+
+    #[test]
+    fn commands_display() -> color_eyre::Result<()> {
+        assert_eq!(Command::SetCall(StationId::new("KC1GSL")?).to_string(), "MYCALL KC1GSL");
+        assert_eq!(Command::Listen(true).to_string(), "LISTEN TRUE");
+        assert_eq!(Command::ArqCall { target: StationId::new("KW1U")?, repeats: 5 }.to_string(), "ARQCALL KW1U 5");
+        assert_eq!(Command::Disconnect.to_string(), "DISCONNECT");
+        Ok(())
+    }
+
+    #[test]
+    fn data_frames() {
+        let mut frames = BufferProcessor::new(data_frame as DataFrameParser);
+        frames.put_slice(b"\x00\x06ARQabc\x00\x05FEC");
+        assert!(matches!(frames.iter().next(), Some(Ok(frame)) if frame == "ARQabc"));
+        assert!(frames.iter().next().is_none());
+        frames.put_slice(b"de");
+        assert!(matches!(frames.iter().next(), Some(Ok(frame)) if frame == "FECde"));
+    }
+
+    #[test(tokio::test)]
+    async fn connect_exchange_and_disconnect() -> color_eyre::Result<()> {
+        let control = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+        let data = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+        let mut builder = ArdopTnc::builder();
+        builder
+            .control_port(NonZeroU16::new(control.local_addr()?.port()).unwrap())
+            .data_port(NonZeroU16::new(data.local_addr()?.port()).unwrap());
+        let (tnc, control, data) = tokio::join!(builder.build(), control.accept(), data.accept());
+        let (mut tnc, mut control, mut data) = (tnc?, BufReader::new(control?.0), data?.0);
+
+        let script = async {
+            let mut command = Vec::new();
+            control.read_until(b'\r', &mut command).await?;
+            assert_eq!(command, b"MYCALL KC1GSL\r");
+            control.get_mut().write_all(b"MYCALL now KC1GSL\r").await?;
+
+            command.clear();
+            control.read_until(b'\r', &mut command).await?;
+            assert_eq!(command, b"ARQCALL KW1U 5\r");
+            control.get_mut().write_all(b"ARQCALL KW1U 5\rNEWSTATE ISS\rCONNECTED KW1U 500\r").await?;
+
+            data.write_all(b"\x00\x0BARQKW1U de ").await?;
+            data.write_all(b"\x00\x05IDFxx\x00\x04ARQ>").await?;
+            let mut frame = [0; 11];
+            data.read_exact(&mut frame).await?;
+            assert_eq!(&frame, b"\x00\x09;PQ: 1234");
+
+            command.clear();
+            control.read_until(b'\r', &mut command).await?;
+            assert_eq!(command, b"DISCONNECT\r");
+            control.get_mut().write_all(b"DISCONNECT\rNEWSTATE DISC\rDISCONNECTED\r").await?;
+            color_eyre::Result::<()>::Ok(())
+        };
+
+        let session = async {
+            let mut stream = crate::modem::Modem::connect(&mut tnc, &StationId::new("KC1GSL")?, &StationId::new("KW1U")?).await?;
+            let mut greeting = [0; 9];
+            stream.read_exact(&mut greeting).await?;
+            assert_eq!(&greeting, b"KW1U de >");
+            stream.write_all(b";PQ: 1234").await?;
+            stream.flush().await?;
+            stream.disconnect().await?;
+            color_eyre::Result::<()>::Ok(())
+        };
+
+        let (script, session) = tokio::join!(script, session);
+        script?;
+        session?;
+        assert_eq!(tnc.connection(), ConnectionState::Disconnected);
+        assert_eq!(tnc.state(), State::Disconnected);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn reads_and_writes_after_remote_disconnect() -> color_eyre::Result<()> {
+        let control = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+        let data = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+        let mut builder = ArdopTnc::builder();
+        builder
+            .control_port(NonZeroU16::new(control.local_addr()?.port()).unwrap())
+            .data_port(NonZeroU16::new(data.local_addr()?.port()).unwrap());
+        let (tnc, control, data) = tokio::join!(builder.build(), control.accept(), data.accept());
+        let (mut tnc, mut control, _data) = (tnc?, BufReader::new(control?.0), data?.0);
+
+        let (stream, script) = tokio::join!(tnc.connect(StationId::new("KW1U")?), async {
+            let mut command = Vec::new();
+            control.read_until(b'\r', &mut command).await?;
+            assert_eq!(command, b"ARQCALL KW1U 5\r");
+            control.get_mut().write_all(b"ARQCALL KW1U 5\rCONNECTED KW1U 500\r").await?;
+            color_eyre::Result::<()>::Ok(())
+        });
+        script?;
+        let mut stream = stream?;
+        control.get_mut().write_all(b"DISCONNECTED\r").await?;
+
+        let mut buf = [0; 16];
+        assert_eq!(tokio::time::timeout(std::time::Duration::from_secs(1), stream.read(&mut buf)).await??, 0);
+        assert_eq!(stream.read(&mut buf).await?, 0);
+        assert_eq!(stream.write(b"late").await.unwrap_err().kind(), std::io::ErrorKind::ConnectionAborted);
+        assert_eq!(stream.write(b"later").await.unwrap_err().kind(), std::io::ErrorKind::ConnectionAborted);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn unrecognized_lines_are_skipped() -> color_eyre::Result<()> {
+        let control = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+        let data = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+        let mut builder = ArdopTnc::builder();
+        builder
+            .control_port(NonZeroU16::new(control.local_addr()?.port()).unwrap())
+            .data_port(NonZeroU16::new(data.local_addr()?.port()).unwrap());
+        let (tnc, control, data) = tokio::join!(builder.build(), control.accept(), data.accept());
+        let (tnc, mut control, _data) = (tnc?, BufReader::new(control?.0), data?.0);
+
+        let (sent, script) = tokio::join!(tnc.send_callsign(StationId::new("KC1GSL")?), async {
+            let mut command = Vec::new();
+            control.read_until(b'\r', &mut command).await?;
+            assert_eq!(command, b"MYCALL KC1GSL\r");
+            control.get_mut().write_all(b"mycall? \xff\r\rMYCALL now KC1GSL\r").await?;
+            color_eyre::Result::<()>::Ok(())
+        });
+        script?;
+        sent?;
+        Ok(())
+    }
+
+    #[test]
+    fn builder_validates_ports() -> color_eyre::Result<()> {
+        let mut builder = ArdopTnc::builder();
+        assert_eq!(builder.validate()?.get(), 8516);
+
+        builder.data_port(NonZeroU16::new(8515).unwrap());
+        let err = builder.validate().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("must differ"));
+        Ok(())
+    }
+}
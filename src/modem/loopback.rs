@@ -0,0 +1,111 @@
+use tokio::io::DuplexStream;
+use tokio::sync::mpsc;
+use crate::{StationId, StationIdRef};
+
+/// A [`Modem`](super::Modem) with no radio behind it. Each connection is an in-memory
+/// stream whose far end is handed to the test, which plays the part of the peer.
+#[derive(Debug)]
+pub struct LoopbackModem {
+    max_buf_size: usize,
+    peers: mpsc::UnboundedSender<LoopbackPeer>,
+}
+
+/// The far end of a loopback connection.
+#[derive(Debug)]
+pub struct LoopbackPeer {
+    pub station: StationId,
+    pub stream: DuplexStream,
+}
+
+impl LoopbackModem {
+    /// Returns the modem along with the receiver of the peer side of each connection
+    /// made through it. Connecting fails once the receiver has been dropped.
+    pub fn new(max_buf_size: usize) -> (Self, mpsc::UnboundedReceiver<LoopbackPeer>) {
+        let (peers, receiver) = mpsc::unbounded_channel();
+        (Self { max_buf_size, peers }, receiver)
+    }
+}
+
+impl super::Modem for LoopbackModem {
+    type Connection<'a> = DuplexStream;
+    type ConnectionError = std::io::Error;
+
+    async fn connect(&mut self, _from: &StationIdRef, to: &StationIdRef) -> std::io::Result<DuplexStream> {
+        let (local, remote) = tokio::io::duplex(self.max_buf_size);
+        self.peers.send(LoopbackPeer { station: to.to_owned(), stream: remote })
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "no peer is listening"))?;
+        Ok(local)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_log::test;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::modem::Modem;
+    use super::*;
+
+    #[test(tokio::test)]
+    async fn echoes_through_loopback() -> color_eyre::Result<()> {
+        let (mut modem, mut peers) = LoopbackModem::new(64);
+        let mut stream = modem.connect(&StationId::new("KC1GSL")?, &StationId::new("KW1U")?).await?;
+
+        let mut peer = peers.recv().await.expect("connection should reach the peer");
+        assert_eq!(peer.station.as_str(), "KW1U");
+        let echo = tokio::spawn(async move {
+            let mut buffer = [0; 16];
+            loop {
+                let n = peer.stream.read(&mut buffer).await?;
+                if n == 0 {
+                    return std::io::Result::Ok(());
+                }
+                peer.stream.write_all(&buffer[..n]).await?;
+            }
+        });
+
+        stream.write_all(b"[WL2K-5.0-B2FWIHJM$]\r").await?;
+        let mut echoed = [0; 21];
+        stream.read_exact(&mut echoed).await?;
+        assert_eq!(&echoed, b"[WL2K-5.0-B2FWIHJM$]\r");
+
+        stream.shutdown().await?;
+        echo.await??;
+        Ok(())
+    }
+
+    /// Stands in for session code that only knows it has some modem.
+    async fn greet<M: Modem>(modem: &mut M, from: &StationIdRef, to: &StationIdRef) -> color_eyre::Result<Vec<u8>> {
+        let mut stream = modem.connect(from, to).await.map_err(Into::into)?;
+        stream.write_all(b";PQ: 12345678\r").await?;
+        let mut reply = vec![0; 14];
+        stream.read_exact(&mut reply).await?;
+        Ok(reply)
+    }
+
+    #[test(tokio::test)]
+    async fn generic_code_drives_loopback() -> color_eyre::Result<()> {
+        let (mut modem, mut peers) = LoopbackModem::new(64);
+        let peer = tokio::spawn(async move {
+            let mut peer = peers.recv().await.expect("connection should reach the peer");
+            let mut challenge = [0; 14];
+            peer.stream.read_exact(&mut challenge).await?;
+            assert_eq!(&challenge, b";PQ: 12345678\r");
+            peer.stream.write_all(b";PR: 87654321\r").await?;
+            std::io::Result::Ok(peer.station)
+        });
+
+        let reply = greet(&mut modem, &StationId::new("KC1GSL")?, &StationId::new("KW1U")?).await?;
+        assert_eq!(reply, b";PR: 87654321\r");
+        assert_eq!(peer.await??.as_str(), "KW1U");
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn refuses_without_peer() -> color_eyre::Result<()> {
+        let (mut modem, peers) = LoopbackModem::new(64);
+        drop(peers);
+        let err = modem.connect(&StationId::new("KC1GSL")?, &StationId::new("KW1U")?).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+        Ok(())
+    }
+}
@@ -1,95 +1,106 @@
-
-struct BufferProcessor<P, I, O, E> {
+use bytes::buf::UninitSlice;
+use bytes::{BufMut, Bytes, BytesMut};
+use nom::error::VerboseError;
+use nom::IResult;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use crate::parser::{OwnedVerboseError, ToOwnedError};
+
+/// Collects bytes read from a stream and splits complete frames, such as lines, off the
+/// front as `parser` recognizes them. Incomplete frames stay buffered until more arrives.
+///
+/// Read straight into the processor, which is a [`BufMut`], and then drain it with
+/// [`iter`](Self::iter).
+pub struct BufferProcessor<P> {
     parser: P,
     until_next_parse: usize,
-    buffer: bytes::BytesMut,
-    _phantom: std::marker::PhantomData<(I, O, E)>
+    buffer: BytesMut,
 }
 
-impl<P, I, O, E> BufferProcessor<P, I, O, E> {
-    fn new(parser: P) -> Self {
-        Self {
-            parser,
-            until_next_parse: 1,
-            buffer: bytes::BytesMut::new(),
-            _phantom: std::marker::PhantomData,
-        }
+impl<P> BufferProcessor<P> {
+    pub fn new(parser: P) -> Self {
+        Self::with_capacity(parser, 0)
     }
 
-    fn with_capacity(parser: P, capacity: usize) -> Self {
+    pub fn with_capacity(parser: P, capacity: usize) -> Self {
         Self {
             parser,
             until_next_parse: 1,
-            buffer: bytes::BytesMut::with_capacity(capacity),
-            _phantom: std::marker::PhantomData,
+            buffer: BytesMut::with_capacity(capacity),
         }
     }
 }
 
-impl<P, I, O, E> BufferProcessor<P, I, O, E>
+impl<P> BufferProcessor<P>
     where
-        P: Fn(I) -> IResult<I, O, E>
+        P: for<'b> Fn(&'b [u8]) -> IResult<&'b [u8], &'b [u8], VerboseError<&'b [u8]>>
 {
-    fn iter(&mut self) -> ParsedIterator<P, I, O, E> {
+    /// Yields each complete frame buffered so far. A frame the parser rejects is left in
+    /// the buffer, so the same error is yielded again until the caller gives up.
+    pub fn iter(&mut self) -> ParsedIterator<'_, P> {
         ParsedIterator {
             processor: self,
-            position: 0,
         }
     }
-}
 
-struct ParsedIterator<'a, P, I, O, E> {
-    processor: &'a mut BufferProcessor<P, I, O, E>,
-    position: usize,
-}
+    /// Reads from `stream`, handing each frame to `f` until it returns `true`. Returns
+    /// `false` if the stream ended first.
+    pub async fn read_until<R: AsyncRead + Unpin>(&mut self, stream: &mut R, mut f: impl FnMut(&[u8]) -> bool) -> color_eyre::Result<bool> {
+        loop {
+            for frame in self.iter() {
+                if f(&frame?) {
+                    return Ok(true);
+                }
+            }
 
-impl<'a, I, P, O, E> Drop for ParsedIterator<'a, I, P, O, E> {
-    fn drop(&mut self) {
-        if self.position == self.processor.buffer.len() {
-            self.processor.buffer.clear();
-        } else if self.position > 0 {
-            let new = self.processor.buffer.split_off(self.position);
-            self.processor.buffer = new;
-            tracing::trace!(bytes = self.processor.buffer.len(), "retained incomplete parts");
+            if stream.read_buf(self).await? == 0 {
+                return Ok(false);
+            }
         }
     }
 }
 
-impl<'a, P, I, O, E> Iterator for ParsedIterator<'a, P, I, O, E>
+pub struct ParsedIterator<'a, P> {
+    processor: &'a mut BufferProcessor<P>,
+}
+
+impl<'a, P> Iterator for ParsedIterator<'a, P>
     where
-        P: Fn(I) -> IResult<I, O, E>,
-        I: From<&'a [u8]> + AsRef<[u8]> + 'a,
+        P: for<'b> Fn(&'b [u8]) -> IResult<&'b [u8], &'b [u8], VerboseError<&'b [u8]>>
 {
-    type Item = Result<O, E>;
+    type Item = Result<Bytes, OwnedVerboseError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.processor.until_next_parse > 0 {
+        let processor = &mut *self.processor;
+        if processor.until_next_parse > 0 {
             return None;
         }
 
-        let borrowed = &self.processor.buffer[..];
-
-        match (self.processor.parser)(I::from(borrowed)) {
-            Ok((remaining, output)) => {
-                self.position = self.processor.buffer.len() - remaining.as_ref().len();
-                Some(Ok(output))
+        let buffer = &processor.buffer[..];
+        let (consumed, frame) = match (processor.parser)(buffer) {
+            Ok((remaining, frame)) => {
+                let start = frame.as_ptr() as usize - buffer.as_ptr() as usize;
+                (buffer.len() - remaining.len(), start..start + frame.len())
             }
             Err(nom::Err::Incomplete(nom::Needed::Unknown)) => {
-                self.processor.until_next_parse = 1;
-                None
+                processor.until_next_parse = 1;
+                return None;
             }
             Err(nom::Err::Incomplete(nom::Needed::Size(bytes))) => {
-                self.processor.until_next_parse = bytes.get();
-                None
-            },
+                processor.until_next_parse = bytes.get();
+                return None;
+            }
             Err(nom::Err::Error(err) | nom::Err::Failure(err)) => {
-                Some(Err(err))
+                return Some(Err(err.to_owned_error()));
             }
-        }
+        };
+
+        let parsed = processor.buffer.split_to(consumed).freeze();
+        tracing::trace!(bytes = processor.buffer.len(), "retained incomplete parts");
+        Some(Ok(parsed.slice(frame)))
     }
 }
 
-unsafe impl<P, I, O, E> bytes::BufMut for BufferProcessor<P, I, O, E> {
+unsafe impl<P> BufMut for BufferProcessor<P> {
     #[inline(always)]
     fn remaining_mut(&self) -> usize {
         self.buffer.remaining_mut()
@@ -101,7 +112,7 @@ unsafe impl<P, I, O, E> bytes::BufMut for BufferProcessor<P, I, O, E> {
 
         // Keep track of bytes that have been added to the buffer so we know
         // when it is reasonable to try parsing again.
-        self.until_next_parse.saturating_sub(cnt);
+        self.until_next_parse = self.until_next_parse.saturating_sub(cnt);
     }
 
     #[inline(always)]
@@ -113,7 +124,8 @@ unsafe impl<P, I, O, E> bytes::BufMut for BufferProcessor<P, I, O, E> {
 #[cfg(test)]
 mod tests {
     use test_log::test;
-    use bytes::BufMut;
+    use tokio::io::AsyncWriteExt;
+    use crate::modem::vara::line;
     use super::*;
 
     #[test]
@@ -124,24 +136,37 @@ mod tests {
         let mut iter = processor.iter();
         assert!(matches!(iter.next(), Some(Ok(l)) if l == "CONNECTED KC1GSL KW1U"));
         assert!(matches!(iter.next(), Some(Ok(l)) if l == "PTT ON"));
-        assert!(matches!(iter.next(), Some(None)));
-    }
-}
+        assert!(iter.next().is_none());
 
+        processor.put_slice(b" OFF\r");
+        assert!(matches!(processor.iter().next(), Some(Ok(l)) if l == "PTT OFF"));
+        assert!(processor.iter().next().is_none());
+    }
 
+    #[test(tokio::test)]
+    async fn read_until_spans_reads() -> color_eyre::Result<()> {
+        let (mut writer, mut reader) = tokio::io::duplex(8);
+        let sender = tokio::spawn(async move {
+            writer.write_all(b"Welcome\r\nKW1U >\rleft over\r").await
+        });
 
-Err(err) => {
-+                Err(VerboseError {
-+                    errors: err.errors.into_iter().map(|e| {
-+                        (e.0.to_string(), e.1)
-+                    }).collect()
-+                })?
+        let mut processor = BufferProcessor::new(line);
+        let mut lines = Vec::new();
+        let found = processor.read_until(&mut reader, |l| {
+            lines.push(String::from_utf8_lossy(l).into_owned());
+            l.ends_with(b">")
+        }).await?;
+        assert!(found);
+        assert_eq!(lines, ["Welcome", "\nKW1U >"]);
+
+        lines.clear();
+        let found = processor.read_until(&mut reader, |l| {
+            lines.push(String::from_utf8_lossy(l).into_owned());
+            false
+        }).await?;
+        assert!(!found);
+        assert_eq!(lines, ["left over"]);
+        sender.await??;
+        Ok(())
+    }
 }
-
-.map_err(|err| {
-            VerboseError {
-                errors: err.errors.into_iter().map(|e| {
-                    (crate::parser::StrOrByteSlice::Bytes(e.0).to_string(), e.1)
-                }).collect()
-            }
-        }
@@ -12,7 +12,8 @@ use nom::error::VerboseError;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::{Sender, Receiver};
-use crate::parser::MappableParserInputError;
+use crate::modem::{SessionLimit, SessionPermit};
+use crate::parser::{OwnedVerboseError, StrOrByteSlice, ToOwnedError};
 
 
 //use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
@@ -35,6 +36,15 @@ pub enum Command {
     SetCall(MyCallSigns),
     SetCompression(CompressionMode),
     SetBandwidth(BandwidthMode),
+    /// Public mode lets unregistered stations connect, at a reduced speed.
+    SetPublic(bool),
+    Version,
+    /// Discards data waiting to be transmitted, keeping the connection.
+    CleanBuffer,
+    /// Whether VARA identifies in Morse with `MYCALL` when each session ends.
+    CwId { enabled: bool },
+    /// Sent as written, for verbs this crate doesn't know yet. Must not contain `\r`.
+    Raw(String),
 }
 
 impl fmt::Display for Command {
@@ -48,6 +58,11 @@ impl fmt::Display for Command {
             Self::SetCall(calls) => write!(f, "MYCALL {}", calls)?,
             Self::SetCompression(mode) => write!(f, "COMPRESSION {}", mode)?,
             Self::SetBandwidth(mode) => write!(f, "BW{}", mode)?,
+            Self::SetPublic(on) => write!(f, "PUBLIC {}", if *on { "ON" } else { "OFF" })?,
+            Self::Version => f.write_str("VERSION")?,
+            Self::CleanBuffer => f.write_str("CLEANTXBUFFER")?,
+            Self::CwId { enabled } => write!(f, "CWID {}", if *enabled { "ON" } else { "OFF" })?,
+            Self::Raw(command) => f.write_str(command)?,
         }
         Ok(())
     }
@@ -71,6 +86,24 @@ impl fmt::Display for CompressionMode {
     }
 }
 
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("invalid compression mode; expected OFF, TEXT, or FILES")]
+pub struct InvalidCompressionMode;
+
+/// Accepts the tokens `Display` writes, in any case.
+impl std::str::FromStr for CompressionMode {
+    type Err = InvalidCompressionMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "OFF" => Ok(Self::Off),
+            "TEXT" => Ok(Self::Text),
+            "FILES" => Ok(Self::Files),
+            _ => Err(InvalidCompressionMode),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum BandwidthMode {
     Narrow,
@@ -89,9 +122,46 @@ impl fmt::Display for BandwidthMode {
     }
 }
 
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("invalid bandwidth; expected 500, 2300, or 2750")]
+pub struct InvalidBandwidthMode;
+
+/// Accepts the bandwidths in hertz that `Display` writes.
+impl std::str::FromStr for BandwidthMode {
+    type Err = InvalidBandwidthMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "500" => Ok(Self::Narrow),
+            "2300" => Ok(Self::Wide),
+            "2750" => Ok(Self::Tactical),
+            _ => Err(InvalidBandwidthMode),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct MyCallSigns(pub StationId, pub Vec<StationId>);
 
+/// The TNC answered `WRONG` to `MYCALL`. It doesn't say which call it objected to.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("the tnc rejected the callsigns {calls}")]
+pub struct InvalidCallsign {
+    pub calls: MyCallSigns,
+}
+
+/// The data port is still held by an open [`VaraStream`], so no other session can start.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("a stream is already open on the data port")]
+pub struct StreamAlreadyOpen;
+
+/// Every attempt made by `connect_with_retry` failed. Holds each attempt's error in order.
+#[derive(Debug, thiserror::Error)]
+#[error("failed to connect after {} attempts", .attempts.len())]
+pub struct ConnectAttemptsExhausted {
+    pub attempts: Vec<color_eyre::Report>,
+}
+
 impl fmt::Display for MyCallSigns {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt::Display::fmt(&self.0, f)?;
@@ -142,6 +212,9 @@ pub enum Update<'a> {
     TransceiverControl(TransceiverCommand),
     Registered { my_call: &'a StationIdRef },
     RemoteRegistration(Registration),
+    Version(&'a str),
+    /// Carries only the fields the line reported.
+    LinkStats(LinkStats),
     // CQFrame(CQFrame<'a>),
 }
 
@@ -153,6 +226,8 @@ fn update(data: &[u8]) -> IResult<&[u8], Update, VerboseError<&[u8]>> {
         nom::combinator::map(connection_state, Update::Connection),
         nom::combinator::map(transmit_state, Update::TransceiverControl),
         registered,
+        nom::combinator::map(version, Update::Version),
+        nom::combinator::map(link_stats, Update::LinkStats),
         // nom::combinator::map(remote_registration, Update::RemoteRegistration),
     ))(data)
 }
@@ -165,6 +240,55 @@ fn buffer(data: &[u8]) -> IResult<&[u8], Update, VerboseError<&[u8]>> {
     Ok((remaining, Update::Buffer { bytes_remaining }))
 }
 
+fn version(data: &[u8]) -> IResult<&[u8], &str, VerboseError<&[u8]>> {
+    nom::sequence::preceded(
+        nom::bytes::complete::tag("VERSION "),
+        nom::combinator::map_res(nom::bytes::complete::take_till1(|c| c == b'\r'), std::str::from_utf8)
+    )(data)
+}
+
+/// Link quality as last reported during a connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    /// Signal-to-noise ratio in dB, rounded to a whole number.
+    pub snr: Option<i16>,
+    pub throughput_bps: Option<u32>,
+}
+
+/// Recognizes `SN`/`SNR` and `BITRATE` status lines. VARA versions disagree on what
+/// follows the keyword, so anything unreadable leaves that field `None`.
+fn link_stats(data: &[u8]) -> IResult<&[u8], LinkStats, VerboseError<&[u8]>> {
+    fn text(rest: &[u8]) -> &str {
+        std::str::from_utf8(rest).unwrap_or_default()
+    }
+
+    fn snr(rest: &[u8]) -> Option<i16> {
+        let value = text(rest).trim().trim_end_matches("dB").trim_end();
+        value.parse::<f32>().ok()
+            .filter(|snr| snr.is_finite())
+            .map(|snr| snr.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16)
+    }
+
+    // e.g. `BITRATE 2437` or `BITRATE (7) 2437 bps`, where the parenthesized value is the
+    // speed level rather than the rate.
+    fn throughput(rest: &[u8]) -> Option<u32> {
+        text(rest).split_whitespace()
+            .filter(|token| !token.starts_with('('))
+            .find_map(|token| token.parse().ok())
+    }
+
+    nom::branch::alt((
+        nom::combinator::map(
+            nom::sequence::preceded(nom::branch::alt((nom::bytes::complete::tag("SNR"), nom::bytes::complete::tag("SN"))), nom::combinator::rest),
+            |rest| LinkStats { snr: snr(rest), throughput_bps: None },
+        ),
+        nom::combinator::map(
+            nom::sequence::preceded(nom::bytes::complete::tag("BITRATE"), nom::combinator::rest),
+            |rest| LinkStats { snr: None, throughput_bps: throughput(rest) },
+        ),
+    ))(data)
+}
+
 fn registered(data: &[u8]) -> IResult<&[u8], Update, VerboseError<&[u8]>> {
     nom::sequence::preceded(
         nom::bytes::complete::tag("REGISTERED "),
@@ -242,15 +366,48 @@ fn command_result(data: &[u8]) -> IResult<&[u8], CommandResult, VerboseError<&[u
     nom::branch::alt((nom::combinator::value(CommandResult::Ok, nom::bytes::complete::tag("OK")), nom::combinator::value(CommandResult::Wrong, nom::bytes::complete::tag("WRONG"))))(data)
 }
 
+/// Some VARA builds follow `PTT ON`/`PTT OFF` with timing hints, such as `PTT ON 50`, asking
+/// the controller to wait that many milliseconds before switching the rig.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TransceiverCommand {
-    Receive,
-    Transmit,
+    /// `delay` is how long to hold the rig keyed before unkeying it.
+    Receive { delay: Option<std::time::Duration> },
+    /// `delay` is how long to wait before keying the rig.
+    Transmit { delay: Option<std::time::Duration> },
+}
+
+impl TransceiverCommand {
+    pub fn is_transmit(&self) -> bool {
+        matches!(self, Self::Transmit { .. })
+    }
+
+    pub fn delay(&self) -> Option<std::time::Duration> {
+        match self {
+            Self::Receive { delay } | Self::Transmit { delay } => *delay,
+        }
+    }
 }
 
 fn transmit_state(data: &[u8]) -> IResult<&[u8], TransceiverCommand, VerboseError<&[u8]>> {
-    let (rest, is_transmit) = nom::sequence::preceded(nom::bytes::complete::tag("PTT "), on_or_off)(data)?;
-    Ok((rest, if is_transmit { TransceiverCommand::Transmit } else { TransceiverCommand::Receive }))
+    // e.g. `PTT ON 50` or `PTT OFF 20ms`. Parameters that are not a delay are ignored.
+    fn delay(rest: &[u8]) -> Option<std::time::Duration> {
+        std::str::from_utf8(rest).unwrap_or_default()
+            .split_whitespace()
+            .find_map(|token| token.trim_end_matches("ms").parse().ok())
+            .map(std::time::Duration::from_millis)
+    }
+
+    let (rest, (is_transmit, delay)) = nom::sequence::preceded(
+        nom::bytes::complete::tag("PTT "),
+        nom::sequence::pair(
+            on_or_off,
+            nom::branch::alt((
+                nom::combinator::map(nom::combinator::eof, |_| None),
+                nom::combinator::map(nom::sequence::preceded(nom::character::complete::space1, nom::combinator::rest), delay),
+            )),
+        ),
+    )(data)?;
+    Ok((rest, if is_transmit { TransceiverCommand::Transmit { delay } } else { TransceiverCommand::Receive { delay } }))
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -276,6 +433,9 @@ pub enum ConnectionState<'a> {
     Connected {
         my_station: &'a StationIdRef,
         other_station: &'a StationIdRef,
+        /// Reported by some VARA versions after the callsigns.
+        bandwidth: Option<u16>,
+        snr: Option<i16>,
     },
 }
 
@@ -287,10 +447,51 @@ pub enum ConnectionStateOwned {
     Connected {
         my_station: StationId,
         other_station: StationId,
+        bandwidth: Option<u16>,
+        snr: Option<i16>,
     },
 }
 
+/// How the most recent [`VaraStream`] ended, as seen when it was dropped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SessionOutcome {
+    /// The connection was already closed, by either end.
+    Disconnected,
+    /// The connection attempt was canceled before it completed.
+    Canceled,
+    /// The stream was dropped while still connected, so a disconnect was forced.
+    Forced { last_state: ConnectionStateOwned },
+}
+
+/// A step in the life of a connection, as reported by [`VaraTncHandle::subscribe_events`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// The TNC reported `PENDING`, for a call going out or coming in.
+    Connecting,
+    Connected { peer: StationId },
+    Disconnected { reason: DisconnectReason },
+    /// Something went wrong with the TNC itself, rather than with the connection.
+    Error { message: String },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The connection closed, from either end.
+    Ended,
+    /// The connection attempt was canceled before it completed.
+    Canceled,
+}
+
 impl ConnectionStateOwned {
+    fn event(&self) -> ConnectionEvent {
+        match self {
+            Self::Pending => ConnectionEvent::Connecting,
+            Self::Connected { other_station, .. } => ConnectionEvent::Connected { peer: other_station.clone() },
+            Self::Disconnected => ConnectionEvent::Disconnected { reason: DisconnectReason::Ended },
+            Self::Canceled => ConnectionEvent::Disconnected { reason: DisconnectReason::Canceled },
+        }
+    }
+
     fn is_connected(&self) -> bool {
         matches!(self, Self::Connected { .. })
     }
@@ -298,6 +499,11 @@ impl ConnectionStateOwned {
     fn is_disconnected(&self) -> bool {
         matches!(self, Self::Disconnected)
     }
+
+    /// Whether the link is down, either disconnected or, if it never came up, canceled.
+    fn is_ended(&self) -> bool {
+        matches!(self, Self::Disconnected | Self::Canceled)
+    }
 }
 
 impl<'a> ConnectionState<'a> {
@@ -306,10 +512,12 @@ impl<'a> ConnectionState<'a> {
             Self::Disconnected => ConnectionStateOwned::Disconnected,
             Self::Pending => ConnectionStateOwned::Pending,
             Self::Canceled => ConnectionStateOwned::Canceled,
-            Self::Connected { my_station, other_station } => {
+            Self::Connected { my_station, other_station, bandwidth, snr } => {
                 ConnectionStateOwned::Connected {
                     my_station: my_station.to_owned(),
                     other_station: other_station.to_owned(),
+                    bandwidth,
+                    snr,
                 }
             }
         }
@@ -337,7 +545,12 @@ fn connected(data: &[u8]) -> IResult<&[u8], ConnectionState, VerboseError<&[u8]>
             crate::types::callsign,
         ),
     )(data)?;
-    Ok((rest, ConnectionState::Connected { my_station, other_station }))
+    let (rest, link) = nom::combinator::opt(nom::sequence::pair(
+        nom::sequence::preceded(nom::bytes::complete::tag(" "), nom::character::complete::u16),
+        nom::sequence::preceded(nom::bytes::complete::tag(" "), nom::character::complete::i16),
+    ))(rest)?;
+    let (bandwidth, snr) = link.map_or((None, None), |(bandwidth, snr)| (Some(bandwidth), Some(snr)));
+    Ok((rest, ConnectionState::Connected { my_station, other_station, bandwidth, snr }))
 }
 
 fn connection_state(data: &[u8]) -> IResult<&[u8], ConnectionState, VerboseError<&[u8]>> {
@@ -404,85 +617,131 @@ fn connection_state(data: &[u8]) -> IResult<&[u8], ConnectionState, VerboseError
 //         }
 //     }
 // }
-//
-// impl<'a> super::Modem<'a> for Vara {
-//     type Connection = VaraConnection<'a>;
-//     type ConnectionError = std::io::Error;
-//
-//     fn connect(&'a mut self, station: &StationIdRef) -> Result<Self::Connection, Self::ConnectionError> {
-//         self.send_command(&format!("CONNECT KC1GSL {}", station))?;
-//         Ok(VaraConnection {
-//             parent: self,
-//         })
-//     }
-// }
 
-fn line(data: &[u8]) -> IResult<&[u8], &[u8], VerboseError<&[u8]>> {
-    nom::sequence::terminated(nom::bytes::streaming::take_until1("\r"), nom::bytes::streaming::tag("\r"))(data)
+/// A `\r`-terminated line, without its terminator. Empty lines before it are skipped.
+pub fn line(data: &[u8]) -> IResult<&[u8], &[u8], VerboseError<&[u8]>> {
+    use nom::bytes::streaming::{tag, take_until1};
+    nom::sequence::delimited(nom::multi::many0_count(tag("\r")), take_until1("\r"), tag("\r"))(data)
+}
+
+/// Decodes each line of captured control-port traffic, such as from a packet capture,
+/// without a TNC. A line that doesn't parse yields an error and decoding carries on with
+/// the next one. A partial line at the end is left out.
+pub fn parse_control_stream(mut data: &[u8]) -> impl Iterator<Item = Result<TncResponse<'_>, OwnedVerboseError>> {
+    std::iter::from_fn(move || {
+        let (rest, frame) = line(data).ok()?;
+        data = rest;
+        let response = nom::combinator::all_consuming(tnc_response)(frame).finish();
+        Some(response.map(|(_, response)| response).map_err(ToOwnedError::to_owned_error))
+    })
+}
+
+/// A complete line exchanged on the control port, without its `\r` terminator.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlLine {
+    Sent(bytes::Bytes),
+    Received(bytes::Bytes),
 }
 
 #[tracing::instrument(skip(rx, tx, stream), err)]
-async fn manage_modem_thread(mut rx: Receiver<(Command, tokio::sync::oneshot::Sender<CommandResult>)>, mut tx: TncStatusSender, mut stream: TcpStream) -> color_eyre::Result<()> {
+async fn manage_modem_thread(mut rx: Receiver<(Command, tokio::sync::oneshot::Sender<CommandResult>)>, mut tx: TncStatusSender, mut stream: TcpStream, terminator: &'static str) -> color_eyre::Result<()> {
     let mut cmd_buffer = String::with_capacity(32);
     let mut upd_buffer = bytes::BytesMut::with_capacity(32);
-    let mut response_queue = VecDeque::with_capacity(4);
+    let mut response_queue: VecDeque<PendingReply> = VecDeque::with_capacity(4);
     let mut command_active = true;
 
     while command_active {
         tokio::select!(
             recv = rx.recv() => {
                 if let Some((command, reply)) = recv {
-                    response_queue.push_back(reply);
-                    stream.writable().await?;
+                    // A command that timed out may never be answered; keeping its slot would
+                    // hand every later command the reply meant for the one before it.
+                    response_queue.retain(|(_, reply)| !reply.is_closed());
                     cmd_buffer.clear();
-                    write!(&mut cmd_buffer, "{}\r", command).unwrap();
+                    write!(&mut cmd_buffer, "{}{}", command, terminator).unwrap();
+                    response_queue.push_back((command, reply));
+                    stream.writable().await?;
                     tracing::trace!(command = cmd_buffer.as_str(), "sending command");
                     stream.write_all(cmd_buffer.as_bytes()).await?;
+                    let _ = tx.control_trace.send(ControlLine::Sent(bytes::Bytes::copy_from_slice(&cmd_buffer.as_bytes()[..cmd_buffer.len() - terminator.len()])));
                 } else {
                     command_active = false
                 }
             },
             _ = stream.readable() => {
-                let results = do_a_thing(&mut stream, &mut upd_buffer, &mut tx)?;
-                for result in results {
-                    if let Some(reply) = response_queue.pop_front() {
-                        let _ = reply.send(result);
-                    } else {
-                        tracing::warn!("mismatched reply queue");
-                    }
-                }
+                let Some(answers) = do_a_thing(&mut stream, &mut upd_buffer, &mut tx)? else {
+                    return control_closed(response_queue, &tx);
+                };
+                send_replies(&mut response_queue, answers, &tx);
             }
         );
     }
+    // Nobody is waiting on replies to commands that timed out.
+    response_queue.retain(|(_, reply)| !reply.is_closed());
     tracing::info!(expected_replies = response_queue.len(), "command input closed");
     while !response_queue.is_empty() {
-        let results = do_a_thing(&mut stream, &mut upd_buffer, &mut tx)?;
-        for result in results {
-            if let Some(reply) = response_queue.pop_front() {
-                let _ = reply.send(result);
-            } else {
-                tracing::warn!("mismatched reply queue");
-            }
-        }
+        stream.readable().await?;
+        let Some(answers) = do_a_thing(&mut stream, &mut upd_buffer, &mut tx)? else {
+            return control_closed(response_queue, &tx);
+        };
+        send_replies(&mut response_queue, answers, &tx);
     }
     tracing::info!("all replies sent; exiting command loop");
     Ok(())
 }
 
-fn stringify_input<T: std::fmt::Display>(error: nom::Err<VerboseError<T>>) -> nom::Err<VerboseError<String>> {
-    error.map(|err| {
-        VerboseError {
-            errors: err.errors.into_iter().map(|e| (e.0.to_string(), e.1)).collect()
+/// Ends the managing thread once the TNC has closed the control connection. Dropping the
+/// pending replies, and the status senders with them, fails everyone still waiting.
+fn control_closed(response_queue: VecDeque<PendingReply>, tx: &TncStatusSender) -> color_eyre::Result<()> {
+    tracing::warn!(pending_replies = response_queue.len(), "tnc closed the control connection");
+    let _ = tx.connection_events.send(ConnectionEvent::Error { message: "the tnc closed the control connection".into() });
+    Ok(())
+}
+
+type PendingReply = (Command, tokio::sync::oneshot::Sender<CommandResult>);
+
+/// Something the TNC sent that may answer the oldest pending command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Answer {
+    Result(CommandResult),
+    /// VARA answers `VERSION` with this line instead of `OK`, but also sends it unasked.
+    Version,
+}
+
+/// Replies to pending commands in the order they were sent.
+///
+/// A command that timed out keeps its place only until the next one is sent, so a late
+/// reply is used up on it if it arrives before then.
+fn send_replies(response_queue: &mut VecDeque<PendingReply>, answers: Vec<Answer>, tx: &TncStatusSender) {
+    for answer in answers {
+        let result = match answer {
+            Answer::Result(result) => result,
+            Answer::Version if matches!(response_queue.front(), Some((Command::Version, _))) => CommandResult::Ok,
+            Answer::Version => continue,
+        };
+        if let Some((command, reply)) = response_queue.pop_front() {
+            if command == Command::CleanBuffer && result == CommandResult::Ok {
+                tx.buffer.send_replace(0);
+            }
+            if reply.is_closed() {
+                tracing::debug!(?result, "discarding late reply to a command that timed out");
+            }
+            let _ = reply.send(result);
+        } else {
+            tracing::warn!("mismatched reply queue");
         }
-    })
+    }
 }
 
+/// Handles whatever the TNC has sent, returning the command answers it contained, or
+/// `None` if it has closed the control connection.
 #[tracing::instrument(skip(stream, upd_buffer, tx), err)]
-fn do_a_thing(stream: &mut TcpStream, upd_buffer: &mut bytes::BytesMut, tx: &mut TncStatusSender) -> color_eyre::Result<Vec<CommandResult>> {
+fn do_a_thing(stream: &mut TcpStream, upd_buffer: &mut bytes::BytesMut, tx: &mut TncStatusSender) -> color_eyre::Result<Option<Vec<Answer>>> {
     let mut to_acknowledge = Vec::new();
     match stream.try_read_buf(upd_buffer) {
-        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return Ok(to_acknowledge),
+        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return Ok(Some(to_acknowledge)),
         Err(err) => return Err(err.into()),
+        Ok(0) => return Ok(None),
         Ok(bytes) => {
             tracing::trace!(bytes, "received bytes from command port");
         }
@@ -491,17 +750,18 @@ fn do_a_thing(stream: &mut TcpStream, upd_buffer: &mut bytes::BytesMut, tx: &mut
     let retain_after = {
         let mut data = upd_buffer.as_bytes();
         while data.len() > 0 {
-            match line(&data).try_map_into_str().map_err(stringify_input) {
+            match line(data).map_err(ToOwnedError::to_owned_error) {
                 Ok((remaining, line)) => {
-                    tracing::trace!(line = std::str::from_utf8(line).unwrap(), remaining = std::str::from_utf8(remaining).unwrap(), "received complete line");
+                    tracing::trace!(line = StrOrByteSlice::Bytes(line).to_lossy_string(), remaining = StrOrByteSlice::Bytes(remaining).to_lossy_string(), "received complete line");
                     data = remaining;
+                    let _ = tx.control_trace.send(ControlLine::Received(bytes::Bytes::copy_from_slice(line)));
 
-                    match nom::combinator::all_consuming(tnc_response)(line).try_map_into_str().map_err(stringify_input).finish() {
+                    match nom::combinator::all_consuming(tnc_response)(line).map_err(ToOwnedError::to_owned_error).finish() {
                         Ok((_ , response)) => {
                             tracing::debug!(?response, "received tnc data");
                             match response {
                                 TncResponse::CommandResult(result) => {
-                                    to_acknowledge.push(result);
+                                    to_acknowledge.push(Answer::Result(result));
                                 }
                                 TncResponse::Update(update) => {
                                     match update {
@@ -519,7 +779,12 @@ fn do_a_thing(stream: &mut TcpStream, upd_buffer: &mut bytes::BytesMut, tx: &mut
                                             tx.registered_calls.send_replace(tx.calls.clone());
                                         }
                                         Update::Connection(state) => {
-                                            tx.connection.send_replace(state.into_owned());
+                                            if let ConnectionState::Connected { snr, .. } = state {
+                                                tx.link_stats.send_replace(LinkStats { snr, throughput_bps: None });
+                                            }
+                                            let state = state.into_owned();
+                                            let _ = tx.connection_events.send(state.event());
+                                            tx.connection.send_replace(state);
                                         }
                                         Update::RemoteRegistration(registration) => {
                                             tx.remote_registration.send_replace(registration);
@@ -527,17 +792,28 @@ fn do_a_thing(stream: &mut TcpStream, upd_buffer: &mut bytes::BytesMut, tx: &mut
                                         Update::TransceiverControl(control) => {
                                             tx.transceiver_control.send_replace(control);
                                         }
+                                        Update::Version(version) => {
+                                            tx.version.send_replace(Some(version.to_owned()));
+                                            to_acknowledge.push(Answer::Version);
+                                        }
+                                        Update::LinkStats(update) => {
+                                            tx.link_stats.send_modify(|stats| {
+                                                stats.snr = update.snr.or(stats.snr);
+                                                stats.throughput_bps = update.throughput_bps.or(stats.throughput_bps);
+                                            });
+                                        }
                                     }
                                 }
                             }
                         }
                         Err(err) => {
-                            return Err(err.into());
+                            // Likely something a newer VARA sends; not worth dropping the link over.
+                            tracing::warn!(line = StrOrByteSlice::Bytes(line).to_lossy_string(), %err, "ignoring unrecognized line from tnc");
                         }
                     }
                 },
                 Err(err) if err.is_incomplete() => {
-                    tracing::trace!(buffer = std::str::from_utf8(data).unwrap(), "incomplete");
+                    tracing::trace!(buffer = StrOrByteSlice::Bytes(data).to_lossy_string(), "incomplete");
                     break
                 },
                 Err(err) => {
@@ -554,16 +830,28 @@ fn do_a_thing(stream: &mut TcpStream, upd_buffer: &mut bytes::BytesMut, tx: &mut
         *upd_buffer = new;
         tracing::trace!(bytes = upd_buffer.len(), "retained incomplete parts");
     }
-    Ok(to_acknowledge)
+    Ok(Some(to_acknowledge))
 }
 
 #[derive(Debug)]
 #[pin_project::pin_project]
 pub struct VaraTnc {
-    data: TcpStream,
+    /// Lent to the open [`VaraStream`], if any, and handed back when it drops.
+    data: std::sync::Mutex<Option<TcpStream>>,
+    handle: VaraTncHandle,
+    managing_thread: tokio::task::JoinHandle<color_eyre::Result<()>>,
+    session_limit: Option<SessionLimit>,
+    stream_tasks: std::sync::Mutex<Vec<tokio::task::JoinHandle<()>>>,
+    last_session: std::sync::Mutex<Option<SessionOutcome>>,
+}
+
+/// Sends commands to a [`VaraTnc`] and reads its status from another task. Handles are
+/// cheap to clone, but only the `VaraTnc` itself can open a stream on the data port.
+#[derive(Clone, Debug)]
+pub struct VaraTncHandle {
     control_channel: Sender<(Command, tokio::sync::oneshot::Sender<CommandResult>)>,
     status: TncStatusReceiver,
-    managing_thread: tokio::task::JoinHandle<color_eyre::Result<()>>,
+    command_timeout: std::time::Duration,
 }
 
 fn channel() -> (TncStatusSender, TncStatusReceiver) {
@@ -573,8 +861,12 @@ fn channel() -> (TncStatusSender, TncStatusReceiver) {
     let (registered_calls_tx, registered_calls_rx) = channel(std::collections::HashSet::new());
     let (heartbeat_tx, heartbeat_rx) = channel(Instant::now());
     let (connection_tx, connection_rx) = channel(ConnectionStateOwned::Disconnected);
-    let (transceiver_tx, transceiver_rx) = channel(TransceiverCommand::Receive);
+    let (transceiver_tx, transceiver_rx) = channel(TransceiverCommand::Receive { delay: None });
     let (remote_registration_tx, remote_registration_rx) = channel(Registration::Unregistered);
+    let (version_tx, version_rx) = channel(None);
+    let (link_stats_tx, link_stats_rx) = channel(LinkStats::default());
+    let (control_trace, _) = tokio::sync::broadcast::channel(64);
+    let (connection_events, _) = tokio::sync::broadcast::channel(16);
 
     let sender = TncStatusSender {
         calls: Default::default(),
@@ -585,7 +877,12 @@ fn channel() -> (TncStatusSender, TncStatusReceiver) {
         connection: connection_tx,
         transceiver_control: transceiver_tx,
         remote_registration: remote_registration_tx,
+        version: version_tx,
+        link_stats: link_stats_tx,
+        control_trace: control_trace.clone(),
+        connection_events: connection_events.clone(),
     };
+    let connection_events = connection_events.downgrade();
 
     let receiver = TncStatusReceiver {
         busy_state: busy_rx,
@@ -595,6 +892,10 @@ fn channel() -> (TncStatusSender, TncStatusReceiver) {
         connection: connection_rx,
         transceiver_control: transceiver_rx,
         remote_registration: remote_registration_rx,
+        version: version_rx,
+        link_stats: link_stats_rx,
+        control_trace,
+        connection_events,
     };
 
     (sender, receiver)
@@ -610,9 +911,13 @@ struct TncStatusSender {
     connection: tokio::sync::watch::Sender<ConnectionStateOwned>,
     transceiver_control: tokio::sync::watch::Sender<TransceiverCommand>,
     remote_registration: tokio::sync::watch::Sender<Registration>,
+    version: tokio::sync::watch::Sender<Option<String>>,
+    link_stats: tokio::sync::watch::Sender<LinkStats>,
+    control_trace: tokio::sync::broadcast::Sender<ControlLine>,
+    connection_events: tokio::sync::broadcast::Sender<ConnectionEvent>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct TncStatusReceiver {
     busy_state: tokio::sync::watch::Receiver<BusyState>,
     buffer: tokio::sync::watch::Receiver<usize>,
@@ -621,6 +926,74 @@ struct TncStatusReceiver {
     connection: tokio::sync::watch::Receiver<ConnectionStateOwned>,
     transceiver_control: tokio::sync::watch::Receiver<TransceiverCommand>,
     remote_registration: tokio::sync::watch::Receiver<Registration>,
+    version: tokio::sync::watch::Receiver<Option<String>>,
+    link_stats: tokio::sync::watch::Receiver<LinkStats>,
+    control_trace: tokio::sync::broadcast::Sender<ControlLine>,
+    /// Weak, so that subscribers see the channel close once the managing thread ends.
+    connection_events: tokio::sync::broadcast::WeakSender<ConnectionEvent>,
+}
+
+/// A point-in-time copy of everything the TNC has reported.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TncStatus {
+    pub busy_state: BusyState,
+    pub buffer: usize,
+    pub registered_calls: std::collections::HashSet<StationId>,
+    pub last_heartbeat: Instant,
+    pub connection: ConnectionStateOwned,
+    pub transceiver_control: TransceiverCommand,
+    pub remote_registration: Registration,
+}
+
+impl TncStatus {
+    /// Lists the fields that changed since `prev`. Heartbeats are not reported.
+    pub fn diff(&self, prev: &TncStatus) -> Vec<StatusChange> {
+        fn registered(calls: &std::collections::HashSet<StationId>) -> String {
+            let mut calls: Vec<&str> = calls.iter().map(|call| call.as_str()).collect();
+            calls.sort_unstable();
+            format!("[{}]", calls.join(", "))
+        }
+
+        let mut changes = Vec::new();
+        if self.busy_state != prev.busy_state {
+            changes.push(StatusChange::new("busy", format!("{:?}", prev.busy_state), format!("{:?}", self.busy_state)));
+        }
+        if self.buffer != prev.buffer {
+            changes.push(StatusChange::new("buffer", prev.buffer.to_string(), self.buffer.to_string()));
+        }
+        if self.registered_calls != prev.registered_calls {
+            changes.push(StatusChange::new("registered", registered(&prev.registered_calls), registered(&self.registered_calls)));
+        }
+        if self.connection != prev.connection {
+            changes.push(StatusChange::new("connection", format!("{:?}", prev.connection), format!("{:?}", self.connection)));
+        }
+        if self.transceiver_control != prev.transceiver_control {
+            changes.push(StatusChange::new("ptt", format!("{:?}", prev.transceiver_control), format!("{:?}", self.transceiver_control)));
+        }
+        if self.remote_registration != prev.remote_registration {
+            changes.push(StatusChange::new("remote registration", format!("{:?}", prev.remote_registration), format!("{:?}", self.remote_registration)));
+        }
+        changes
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatusChange {
+    pub field: &'static str,
+    pub from: String,
+    pub to: String,
+}
+
+impl StatusChange {
+    fn new(field: &'static str, from: String, to: String) -> Self {
+        Self { field, from, to }
+    }
+}
+
+impl fmt::Display for StatusChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}→{}", self.field, self.from, self.to)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -628,28 +1001,78 @@ pub struct VaraTncBuilder {
     host: std::net::IpAddr,
     control_port: NonZeroU16,
     data_port: Option<NonZeroU16>,
+    session_limit: Option<SessionLimit>,
+    command_timeout: std::time::Duration,
+    command_terminator: &'static str,
+    my_call: Option<MyCallSigns>,
+    compression: Option<CompressionMode>,
+    bandwidth: Option<BandwidthMode>,
 }
 
 impl VaraTncBuilder {
-    pub async fn build(&mut self) -> std::io::Result<VaraTnc> {
-        if self.control_port.get() == u16::MAX && self.data_port.is_none() {
-            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid control port with unspecified data port"));
-        }
+    /// Connects to the TNC and sends the initial settings. A callsign the TNC rejects fails
+    /// with an [`InvalidCallsign`].
+    pub async fn build(&mut self) -> color_eyre::Result<VaraTnc> {
+        let data_port = self.validate()?;
 
         let control = TcpStream::connect((self.host, self.control_port.get())).await?;
-        let data = TcpStream::connect((self.host, self.data_port.unwrap_or_else(|| NonZeroU16::new(self.control_port.get() + 1).unwrap()).get())).await?;
+        let data = TcpStream::connect((self.host, data_port.get())).await?;
 
         let (control_tx, control_rx) = tokio::sync::mpsc::channel(1);
         let (status_tx, status_rx) = channel();
 
-        let managing_thread = tokio::spawn(manage_modem_thread(control_rx, status_tx, control));
+        let managing_thread = tokio::spawn(manage_modem_thread(control_rx, status_tx, control, self.command_terminator));
 
-        Ok(VaraTnc {
-            data,
-            control_channel: control_tx,
-            status: status_rx,
+        let tnc = VaraTnc {
+            data: std::sync::Mutex::new(Some(data)),
+            handle: VaraTncHandle {
+                control_channel: control_tx,
+                status: status_rx,
+                command_timeout: self.command_timeout,
+            },
             managing_thread,
-        })
+            session_limit: self.session_limit.clone(),
+            stream_tasks: Default::default(),
+            last_session: Default::default(),
+        };
+        self.configure(&tnc).await?;
+        Ok(tnc)
+    }
+
+    /// Sends the initial settings, in the order `MYCALL`, `COMPRESSION`, `BW`.
+    async fn configure(&self, tnc: &VaraTnc) -> color_eyre::Result<()> {
+        if let Some(calls) = &self.my_call {
+            tnc.send_callsign(calls.clone()).await?;
+        }
+        if let Some(mode) = self.compression {
+            tnc.send_compression(mode).await?;
+        }
+        if let Some(mode) = self.bandwidth {
+            tnc.send_bandwidth(mode).await?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the control and data ports can be used together, returning the data port
+    /// that `build` will connect to. When no data port is set, it is the one after the
+    /// control port. Also checks that the command terminator is not empty.
+    pub fn validate(&self) -> std::io::Result<NonZeroU16> {
+        if self.command_terminator.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "the command terminator must not be empty"));
+        }
+
+        let data_port = match self.data_port {
+            Some(port) => port,
+            None => self.control_port.get().checked_add(1).and_then(NonZeroU16::new).ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("no data port follows control port {}; set the data port explicitly", self.control_port))
+            })?,
+        };
+
+        if data_port == self.control_port {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("control and data ports must differ, but both are {}", data_port)));
+        }
+
+        Ok(data_port)
     }
 
     pub fn host(&mut self, host: std::net::IpAddr) -> &mut Self {
@@ -666,6 +1089,45 @@ impl VaraTncBuilder {
         self.data_port = Some(port);
         self
     }
+
+    /// Shares a limit on concurrent inbound sessions; connections beyond it are declined by `accept`.
+    pub fn session_limit(&mut self, limit: SessionLimit) -> &mut Self {
+        self.session_limit = Some(limit);
+        self
+    }
+
+    /// How long to wait for the TNC to answer a command. Defaults to [`DEFAULT_COMMAND_TIMEOUT`].
+    pub fn command_timeout(&mut self, timeout: std::time::Duration) -> &mut Self {
+        self.command_timeout = timeout;
+        self
+    }
+
+    /// What ends each command sent on the control port. VARA expects `\r`, the default, but
+    /// emulators and line-oriented test harnesses may want `\r\n`. It must not be empty;
+    /// `build` rejects an empty terminator.
+    pub fn command_terminator(&mut self, terminator: &'static str) -> &mut Self {
+        self.command_terminator = terminator;
+        self
+    }
+
+    /// Callsigns for `build` to register with the TNC once connected. `build` fails if
+    /// the TNC rejects them.
+    pub fn my_call<T: Into<MyCallSigns>>(&mut self, calls: T) -> &mut Self {
+        self.my_call = Some(calls.into());
+        self
+    }
+
+    /// Compression for `build` to set once connected, after any callsigns.
+    pub fn default_compression(&mut self, mode: CompressionMode) -> &mut Self {
+        self.compression = Some(mode);
+        self
+    }
+
+    /// Bandwidth for `build` to set once connected, after any callsigns and compression.
+    pub fn default_bandwidth(&mut self, mode: BandwidthMode) -> &mut Self {
+        self.bandwidth = Some(mode);
+        self
+    }
 }
 
 impl From<StationId> for MyCallSigns {
@@ -674,30 +1136,67 @@ impl From<StationId> for MyCallSigns {
     }
 }
 
-impl VaraTnc {
-    pub fn builder() -> VaraTncBuilder {
-        VaraTncBuilder {
-            host: std::net::Ipv4Addr::LOCALHOST.into(),
-            control_port: 8300.try_into().unwrap(),
-            data_port: None,
-        }
+impl From<(StationId, Vec<StationId>)> for MyCallSigns {
+    fn from((primary, secondary): (StationId, Vec<StationId>)) -> Self {
+        Self(primary, secondary)
     }
+}
 
-    async fn send_command(&self, command: Command) -> color_eyre::Result<()> {
-        let (cmd_tx, cmd_rx) = tokio::sync::oneshot::channel();
-
-        self.control_channel.send((command, cmd_tx)).await?;
+impl VaraTncHandle {
+    pub fn command_timeout(&self) -> std::time::Duration {
+        self.command_timeout
+    }
 
-        if cmd_rx.await? == CommandResult::Wrong {
+    async fn send_command(&self, command: Command) -> color_eyre::Result<()> {
+        if self.command_result(command).await? == CommandResult::Wrong {
             return Err(color_eyre::eyre::eyre!("failed to send connect command to tnc"));
         }
 
         Ok(())
     }
 
+    async fn command_result(&self, command: Command) -> color_eyre::Result<CommandResult> {
+        let (cmd_tx, cmd_rx) = tokio::sync::oneshot::channel();
+        let sent = command.to_string();
+
+        self.control_channel.send((command, cmd_tx)).await
+            .map_err(|_| color_eyre::eyre::eyre!("the tnc control connection is closed"))?;
+
+        let result = tokio::time::timeout(self.command_timeout, cmd_rx).await
+            .map_err(|_| color_eyre::eyre::eyre!("timed out after {:?} waiting for the tnc to answer {}", self.command_timeout, sent))?;
+        result.map_err(|_| color_eyre::eyre::eyre!("the tnc control connection closed before it answered {}", sent))
+    }
+
+    /// Sends `command` to the TNC as written and returns its answer, for trying out
+    /// commands this crate doesn't support yet. A `WRONG` answer is not an error here.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn send_raw(&self, command: &str) -> color_eyre::Result<CommandResult> {
+        if command.contains('\r') {
+            return Err(color_eyre::eyre::eyre!("raw command must be a single line without a carriage return"));
+        }
+        self.command_result(Command::Raw(command.to_owned())).await
+    }
+
+    /// Sets the callsign the TNC answers to. If the TNC refuses it, the error is an
+    /// [`InvalidCallsign`].
     #[tracing::instrument(skip(self), err)]
     pub async fn send_callsign<T: Into<MyCallSigns> + Debug>(&self, cs: T) -> color_eyre::Result<()> {
-        self.send_command(Command::SetCall(cs.into())).await
+        self.set_calls(cs.into()).await
+    }
+
+    /// Sets the primary callsign along with secondary ones the TNC also answers to, as for
+    /// a gateway listening on several calls. If the TNC refuses them, the error is an
+    /// [`InvalidCallsign`].
+    #[tracing::instrument(skip(self), err)]
+    pub async fn send_callsigns(&self, primary: StationId, secondary: Vec<StationId>) -> color_eyre::Result<()> {
+        self.set_calls(MyCallSigns(primary, secondary)).await
+    }
+
+    async fn set_calls(&self, calls: MyCallSigns) -> color_eyre::Result<()> {
+        match self.command_result(Command::SetCall(calls.clone())).await? {
+            CommandResult::Ok => Ok(()),
+            CommandResult::Wrong => Err(InvalidCallsign { calls }.into()),
+        }
     }
 
     #[tracing::instrument(skip(self), err)]
@@ -710,6 +1209,37 @@ impl VaraTnc {
         self.send_command(Command::SetBandwidth(mode)).await
     }
 
+    #[tracing::instrument(skip(self), err)]
+    pub async fn set_public(&self, on: bool) -> color_eyre::Result<()> {
+        self.send_command(Command::SetPublic(on)).await
+    }
+
+    /// Asks the TNC for its version, which is then available from [`version`](Self::version).
+    #[tracing::instrument(skip(self), err)]
+    pub async fn send_version(&self) -> color_eyre::Result<()> {
+        self.send_command(Command::Version).await
+    }
+
+    /// Discards whatever is still waiting to be transmitted, such as the rest of a message
+    /// being cancelled, while staying connected.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn clean_buffer(&self) -> color_eyre::Result<()> {
+        self.send_command(Command::CleanBuffer).await
+    }
+
+    /// Turns the end-of-session CW identification on or off. VARA keys the transmitter
+    /// for the ID with `PTT ON`/`PTT OFF` like any other transmission, so it shows up in
+    /// [`subscribe_rig_command`](Self::subscribe_rig_command) and the rig follows it.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn send_cwid(&self, enabled: bool) -> color_eyre::Result<()> {
+        self.send_command(Command::CwId { enabled }).await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    pub async fn send_listen(&self, mode: ListenMode) -> color_eyre::Result<()> {
+        self.send_command(Command::Listen(mode)).await
+    }
+
     #[tracing::instrument(skip(self), err)]
     pub async fn send_disconnect(&self) -> color_eyre::Result<()> {
         self.send_command(Command::Disconnect).await
@@ -724,14 +1254,47 @@ impl VaraTnc {
         self.status.transceiver_control.clone()
     }
 
+    /// Follows the SNR and throughput VARA reports, which start over with each connection.
+    pub fn subscribe_link_stats(&self) -> tokio::sync::watch::Receiver<LinkStats> {
+        self.status.link_stats.clone()
+    }
+
+    /// Receives every line sent or received on the control port from now on. A subscriber
+    /// that falls too far behind misses lines rather than holding up the TNC.
+    pub fn subscribe_control_trace(&self) -> tokio::sync::broadcast::Receiver<ControlLine> {
+        self.status.control_trace.subscribe()
+    }
+
+    /// Follows connections as they are attempted, made, and closed, from now on, without
+    /// correlating the status channels by hand. A subscriber that falls too far behind is
+    /// told how many events it missed. The stream ends once the control connection closes.
+    pub fn subscribe_events(&self) -> impl futures_core::Stream<Item = ConnectionEvent> + Send + Unpin {
+        ConnectionEvents {
+            next: self.status.connection_events.upgrade().map(|events| Box::pin(next_event(events.subscribe())) as _),
+        }
+    }
+
     pub fn remote_registration(&self) -> Registration {
         *self.status.remote_registration.borrow()
     }
 
+    pub fn version(&self) -> Option<String> {
+        self.status.version.borrow().clone()
+    }
+
     pub fn last_heartbeat(&self) -> Instant {
         *self.status.last_heartbeat.borrow()
     }
 
+    /// The local callsigns the TNC has confirmed as registered so far.
+    pub fn registered_calls(&self) -> std::collections::HashSet<StationId> {
+        self.status.registered_calls.borrow().clone()
+    }
+
+    pub fn subscribe_registered_calls(&self) -> tokio::sync::watch::Receiver<std::collections::HashSet<StationId>> {
+        self.status.registered_calls.clone()
+    }
+
     pub fn local_registration(&self, station: &StationIdRef) -> Registration {
         if self.status.registered_calls.borrow().contains(station) {
             Registration::Registered
@@ -740,6 +1303,17 @@ impl VaraTnc {
         }
     }
 
+    /// Waits up to `timeout` for the TNC to report `station` as registered, which it
+    /// does some time after `send_callsign`.
+    pub async fn await_registration(&self, station: &StationIdRef, timeout: std::time::Duration) -> Registration {
+        let mut calls = self.status.registered_calls.clone();
+        let registered = async { calls.wait_for(|calls| calls.contains(station)).await.is_ok() };
+        match tokio::time::timeout(timeout, registered).await {
+            Ok(true) => Registration::Registered,
+            _ => Registration::Unregistered,
+        }
+    }
+
     pub fn buffer(&self) -> usize {
         *self.status.buffer.borrow()
     }
@@ -748,151 +1322,1937 @@ impl VaraTnc {
         *self.status.busy_state.borrow()
     }
 
-    #[tracing::instrument(skip(self), err)]
-    pub async fn connect<'a>(&'a mut self, from: StationId, to: StationId) -> color_eyre::Result<VaraStream<'a>> {
-        self.send_command(Command::Connect(ConnectCommand {
-            origin: from,
-            target: to,
-            path: ConnectPath::Direct,
-        })).await?;
-
-        let (force_dc, force_disconnect) = tokio::sync::oneshot::channel();
-        let cloned_control = self.control_channel.clone();
-        let _force_dc = tokio::spawn(async move {
-            if let Ok(()) = force_disconnect.await {
-                let (tx, rx) = tokio::sync::oneshot::channel();
-                let _ = cloned_control.send((Command::Disconnect, tx)).await;
-                let _ = rx.await;
-            }
-        });
-
-        self.status.connection.changed().await?;
+    pub fn status_snapshot(&self) -> TncStatus {
+        TncStatus {
+            busy_state: *self.status.busy_state.borrow(),
+            buffer: *self.status.buffer.borrow(),
+            registered_calls: self.status.registered_calls.borrow().clone(),
+            last_heartbeat: *self.status.last_heartbeat.borrow(),
+            connection: self.status.connection.borrow().clone(),
+            transceiver_control: *self.status.transceiver_control.borrow(),
+            remote_registration: *self.status.remote_registration.borrow(),
+        }
+    }
+}
 
-        if self.status.connection.borrow().is_connected() {
-            let mut subscriber = self.status.connection.clone();
-            let (remote_dc, remote_disconnect) = tokio::sync::oneshot::channel();
-            let _remote_dc = tokio::spawn(async move {
-                loop {
-                    let _ = subscriber.changed().await;
-                    if subscriber.borrow().is_disconnected() {
-                        let _ = remote_dc.send(());
-                        break;
-                    }
-                }
-            });
-
-            Ok(VaraStream {
-                tnc: self,
-                force_disconnect: Some(force_dc),
-                remote_disconnect: remote_disconnect,
-            })
-        } else if self.status.connection.borrow().is_disconnected() {
-            Err(color_eyre::eyre::eyre!("failed to connect"))
-        } else {
-            Err(color_eyre::eyre::eyre!("connection state unexpected"))
+impl VaraTnc {
+    pub fn builder() -> VaraTncBuilder {
+        VaraTncBuilder {
+            host: std::net::Ipv4Addr::LOCALHOST.into(),
+            control_port: 8300.try_into().unwrap(),
+            data_port: None,
+            session_limit: None,
+            command_timeout: DEFAULT_COMMAND_TIMEOUT,
+            command_terminator: "\r",
+            my_call: None,
+            compression: None,
+            bandwidth: None,
         }
     }
 
-    fn pinned_data(self: Pin<&mut Self>) -> Pin<&mut TcpStream> {
-        Pin::new(self.project().data)
+    /// Overrides the builder's command timeout for commands sent from now on. Handles
+    /// taken before the change keep the timeout they were cloned with.
+    pub fn set_command_timeout(&mut self, timeout: std::time::Duration) {
+        self.handle.command_timeout = timeout;
     }
-}
 
-#[derive(Debug)]
-#[pin_project::pin_project(PinnedDrop)]
-pub struct VaraStream<'a> {
-    tnc: &'a mut VaraTnc,
-    force_disconnect: Option<tokio::sync::oneshot::Sender<()>>,
-    remote_disconnect: tokio::sync::oneshot::Receiver<()>,
-}
+    /// A handle for sending commands and reading status from other tasks.
+    pub fn handle(&self) -> VaraTncHandle {
+        self.handle.clone()
+    }
 
-impl<'a> VaraStream<'a> {
-    pub async fn disconnect(self) -> color_eyre::Result<()> {
-        self.tnc.send_disconnect().await
+    /// How the last stream from `connect` or `accept` ended, once it has been dropped.
+    pub fn last_session_outcome(&self) -> Option<SessionOutcome> {
+        self.last_session.lock().unwrap().clone()
     }
 
-    pub async fn abort(self) -> color_eyre::Result<()> {
-        self.tnc.send_abort().await
+    // The handle's commands and status, without cloning one to get at them.
+    pub fn command_timeout(&self) -> std::time::Duration {
+        self.handle.command_timeout()
     }
-}
+
+    pub async fn send_raw(&self, command: &str) -> color_eyre::Result<CommandResult> {
+        self.handle.send_raw(command).await
+    }
+
+    pub async fn send_callsign<T: Into<MyCallSigns> + Debug>(&self, cs: T) -> color_eyre::Result<()> {
+        self.handle.send_callsign(cs).await
+    }
+
+    pub async fn send_callsigns(&self, primary: StationId, secondary: Vec<StationId>) -> color_eyre::Result<()> {
+        self.handle.send_callsigns(primary, secondary).await
+    }
+
+    pub async fn send_compression(&self, mode: CompressionMode) -> color_eyre::Result<()> {
+        self.handle.send_compression(mode).await
+    }
+
+    pub async fn send_bandwidth(&self, mode: BandwidthMode) -> color_eyre::Result<()> {
+        self.handle.send_bandwidth(mode).await
+    }
+
+    pub async fn set_public(&self, on: bool) -> color_eyre::Result<()> {
+        self.handle.set_public(on).await
+    }
+
+    pub async fn send_version(&self) -> color_eyre::Result<()> {
+        self.handle.send_version().await
+    }
+
+    pub async fn clean_buffer(&self) -> color_eyre::Result<()> {
+        self.handle.clean_buffer().await
+    }
+
+    pub async fn send_cwid(&self, enabled: bool) -> color_eyre::Result<()> {
+        self.handle.send_cwid(enabled).await
+    }
+
+    pub async fn send_listen(&self, mode: ListenMode) -> color_eyre::Result<()> {
+        self.handle.send_listen(mode).await
+    }
+
+    pub async fn send_disconnect(&self) -> color_eyre::Result<()> {
+        self.handle.send_disconnect().await
+    }
+
+    pub async fn send_abort(&self) -> color_eyre::Result<()> {
+        self.handle.send_abort().await
+    }
+
+    pub fn subscribe_rig_command(&self) -> tokio::sync::watch::Receiver<TransceiverCommand> {
+        self.handle.subscribe_rig_command()
+    }
+
+    pub fn subscribe_link_stats(&self) -> tokio::sync::watch::Receiver<LinkStats> {
+        self.handle.subscribe_link_stats()
+    }
+
+    pub fn subscribe_control_trace(&self) -> tokio::sync::broadcast::Receiver<ControlLine> {
+        self.handle.subscribe_control_trace()
+    }
+
+    pub fn subscribe_events(&self) -> impl futures_core::Stream<Item = ConnectionEvent> + Send + Unpin {
+        self.handle.subscribe_events()
+    }
+
+    pub fn remote_registration(&self) -> Registration {
+        self.handle.remote_registration()
+    }
+
+    pub fn version(&self) -> Option<String> {
+        self.handle.version()
+    }
+
+    pub fn last_heartbeat(&self) -> Instant {
+        self.handle.last_heartbeat()
+    }
+
+    pub fn registered_calls(&self) -> std::collections::HashSet<StationId> {
+        self.handle.registered_calls()
+    }
+
+    pub fn subscribe_registered_calls(&self) -> tokio::sync::watch::Receiver<std::collections::HashSet<StationId>> {
+        self.handle.subscribe_registered_calls()
+    }
+
+    pub fn local_registration(&self, station: &StationIdRef) -> Registration {
+        self.handle.local_registration(station)
+    }
+
+    pub async fn await_registration(&self, station: &StationIdRef, timeout: std::time::Duration) -> Registration {
+        self.handle.await_registration(station, timeout).await
+    }
+
+    pub fn buffer(&self) -> usize {
+        self.handle.buffer()
+    }
+
+    pub fn busy_state(&self) -> BusyState {
+        self.handle.busy_state()
+    }
+
+    pub fn status_snapshot(&self) -> TncStatus {
+        self.handle.status_snapshot()
+    }
+
+    /// Connects to `to`. The stream only borrows the TNC, so status queries such as
+    /// [`buffer`](Self::buffer) keep working while it is open.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn connect(&self, from: StationId, to: StationId) -> color_eyre::Result<VaraStream<'_>> {
+        self.connect_via(from, to, ConnectPath::Direct).await
+    }
+
+    /// Listens before transmitting: waits up to `wait` for the TNC to report a clear
+    /// channel, then connects over `path`. Fails without transmitting if the channel is
+    /// still busy when `wait` runs out.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn connect_when_clear(&self, from: StationId, to: StationId, path: ConnectPath, wait: std::time::Duration) -> color_eyre::Result<VaraStream<'_>> {
+        let mut busy_state = self.handle.status.busy_state.clone();
+        let clear = async { busy_state.wait_for(|state| *state == BusyState::NotBusy).await.map(drop) };
+        match tokio::time::timeout(wait, clear).await {
+            Ok(result) => result?,
+            Err(_) => return Err(color_eyre::eyre::eyre!("channel still busy after {:?}; not connecting", wait)),
+        }
+
+        self.connect_via(from, to, path).await
+    }
+
+    /// Connects over `path`, trying up to `attempts` times. Each attempt listens for a clear
+    /// channel for up to `backoff` first, as [`connect_when_clear`](Self::connect_when_clear)
+    /// does. A failed attempt is aborted and the TNC allowed to settle before waiting a
+    /// further `backoff` times the number of attempts so far and trying again.
+    ///
+    /// At least one attempt is always made. If none succeed, the error is a
+    /// [`ConnectAttemptsExhausted`]. If a stream is already open, nothing is retried or
+    /// aborted, and the error is a [`StreamAlreadyOpen`].
+    #[tracing::instrument(skip(self), err)]
+    pub async fn connect_with_retry(&self, from: StationId, to: StationId, path: ConnectPath, attempts: u8, backoff: std::time::Duration) -> color_eyre::Result<VaraStream<'_>> {
+        let mut failures = Vec::new();
+        for attempt in 1..=attempts.max(1) {
+            match self.connect_when_clear(from.clone(), to.clone(), path.clone(), backoff).await {
+                Ok(stream) => return Ok(stream),
+                Err(error) if error.is::<StreamAlreadyOpen>() => return Err(error),
+                Err(error) => {
+                    tracing::info!(attempt, %error, "connect attempt failed");
+                    failures.push(error);
+                }
+            }
+
+            if attempt < attempts {
+                self.abort_session().await?;
+                tokio::time::sleep(backoff * u32::from(attempt)).await;
+            }
+        }
+
+        Err(ConnectAttemptsExhausted { attempts: failures }.into())
+    }
+
+    async fn connect_via(&self, from: StationId, to: StationId, path: ConnectPath) -> color_eyre::Result<VaraStream<'_>> {
+        if self.data.lock().unwrap().is_none() {
+            return Err(StreamAlreadyOpen.into());
+        }
+
+        let mut connection = self.handle.status.connection.clone();
+        connection.borrow_and_update();
+
+        // Dropping this future, as on Ctrl-C, must not leave the TNC calling. The stream's
+        // own watchdog tasks are only spawned once it is open, so there are none to clean up.
+        let abort = AbortOnDrop(Some(self.handle.control_channel.clone()));
+        let outcome = async {
+            self.handle.send_command(Command::Connect(ConnectCommand {
+                origin: from,
+                target: to,
+                path,
+            })).await?;
+
+            connection.changed().await?;
+            connection.wait_for(|state| *state != ConnectionStateOwned::Pending).await?;
+            color_eyre::Result::<()>::Ok(())
+        }.await;
+        abort.disarm();
+        outcome?;
+
+        if connection.borrow().is_connected() {
+            self.open_stream(None)
+        } else if connection.borrow().is_disconnected() {
+            Err(color_eyre::eyre::eyre!("failed to connect"))
+        } else {
+            Err(color_eyre::eyre::eyre!("connection state unexpected"))
+        }
+    }
+
+    /// Aborts any session in progress and waits up to [`CLOSE_TIMEOUT`] for the TNC to report
+    /// the link down, disconnected or canceled, so that the next `connect` or `accept` starts
+    /// clean. Disconnects still pending from
+    /// earlier streams are cancelled rather than left to fire during the next session.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn reset(&mut self) -> color_eyre::Result<()> {
+        self.abort_session().await
+    }
+
+    async fn abort_session(&self) -> color_eyre::Result<()> {
+        let tasks = std::mem::take(&mut *self.stream_tasks.lock().unwrap());
+        for task in tasks {
+            task.abort();
+        }
+
+        self.send_abort().await?;
+
+        let mut connection = self.handle.status.connection.clone();
+        let ended = async { connection.wait_for(ConnectionStateOwned::is_ended).await.map(drop) };
+        match tokio::time::timeout(CLOSE_TIMEOUT, ended).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(color_eyre::eyre::eyre!("timed out waiting for the tnc to confirm the abort")),
+        }
+    }
+
+    /// Waits for an inbound connection while the TNC is listening (see `send_listen`).
+    ///
+    /// If a session limit is configured and already at capacity, the inbound
+    /// connection is disconnected immediately and `accept` keeps waiting.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn accept(&self) -> color_eyre::Result<VaraStream<'_>> {
+        let mut connection = self.handle.status.connection.clone();
+        loop {
+            connection.wait_for(ConnectionStateOwned::is_connected).await?;
+
+            let permit = match &self.session_limit {
+                Some(limit) => match limit.try_acquire() {
+                    Some(permit) => Some(permit),
+                    None => {
+                        tracing::warn!(active_sessions = limit.active_sessions(), "session limit reached; declining inbound connection");
+                        self.send_disconnect().await?;
+                        connection.wait_for(ConnectionStateOwned::is_disconnected).await?;
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            return self.open_stream(permit);
+        }
+    }
+
+    fn open_stream(&self, permit: Option<SessionPermit>) -> color_eyre::Result<VaraStream<'_>> {
+        let data = self.data.lock().unwrap().take()
+            .ok_or(StreamAlreadyOpen)?;
+        let connected = self.handle.status.connection.borrow().clone();
+        let (force_dc, force_disconnect) = tokio::sync::oneshot::channel();
+        let cloned_control = self.handle.control_channel.clone();
+        let force_dc_task = tokio::spawn(async move {
+            if let Ok(()) = force_disconnect.await {
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                let _ = cloned_control.send((Command::Disconnect, tx)).await;
+                let _ = rx.await;
+            }
+        });
+
+        let mut subscriber = self.handle.status.connection.clone();
+        let (remote_dc, remote_disconnect) = tokio::sync::oneshot::channel();
+        let remote_dc_task = tokio::spawn(async move {
+            loop {
+                let _ = subscriber.changed().await;
+                if subscriber.borrow().is_disconnected() {
+                    let _ = remote_dc.send(());
+                    break;
+                }
+            }
+        });
+
+        let mut stream_tasks = self.stream_tasks.lock().unwrap();
+        stream_tasks.retain(|task| !task.is_finished());
+        stream_tasks.extend([force_dc_task, remote_dc_task]);
+
+        Ok(VaraStream {
+            tnc: self,
+            data: Some(data),
+            connected,
+            force_disconnect: Some(force_dc),
+            remote_disconnect: Some(remote_disconnect),
+            _permit: permit,
+        })
+    }
+}
+
+/// Sends `ABORT` when dropped, unless disarmed first.
+struct AbortOnDrop(Option<Sender<(Command, tokio::sync::oneshot::Sender<CommandResult>)>>);
+
+impl AbortOnDrop {
+    fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        let Some(control_channel) = self.0.take() else { return };
+        tracing::debug!("connect abandoned; aborting");
+        let (reply, _) = tokio::sync::oneshot::channel();
+        if let Err(tokio::sync::mpsc::error::TrySendError::Full(command)) = control_channel.try_send((Command::Abort, reply)) {
+            // The connect itself may still be waiting to go out, so queue behind it.
+            if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                runtime.spawn(async move {
+                    let _ = control_channel.send(command).await;
+                });
+            }
+        }
+    }
+}
+
+/// How long `send_command` waits for `OK` or `WRONG` unless the builder says otherwise.
+pub const DEFAULT_COMMAND_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// The most data `write_all_metered` lets the modem hold before pausing.
+pub const METERED_BUFFER_LIMIT: usize = 2048;
+
+/// How long `close` waits for the TNC to confirm a disconnect before aborting the link.
+pub const CLOSE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+impl crate::modem::Modem for VaraTnc {
+    type Connection<'a> = VaraStream<'a>;
+    type ConnectionError = color_eyre::Report;
+
+    async fn connect(&mut self, from: &StationIdRef, to: &StationIdRef) -> color_eyre::Result<VaraStream<'_>> {
+        VaraTnc::connect(self, from.to_owned(), to.to_owned()).await
+    }
+}
+
+#[derive(Debug)]
+#[pin_project::pin_project(PinnedDrop)]
+pub struct VaraStream<'a> {
+    tnc: &'a VaraTnc,
+    data: Option<TcpStream>,
+    connected: ConnectionStateOwned,
+    force_disconnect: Option<tokio::sync::oneshot::Sender<()>>,
+    /// Taken once the remote end disconnects.
+    remote_disconnect: Option<tokio::sync::oneshot::Receiver<()>>,
+    _permit: Option<SessionPermit>,
+}
+
+impl<'a> VaraStream<'a> {
+    /// The connection as the TNC confirmed it when the stream opened.
+    pub fn connection(&self) -> &ConnectionStateOwned {
+        &self.connected
+    }
+
+    /// The remote station as the TNC confirmed it, which may differ from the one
+    /// requested, as when connecting through a digipeater.
+    pub fn peer(&self) -> Option<&StationIdRef> {
+        match &self.connected {
+            ConnectionStateOwned::Connected { other_station, .. } => Some(other_station),
+            _ => None,
+        }
+    }
+
+    /// Whether the TNC still reports the RF link as connected. The data port can stay open
+    /// after the link drops, so this follows the TNC's connection state rather than the socket.
+    pub fn is_connected(&self) -> bool {
+        self.tnc.handle.status.connection.borrow().is_connected()
+    }
+
+    /// Polls until the data port can take more data. Fails with `ConnectionAborted` once the
+    /// link is down, even if the socket itself would still accept a write.
+    pub fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if poll_remote_disconnect(&mut self.remote_disconnect, cx).is_ready() || !self.is_connected() {
+            return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "connection closed on remote end")));
+        }
+
+        self.data.as_ref().expect("data port taken before the stream dropped").poll_write_ready(cx)
+    }
+
+    /// Waits until the data port can take more data, as [`poll_write_ready`](Self::poll_write_ready).
+    pub async fn writable(&mut self) -> std::io::Result<()> {
+        std::future::poll_fn(|cx| self.poll_write_ready(cx)).await
+    }
+
+    /// Disconnects and waits for the TNC to confirm, so the session ends cleanly. The same
+    /// as [`close`](Self::close).
+    pub async fn disconnect(self) -> color_eyre::Result<()> {
+        self.close().await
+    }
+
+    /// Disconnects and waits up to [`CLOSE_TIMEOUT`] for the TNC to confirm, aborting the
+    /// link if it doesn't.
+    ///
+    /// Dropping a connected stream also disconnects, but only queues the command; it may
+    /// never be sent if the process exits first.
+    pub async fn close(self) -> color_eyre::Result<()> {
+        self.close_within(CLOSE_TIMEOUT).await
+    }
+
+    async fn close_within(self, timeout: std::time::Duration) -> color_eyre::Result<()> {
+        if !self.tnc.handle.status.connection.borrow().is_disconnected() {
+            self.tnc.send_disconnect().await?;
+        }
+
+        let mut connection = self.tnc.handle.status.connection.clone();
+        let disconnected = async { connection.wait_for(ConnectionStateOwned::is_disconnected).await.map(drop) };
+        match tokio::time::timeout(timeout, disconnected).await {
+            Ok(result) => Ok(result?),
+            Err(_) => {
+                tracing::warn!(?timeout, "no confirmation of disconnect; aborting");
+                self.tnc.send_abort().await?;
+                Err(color_eyre::eyre::eyre!("timed out waiting for the tnc to disconnect"))
+            }
+        }
+    }
+
+    /// Drops the link at once and waits up to [`CLOSE_TIMEOUT`] for the TNC to report it
+    /// down: disconnected, or canceled if it was still pending.
+    pub async fn abort(self) -> color_eyre::Result<()> {
+        self.abort_within(CLOSE_TIMEOUT).await
+    }
+
+    async fn abort_within(self, timeout: std::time::Duration) -> color_eyre::Result<()> {
+        self.tnc.send_abort().await?;
+
+        let mut connection = self.tnc.handle.status.connection.clone();
+        let ended = async { connection.wait_for(ConnectionStateOwned::is_ended).await.map(drop) };
+        match tokio::time::timeout(timeout, ended).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(color_eyre::eyre::eyre!("timed out waiting for the tnc to confirm the abort")),
+        }
+    }
+
+    /// Writes all of `data`, pausing while the modem reports that more than
+    /// [`METERED_BUFFER_LIMIT`] bytes are still waiting to be sent.
+    pub async fn write_all_metered(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.write_metered(data, false, |_, _| {}).await
+    }
+
+    /// Writes all of `data` as [`write_all_metered`](Self::write_all_metered) does, calling
+    /// `progress` with the bytes the modem has sent so far and the total. It then waits for
+    /// the modem to empty its buffer, so `progress` only reaches the total once everything
+    /// has gone out over the air.
+    pub async fn write_message_with_progress(&mut self, data: &[u8], mut progress: impl FnMut(usize, usize)) -> std::io::Result<()> {
+        let total = data.len();
+        let mut reported = None;
+        self.write_metered(data, true, |written, queued| {
+            let sent = written.saturating_sub(queued);
+            if reported.is_none_or(|reported| sent > reported) {
+                reported = Some(sent);
+                progress(sent, total);
+            }
+        }).await
+    }
+
+    /// Writes `data` in chunks the modem's buffer has room for, telling `observe` how much
+    /// has been written and how much the modem still holds after each write or report. With
+    /// `drain`, keeps waiting until the modem reports an empty buffer.
+    async fn write_metered(&mut self, mut data: &[u8], drain: bool, mut observe: impl FnMut(usize, usize)) -> std::io::Result<()> {
+        let mut buffer = self.tnc.handle.status.buffer.clone();
+        let mut queued = *buffer.borrow_and_update();
+        let mut written = 0;
+        observe(written, queued);
+
+        while !data.is_empty() || (drain && queued > 0) {
+            if queued >= METERED_BUFFER_LIMIT || data.is_empty() {
+                tokio::select! {
+                    changed = buffer.changed() => {
+                        if changed.is_err() {
+                            return Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "tnc status closed"));
+                        }
+                    }
+                    _ = std::future::poll_fn(|cx| poll_remote_disconnect(&mut self.remote_disconnect, cx)) => {
+                        return Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "connection closed on remote end"));
+                    }
+                }
+                queued = *buffer.borrow_and_update();
+                observe(written, queued);
+                continue;
+            }
+
+            let chunk = (METERED_BUFFER_LIMIT - queued).min(data.len());
+            self.write_all(&data[..chunk]).await?;
+            data = &data[chunk..];
+            written += chunk;
+
+            // Count what was just written until the modem reports its buffer again.
+            queued += chunk;
+            if buffer.has_changed().unwrap_or(false) {
+                queued = *buffer.borrow_and_update();
+            }
+            observe(written, queued);
+        }
+
+        Ok(())
+    }
+}
 
 #[pin_project::pinned_drop]
 impl<'a> PinnedDrop for VaraStream<'a> {
     fn drop(self: Pin<&mut Self>) {
-        if !self.tnc.status.connection.borrow().is_disconnected() {
-            let this = self.project();
-            if let Some(dc) = this.force_disconnect.take() {
-                let _ = dc.send(());
+        let this = self.project();
+        let state = this.tnc.handle.status.connection.borrow().clone();
+        let outcome = match state {
+            ConnectionStateOwned::Disconnected => SessionOutcome::Disconnected,
+            ConnectionStateOwned::Canceled => SessionOutcome::Canceled,
+            last_state => {
+                // Queue the disconnect directly where possible, so it doesn't depend on a
+                // spawned task getting to run. Only if a command is already waiting is it
+                // left to the task.
+                let (reply, _) = tokio::sync::oneshot::channel();
+                if this.tnc.handle.control_channel.try_send((Command::Disconnect, reply)).is_err() {
+                    if let Some(dc) = this.force_disconnect.take() {
+                        let _ = dc.send(());
+                    }
+                }
+                SessionOutcome::Forced { last_state }
             }
-            // let _ = this.force_disconnect.send(());
-        }
+        };
+        tracing::debug!(?outcome, "stream closed");
+        *this.tnc.last_session.lock().unwrap() = Some(outcome);
+        *this.tnc.data.lock().unwrap() = this.data.take();
+    }
+}
+
+/// Resolves once the remote end has disconnected, and keeps resolving after that. The
+/// receiver is dropped when it completes, since it must not be polled again.
+pub(crate) fn poll_remote_disconnect(remote_disconnect: &mut Option<tokio::sync::oneshot::Receiver<()>>, cx: &mut Context<'_>) -> Poll<()> {
+    if let Some(receiver) = remote_disconnect {
+        std::task::ready!(Pin::new(receiver).poll(cx)).ok();
+        *remote_disconnect = None;
+    }
+    Poll::Ready(())
+}
+
+type NextEvent = Pin<Box<dyn Future<Output = (Result<ConnectionEvent, tokio::sync::broadcast::error::RecvError>, tokio::sync::broadcast::Receiver<ConnectionEvent>)> + Send>>;
+
+/// Receives from `events`, handing the receiver back so it can wait for the next event.
+async fn next_event(mut events: tokio::sync::broadcast::Receiver<ConnectionEvent>) -> (Result<ConnectionEvent, tokio::sync::broadcast::error::RecvError>, tokio::sync::broadcast::Receiver<ConnectionEvent>) {
+    (events.recv().await, events)
+}
+
+/// Taken once the broadcast channel closes.
+struct ConnectionEvents {
+    next: Option<NextEvent>,
+}
+
+impl futures_core::Stream for ConnectionEvents {
+    type Item = ConnectionEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ConnectionEvent>> {
+        let Some(next) = self.next.as_mut() else {
+            return Poll::Ready(None);
+        };
+        let (result, events) = std::task::ready!(next.as_mut().poll(cx));
+        let event = match result {
+            Ok(event) => event,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                ConnectionEvent::Error { message: format!("missed {} connection events", missed) }
+            }
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                self.next = None;
+                return Poll::Ready(None);
+            }
+        };
+        self.next = Some(Box::pin(next_event(events)));
+        Poll::Ready(Some(event))
     }
 }
 
+/// The data port, which a stream only gives up when it drops.
+fn live(data: &mut Option<TcpStream>) -> Pin<&mut TcpStream> {
+    Pin::new(data.as_mut().expect("data port taken before the stream dropped"))
+}
+
 impl<'a> AsyncRead for VaraStream<'a> {
     fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
         let this = self.project();
-        match Pin::new(this.remote_disconnect).poll(cx) {
-            Poll::Pending => {}
-            Poll::Ready(_) => {
-                return Poll::Ready(Ok(()));
-            }
+        if poll_remote_disconnect(this.remote_disconnect, cx).is_ready() {
+            return Poll::Ready(Ok(()));
         }
 
-        Pin::new(&mut **this.tnc).pinned_data().poll_read(cx, buf)
+        live(this.data).poll_read(cx, buf)
     }
 }
 
 impl<'a> AsyncWrite for VaraStream<'a> {
     fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
         let this = self.project();
-        match Pin::new(this.remote_disconnect).poll(cx) {
-            Poll::Pending => {}
-            Poll::Ready(_) => {
-                return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "connection closed on remote end")));
-            }
+        if poll_remote_disconnect(this.remote_disconnect, cx).is_ready() {
+            return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "connection closed on remote end")));
         }
 
-        Pin::new(&mut **this.tnc).pinned_data().poll_write(cx, buf)
+        live(this.data).poll_write(cx, buf)
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
         let this = self.project();
-        match Pin::new(this.remote_disconnect).poll(cx) {
-            Poll::Pending => {}
-            Poll::Ready(_) => {
-                return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "connection closed on remote end")));
-            }
+        if poll_remote_disconnect(this.remote_disconnect, cx).is_ready() {
+            return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "connection closed on remote end")));
         }
 
-        Pin::new(&mut **this.tnc).pinned_data().poll_flush(cx)
+        live(this.data).poll_flush(cx)
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
         let this = self.project();
-        match Pin::new(this.remote_disconnect).poll(cx) {
-            Poll::Pending => {}
-            Poll::Ready(_) => {
-                return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "connection closed on remote end")));
-            }
+        if poll_remote_disconnect(this.remote_disconnect, cx).is_ready() {
+            return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "connection closed on remote end")));
         }
 
-        Pin::new(&mut **this.tnc).pinned_data().poll_shutdown(cx)
+        live(this.data).poll_shutdown(cx)
     }
 
     fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<Result<usize, Error>> {
         let this = self.project();
-        match Pin::new(this.remote_disconnect).poll(cx) {
-            Poll::Pending => {}
-            Poll::Ready(_) => {
-                return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "connection closed on remote end")));
-            }
+        if poll_remote_disconnect(this.remote_disconnect, cx).is_ready() {
+            return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "connection closed on remote end")));
         }
 
-        Pin::new(&mut **this.tnc).pinned_data().poll_write_vectored(cx, bufs)
+        live(this.data).poll_write_vectored(cx, bufs)
     }
 
     fn is_write_vectored(&self) -> bool {
-        self.tnc.data.is_write_vectored()
+        self.data.as_ref().is_some_and(TcpStream::is_write_vectored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use test_log::test;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::TcpListener;
+    use super::*;
+
+    struct MockTnc {
+        control: BufReader<TcpStream>,
+        data: BufReader<TcpStream>,
+    }
+
+    impl MockTnc {
+        async fn start(builder: &mut VaraTncBuilder) -> color_eyre::Result<(VaraTnc, MockTnc)> {
+            let control = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+            let data = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+            builder
+                .control_port(NonZeroU16::new(control.local_addr()?.port()).unwrap())
+                .data_port(NonZeroU16::new(data.local_addr()?.port()).unwrap());
+
+            let (tnc, control, data) = tokio::join!(builder.build(), control.accept(), data.accept());
+            Ok((tnc?, MockTnc {
+                control: BufReader::new(control?.0),
+                data: BufReader::new(data?.0),
+            }))
+        }
+
+        async fn expect_command(&mut self) -> color_eyre::Result<String> {
+            let mut line = Vec::new();
+            self.control.read_until(b'\r', &mut line).await?;
+            line.pop();
+            Ok(String::from_utf8(line)?)
+        }
+
+        async fn send(&mut self, line: &str) -> color_eyre::Result<()> {
+            self.control.get_mut().write_all(format!("{}\r", line).as_bytes()).await?;
+            Ok(())
+        }
+
+        async fn expect_data_line(&mut self) -> color_eyre::Result<String> {
+            let mut line = Vec::new();
+            self.data.read_until(b'\r', &mut line).await?;
+            line.pop();
+            Ok(String::from_utf8(line)?)
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn send_traffic_to_b2f_peer() -> color_eyre::Result<()> {
+        use tokio::io::AsyncReadExt;
+
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        let traffic = crate::test();
+        let from = StationId::new("KC1GSL")?;
+        let to = StationId::new("KC1KVY")?;
+
+        let (sent, body) = tokio::join!(
+            crate::send_traffic(&tnc, from, to, &traffic),
+            async {
+                assert_eq!(mock.expect_command().await?, "CONNECT KC1GSL KC1KVY");
+                mock.send("OK").await?;
+                mock.send("CONNECTED KC1GSL KC1KVY").await?;
+
+                mock.data.get_mut().write_all(b"[WL2K-5.0-B2FWIHJM$]\rKC1KVY de KC1GSL >\r").await?;
+                assert_eq!(mock.expect_data_line().await?, crate::fbb::local_sid());
+
+                let proposal = mock.expect_data_line().await?;
+                let terminated = format!("{}\r", proposal);
+                let (_, parsed) = crate::fbb::winlink_proposal(terminated.as_bytes()).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+                let end = mock.expect_data_line().await?;
+                let check = u8::from_str_radix(end.strip_prefix("F> ").unwrap(), 16)?;
+                let sum = terminated.bytes().fold(check, |x, y| x.wrapping_add(y));
+                assert_eq!(sum, 0, "proposal checksum should balance");
+
+                mock.data.get_mut().write_all(b"FS +\r").await?;
+                let mut block = Vec::new();
+                let body = loop {
+                    mock.data.read_buf(&mut block).await?;
+                    match crate::fbb::b2_message_block(&block) {
+                        Ok((_, message)) => break message.decompress()?,
+                        Err(nom::Err::Incomplete(_)) => continue,
+                        Err(e) => return Err(color_eyre::eyre::eyre!("{e:?}")),
+                    }
+                };
+                assert_eq!(body.len(), parsed.uncompressed_message_size.0 as usize);
+
+                mock.data.get_mut().write_all(b"FF\r").await?;
+                assert_eq!(mock.expect_data_line().await?, "FQ");
+                assert_eq!(mock.expect_command().await?, "DISCONNECT");
+                mock.send("OK").await?;
+                mock.send("DISCONNECTED").await?;
+                Ok(String::from_utf8(body)?)
+            },
+        );
+
+        assert_eq!(sent?, crate::fbb::MessageChoice::Accept { offset: 0 });
+        let body = body?;
+        assert!(body.starts_with("MID: "));
+        assert!(body.contains("\r\nSubject: Radiogram NR 22\r\n"));
+        assert!(body.contains("\r\nTO BOB SPARKES\r\n"));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn second_inbound_connection_declined_at_session_limit() -> color_eyre::Result<()> {
+        let limit = SessionLimit::new(1);
+        let (tnc_a, mut mock_a) = MockTnc::start(VaraTnc::builder().session_limit(limit.clone())).await?;
+        let (tnc_b, mut mock_b) = MockTnc::start(VaraTnc::builder().session_limit(limit.clone())).await?;
+
+        let (stream_a, sent) = tokio::join!(tnc_a.accept(), mock_a.send("CONNECTED KC1GSL KW1U"));
+        sent?;
+        let _stream_a = stream_a?;
+        assert_eq!(limit.active_sessions(), 1);
+
+        let (accepted, script) = tokio::join!(
+            tokio::time::timeout(Duration::from_millis(500), tnc_b.accept()),
+            async {
+                mock_b.send("CONNECTED KC1GSL W1AW").await?;
+                assert_eq!(mock_b.expect_command().await?, "DISCONNECT");
+                mock_b.send("OK").await?;
+                mock_b.send("DISCONNECTED").await
+            },
+        );
+        script?;
+        assert!(accepted.is_err(), "second inbound connection should not have been accepted");
+        assert_eq!(limit.active_sessions(), 1);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn connect_reports_confirmed_peer() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+
+        let (stream, script) = tokio::join!(tnc.connect(StationId::new("KC1GSL")?, StationId::new("KW1U")?), async {
+            assert_eq!(mock.expect_command().await?, "CONNECT KC1GSL KW1U");
+            mock.send("OK").await?;
+            mock.send("CONNECTED KC1GSL KW1U-7 2300 5").await
+        });
+        script?;
+        let stream = stream?;
+        assert_eq!(stream.peer().map(|peer| peer.as_str()), Some("KW1U-7"));
+        assert_eq!(stream.connection(), &ConnectionStateOwned::Connected {
+            my_station: StationId::new("KC1GSL")?,
+            other_station: StationId::new("KW1U-7")?,
+            bandwidth: Some(2300),
+            snr: Some(5),
+        });
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn status_readable_while_stream_open() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+
+        let (stream, script) = tokio::join!(tnc.connect(StationId::new("KC1GSL")?, StationId::new("KW1U")?), async {
+            mock.expect_command().await?;
+            mock.send("OK").await?;
+            mock.send("CONNECTED KC1GSL KW1U").await
+        });
+        script?;
+        let mut stream = stream?;
+
+        let mut buffer = tnc.handle.status.buffer.clone();
+        mock.send("BUFFER 42").await?;
+        buffer.wait_for(|queued| *queued == 42).await?;
+        assert_eq!(tnc.buffer(), 42);
+
+        stream.write_all(b"still open\r").await?;
+        assert_eq!(mock.expect_data_line().await?, "still open");
+        assert!(tnc.connect(StationId::new("KC1GSL")?, StationId::new("W1AW")?).await.is_err());
+        drop(stream);
+        assert!(tnc.data.lock().unwrap().is_some());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn connect_when_clear_waits_for_busy_channel() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        let from = StationId::new("KC1GSL")?;
+        let to = StationId::new("KW1U")?;
+
+        let mut busy_state = tnc.handle.status.busy_state.clone();
+        mock.send("BUSY ON").await?;
+        busy_state.wait_for(|state| *state == BusyState::Busy).await?;
+
+        let err = tnc.connect_when_clear(from.clone(), to.clone(), ConnectPath::Direct, Duration::from_millis(50)).await.unwrap_err();
+        assert!(err.to_string().contains("busy"), "{err}");
+
+        let (stream, script) = tokio::join!(tnc.connect_when_clear(from, to, ConnectPath::Direct, Duration::from_secs(5)), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            mock.send("BUSY OFF").await?;
+            assert_eq!(mock.expect_command().await?, "CONNECT KC1GSL KW1U");
+            mock.send("OK").await?;
+            mock.send("CONNECTED KC1GSL KW1U").await
+        });
+        script?;
+        assert_eq!(stream?.peer().map(|peer| peer.as_str()), Some("KW1U"));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn connect_with_retry_succeeds_on_third_attempt() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        let from = StationId::new("KC1GSL")?;
+        let to = StationId::new("KW1U")?;
+
+        let (stream, script) = tokio::join!(tnc.connect_with_retry(from.clone(), to.clone(), ConnectPath::Direct, 3, Duration::from_millis(10)), async {
+            for _ in 0..2 {
+                assert_eq!(mock.expect_command().await?, "CONNECT KC1GSL KW1U");
+                mock.send("OK").await?;
+                mock.send("PENDING").await?;
+                mock.send("DISCONNECTED").await?;
+                assert_eq!(mock.expect_command().await?, "ABORT");
+                mock.send("OK").await?;
+            }
+            assert_eq!(mock.expect_command().await?, "CONNECT KC1GSL KW1U");
+            mock.send("OK").await?;
+            mock.send("CONNECTED KC1GSL KW1U").await
+        });
+        script?;
+        let stream = stream?;
+        assert_eq!(stream.peer().map(|peer| peer.as_str()), Some("KW1U"));
+        drop(stream);
+        assert_eq!(mock.expect_command().await?, "DISCONNECT");
+        mock.send("OK").await?;
+        mock.send("DISCONNECTED").await?;
+
+        let (failed, script) = tokio::join!(tnc.connect_with_retry(from, to, ConnectPath::Direct, 1, Duration::from_millis(10)), async {
+            assert_eq!(mock.expect_command().await?, "CONNECT KC1GSL KW1U");
+            mock.send("OK").await?;
+            mock.send("DISCONNECTED").await
+        });
+        script?;
+        let err = failed.map(drop).unwrap_err();
+        let exhausted = err.downcast_ref::<ConnectAttemptsExhausted>().expect("aggregated error");
+        assert_eq!(exhausted.attempts.len(), 1);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn connect_with_retry_recovers_from_canceled_attempt() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+
+        let retry = tnc.connect_with_retry(StationId::new("KC1GSL")?, StationId::new("KW1U")?, ConnectPath::Direct, 2, Duration::from_millis(10));
+        let (stream, script) = tokio::join!(tokio::time::timeout(Duration::from_secs(5), retry), async {
+            assert_eq!(mock.expect_command().await?, "CONNECT KC1GSL KW1U");
+            mock.send("OK").await?;
+            mock.send("PENDING").await?;
+            mock.send("CANCELPENDING").await?;
+            assert_eq!(mock.expect_command().await?, "ABORT");
+            mock.send("OK").await?;
+            assert_eq!(mock.expect_command().await?, "CONNECT KC1GSL KW1U");
+            mock.send("OK").await?;
+            mock.send("CONNECTED KC1GSL KW1U").await
+        });
+        script?;
+        assert_eq!(stream??.peer().map(|peer| peer.as_str()), Some("KW1U"));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn connect_with_retry_leaves_open_stream_alone() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        let from = StationId::new("KC1GSL")?;
+        let to = StationId::new("KW1U")?;
+
+        let (stream, script) = tokio::join!(tnc.connect(from.clone(), to.clone()), async {
+            mock.expect_command().await?;
+            mock.send("OK").await?;
+            mock.send("CONNECTED KC1GSL KW1U").await
+        });
+        script?;
+        let stream = stream?;
+
+        let err = tnc.connect_with_retry(from, to, ConnectPath::Direct, 3, Duration::from_millis(10)).await.map(drop).unwrap_err();
+        assert!(err.is::<StreamAlreadyOpen>());
+        assert!(stream.is_connected());
+
+        // The next command the TNC sees is the stream's own disconnect, not an abort.
+        drop(stream);
+        assert_eq!(mock.expect_command().await?, "DISCONNECT");
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn dropped_connect_aborts() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        let from = StationId::new("KC1GSL")?;
+        let to = StationId::new("KW1U")?;
+
+        let mut connection = tnc.handle.status.connection.clone();
+        let (abandoned, script) = tokio::join!(
+            tokio::time::timeout(Duration::from_millis(200), tnc.connect(from.clone(), to.clone())),
+            async {
+                assert_eq!(mock.expect_command().await?, "CONNECT KC1GSL KW1U");
+                mock.send("OK").await?;
+                mock.send("PENDING").await?;
+                connection.wait_for(|state| *state == ConnectionStateOwned::Pending).await?;
+                color_eyre::Result::<()>::Ok(())
+            },
+        );
+        script?;
+        assert!(abandoned.is_err(), "connect should still have been pending");
+        assert_eq!(mock.expect_command().await?, "ABORT");
+        mock.send("OK").await?;
+        mock.send("DISCONNECTED").await?;
+        connection.wait_for(ConnectionStateOwned::is_disconnected).await?;
+
+        let (stream, script) = tokio::join!(tnc.connect(from, to), async {
+            assert_eq!(mock.expect_command().await?, "CONNECT KC1GSL KW1U");
+            mock.send("OK").await?;
+            mock.send("PENDING").await?;
+            mock.send("CONNECTED KC1GSL KW1U").await
+        });
+        script?;
+        assert_eq!(stream?.peer().map(|peer| peer.as_str()), Some("KW1U"));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn abort_waits_for_disconnect() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+
+        let (stream, script) = tokio::join!(tnc.connect(StationId::new("KC1GSL")?, StationId::new("KW1U")?), async {
+            mock.expect_command().await?;
+            mock.send("OK").await?;
+            mock.send("CONNECTED KC1GSL KW1U").await
+        });
+        script?;
+        let (aborted, script) = tokio::join!(stream?.abort(), async {
+            assert_eq!(mock.expect_command().await?, "ABORT");
+            mock.send("OK").await?;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            mock.send("DISCONNECTED").await
+        });
+        script?;
+        aborted?;
+        assert!(tnc.handle.status.connection.borrow().is_disconnected());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn is_connected_follows_tnc_state() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+
+        let (stream, script) = tokio::join!(tnc.connect(StationId::new("KC1GSL")?, StationId::new("KW1U")?), async {
+            mock.expect_command().await?;
+            mock.send("OK").await?;
+            mock.send("CONNECTED KC1GSL KW1U").await
+        });
+        script?;
+        let mut stream = stream?;
+        assert!(stream.is_connected());
+        stream.writable().await?;
+
+        let mut connection = tnc.handle.status.connection.clone();
+        mock.send("DISCONNECTED").await?;
+        connection.wait_for(ConnectionStateOwned::is_disconnected).await?;
+        assert!(!stream.is_connected());
+        assert_eq!(stream.writable().await.unwrap_err().kind(), std::io::ErrorKind::ConnectionAborted);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn reads_after_remote_disconnect_stay_at_eof() -> color_eyre::Result<()> {
+        use tokio::io::AsyncReadExt;
+
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+
+        let (stream, script) = tokio::join!(tnc.connect(StationId::new("KC1GSL")?, StationId::new("KW1U")?), async {
+            mock.expect_command().await?;
+            mock.send("OK").await?;
+            mock.send("CONNECTED KC1GSL KW1U").await
+        });
+        script?;
+        let mut stream = stream?;
+        mock.send("DISCONNECTED").await?;
+
+        let mut buf = [0; 16];
+        assert_eq!(tokio::time::timeout(Duration::from_secs(1), stream.read(&mut buf)).await??, 0);
+        assert_eq!(stream.read(&mut buf).await?, 0);
+        assert_eq!(stream.write(b"late").await.unwrap_err().kind(), std::io::ErrorKind::ConnectionAborted);
+        assert_eq!(stream.write(b"later").await.unwrap_err().kind(), std::io::ErrorKind::ConnectionAborted);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn abort_while_pending_resolves_canceled() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+
+        let (stream, script) = tokio::join!(tnc.connect(StationId::new("KC1GSL")?, StationId::new("KW1U")?), async {
+            mock.expect_command().await?;
+            mock.send("OK").await?;
+            mock.send("CONNECTED KC1GSL KW1U").await
+        });
+        script?;
+        let stream = stream?;
+        let mut connection = tnc.handle.status.connection.clone();
+        mock.send("PENDING").await?;
+        connection.wait_for(|state| *state == ConnectionStateOwned::Pending).await?;
+
+        let (aborted, script) = tokio::join!(stream.abort_within(Duration::from_secs(5)), async {
+            assert_eq!(mock.expect_command().await?, "ABORT");
+            mock.send("OK").await?;
+            mock.send("CANCELPENDING").await
+        });
+        script?;
+        aborted?;
+        assert_eq!(*tnc.handle.status.connection.borrow(), ConnectionStateOwned::Canceled);
+        assert_eq!(tnc.last_session_outcome(), Some(SessionOutcome::Canceled));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn close_waits_for_disconnect() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        let from = StationId::new("KC1GSL")?;
+        let to = StationId::new("KW1U")?;
+
+        let (stream, script) = tokio::join!(tnc.connect(from.clone(), to.clone()), async {
+            mock.expect_command().await?;
+            mock.send("OK").await?;
+            mock.send("CONNECTED KC1GSL KW1U").await
+        });
+        script?;
+        let (closed, script) = tokio::join!(stream?.close(), async {
+            assert_eq!(mock.expect_command().await?, "DISCONNECT");
+            mock.send("OK").await?;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            mock.send("DISCONNECTED").await
+        });
+        script?;
+        closed?;
+        assert!(tnc.handle.status.connection.borrow().is_disconnected());
+        assert_eq!(tnc.last_session_outcome(), Some(SessionOutcome::Disconnected));
+
+        let (stream, script) = tokio::join!(tnc.connect(from, to), async {
+            mock.expect_command().await?;
+            mock.send("OK").await?;
+            mock.send("CONNECTED KC1GSL KW1U").await
+        });
+        script?;
+        let (closed, script) = tokio::join!(stream?.close_within(Duration::from_millis(100)), async {
+            assert_eq!(mock.expect_command().await?, "DISCONNECT");
+            mock.send("OK").await?;
+            assert_eq!(mock.expect_command().await?, "ABORT");
+            mock.send("OK").await
+        });
+        script?;
+        assert!(closed.is_err());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn reset_cancels_pending_disconnects() -> color_eyre::Result<()> {
+        let (mut tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        let from = StationId::new("KC1GSL")?;
+        let to = StationId::new("KW1U")?;
+
+        let (stream, script) = tokio::join!(tnc.connect(from.clone(), to.clone()), async {
+            assert_eq!(mock.expect_command().await?, "CONNECT KC1GSL KW1U");
+            mock.send("OK").await?;
+            mock.send("CONNECTED KC1GSL KW1U").await
+        });
+        script?;
+        let stream = stream?;
+        // With a command already queued, dropping the stream can't queue its disconnect
+        // directly and leaves it to the watchdog task, which `reset` must cancel before it
+        // gets to run.
+        let (queued, _) = tokio::sync::oneshot::channel();
+        tnc.handle.control_channel.try_send((Command::Version, queued)).expect("control channel has room");
+        drop(stream);
+        assert!(!tnc.stream_tasks.lock().unwrap().is_empty());
+
+        let (reset, script) = tokio::join!(tnc.reset(), async {
+            assert_eq!(mock.expect_command().await?, "VERSION");
+            mock.send("VERSION 4.8.3").await?;
+            assert_eq!(mock.expect_command().await?, "ABORT");
+            mock.send("OK").await?;
+            mock.send("DISCONNECTED").await
+        });
+        script?;
+        reset?;
+
+        let (connected, script) = tokio::join!(tnc.connect(from, to), async {
+            assert_eq!(mock.expect_command().await?, "CONNECT KC1GSL KW1U");
+            mock.send("OK").await?;
+            mock.send("DISCONNECTED").await
+        });
+        script?;
+        assert!(connected.map(drop).is_err());
+
+        let (reset, script) = tokio::join!(tnc.reset(), async {
+            assert_eq!(mock.expect_command().await?, "ABORT");
+            mock.send("OK").await
+        });
+        script?;
+        reset?;
+        assert!(tnc.stream_tasks.lock().unwrap().is_empty());
+
+        let (sent, script) = tokio::join!(tnc.send_version(), async {
+            assert_eq!(mock.expect_command().await?, "VERSION");
+            mock.send("VERSION 4.8.3").await
+        });
+        script?;
+        sent?;
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn progress_completes_once_buffer_drains() -> color_eyre::Result<()> {
+        use tokio::io::AsyncReadExt;
+
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        let (stream, script) = tokio::join!(tnc.connect(StationId::new("KC1GSL")?, StationId::new("KW1U")?), async {
+            mock.expect_command().await?;
+            mock.send("OK").await?;
+            mock.send("CONNECTED KC1GSL KW1U").await
+        });
+        script?;
+        let mut stream = stream?;
+
+        let data = vec![0x55; 3000];
+        // Each buffer report is only sent once the previous one has shown up as progress.
+        let (updates, mut progress) = tokio::sync::mpsc::unbounded_channel();
+        let (written, script) = tokio::join!(stream.write_message_with_progress(&data, |sent, total| updates.send((sent, total)).unwrap()), async {
+            assert_eq!(progress.recv().await, Some((0, 3000)));
+            let mut received = vec![0; METERED_BUFFER_LIMIT];
+            mock.data.read_exact(&mut received).await?;
+            mock.send("BUFFER 1024").await?;
+            assert_eq!(progress.recv().await, Some((1024, 3000)));
+            let mut received = vec![0; data.len() - METERED_BUFFER_LIMIT];
+            mock.data.read_exact(&mut received).await?;
+            mock.send("BUFFER 500").await?;
+            assert_eq!(progress.recv().await, Some((2500, 3000)));
+            mock.send("BUFFER 0").await?;
+            assert_eq!(progress.recv().await, Some((3000, 3000)));
+            color_eyre::Result::<()>::Ok(())
+        });
+        script?;
+        written?;
+        assert_eq!(progress.try_recv(), Err(tokio::sync::mpsc::error::TryRecvError::Empty));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn metered_write_waits_for_buffer_to_drain() -> color_eyre::Result<()> {
+        use tokio::io::AsyncReadExt;
+
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        let (stream, script) = tokio::join!(tnc.connect(StationId::new("KC1GSL")?, StationId::new("KW1U")?), async {
+            mock.expect_command().await?;
+            mock.send("OK").await?;
+            mock.send("CONNECTED KC1GSL KW1U").await?;
+            mock.send(&format!("BUFFER {}", METERED_BUFFER_LIMIT)).await
+        });
+        script?;
+        let mut stream = stream?;
+        while stream.tnc.buffer() != METERED_BUFFER_LIMIT {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let (written, received) = tokio::join!(stream.write_all_metered(&data), async {
+            let mut received = Vec::new();
+            let mut chunk = [0; 1024];
+            assert!(tokio::time::timeout(Duration::from_millis(50), mock.data.read(&mut chunk)).await.is_err(), "wrote while the buffer was full");
+
+            while received.len() < data.len() {
+                mock.send("BUFFER 0").await?;
+                let mut since_report = 0;
+                while let Ok(read) = tokio::time::timeout(Duration::from_millis(50), mock.data.read(&mut chunk)).await {
+                    let read = read?;
+                    since_report += read;
+                    received.extend_from_slice(&chunk[..read]);
+                }
+                assert!(since_report <= METERED_BUFFER_LIMIT, "wrote {} bytes after one report", since_report);
+            }
+            color_eyre::Result::<_>::Ok(received)
+        });
+        written?;
+        assert_eq!(received?, data);
+
+        mock.send(&format!("BUFFER {}", METERED_BUFFER_LIMIT)).await?;
+        while stream.tnc.buffer() != METERED_BUFFER_LIMIT {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let (written, sent) = tokio::join!(stream.write_all_metered(&data), mock.send("DISCONNECTED"));
+        sent?;
+        assert_eq!(written.unwrap_err().kind(), std::io::ErrorKind::ConnectionAborted);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn events_follow_connect_and_disconnect() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        let mut events = tnc.subscribe_events();
+
+        let (stream, script) = tokio::join!(tnc.connect(StationId::new("KC1GSL")?, StationId::new("KW1U")?), async {
+            mock.expect_command().await?;
+            mock.send("OK").await?;
+            mock.send("PENDING").await?;
+            mock.send("CONNECTED KC1GSL KW1U").await
+        });
+        script?;
+        let stream = stream?;
+        mock.send("DISCONNECTED").await?;
+        drop(stream);
+
+        let mut seen = Vec::new();
+        while seen.len() < 3 {
+            let next = std::future::poll_fn(|cx| futures_core::Stream::poll_next(Pin::new(&mut events), cx));
+            seen.push(tokio::time::timeout(Duration::from_secs(1), next).await?.expect("events ended early"));
+        }
+        assert_eq!(seen, [
+            ConnectionEvent::Connecting,
+            ConnectionEvent::Connected { peer: StationId::new("KW1U")? },
+            ConnectionEvent::Disconnected { reason: DisconnectReason::Ended },
+        ]);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn events_end_when_control_connection_closes() -> color_eyre::Result<()> {
+        let (tnc, mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        let mut events = tnc.subscribe_events();
+        drop(mock);
+
+        async fn next(events: &mut (impl futures_core::Stream<Item = ConnectionEvent> + Unpin)) -> color_eyre::Result<Option<ConnectionEvent>> {
+            let next = std::future::poll_fn(|cx| futures_core::Stream::poll_next(Pin::new(&mut *events), cx));
+            Ok(tokio::time::timeout(Duration::from_secs(1), next).await?)
+        }
+        assert!(matches!(next(&mut events).await?, Some(ConnectionEvent::Error { .. })));
+        assert_eq!(next(&mut events).await?, None);
+        assert_eq!(next(&mut events).await?, None);
+        assert_eq!(next(&mut tnc.subscribe_events()).await?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn events_subscribe_outside_a_runtime() {
+        let (_sender, receiver) = channel();
+        let handle = VaraTncHandle {
+            control_channel: tokio::sync::mpsc::channel(1).0,
+            status: receiver,
+            command_timeout: DEFAULT_COMMAND_TIMEOUT,
+        };
+        drop(handle.subscribe_events());
+    }
+
+    #[test(tokio::test)]
+    async fn control_trace_sees_both_directions() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        let mut trace = tnc.subscribe_control_trace();
+
+        let (sent, script) = tokio::join!(tnc.send_bandwidth(BandwidthMode::Wide), async {
+            mock.expect_command().await?;
+            mock.send("BUSY ON").await?;
+            mock.send("OK").await
+        });
+        script?;
+        sent?;
+
+        assert_eq!(trace.recv().await?, ControlLine::Sent(bytes::Bytes::from_static(b"BW2300")));
+        assert_eq!(trace.recv().await?, ControlLine::Received(bytes::Bytes::from_static(b"BUSY ON")));
+        assert_eq!(trace.recv().await?, ControlLine::Received(bytes::Bytes::from_static(b"OK")));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn session_outcome_records_forced_disconnect() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        let from = StationId::new("KC1GSL")?;
+        let to = StationId::new("KW1U")?;
+        assert_eq!(tnc.last_session_outcome(), None);
+
+        let (stream, script) = tokio::join!(tnc.connect(from.clone(), to.clone()), async {
+            mock.expect_command().await?;
+            mock.send("OK").await?;
+            mock.send("CONNECTED KC1GSL KW1U").await
+        });
+        script?;
+        drop(stream?);
+        assert_eq!(tnc.last_session_outcome(), Some(SessionOutcome::Forced {
+            last_state: ConnectionStateOwned::Connected {
+                my_station: from.clone(),
+                other_station: to.clone(),
+                bandwidth: None,
+                snr: None,
+            },
+        }));
+        assert_eq!(mock.expect_command().await?, "DISCONNECT");
+        mock.send("OK").await?;
+        mock.send("DISCONNECTED").await?;
+
+        let (disconnected, script) = tokio::join!(
+            async {
+                tnc.handle.status.connection.clone().wait_for(ConnectionStateOwned::is_disconnected).await?;
+                tnc.connect(from, to).await?.disconnect().await
+            },
+            async {
+                assert_eq!(mock.expect_command().await?, "CONNECT KC1GSL KW1U");
+                mock.send("OK").await?;
+                mock.send("CONNECTED KC1GSL KW1U").await?;
+                assert_eq!(mock.expect_command().await?, "DISCONNECT");
+                mock.send("OK").await?;
+                mock.send("DISCONNECTED").await
+            },
+        );
+        script?;
+        disconnected?;
+        assert_eq!(tnc.last_session_outcome(), Some(SessionOutcome::Disconnected));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn registration_arrives_after_callsign() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        let call = StationId::new("KC1GSL")?;
+
+        let (sent, script) = tokio::join!(tnc.send_callsign(call.clone()), async {
+            mock.expect_command().await?;
+            mock.send("OK").await
+        });
+        script?;
+        sent?;
+        assert_eq!(tnc.local_registration(&call), Registration::Unregistered);
+
+        let (registration, script) = tokio::join!(tnc.await_registration(&call, Duration::from_secs(5)), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            mock.send("REGISTERED KC1GSL").await
+        });
+        script?;
+        assert_eq!(registration, Registration::Registered);
+
+        let other = StationId::new("KW1U")?;
+        assert_eq!(tnc.await_registration(&other, Duration::from_millis(50)).await, Registration::Unregistered);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn registered_calls_collects_each_call() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        let mut calls = tnc.subscribe_registered_calls();
+        assert!(tnc.registered_calls().is_empty());
+
+        mock.send("REGISTERED KC1GSL").await?;
+        mock.send("REGISTERED KC1GSL-10").await?;
+        calls.wait_for(|calls| calls.len() == 2).await?;
+
+        let expected = [StationId::new("KC1GSL")?, StationId::new("KC1GSL-10")?].into_iter().collect();
+        assert_eq!(tnc.registered_calls(), expected);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn send_callsigns_lists_secondary_calls() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        let secondary = vec![StationId::new("KC1GSL")?, StationId::new("KW1U")?];
+
+        let (sent, script) = tokio::join!(tnc.send_callsigns(StationId::new("W1AW")?, secondary.clone()), async {
+            assert_eq!(mock.expect_command().await?, "MYCALL W1AW KC1GSL KW1U");
+            mock.send("OK").await
+        });
+        script?;
+        sent?;
+
+        let calls = MyCallSigns::from((StationId::new("W1AW")?, secondary));
+        assert_eq!(calls.to_string(), "W1AW KC1GSL KW1U");
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn rejected_callsign_is_invalid_callsign() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        let call = StationId::new("KC1GSL-7")?;
+
+        let (sent, script) = tokio::join!(tnc.send_callsign(call.clone()), async {
+            assert_eq!(mock.expect_command().await?, "MYCALL KC1GSL-7");
+            mock.send("WRONG").await
+        });
+        script?;
+        let err = sent.unwrap_err();
+        assert_eq!(err.downcast_ref::<InvalidCallsign>(), Some(&InvalidCallsign { calls: MyCallSigns::from(call) }));
+        assert!(err.to_string().contains("KC1GSL-7"));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn send_raw_returns_answer() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+
+        let (result, script) = tokio::join!(tnc.send_raw("P2P SESSION ON"), async {
+            assert_eq!(mock.expect_command().await?, "P2P SESSION ON");
+            mock.send("OK").await
+        });
+        script?;
+        assert_eq!(result?, CommandResult::Ok);
+
+        let (result, script) = tokio::join!(tnc.send_raw("FROBNICATE"), async {
+            assert_eq!(mock.expect_command().await?, "FROBNICATE");
+            mock.send("WRONG").await
+        });
+        script?;
+        assert_eq!(result?, CommandResult::Wrong);
+
+        assert!(tnc.send_raw("LISTEN ON\rABORT").await.is_err());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn cloned_handles_issue_commands() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        let first = tnc.handle();
+        let second = first.clone();
+
+        let public = tokio::spawn(async move { first.set_public(true).await });
+        assert_eq!(mock.expect_command().await?, "PUBLIC ON");
+        mock.send("OK").await?;
+        public.await??;
+
+        let version = tokio::spawn(async move {
+            second.send_version().await?;
+            color_eyre::Result::<_>::Ok(second.version())
+        });
+        assert_eq!(mock.expect_command().await?, "VERSION");
+        mock.send("VERSION 4.8.3").await?;
+        assert_eq!(version.await??.as_deref(), Some("4.8.3"));
+        assert_eq!(tnc.version().as_deref(), Some("4.8.3"));
+        Ok(())
+    }
+
+    #[test]
+    fn modes_round_trip_through_strings() {
+        for mode in [BandwidthMode::Narrow, BandwidthMode::Wide, BandwidthMode::Tactical] {
+            assert_eq!(mode.to_string().parse(), Ok(mode));
+        }
+        for mode in [CompressionMode::Off, CompressionMode::Text, CompressionMode::Files] {
+            assert_eq!(mode.to_string().parse(), Ok(mode));
+            assert_eq!(mode.to_string().to_lowercase().parse(), Ok(mode));
+        }
+        assert_eq!("2000".parse::<BandwidthMode>(), Err(InvalidBandwidthMode));
+        assert_eq!("wide".parse::<BandwidthMode>(), Err(InvalidBandwidthMode));
+        assert_eq!("ZIP".parse::<CompressionMode>(), Err(InvalidCompressionMode));
+    }
+
+    #[test]
+    fn builder_validates_ports() -> color_eyre::Result<()> {
+        let mut builder = VaraTnc::builder();
+        assert_eq!(builder.validate()?.get(), 8301);
+
+        builder.control_port(NonZeroU16::new(u16::MAX).unwrap());
+        let err = builder.validate().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("65535"));
+
+        builder.data_port(NonZeroU16::new(8400).unwrap());
+        assert_eq!(builder.validate()?.get(), 8400);
+
+        builder.control_port(NonZeroU16::new(8400).unwrap());
+        let err = builder.validate().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("must differ"));
+        Ok(())
+    }
+
+    #[test]
+    fn builder_rejects_empty_terminator() {
+        let err = VaraTnc::builder().command_terminator("").validate().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("terminator"));
+    }
+
+    #[test(tokio::test)]
+    async fn build_rejects_colliding_ports() {
+        let err = VaraTnc::builder()
+            .control_port(NonZeroU16::new(8300).unwrap())
+            .data_port(NonZeroU16::new(8300).unwrap())
+            .build()
+            .await
+            .map(drop)
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<std::io::Error>().map(std::io::Error::kind), Some(std::io::ErrorKind::InvalidInput));
+    }
+
+    #[test]
+    fn status_diff_lists_changed_fields() -> color_eyre::Result<()> {
+        let prev = TncStatus {
+            busy_state: BusyState::NotBusy,
+            buffer: 0,
+            registered_calls: Default::default(),
+            last_heartbeat: Instant::now(),
+            connection: ConnectionStateOwned::Disconnected,
+            transceiver_control: TransceiverCommand::Receive { delay: None },
+            remote_registration: Registration::Unregistered,
+        };
+        let next = TncStatus {
+            busy_state: BusyState::Busy,
+            buffer: 1500,
+            last_heartbeat: Instant::now(),
+            ..prev.clone()
+        };
+
+        let changes: Vec<String> = next.diff(&prev).iter().map(ToString::to_string).collect();
+        assert_eq!(changes, ["busy: NotBusy→Busy", "buffer: 0→1500"]);
+        assert!(next.diff(&next).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn connected_rejects_malformed_calls() {
+        let (_, state) = connected(b"CONNECTED KC1GSL KW1U-7").unwrap();
+        assert!(matches!(
+            state,
+            ConnectionState::Connected { my_station, other_station, bandwidth: None, snr: None }
+                if my_station.as_str() == "KC1GSL" && other_station.as_str() == "KW1U-7"
+        ));
+        assert!(connected(b"CONNECTED 12 34").is_err());
+        assert!(connected(b"CONNECTED KC1GSL 1ABC").is_err());
+    }
+
+    #[test]
+    fn connected_with_bandwidth_and_snr() -> color_eyre::Result<()> {
+        let (_, state) = nom::combinator::all_consuming(connection_state)(b"CONNECTED KC1GSL KW1U 2300 -4").map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert!(matches!(
+            state,
+            ConnectionState::Connected { my_station, other_station, bandwidth: Some(2300), snr: Some(-4) }
+                if my_station.as_str() == "KC1GSL" && other_station.as_str() == "KW1U"
+        ));
+
+        let (_, state) = nom::combinator::all_consuming(connection_state)(b"CONNECTED KC1GSL KW1U").map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert_eq!(state.into_owned(), ConnectionStateOwned::Connected {
+            my_station: StationId::new("KC1GSL")?,
+            other_station: StationId::new("KW1U")?,
+            bandwidth: None,
+            snr: None,
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn version_between_heartbeats() -> color_eyre::Result<()> {
+        let data = b"IAMALIVE\rVERSION 4.8.3\rIAMALIVE\r";
+        let (rest, first) = line(data).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert!(matches!(update(first), Ok((b"", Update::Heartbeat))));
+        let (rest, second) = line(rest).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert!(matches!(update(second), Ok((b"", Update::Version("4.8.3")))));
+        let (_, third) = line(rest).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert!(matches!(update(third), Ok((b"", Update::Heartbeat))));
+        assert!(update(b"VERSION ").is_err());
+        assert_eq!(Command::Version.to_string(), "VERSION");
+        Ok(())
+    }
+
+    fn control_lines() -> impl proptest::strategy::Strategy<Value = Vec<u8>> {
+        use proptest::prelude::*;
+        // Arbitrary bytes rarely get past the first tag, so also build lines from the
+        // words VARA uses.
+        prop_oneof![
+            proptest::collection::vec(any::<u8>(), 0..64),
+            "(OK|WRONG|IAMALIVE|BUFFER|BUSY|PTT|PENDING|CANCELPENDING|CONNECTED|DISCONNECTED|REGISTERED|VERSION|SN|SNR|BITRATE)( [A-Za-z0-9.()/-]{0,12}){0,4}"
+                .prop_map(String::into_bytes),
+        ]
+    }
+
+    proptest::proptest! {
+        #![proptest_config(proptest::test_runner::Config {
+            cases: 512,
+            rng_seed: proptest::test_runner::RngSeed::Fixed(0x5641_5241),
+            ..proptest::test_runner::Config::default()
+        })]
+
+        #[test]
+        fn tnc_response_never_panics(line in control_lines()) {
+            let _ = tnc_response(&line);
+            let _ = nom::combinator::all_consuming(tnc_response)(&line);
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn unrecognized_lines_are_skipped() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+
+        mock.send("BUSY ON").await?;
+        mock.send("FROBNICATE 12 \u{1}").await?;
+        mock.send("").await?;
+        mock.send("BUFFER 42").await?;
+        let (sent, script) = tokio::join!(tnc.send_version(), async {
+            assert_eq!(mock.expect_command().await?, "VERSION");
+            mock.send("VERSION 4.8.3").await
+        });
+        script?;
+        sent?;
+        assert_eq!(tnc.busy_state(), BusyState::Busy);
+        assert_eq!(tnc.buffer(), 42);
+        Ok(())
+    }
+
+    #[test]
+    fn parses_captured_control_stream() -> color_eyre::Result<()> {
+        let capture = b"OK\rIAMALIVE\rPENDING\rCONNECTED KC1GSL KW1U 2300 -4\r\rBUFFER 120\rFROBNICATE\rPTT ON\rWRONG\rDISCONN";
+        let mut responses = parse_control_stream(capture);
+
+        assert_eq!(responses.next().transpose()?, Some(TncResponse::CommandResult(CommandResult::Ok)));
+        assert_eq!(responses.next().transpose()?, Some(TncResponse::Update(Update::Heartbeat)));
+        assert_eq!(responses.next().transpose()?, Some(TncResponse::Update(Update::Connection(ConnectionState::Pending))));
+        assert_eq!(responses.next().transpose()?, Some(TncResponse::Update(Update::Connection(ConnectionState::Connected {
+            my_station: &StationIdRef::from_str("KC1GSL")?,
+            other_station: &StationIdRef::from_str("KW1U")?,
+            bandwidth: Some(2300),
+            snr: Some(-4),
+        }))));
+        assert_eq!(responses.next().transpose()?, Some(TncResponse::Update(Update::Buffer { bytes_remaining: 120 })));
+        assert!(matches!(responses.next(), Some(Err(_))));
+        assert_eq!(responses.next().transpose()?, Some(TncResponse::Update(Update::TransceiverControl(TransceiverCommand::Transmit { delay: None }))));
+        assert_eq!(responses.next().transpose()?, Some(TncResponse::CommandResult(CommandResult::Wrong)));
+        assert!(responses.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn transmit_state_with_timing() {
+        let control = |line: &'static [u8]| match nom::combinator::all_consuming(update)(line) {
+            Ok((_, Update::TransceiverControl(control))) => Some(control),
+            _ => None,
+        };
+
+        assert_eq!(control(b"PTT ON"), Some(TransceiverCommand::Transmit { delay: None }));
+        assert_eq!(control(b"PTT OFF"), Some(TransceiverCommand::Receive { delay: None }));
+        assert_eq!(control(b"PTT ON 50"), Some(TransceiverCommand::Transmit { delay: Some(Duration::from_millis(50)) }));
+        assert_eq!(control(b"PTT OFF 20ms"), Some(TransceiverCommand::Receive { delay: Some(Duration::from_millis(20)) }));
+        assert_eq!(control(b"PTT ON TXDELAY 120"), Some(TransceiverCommand::Transmit { delay: Some(Duration::from_millis(120)) }));
+        assert_eq!(control(b"PTT ON VOX"), Some(TransceiverCommand::Transmit { delay: None }));
+        assert_eq!(control(b"PTT ONX"), None);
+    }
+
+    #[test]
+    fn link_stats_formats() {
+        let stats = |line: &'static [u8]| match nom::combinator::all_consuming(update)(line) {
+            Ok((_, Update::LinkStats(stats))) => Some(stats),
+            _ => None,
+        };
+
+        assert_eq!(stats(b"SN 12.6"), Some(LinkStats { snr: Some(13), throughput_bps: None }));
+        assert_eq!(stats(b"SN -3.4"), Some(LinkStats { snr: Some(-3), throughput_bps: None }));
+        assert_eq!(stats(b"SNR -7 dB"), Some(LinkStats { snr: Some(-7), throughput_bps: None }));
+        assert_eq!(stats(b"BITRATE (7) 2437 bps"), Some(LinkStats { snr: None, throughput_bps: Some(2437) }));
+        assert_eq!(stats(b"BITRATE 1200"), Some(LinkStats { snr: None, throughput_bps: Some(1200) }));
+        assert_eq!(stats(b"SN n/a"), Some(LinkStats::default()));
+        assert_eq!(stats(b"BITRATE (3)"), Some(LinkStats::default()));
+    }
+
+    #[test(tokio::test)]
+    async fn link_stats_accumulate_per_connection() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        let mut stats = tnc.subscribe_link_stats();
+
+        mock.send("CONNECTED KC1GSL KW1U 2300 -4").await?;
+        mock.send("BITRATE (5) 1723 bps").await?;
+        stats.wait_for(|stats| stats.throughput_bps.is_some()).await?;
+        assert_eq!(*stats.borrow(), LinkStats { snr: Some(-4), throughput_bps: Some(1723) });
+
+        mock.send("SN 6.1").await?;
+        stats.wait_for(|stats| stats.snr == Some(6)).await?;
+        assert_eq!(stats.borrow().throughput_bps, Some(1723));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn version_reply_completes_command() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        assert_eq!(tnc.version(), None);
+
+        let (sent, mocked) = tokio::join!(tnc.send_version(), async {
+            assert_eq!(mock.expect_command().await?, "VERSION");
+            mock.send("IAMALIVE").await?;
+            mock.send("VERSION 4.8.3").await?;
+            color_eyre::Result::<()>::Ok(())
+        });
+        mocked?;
+        sent?;
+        assert_eq!(tnc.version().as_deref(), Some("4.8.3"));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn clean_buffer_resets_buffer() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+        mock.send("BUFFER 1500").await?;
+        while tnc.buffer() != 1500 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let (cleaned, script) = tokio::join!(tnc.clean_buffer(), async {
+            assert_eq!(mock.expect_command().await?, "CLEANTXBUFFER");
+            mock.send("OK").await
+        });
+        script?;
+        cleaned?;
+        assert_eq!(tnc.buffer(), 0);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn unasked_version_does_not_answer_other_commands() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(&mut VaraTnc::builder()).await?;
+
+        let (sent, script) = tokio::join!(tnc.set_public(true), async {
+            assert_eq!(mock.expect_command().await?, "PUBLIC ON");
+            mock.send("VERSION 4.8.3").await?;
+            mock.send("WRONG").await
+        });
+        script?;
+        assert!(sent.is_err());
+        assert_eq!(tnc.version().as_deref(), Some("4.8.3"));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn command_times_out_without_reply() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(VaraTnc::builder().command_timeout(Duration::from_millis(50))).await?;
+
+        let (sent, command) = tokio::join!(tnc.send_listen(ListenMode::Enable), mock.expect_command());
+        assert_eq!(command?, "LISTEN ON");
+        let err = sent.unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{err}");
+
+        // The TNC never answers LISTEN, so the OK has to be the answer to PUBLIC.
+        let (sent, script) = tokio::join!(tnc.set_public(true), async {
+            assert_eq!(mock.expect_command().await?, "PUBLIC ON");
+            mock.send("OK").await
+        });
+        script?;
+        sent?;
+
+        // Nor does the queue stay off by one for the command after that.
+        let (sent, script) = tokio::join!(tnc.set_public(false), async {
+            assert_eq!(mock.expect_command().await?, "PUBLIC OFF");
+            mock.send("WRONG").await
+        });
+        script?;
+        assert!(sent.is_err());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn late_reply_is_used_up_on_timed_out_command() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(VaraTnc::builder().command_timeout(Duration::from_millis(50))).await?;
+
+        let (sent, command) = tokio::join!(tnc.send_listen(ListenMode::Enable), mock.expect_command());
+        assert_eq!(command?, "LISTEN ON");
+        assert!(sent.is_err());
+
+        // Give the late OK time to arrive before the next command goes out.
+        mock.send("OK").await?;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let (sent, script) = tokio::join!(tnc.set_public(true), async {
+            assert_eq!(mock.expect_command().await?, "PUBLIC ON");
+            mock.send("WRONG").await
+        });
+        script?;
+        assert!(sent.is_err());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn build_applies_initial_settings_in_order() -> color_eyre::Result<()> {
+        let control = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+        let data = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+        let mut builder = VaraTnc::builder();
+        builder
+            .control_port(NonZeroU16::new(control.local_addr()?.port()).unwrap())
+            .data_port(NonZeroU16::new(data.local_addr()?.port()).unwrap())
+            .my_call(StationId::new("KC1GSL")?)
+            .default_compression(CompressionMode::Text)
+            .default_bandwidth(BandwidthMode::Wide);
+
+        let script = |answer: &'static str, rejected: &'static str| {
+            let (control, data) = (&control, &data);
+            async move {
+                let (control, data) = tokio::join!(control.accept(), data.accept());
+                let mut mock = MockTnc {
+                    control: BufReader::new(control?.0),
+                    data: BufReader::new(data?.0),
+                };
+                let mut commands = Vec::new();
+                for _ in 0..3 {
+                    let command = mock.expect_command().await?;
+                    let wrong = command.starts_with(rejected);
+                    commands.push(command);
+                    mock.send(if wrong { answer } else { "OK" }).await?;
+                    if wrong {
+                        break;
+                    }
+                }
+                color_eyre::Result::<_>::Ok((mock, commands))
+            }
+        };
+
+        let (tnc, script_result) = tokio::join!(builder.build(), script("OK", "BW"));
+        let (_mock, commands) = script_result?;
+        assert_eq!(commands, ["MYCALL KC1GSL", "COMPRESSION TEXT", "BW2300"]);
+        drop(tnc?);
+
+        let (tnc, script_result) = tokio::join!(builder.build(), script("WRONG", "BW"));
+        script_result?;
+        assert!(tnc.is_err());
+
+        let (tnc, script_result) = tokio::join!(builder.build(), script("WRONG", "MYCALL"));
+        let (_mock, commands) = script_result?;
+        assert_eq!(commands, ["MYCALL KC1GSL"]);
+        let err = tnc.map(drop).unwrap_err();
+        assert_eq!(err.downcast_ref::<InvalidCallsign>(), Some(&InvalidCallsign { calls: MyCallSigns::from(StationId::new("KC1GSL")?) }));
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn closed_control_connection_fails_commands() -> color_eyre::Result<()> {
+        let control = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+        let data = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+        let mut builder = VaraTnc::builder();
+        builder
+            .control_port(NonZeroU16::new(control.local_addr()?.port()).unwrap())
+            .data_port(NonZeroU16::new(data.local_addr()?.port()).unwrap());
+        let (tnc, control, _data) = tokio::join!(builder.build(), control.accept(), data.accept());
+        let tnc = tnc?;
+        drop(control?);
+
+        let sent = tokio::time::timeout(Duration::from_secs(5), tnc.send_version()).await?;
+        assert!(sent.unwrap_err().to_string().contains("closed"));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !tnc.managing_thread.is_finished() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        }).await?;
+        assert!(tnc.send_version().await.is_err());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn command_terminator_is_honored() -> color_eyre::Result<()> {
+        let (tnc, mut mock) = MockTnc::start(VaraTnc::builder().command_terminator("\r\n")).await?;
+
+        let (sent, script) = tokio::join!(tnc.send_version(), async {
+            let mut line = String::new();
+            mock.control.read_line(&mut line).await?;
+            assert_eq!(line, "VERSION\r\n");
+            mock.send("OK").await
+        });
+        script?;
+        sent?;
+        Ok(())
+    }
+
+    #[test]
+    fn settings_commands_display() {
+        assert_eq!(Command::SetPublic(true).to_string(), "PUBLIC ON");
+        assert_eq!(Command::SetPublic(false).to_string(), "PUBLIC OFF");
+        assert_eq!(Command::CleanBuffer.to_string(), "CLEANTXBUFFER");
+        assert_eq!(Command::CwId { enabled: true }.to_string(), "CWID ON");
+        assert_eq!(Command::CwId { enabled: false }.to_string(), "CWID OFF");
+        assert_eq!(Command::SetBandwidth(BandwidthMode::Wide).to_string(), "BW2300");
+        assert_eq!(Command::SetCompression(CompressionMode::Text).to_string(), "COMPRESSION TEXT");
     }
 }
@@ -1,5 +1,5 @@
 use std::fmt;
-use nom::error::{Error, VerboseError};
+use nom::error::{Error, VerboseError, VerboseErrorKind};
 use nom::IResult;
 
 #[derive(Clone, Copy, Eq, PartialEq)]
@@ -8,30 +8,85 @@ pub enum StrOrByteSlice<'a> {
     Bytes(&'a [u8]),
 }
 
+impl<'a> StrOrByteSlice<'a> {
+    /// Renders the input as text for log messages, replacing any invalid UTF-8,
+    /// rather than as the hex listing used by `Display`.
+    pub fn to_lossy_string(&self) -> String {
+        match self {
+            Self::Str(s) => (*s).to_owned(),
+            Self::Bytes(bs) => String::from_utf8_lossy(bs).into_owned(),
+        }
+    }
+}
+
+/// Bytes beyond this many are summarized rather than listed.
+const MAX_LISTED_BYTES: usize = 256;
+
+/// Lists bytes in hex, as in `[0x4F, 0x4B]`, writing straight to the formatter.
+fn write_hex(bs: &[u8], f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("[")?;
+    for (i, b) in bs.iter().take(MAX_LISTED_BYTES).enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{:#04X}", b)?;
+    }
+    if bs.len() > MAX_LISTED_BYTES {
+        write!(f, ", … {} more", bs.len() - MAX_LISTED_BYTES)?;
+    }
+    f.write_str("]")
+}
+
+/// Renders `data` the way `hexdump -C` does: an offset, sixteen bytes in hex, and the same
+/// bytes as ASCII on each row, with `.` for anything unprintable. Rows are separated by
+/// newlines, with none after the last.
+pub fn hexdump(data: &[u8]) -> impl fmt::Display + '_ {
+    HexDump(data)
+}
+
+struct HexDump<'a>(&'a [u8]);
+
+impl<'a> fmt::Display for HexDump<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (row, bytes) in self.0.chunks(16).enumerate() {
+            if row > 0 {
+                f.write_str("\n")?;
+            }
+            write!(f, "{:08x}  ", row * 16)?;
+            for i in 0..16 {
+                match bytes.get(i) {
+                    Some(b) => write!(f, "{:02x} ", b)?,
+                    None => f.write_str("   ")?,
+                }
+                if i == 7 {
+                    f.write_str(" ")?;
+                }
+            }
+            f.write_str(" |")?;
+            for &b in bytes {
+                let c = if b.is_ascii_graphic() || b == b' ' { char::from(b) } else { '.' };
+                write!(f, "{}", c)?;
+            }
+            f.write_str("|")?;
+        }
+        Ok(())
+    }
+}
+
 impl<'a> fmt::Debug for StrOrByteSlice<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        use fmt::Write;
         match self {
             Self::Str(s) => fmt::Debug::fmt(s, f),
-            Self::Bytes(bs) => {
-                f.debug_list()
-                    .entries(bs.iter().map(|b| format!("{:#04X}", b)))
-                    .finish()
-            }
+            Self::Bytes(bs) => write_hex(bs, f),
         }
     }
 }
 
 impl<'a> fmt::Display for StrOrByteSlice<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        use fmt::Write;
         match self {
             Self::Str(s) => fmt::Display::fmt(s, f),
-            Self::Bytes(bs) => {
-                f.debug_list()
-                    .entries(bs.iter().map(|b| format!("{:#04X}", b)))
-                    .finish()
-            }
+            Self::Bytes(bs) => write_hex(bs, f),
         }
     }
 }
@@ -119,3 +174,105 @@ impl<'a> MappableParserInputError for Error<&'a str> {
         }
     }
 }
+
+/// A [`VerboseError`] holding copies of the input at each failure, so that it can
+/// outlive the buffer that was being parsed.
+#[derive(Clone, Debug, PartialEq, thiserror::Error)]
+pub struct OwnedVerboseError {
+    pub errors: Vec<(String, VerboseErrorKind)>,
+}
+
+impl fmt::Display for OwnedVerboseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "parse error:")?;
+        for (input, kind) in &self.errors {
+            match kind {
+                VerboseErrorKind::Nom(e) => writeln!(f, "{:?} at: {:?}", e, input)?,
+                VerboseErrorKind::Char(c) => writeln!(f, "expected '{}' at: {:?}", c, input)?,
+                VerboseErrorKind::Context(s) => writeln!(f, "in section '{}', at: {:?}", s, input)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) trait ToOwnedError {
+    type Output;
+    fn to_owned_error(self) -> Self::Output;
+}
+
+impl<X: ToOwnedError> ToOwnedError for nom::Err<X> {
+    type Output = nom::Err<X::Output>;
+    fn to_owned_error(self) -> Self::Output {
+        self.map(ToOwnedError::to_owned_error)
+    }
+}
+
+impl<'a> ToOwnedError for VerboseError<StrOrByteSlice<'a>> {
+    type Output = OwnedVerboseError;
+    fn to_owned_error(self) -> Self::Output {
+        OwnedVerboseError {
+            errors: self.errors.into_iter().map(|(input, kind)| (input.to_lossy_string(), kind)).collect()
+        }
+    }
+}
+
+impl ToOwnedError for VerboseError<&[u8]> {
+    type Output = OwnedVerboseError;
+    fn to_owned_error(self) -> Self::Output {
+        self.try_map_into_str().to_owned_error()
+    }
+}
+
+impl ToOwnedError for VerboseError<&str> {
+    type Output = OwnedVerboseError;
+    fn to_owned_error(self) -> Self::Output {
+        self.try_map_into_str().to_owned_error()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lossy_string() {
+        assert_eq!(StrOrByteSlice::Str("CONNECTED KC1GSL").to_lossy_string(), "CONNECTED KC1GSL");
+        assert_eq!(StrOrByteSlice::Bytes(b"CONNECTED KC1GSL \xFF").to_lossy_string(), "CONNECTED KC1GSL \u{FFFD}");
+        assert_eq!(StrOrByteSlice::Bytes(b"OK").to_string(), "[0x4F, 0x4B]");
+        assert_eq!(format!("{:?}", StrOrByteSlice::Bytes(b"")), "[]");
+    }
+
+    #[test]
+    fn large_byte_slices_are_capped() {
+        let frame = vec![0xA5; 4096];
+        let listed = StrOrByteSlice::Bytes(&frame).to_string();
+        assert!(listed.starts_with("[0xA5, 0xA5, "));
+        assert!(listed.ends_with(", 0xA5, … 3840 more]"));
+        assert_eq!(listed.matches("0xA5").count(), MAX_LISTED_BYTES);
+        assert_eq!(format!("{:?}", StrOrByteSlice::Bytes(&frame)), listed);
+    }
+
+    #[test]
+    fn hexdump_rows() {
+        let dump = hexdump(b"[RMS-1.2-B2FHM$]\rKW1U >\r\x01\xff").to_string();
+        assert_eq!(dump, concat!(
+            "00000000  5b 52 4d 53 2d 31 2e 32  2d 42 32 46 48 4d 24 5d  |[RMS-1.2-B2FHM$]|\n",
+            "00000010  0d 4b 57 31 55 20 3e 0d  01 ff                    |.KW1U >...|",
+        ));
+        assert_eq!(hexdump(b"").to_string(), "");
+    }
+
+    #[test]
+    fn owned_error_outlives_input() {
+        let error = {
+            let buffer = b"CONNECTED KC1GSL\r".to_vec();
+            let result: IResult<&[u8], &[u8], VerboseError<&[u8]>> = nom::bytes::complete::tag("DISCONNECTED")(&buffer[..]);
+            result.unwrap_err().to_owned_error()
+        };
+
+        let nom::Err::Error(error) = error else { panic!("expected a recoverable error") };
+        assert_eq!(error.errors, vec![(String::from("CONNECTED KC1GSL\r"), VerboseErrorKind::Nom(nom::error::ErrorKind::Tag))]);
+        assert!(error.to_string().contains("CONNECTED KC1GSL"));
+    }
+}
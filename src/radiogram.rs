@@ -0,0 +1,774 @@
+//! The text form of an NTS radiogram, as operators paste it:
+//!
+//! ```text
+//! NR 22 R HXC KC1GSL 21 BILLERICA MA DEC 3
+//! TO BOB SPARKES
+//! KC1KVY
+//! 12 MAIN ST
+//! TEL 978 555 1212
+//! BT
+//! THIS IS A TEST OF A PROGRAM I WROTE TO ASSIST ME IN PUSHING
+//! TRAFFIC INTO THE DIGITAL TRAFFIC NETWORK 73
+//! BT
+//! MARCUS KC1GSL
+//! AR
+//! ```
+//!
+//! The address block may name the addressee's station, address lines, a `TEL` phone
+//! number, an `EMAIL` address, and an `OPNOTE`. The station is a call sign, or a tactical
+//! designator such as `EOC` on the line right after `TO`. The signature may be followed
+//! by its own `OPNOTE`.
+
+use std::fmt;
+
+use nom::branch::alt;
+use nom::bytes::complete::{tag, take_till1, take_while_m_n};
+use nom::character::complete::{alpha1, char, digit1, space0, space1};
+use nom::combinator::{all_consuming, map, map_res, opt, recognize, value, verify};
+use nom::multi::{many1, many_till, separated_list1};
+use nom::sequence::{pair, preceded, terminated, tuple};
+use nom::IResult;
+use thiserror::Error;
+
+use crate::{Addressable, Check, ContentType, Destination, Handling, HandlingDirective, Precedence, ServiceType, Signature, StationId, Traffic, TrafficHeader, TrafficType};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TrafficParseError {
+    #[error("invalid preamble")]
+    InvalidPreamble,
+    #[error("missing addressee")]
+    MissingAddressee,
+    #[error("missing break before the body")]
+    MissingBody,
+    #[error("missing break before the signature")]
+    MissingSignature,
+    #[error("unexpected line after the signature: {0}")]
+    UnexpectedLine(String),
+}
+
+fn word(s: &str) -> IResult<&str, &str> {
+    take_till1(|c: char| c == ' ' || c == '\t')(s)
+}
+
+fn precedence(s: &str) -> IResult<&str, Precedence> {
+    alt((
+        value(Precedence::Emergency, tag("EMERGENCY")),
+        value(Precedence::Priority, tag("P")),
+        value(Precedence::Welfare, tag("W")),
+        value(Precedence::Routine, tag("R")),
+    ))(s)
+}
+
+fn hx_action(s: &str) -> IResult<&str, HandlingDirective> {
+    alt((
+        map(preceded(char('A'), nom::character::complete::u16), |distance| HandlingDirective::LandlineCollect { distance }),
+        map(preceded(char('B'), nom::character::complete::u8), |hours| HandlingDirective::DeliverWithin { hours }),
+        value(HandlingDirective::ReportDelivery, char('C')),
+        value(HandlingDirective::TraceRelayAndDelivery, char('D')),
+        value(HandlingDirective::RequestReply, char('E')),
+        map(preceded(char('F'), digit1), |date: &str| HandlingDirective::HoldUntil { date: date.to_owned() }),
+        value(HandlingDirective::CancelIfFeeRequired, char('G')),
+    ))(s)
+}
+
+/// A single `HX` token, which may combine several codes, as in `HXCE` or `HXA050G`.
+fn hx_token(s: &str) -> IResult<&str, Vec<HandlingDirective>> {
+    preceded(tag("HX"), many1(hx_action))(s)
+}
+
+fn handling(s: &str) -> IResult<&str, Handling> {
+    map(separated_list1(space1, hx_token), |tokens| Handling::with_directives(tokens.into_iter().flatten()))(s)
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("invalid HX handling code")]
+pub struct InvalidHxCode;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("invalid precedence")]
+pub struct InvalidPrecedence;
+
+impl Precedence {
+    /// Parses the code used in a preamble. Emergency is only ever spelled out, so `E` is
+    /// not accepted.
+    pub fn from_code(code: &str) -> Result<Self, InvalidPrecedence> {
+        all_consuming(precedence)(code)
+            .map(|(_, precedence)| precedence)
+            .map_err(|_| InvalidPrecedence)
+    }
+
+    pub fn as_code(&self) -> &'static str {
+        match self {
+            Precedence::Routine => "R",
+            Precedence::Welfare => "W",
+            Precedence::Priority => "P",
+            Precedence::Emergency => "EMERGENCY",
+        }
+    }
+}
+
+impl HandlingDirective {
+    /// Parses a single code, such as `HXC` or `HXA050`.
+    pub fn from_hx_code(code: &str) -> Result<Self, InvalidHxCode> {
+        all_consuming(preceded(tag("HX"), hx_action))(code)
+            .map(|(_, directive)| directive)
+            .map_err(|_| InvalidHxCode)
+    }
+
+    /// The canonical code, with distances and hours written as three digits.
+    pub fn as_hx_code(&self) -> String {
+        match self {
+            HandlingDirective::LandlineCollect { distance } => format!("HXA{:03}", distance),
+            HandlingDirective::DeliverWithin { hours } => format!("HXB{:03}", hours),
+            HandlingDirective::ReportDelivery => String::from("HXC"),
+            HandlingDirective::TraceRelayAndDelivery => String::from("HXD"),
+            HandlingDirective::RequestReply => String::from("HXE"),
+            HandlingDirective::HoldUntil { date } => format!("HXF{}", date),
+            HandlingDirective::CancelIfFeeRequired => String::from("HXG"),
+        }
+    }
+}
+
+impl fmt::Display for HandlingDirective {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.as_hx_code())
+    }
+}
+
+impl Handling {
+    /// Parses the handling codes from a preamble, whether combined as in `HXCE` or
+    /// separated by spaces as in `HXC HXE`.
+    pub fn from_hx_codes(codes: &str) -> Result<Self, InvalidHxCode> {
+        all_consuming(handling)(codes)
+            .map(|(_, handling)| handling)
+            .map_err(|_| InvalidHxCode)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.directives.is_empty()
+    }
+}
+
+/// Renders each code in turn, in a stable order, separated by spaces.
+impl fmt::Display for Handling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut directives: Vec<_> = self.directives.iter().collect();
+        directives.sort();
+        for (i, directive) in directives.into_iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{}", directive)?;
+        }
+        Ok(())
+    }
+}
+
+/// The group count, after `ARL` when the body uses ARL numbered radiograms, as in `21`,
+/// `ARL 8`, or `ARL8`. An `R` just before the count, as in `R21`, marks it as revised.
+fn check(s: &str) -> IResult<&str, Check> {
+    map(
+        tuple((opt(terminated(tag("ARL"), space0)), opt(char('R')), nom::character::complete::u16)),
+        |(arl, revised, count)| Check {
+            content: if arl.is_some() { ContentType::Arl } else { ContentType::Standard },
+            count,
+            revised: revised.is_some(),
+        },
+    )(s)
+}
+
+fn time_filed(s: &str) -> IResult<&str, &str> {
+    recognize(pair(take_while_m_n(4, 4, |c: char| c.is_ascii_digit()), opt(alpha1)))(s)
+}
+
+fn date(s: &str) -> IResult<&str, &str> {
+    recognize(tuple((
+        alt((
+            tag("JAN"), tag("FEB"), tag("MAR"), tag("APR"), tag("MAY"), tag("JUN"),
+            tag("JUL"), tag("AUG"), tag("SEP"), tag("OCT"), tag("NOV"), tag("DEC"),
+        )),
+        space1,
+        take_while_m_n(1, 2, |c: char| c.is_ascii_digit()),
+    )))(s)
+}
+
+fn service(s: &str) -> IResult<&str, &str> {
+    terminated(alt((tag("SERVICE"), tag("SVC"))), space1)(s)
+}
+
+fn preamble(s: &str) -> IResult<&str, TrafficHeader> {
+    let (s, leading_service) = opt(service)(s)?;
+    let (s, number) = preceded(opt(pair(tag("NR"), space1)), terminated(nom::character::complete::u16, space1))(s)?;
+    let (s, trailing_service) = opt(service)(s)?;
+    let service = if leading_service.or(trailing_service).is_some() { ServiceType::Service } else { ServiceType::Normal };
+    let (s, traffic_type) = map(opt(terminated(tag("TEST"), space1)), |test| if test.is_some() { TrafficType::Test } else { TrafficType::Normal })(s)?;
+    let (s, precedence) = terminated(precedence, space1)(s)?;
+    let (s, handling) = map(opt(terminated(handling, space1)), Option::unwrap_or_default)(s)?;
+    let (s, originator) = terminated(map_res(word, StationId::new), space1)(s)?;
+    let (s, check) = terminated(check, space1)(s)?;
+    let (s, (origin, (time_filed, date))) = verify(
+        many_till(terminated(word, space1), all_consuming(pair(opt(terminated(time_filed, space1)), date))),
+        |(origin, _): &(Vec<&str>, _)| !origin.is_empty(),
+    )(s)?;
+
+    Ok((s, TrafficHeader {
+        service,
+        number,
+        traffic_type,
+        precedence,
+        handling,
+        originator,
+        check,
+        origin: origin.join(" "),
+        time_filed: time_filed.map(str::to_owned),
+        date: date.to_owned(),
+    }))
+}
+
+/// A single word of capital letters and digits, such as `EOC` or `SHELTER1`. Only the
+/// line right after `TO` is taken as a tactical station, where a call sign would go.
+fn is_tactical(line: &str) -> bool {
+    line.bytes().any(|b| b.is_ascii_uppercase())
+        && line.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+}
+
+impl Traffic {
+    pub fn parse(input: &str) -> Result<Traffic, TrafficParseError> {
+        let mut lines = input.lines().map(str::trim).filter(|line| !line.is_empty());
+
+        let header = lines.next()
+            .and_then(|line| all_consuming(preamble)(line).ok())
+            .map(|(_, header)| header)
+            .ok_or(TrafficParseError::InvalidPreamble)?;
+
+        let addressee = lines.next()
+            .and_then(|line| line.strip_prefix("TO "))
+            .ok_or(TrafficParseError::MissingAddressee)?;
+        let mut destination = Destination {
+            addressee: addressee.trim().to_owned(),
+            station: None,
+            address: Vec::new(),
+            phone: None,
+            email: None,
+            op_note: None,
+        };
+        let mut first = true;
+        loop {
+            let line = lines.next().ok_or(TrafficParseError::MissingBody)?;
+            let first = std::mem::replace(&mut first, false);
+            if line == "BT" {
+                break;
+            } else if let Some(phone) = line.strip_prefix("TEL ") {
+                destination.phone = Some(phone.trim().to_owned());
+            } else if let Some(email) = line.strip_prefix("EMAIL ") {
+                destination.email = Some(email.trim().to_owned());
+            } else if let Some(note) = line.strip_prefix("OPNOTE ") {
+                destination.op_note = Some(note.trim().to_owned());
+            } else if let (None, Ok(station)) = (&destination.station, StationId::new(line)) {
+                destination.station = Some(Addressable::Station(station));
+            } else if first && is_tactical(line) {
+                destination.station = Some(Addressable::Tactical(line.to_owned()));
+            } else {
+                destination.address.push(line.to_owned());
+            }
+        }
+
+        let mut body = Vec::new();
+        loop {
+            let line = lines.next().ok_or(TrafficParseError::MissingSignature)?;
+            if line == "BT" {
+                break;
+            }
+            body.extend(line.split_whitespace());
+        }
+
+        let mut signature = Signature {
+            signed_by: lines.next().ok_or(TrafficParseError::MissingSignature)?.to_owned(),
+            op_note: None,
+        };
+        let mut rest = lines.peekable();
+        if let Some(note) = rest.peek().and_then(|line| line.strip_prefix("OPNOTE ")) {
+            signature.op_note = Some(note.trim().to_owned());
+            rest.next();
+        }
+        match rest.next() {
+            None | Some("AR") => {}
+            Some(line) => return Err(TrafficParseError::UnexpectedLine(line.to_owned())),
+        }
+
+        Ok(Traffic {
+            header,
+            destination,
+            body: body.join(" "),
+            signature,
+        })
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("check is {stated}, but the body has {computed} groups")]
+pub struct CheckMismatch {
+    pub stated: u16,
+    pub computed: u16,
+}
+
+impl Traffic {
+    /// Counts the groups in the body. Anything between spaces is one group, including
+    /// initials run together, mixed groups like `W1AW`, and `X` standing in for a period.
+    /// A spelled-out ARL number, as in `ARL SIXTY SEVEN`, counts as one group after `ARL`.
+    pub fn computed_check(&self) -> u16 {
+        let groups: Vec<&str> = self.body.split_whitespace().collect();
+        let mut count = 0;
+        let mut i = 0;
+        while i < groups.len() {
+            count += 1;
+            i += 1;
+            if groups[i - 1] == "ARL" {
+                if let Some((_, used)) = crate::arl::spelled_number(&groups[i..]) {
+                    count += 1;
+                    i += used;
+                }
+            }
+        }
+        count
+    }
+
+    /// The body re-flowed into lines of `groups_per_line` groups, counted as for the check,
+    /// for reading on the air or printing. An ARL number stays on the line with its `ARL`,
+    /// moving to the next line together if the two don't fit.
+    pub fn body_grouped(&self, groups_per_line: usize) -> String {
+        let groups_per_line = groups_per_line.max(1);
+        let groups: Vec<&str> = self.body.split_whitespace().collect();
+        let mut lines = Vec::new();
+        let mut line: Vec<&str> = Vec::new();
+        let mut count = 0;
+        let mut i = 0;
+        while i < groups.len() {
+            let (words, counted) = match crate::arl::spelled_number(&groups[i + 1..]) {
+                Some((_, used)) if groups[i] == "ARL" => (1 + used, 2),
+                _ => (1, 1),
+            };
+            if count > 0 && count + counted > groups_per_line {
+                lines.push(line.join(" "));
+                line.clear();
+                count = 0;
+            }
+            line.extend_from_slice(&groups[i..i + words]);
+            count += counted;
+            i += words;
+        }
+        if !line.is_empty() {
+            lines.push(line.join(" "));
+        }
+        lines.join("\n")
+    }
+
+    pub fn verify_check(&self) -> Result<(), CheckMismatch> {
+        let computed = self.computed_check();
+        if computed == self.header.check.count {
+            Ok(())
+        } else {
+            Err(CheckMismatch { stated: self.header.check.count, computed })
+        }
+    }
+}
+
+/// Groups per line when rendering the body, as on a radiogram form.
+const GROUPS_PER_LINE: usize = 5;
+
+impl fmt::Display for Precedence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_code())
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.content == ContentType::Arl {
+            f.write_str("ARL ")?;
+        }
+        if self.revised {
+            f.write_str("R")?;
+        }
+        write!(f, "{}", self.count)
+    }
+}
+
+impl fmt::Display for TrafficHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.service == ServiceType::Service {
+            f.write_str("SVC ")?;
+        }
+        write!(f, "NR {}", self.number)?;
+        if self.traffic_type == TrafficType::Test {
+            f.write_str(" TEST")?;
+        }
+        write!(f, " {}", self.precedence)?;
+        if !self.handling.is_empty() {
+            write!(f, " {}", self.handling)?;
+        }
+        write!(f, " {} {} {}", self.originator, self.check, self.origin)?;
+        if let Some(time_filed) = &self.time_filed {
+            write!(f, " {}", time_filed)?;
+        }
+        write!(f, " {}", self.date)
+    }
+}
+
+/// Renders the radiogram in the same text form that [`Traffic::parse`] reads.
+impl fmt::Display for Traffic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.header)?;
+
+        let destination = &self.destination;
+        writeln!(f, "TO {}", destination.addressee)?;
+        if let Some(station) = &destination.station {
+            writeln!(f, "{}", station)?;
+        }
+        for line in &destination.address {
+            writeln!(f, "{}", line)?;
+        }
+        if let Some(phone) = &destination.phone {
+            writeln!(f, "TEL {}", phone)?;
+        }
+        if let Some(email) = &destination.email {
+            writeln!(f, "EMAIL {}", email)?;
+        }
+        if let Some(note) = &destination.op_note {
+            writeln!(f, "OPNOTE {}", note)?;
+        }
+
+        writeln!(f, "BT")?;
+        for line in self.body_grouped(GROUPS_PER_LINE).lines() {
+            writeln!(f, "{}", line)?;
+        }
+        writeln!(f, "BT")?;
+
+        writeln!(f, "{}", self.signature.signed_by)?;
+        if let Some(note) = &self.signature.op_note {
+            writeln!(f, "OPNOTE {}", note)?;
+        }
+        writeln!(f, "AR")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "\
+NR 22 R HXC KC1GSL 21 BILLERICA MA DEC 3
+TO BOB SPARKES
+KC1KVY
+BT
+THIS IS A TEST OF A PROGRAM I WROTE TO ASSIST ME IN PUSHING
+TRAFFIC INTO THE DIGITAL TRAFFIC NETWORK 73
+BT
+MARCUS KC1GSL
+AR
+";
+
+    #[test]
+    fn parse_sample() -> color_eyre::Result<()> {
+        assert_eq!(Traffic::parse(SAMPLE)?, crate::test());
+        Ok(())
+    }
+
+    #[test]
+    fn body_grouped_keeps_arl_numbers_together() {
+        let mut traffic = crate::test();
+        assert_eq!(traffic.body_grouped(5), "\
+THIS IS A TEST OF
+A PROGRAM I WROTE TO
+ASSIST ME IN PUSHING TRAFFIC
+INTO THE DIGITAL TRAFFIC NETWORK
+73");
+
+        traffic.body = String::from("CONGRATULATIONS ON YOUR NEW ARL FORTY SIX LOVE ARL SIXTY-SEVEN MOM AND DAD");
+        assert_eq!(traffic.body_grouped(5), "\
+CONGRATULATIONS ON YOUR NEW
+ARL FORTY SIX LOVE ARL SIXTY-SEVEN
+MOM AND DAD");
+        assert_eq!(traffic.body_grouped(1), "\
+CONGRATULATIONS
+ON
+YOUR
+NEW
+ARL FORTY SIX
+LOVE
+ARL SIXTY-SEVEN
+MOM
+AND
+DAD");
+
+        traffic.body = String::new();
+        assert_eq!(traffic.body_grouped(5), "");
+    }
+
+    #[test]
+    fn parse_full_radiogram() -> color_eyre::Result<()> {
+        let traffic = Traffic::parse("\
+NR 107 TEST P HXA50 HXE W1AW ARL 8 NEWINGTON CT 1830Z JUN 26
+TO JOHN SMITH
+225 MAIN ST
+NEWINGTON CT 06111
+TEL 860 555 1212
+EMAIL JSMITH@EXAMPLE.COM
+OPNOTE DELIVER BY PHONE
+BT
+ARL FIFTY ARL SIXTY
+BT
+FIELD DAY TEAM
+OPNOTE FROM THE GOTA STATION
+")?;
+
+        assert_eq!(traffic.header.number, 107);
+        assert_eq!(traffic.header.traffic_type, TrafficType::Test);
+        assert_eq!(traffic.header.precedence, Precedence::Priority);
+        assert_eq!(traffic.header.handling, Handling::with_directives([
+            HandlingDirective::LandlineCollect { distance: 50 },
+            HandlingDirective::RequestReply,
+        ]));
+        assert_eq!(traffic.header.check, Check { content: ContentType::Arl, count: 8, revised: false });
+        assert_eq!(traffic.header.origin, "NEWINGTON CT");
+        assert_eq!(traffic.header.time_filed.as_deref(), Some("1830Z"));
+        assert_eq!(traffic.header.date, "JUN 26");
+        assert_eq!(traffic.destination.station, None);
+        assert_eq!(traffic.destination.address, ["225 MAIN ST", "NEWINGTON CT 06111"]);
+        assert_eq!(traffic.destination.phone.as_deref(), Some("860 555 1212"));
+        assert_eq!(traffic.destination.email.as_deref(), Some("JSMITH@EXAMPLE.COM"));
+        assert_eq!(traffic.destination.op_note.as_deref(), Some("DELIVER BY PHONE"));
+        assert_eq!(traffic.body, "ARL FIFTY ARL SIXTY");
+        assert_eq!(traffic.signature.signed_by, "FIELD DAY TEAM");
+        assert_eq!(traffic.signature.op_note.as_deref(), Some("FROM THE GOTA STATION"));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_tactical_station() -> color_eyre::Result<()> {
+        let input = "\
+NR 5 R SHELTER1 3 BILLERICA MA DEC 3
+TO NET CONTROL
+EOC
+BT
+NEED MORE COTS
+BT
+SHELTER MANAGER
+AR
+";
+        assert!(matches!(Traffic::parse(input), Err(TrafficParseError::InvalidPreamble)));
+
+        let input = input.replace("SHELTER1", "KC1GSL");
+        let traffic = Traffic::parse(&input)?;
+        assert_eq!(traffic.destination.station, Some(Addressable::Tactical(String::from("EOC"))));
+        assert_eq!(traffic.destination.address, Vec::<String>::new());
+        assert_eq!(traffic.to_string(), input);
+
+        // Only the first line can be tactical; later single words stay in the address.
+        let traffic = Traffic::parse(&input.replace("EOC\n", "SHELTER1\nGYM\n"))?;
+        assert_eq!(traffic.destination.station, Some(Addressable::Tactical(String::from("SHELTER1"))));
+        assert_eq!(traffic.destination.address, ["GYM"]);
+
+        let traffic = Traffic::parse(&input.replace("EOC", "kc1kvy"))?;
+        assert_eq!(traffic.destination.station.as_ref().and_then(Addressable::as_station).map(|s| s.as_str()), Some("KC1KVY"));
+        Ok(())
+    }
+
+    #[test]
+    fn render_sample() -> color_eyre::Result<()> {
+        let rendered = crate::test().to_string();
+        assert_eq!(rendered, "\
+NR 22 R HXC KC1GSL 21 BILLERICA MA DEC 3
+TO BOB SPARKES
+KC1KVY
+BT
+THIS IS A TEST OF
+A PROGRAM I WROTE TO
+ASSIST ME IN PUSHING TRAFFIC
+INTO THE DIGITAL TRAFFIC NETWORK
+73
+BT
+MARCUS KC1GSL
+AR
+");
+        assert_eq!(Traffic::parse(&rendered)?, crate::test());
+        Ok(())
+    }
+
+    #[test]
+    fn render_round_trips() -> color_eyre::Result<()> {
+        let traffic = Traffic {
+            header: TrafficHeader {
+                service: ServiceType::Service,
+                number: 107,
+                traffic_type: TrafficType::Test,
+                precedence: Precedence::Emergency,
+                handling: Handling::with_directives([
+                    HandlingDirective::RequestReply,
+                    HandlingDirective::DeliverWithin { hours: 24 },
+                    HandlingDirective::HoldUntil { date: String::from("15") },
+                ]),
+                originator: StationId::new("W1AW")?,
+                check: Check { content: ContentType::Arl, count: 8, revised: false },
+                origin: String::from("NEWINGTON CT"),
+                time_filed: Some(String::from("1830Z")),
+                date: String::from("JUN 26"),
+            },
+            destination: Destination {
+                addressee: String::from("JOHN SMITH"),
+                station: None,
+                address: vec![String::from("225 MAIN ST"), String::from("NEWINGTON CT 06111")],
+                phone: Some(String::from("860 555 1212")),
+                email: Some(String::from("JSMITH@EXAMPLE.COM")),
+                op_note: Some(String::from("DELIVER BY PHONE")),
+            },
+            body: String::from("ARL FIFTY ARL SIXTY"),
+            signature: Signature {
+                signed_by: String::from("FIELD DAY TEAM"),
+                op_note: Some(String::from("FROM THE GOTA STATION")),
+            },
+        };
+
+        let rendered = traffic.to_string();
+        assert!(rendered.starts_with("SVC NR 107 TEST EMERGENCY HXB024 HXE HXF15 W1AW ARL 8 NEWINGTON CT 1830Z JUN 26\n"));
+        assert_eq!(Traffic::parse(&rendered)?, traffic);
+        Ok(())
+    }
+
+    #[test]
+    fn service_preamble() -> color_eyre::Result<()> {
+        let mut traffic = crate::test();
+        traffic.header.service = ServiceType::Service;
+        let rendered = traffic.to_string();
+        assert!(rendered.starts_with("SVC NR 22 R HXC KC1GSL 21 BILLERICA MA DEC 3\n"));
+        assert_eq!(Traffic::parse(&rendered)?, traffic);
+
+        for spelled in ["SERVICE NR 22", "SVC 22", "NR 22 SVC", "22 SERVICE"] {
+            let input = rendered.replacen("SVC NR 22", spelled, 1);
+            assert_eq!(Traffic::parse(&input)?.header.service, ServiceType::Service, "{spelled}");
+        }
+        assert_eq!(Traffic::parse(&crate::test().to_string())?.header.service, ServiceType::Normal);
+        Ok(())
+    }
+
+    #[test]
+    fn precedence_codes() -> color_eyre::Result<()> {
+        for precedence in [Precedence::Routine, Precedence::Welfare, Precedence::Priority, Precedence::Emergency] {
+            assert_eq!(Precedence::from_code(precedence.as_code())?, precedence);
+            assert_eq!(precedence.to_string(), precedence.as_code());
+        }
+        assert_eq!(Precedence::Emergency.as_code(), "EMERGENCY");
+        assert_ne!(Precedence::Emergency.as_code(), "E");
+
+        for invalid in ["E", "EMER", "EMERGENCYX", "PRIORITY", "r", "", "R "] {
+            assert_eq!(Precedence::from_code(invalid), Err(InvalidPrecedence), "{:?}", invalid);
+        }
+        assert!(Traffic::parse("NR 1 E KC1GSL 1 BILLERICA MA DEC 3\nTO BOB\nBT\nHI\nBT\nMARCUS\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn hx_codes() -> color_eyre::Result<()> {
+        let cases = [
+            ("HXA050", HandlingDirective::LandlineCollect { distance: 50 }),
+            ("HXB024", HandlingDirective::DeliverWithin { hours: 24 }),
+            ("HXC", HandlingDirective::ReportDelivery),
+            ("HXD", HandlingDirective::TraceRelayAndDelivery),
+            ("HXE", HandlingDirective::RequestReply),
+            ("HXF15", HandlingDirective::HoldUntil { date: String::from("15") }),
+            ("HXG", HandlingDirective::CancelIfFeeRequired),
+        ];
+        for (code, directive) in cases {
+            assert_eq!(HandlingDirective::from_hx_code(code)?, directive);
+            assert_eq!(directive.to_string(), code);
+        }
+
+        assert_eq!(HandlingDirective::from_hx_code("HXA50")?, HandlingDirective::LandlineCollect { distance: 50 });
+        assert_eq!(HandlingDirective::from_hx_code("HXA"), Err(InvalidHxCode));
+        assert_eq!(HandlingDirective::from_hx_code("HXH"), Err(InvalidHxCode));
+        assert_eq!(HandlingDirective::from_hx_code("HXCE"), Err(InvalidHxCode));
+        Ok(())
+    }
+
+    #[test]
+    fn hx_handling_sets() -> color_eyre::Result<()> {
+        let expected = Handling::with_directives([
+            HandlingDirective::LandlineCollect { distance: 50 },
+            HandlingDirective::ReportDelivery,
+            HandlingDirective::RequestReply,
+        ]);
+        assert_eq!(Handling::from_hx_codes("HXA050CE")?, expected);
+        assert_eq!(Handling::from_hx_codes("HXA50 HXCE")?, expected);
+        assert_eq!(Handling::from_hx_codes("HXE HXC HXA050")?, expected);
+        assert_eq!(expected.to_string(), "HXA050 HXC HXE");
+        assert_eq!(Handling::from_hx_codes("HXCX"), Err(InvalidHxCode));
+
+        for (preamble, handling) in [
+            ("NR 1 R HXG W1AW 10 NEWINGTON CT 1200Z FEB 1", "HXG"),
+            ("NR 207 P HXCE KC1GSL ARL 4 BILLERICA MA 0915 OCT 12", "HXC HXE"),
+            ("NR 3 W HXB012D KC1KVY 15 LOWELL MA NOV 30", "HXB012 HXD"),
+        ] {
+            let (_, header) = all_consuming(super::preamble)(preamble).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+            assert_eq!(header.handling.to_string(), handling);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn check_forms() -> color_eyre::Result<()> {
+        let check = |content, count, revised| Check { content, count, revised };
+        for (field, expected, rendered) in [
+            ("21", check(ContentType::Standard, 21, false), "21"),
+            ("ARL 8", check(ContentType::Arl, 8, false), "ARL 8"),
+            ("ARL8", check(ContentType::Arl, 8, false), "ARL 8"),
+            ("ARL   8", check(ContentType::Arl, 8, false), "ARL 8"),
+            ("ARL\t8", check(ContentType::Arl, 8, false), "ARL 8"),
+            ("R21", check(ContentType::Standard, 21, true), "R21"),
+            ("ARL R9", check(ContentType::Arl, 9, true), "ARL R9"),
+        ] {
+            let (_, parsed) = all_consuming(super::check)(field).map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+            assert_eq!(parsed, expected, "{:?}", field);
+            assert_eq!(parsed.to_string(), rendered);
+        }
+        assert!(all_consuming(super::check)("ARL").is_err());
+        assert!(all_consuming(super::check)("R").is_err());
+
+        let (_, header) = all_consuming(super::preamble)("NR 5 R W1AW ARL7 NEWINGTON CT 1200Z FEB 1").map_err(|e| color_eyre::eyre::eyre!("{e:?}"))?;
+        assert_eq!(header.check, check(ContentType::Arl, 7, false));
+        Ok(())
+    }
+
+    #[test]
+    fn computed_check() {
+        let mut traffic = crate::test();
+        assert_eq!(traffic.computed_check(), 21);
+        assert_eq!(traffic.verify_check(), Ok(()));
+
+        let cases = [
+            ("ARL FORTY SIX", 2),
+            ("ARL SIXTY SIX ARL FIFTY", 4),
+            ("ARL ONE HAPPY BIRTHDAY", 4),
+            ("ARL SIXTY-SEVEN", 2),
+            ("J R SMITH ARRIVED X W1AW", 6),
+            ("JR SMITH ARRIVED X-RAY 73", 5),
+            ("", 0),
+        ];
+        for (body, count) in cases {
+            traffic.body = String::from(body);
+            assert_eq!(traffic.computed_check(), count, "{:?}", body);
+        }
+
+        traffic.body = String::from("HELLO THERE");
+        assert_eq!(traffic.verify_check(), Err(CheckMismatch { stated: 21, computed: 2 }));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(Traffic::parse("NR 22 R KC1GSL 21 DEC 3"), Err(TrafficParseError::InvalidPreamble));
+        assert_eq!(Traffic::parse("NR 22 X KC1GSL 21 BILLERICA MA DEC 3"), Err(TrafficParseError::InvalidPreamble));
+        assert_eq!(Traffic::parse("NR 22 R KC1GSL 21 BILLERICA MA DEC 3\nBOB SPARKES"), Err(TrafficParseError::MissingAddressee));
+        assert_eq!(Traffic::parse("NR 22 R KC1GSL 21 BILLERICA MA DEC 3\nTO BOB SPARKES\nKC1KVY"), Err(TrafficParseError::MissingBody));
+        assert_eq!(Traffic::parse("NR 22 R KC1GSL 21 BILLERICA MA DEC 3\nTO BOB SPARKES\nBT\nHELLO"), Err(TrafficParseError::MissingSignature));
+        assert_eq!(Traffic::parse(&SAMPLE.replace("AR\n", "EXTRA\nAR\n")), Err(TrafficParseError::UnexpectedLine(String::from("EXTRA"))));
+    }
+}
@@ -1 +1,430 @@
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+use nom::{AsBytes, IResult};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::Instant;
+use crate::modem::vara::TransceiverCommand;
+
 pub mod elecraft;
+pub mod icom;
+pub mod kenwood;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransmitState {
+    Receive,
+    Transmit,
+}
+
+impl From<TransceiverCommand> for TransmitState {
+    fn from(command: TransceiverCommand) -> Self {
+        if command.is_transmit() { Self::Transmit } else { Self::Receive }
+    }
+}
+
+impl fmt::Display for TransmitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = match self {
+            Self::Receive => "RX",
+            Self::Transmit => "TX",
+        };
+
+        f.write_str(code)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Lsb,
+    Usb,
+    Cw,
+    Data,
+}
+
+/// A radio under computer control.
+pub trait Rig {
+    type Command: fmt::Debug + Send;
+
+    /// Writes a command in the rig's wire format.
+    fn encode_command(&self, command: &Self::Command, buffer: &mut Vec<u8>);
+
+    fn set_transmit_state(&self, state: TransmitState) -> impl Future<Output = color_eyre::Result<()>> + Send;
+
+    /// Tunes the rig, in hertz.
+    fn set_frequency(&self, hz: u64) -> impl Future<Output = color_eyre::Result<()>> + Send;
+
+    fn set_mode(&self, mode: Mode) -> impl Future<Output = color_eyre::Result<()>> + Send;
+}
+
+/// Keys and unkeys the rig as the modem asks, until the modem goes away. When the modem
+/// asks for a delay, waits that long before switching. A command that arrives during the
+/// delay replaces the one waiting, which is never sent.
+pub async fn follow_transceiver_commands<R: Rig>(rig: &R, mut commands: tokio::sync::watch::Receiver<TransceiverCommand>) -> color_eyre::Result<()> {
+    'commands: while let Ok(()) = commands.changed().await {
+        let mut cmd = *commands.borrow_and_update();
+        tracing::trace!(?cmd, "received automated rig control request");
+        while let Some(delay) = cmd.delay() {
+            tokio::select!(
+                _ = tokio::time::sleep(delay) => break,
+                changed = commands.changed() => {
+                    if changed.is_err() {
+                        break 'commands;
+                    }
+                    cmd = *commands.borrow_and_update();
+                    tracing::trace!(?cmd, "rig control request superseded during delay");
+                },
+            );
+        }
+        rig.set_transmit_state(cmd.into()).await?;
+    }
+    tracing::info!("all done with automatic rig control");
+    Ok(())
+}
+
+/// Notices when the rig has not followed the transmit state that it was last asked for.
+#[derive(Debug)]
+pub struct PttMonitor {
+    tolerance: Duration,
+    requested: TransmitState,
+    reported: TransmitState,
+    mismatched_since: Option<Instant>,
+    warned: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PttDesync {
+    pub requested: TransmitState,
+    pub reported: TransmitState,
+    pub duration: Duration,
+}
+
+impl PttMonitor {
+    /// Tolerates the rig lagging behind a request for up to `tolerance`.
+    pub fn new(tolerance: Duration) -> Self {
+        Self {
+            tolerance,
+            requested: TransmitState::Receive,
+            reported: TransmitState::Receive,
+            mismatched_since: None,
+            warned: false,
+        }
+    }
+
+    pub fn request(&mut self, state: TransmitState, now: Instant) {
+        self.requested = state;
+        self.update_mismatch(now);
+    }
+
+    pub fn report(&mut self, state: TransmitState, now: Instant) {
+        self.reported = state;
+        self.update_mismatch(now);
+    }
+
+    fn update_mismatch(&mut self, now: Instant) {
+        if self.requested == self.reported {
+            self.mismatched_since = None;
+            self.warned = false;
+        } else if self.mismatched_since.is_none() {
+            self.mismatched_since = Some(now);
+        }
+    }
+
+    /// Returns the desync once the states have disagreed for longer than the tolerance,
+    /// warning the first time.
+    pub fn check(&mut self, now: Instant) -> Option<PttDesync> {
+        let duration = now.saturating_duration_since(self.mismatched_since?);
+        if duration <= self.tolerance {
+            return None;
+        }
+
+        let desync = PttDesync { requested: self.requested, reported: self.reported, duration };
+        if !self.warned {
+            tracing::warn!(requested = %desync.requested, reported = %desync.reported, ?duration, "rig is not in the requested transmit state");
+            self.warned = true;
+        }
+        Some(desync)
+    }
+}
+
+/// Compares the modem's transmit requests against what the rig reports, until either goes
+/// away. `transmit_state` picks the reported state out of the rig's updates.
+pub async fn monitor_ptt<U: Clone>(
+    tolerance: Duration,
+    mut requested: watch::Receiver<TransceiverCommand>,
+    mut reported: broadcast::Receiver<U>,
+    transmit_state: fn(&U) -> Option<TransmitState>,
+) -> color_eyre::Result<()> {
+    let mut monitor = PttMonitor::new(tolerance);
+    loop {
+        tokio::select!(
+            changed = requested.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                let state = TransmitState::from(*requested.borrow());
+                monitor.request(state, Instant::now());
+            },
+            update = reported.recv() => {
+                match update {
+                    Ok(update) => if let Some(state) = transmit_state(&update) {
+                        monitor.report(state, Instant::now());
+                    },
+                    Err(broadcast::error::RecvError::Lagged(_)) => {},
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            },
+        );
+        monitor.check(Instant::now());
+    }
+    Ok(())
+}
+
+/// Splits off one `;`-terminated CAT response, without its terminator.
+pub(crate) fn line(data: &[u8]) -> IResult<&[u8], &[u8]> {
+    nom::sequence::terminated(nom::bytes::streaming::take_until1(";"), nom::bytes::streaming::tag(";"))(data)
+}
+
+/// How long a rig may take to accept a command, or to answer a poll, before it is presumed
+/// to have gone away, such as when its cable is pulled mid-session.
+pub const RIG_RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+async fn write_command<D: AsyncWrite + Unpin>(stream: &mut D, command: &[u8]) -> color_eyre::Result<()> {
+    tokio::time::timeout(RIG_RESPONSE_TIMEOUT, stream.write_all(command)).await
+        .map_err(|_| color_eyre::eyre::eyre!("rig did not accept {} within {:?}", String::from_utf8_lossy(command), RIG_RESPONSE_TIMEOUT))??;
+    Ok(())
+}
+
+/// Drives a rig whose CAT protocol is ASCII terminated by `;`, passing along each
+/// response that `update` recognizes. If given a `poll`, sends that command on each
+/// interval and gives up if the rig stops answering.
+pub(crate) async fn manage_cat_thread<D, C, U>(
+    mut rx: mpsc::Receiver<C>,
+    tx: broadcast::Sender<U>,
+    mut stream: D,
+    encode: fn(&C, &mut Vec<u8>),
+    update: fn(&[u8]) -> IResult<&[u8], U>,
+    poll: Option<(Duration, C)>,
+) -> color_eyre::Result<()>
+where
+    D: AsyncRead + AsyncWrite + Unpin + 'static,
+    U: fmt::Debug,
+{
+    let mut cmd_buffer = Vec::with_capacity(32);
+    let mut upd_buffer = bytes::BytesMut::with_capacity(32);
+    let mut command_active = true;
+    let mut poll_interval = tokio::time::interval(poll.as_ref().map_or(Duration::from_secs(1), |(period, _)| *period));
+    poll_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // When the oldest unanswered poll times out.
+    let mut answer_deadline: Option<Instant> = None;
+
+    while command_active {
+        tokio::select!(
+            _ = tokio::time::sleep_until(answer_deadline.unwrap_or_else(Instant::now)), if answer_deadline.is_some() => {
+                return Err(color_eyre::eyre::eyre!("rig stopped answering polls for {:?}", RIG_RESPONSE_TIMEOUT));
+            },
+            _ = poll_interval.tick(), if poll.is_some() => {
+                if let Some((_, command)) = &poll {
+                    cmd_buffer.clear();
+                    encode(command, &mut cmd_buffer);
+                    tracing::trace!(command = %String::from_utf8_lossy(&cmd_buffer), "polling");
+                    write_command(&mut stream, &cmd_buffer).await?;
+                    answer_deadline.get_or_insert_with(|| Instant::now() + RIG_RESPONSE_TIMEOUT);
+                }
+            },
+            recv = rx.recv() => {
+                if let Some(command) = recv {
+                    cmd_buffer.clear();
+                    encode(&command, &mut cmd_buffer);
+                    tracing::trace!(command = %String::from_utf8_lossy(&cmd_buffer), "sending command");
+                    write_command(&mut stream, &cmd_buffer).await?;
+                } else {
+                    command_active = false
+                }
+            },
+            result = stream.read_buf(&mut upd_buffer) => {
+                match result {
+                    Err(err) => return Err(err.into()),
+                    Ok(bytes) => {
+                        tracing::trace!(bytes, "received bytes from command port");
+                        answer_deadline = None;
+                    }
+                }
+                let retain_after = {
+                    let mut data = upd_buffer.as_bytes();
+                    while !data.is_empty() {
+                        match line(data) {
+                            Ok((remaining, line)) => {
+                                tracing::trace!(line = std::str::from_utf8(line).unwrap(), remaining = std::str::from_utf8(remaining).unwrap(), "received complete line");
+                                data = remaining;
+                                match nom::combinator::all_consuming(update)(line) {
+                                    Ok((_, update)) => {
+                                        tracing::debug!(?update, "received update");
+                                        let _ = tx.send(update);
+                                    }
+                                    Err(_) => {
+                                        tracing::debug!(line = %String::from_utf8_lossy(line), "ignoring unrecognized response");
+                                    }
+                                }
+                            },
+                            Err(err) if err.is_incomplete() => {
+                                tracing::trace!(buffer = std::str::from_utf8(data).unwrap(), "incomplete");
+                                break
+                            },
+                            Err(err) => {
+                                return Err(err.to_owned().into())
+                            },
+                        }
+                    }
+                    upd_buffer.len() - data.len()
+                };
+                if retain_after == upd_buffer.len() {
+                  upd_buffer.clear();
+                } else if retain_after > 0 {
+                    let new = upd_buffer.split_off(retain_after);
+                    upd_buffer = new;
+                    tracing::trace!(bytes = upd_buffer.len(), "retained incomplete parts");
+                }
+            }
+        );
+    }
+    tracing::info!("exiting command loop");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use test_log::test;
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum MockCommand {
+        TransmitState(TransmitState),
+        Frequency(u64),
+        Mode(Mode),
+    }
+
+    #[derive(Default)]
+    struct MockRig {
+        sent: Arc<Mutex<Vec<MockCommand>>>,
+    }
+
+    impl MockRig {
+        fn record(&self, command: MockCommand) -> impl Future<Output = color_eyre::Result<()>> + Send {
+            self.sent.lock().unwrap().push(command);
+            std::future::ready(Ok(()))
+        }
+    }
+
+    impl Rig for MockRig {
+        type Command = MockCommand;
+
+        fn encode_command(&self, command: &Self::Command, buffer: &mut Vec<u8>) {
+            buffer.extend_from_slice(format!("{:?}", command).as_bytes());
+        }
+
+        fn set_transmit_state(&self, state: TransmitState) -> impl Future<Output = color_eyre::Result<()>> + Send {
+            self.record(MockCommand::TransmitState(state))
+        }
+
+        fn set_frequency(&self, hz: u64) -> impl Future<Output = color_eyre::Result<()>> + Send {
+            self.record(MockCommand::Frequency(hz))
+        }
+
+        fn set_mode(&self, mode: Mode) -> impl Future<Output = color_eyre::Result<()>> + Send {
+            self.record(MockCommand::Mode(mode))
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn follows_transceiver_commands() -> color_eyre::Result<()> {
+        let rig = MockRig::default();
+        let (tx, rx) = tokio::sync::watch::channel(TransceiverCommand::Receive { delay: None });
+
+        let sent = rig.sent.clone();
+        let follow = follow_transceiver_commands(&rig, rx);
+        let drive = async move {
+            tx.send(TransceiverCommand::Transmit { delay: None })?;
+            while sent.lock().unwrap().is_empty() {
+                tokio::task::yield_now().await;
+            }
+            tx.send(TransceiverCommand::Receive { delay: None })?;
+            color_eyre::Result::<_, color_eyre::Report>::Ok(())
+        };
+        let (followed, driven) = tokio::join!(follow, drive);
+        followed?;
+        driven?;
+
+        rig.set_frequency(14_112_000).await?;
+        rig.set_mode(Mode::Data).await?;
+        assert_eq!(*rig.sent.lock().unwrap(), [
+            MockCommand::TransmitState(TransmitState::Transmit),
+            MockCommand::TransmitState(TransmitState::Receive),
+            MockCommand::Frequency(14_112_000),
+            MockCommand::Mode(Mode::Data),
+        ]);
+        Ok(())
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn keys_only_after_requested_delay() -> color_eyre::Result<()> {
+        let rig = MockRig::default();
+        let (tx, rx) = tokio::sync::watch::channel(TransceiverCommand::Receive { delay: None });
+
+        let sent = rig.sent.clone();
+        let follow = follow_transceiver_commands(&rig, rx);
+        let drive = async move {
+            tx.send(TransceiverCommand::Transmit { delay: Some(Duration::from_millis(50)) })?;
+            tokio::time::sleep(Duration::from_millis(49)).await;
+            assert!(sent.lock().unwrap().is_empty());
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            assert_eq!(*sent.lock().unwrap(), [MockCommand::TransmitState(TransmitState::Transmit)]);
+            color_eyre::Result::<_, color_eyre::Report>::Ok(())
+        };
+        let (followed, driven) = tokio::join!(follow, drive);
+        followed?;
+        driven?;
+        Ok(())
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn newer_command_supersedes_delayed_one() -> color_eyre::Result<()> {
+        let rig = MockRig::default();
+        let (tx, rx) = tokio::sync::watch::channel(TransceiverCommand::Receive { delay: None });
+
+        let follow = follow_transceiver_commands(&rig, rx);
+        let drive = async move {
+            tx.send(TransceiverCommand::Transmit { delay: Some(Duration::from_millis(50)) })?;
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            tx.send(TransceiverCommand::Receive { delay: None })?;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            color_eyre::Result::<_, color_eyre::Report>::Ok(())
+        };
+        let (followed, driven) = tokio::join!(follow, drive);
+        followed?;
+        driven?;
+
+        assert_eq!(*rig.sent.lock().unwrap(), [MockCommand::TransmitState(TransmitState::Receive)]);
+        Ok(())
+    }
+
+    #[test]
+    fn ptt_monitor_tolerates_lag() {
+        let start = Instant::now();
+        let mut monitor = PttMonitor::new(Duration::from_millis(200));
+        monitor.request(TransmitState::Transmit, start);
+        assert_eq!(monitor.check(start + Duration::from_millis(100)), None);
+
+        monitor.report(TransmitState::Transmit, start + Duration::from_millis(150));
+        assert_eq!(monitor.check(start + Duration::from_millis(500)), None);
+
+        monitor.request(TransmitState::Receive, start + Duration::from_millis(600));
+        monitor.report(TransmitState::Transmit, start + Duration::from_millis(700));
+        assert_eq!(monitor.check(start + Duration::from_millis(900)), Some(PttDesync {
+            requested: TransmitState::Receive,
+            reported: TransmitState::Transmit,
+            duration: Duration::from_millis(300),
+        }));
+    }
+}
@@ -1,127 +1,273 @@
 use std::fmt;
-use std::fmt::Write;
-use nom::{AsBytes, IResult};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::sync::mpsc;
+use std::time::Duration;
+use nom::IResult;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{broadcast, mpsc};
+use crate::rig::Rig;
+
+pub use crate::rig::{Mode, TransmitState};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Command {
-    SetTransmitState(TransmitState)
+    SetTransmitState(TransmitState),
+    /// Tunes VFO A, in hertz.
+    SetFrequency(u64),
+    SetMode(Mode),
+    /// Asks whether the rig is transmitting. Unlike `TX`, this does not key the rig.
+    ReadTransmitState,
+    /// Sets the output power, in whole watts. Sent as `PC` with three digits, which the
+    /// rig reads as watts outside of extended mode, and clamped to [`MAX_POWER`].
+    SetPower(u16),
 }
 
+/// The most the KX3's internal PA puts out, in watts.
+pub const MAX_POWER: u16 = 15;
+
 impl fmt::Display for Command {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::SetTransmitState(x) => fmt::Display::fmt(x, f),
+            Self::SetFrequency(hz) => write!(f, "FA{:011}", hz),
+            Self::SetMode(mode) => write!(f, "MD{}", mode_code(*mode)),
+            Self::ReadTransmitState => f.write_str("TQ"),
+            Self::SetPower(watts) => write!(f, "PC{:03}", (*watts).min(MAX_POWER)),
         }
     }
 }
 
+fn mode_code(mode: Mode) -> u8 {
+    match mode {
+        Mode::Lsb => 1,
+        Mode::Usb => 2,
+        Mode::Cw => 3,
+        Mode::Data => 6,
+    }
+}
+
+fn mode_from_code(code: u8) -> Option<Mode> {
+    match code {
+        1 => Some(Mode::Lsb),
+        2 => Some(Mode::Usb),
+        3 => Some(Mode::Cw),
+        6 => Some(Mode::Data),
+        _ => None,
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum TransmitState {
-    Receive,
-    Transmit,
+pub enum Update {
+    /// VFO A, in hertz.
+    Frequency(u64),
+    Mode(Mode),
+    TransmitState(TransmitState),
 }
 
-impl fmt::Display for TransmitState {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let code = match self {
-            Self::Receive => "RX",
-            Self::Transmit => "TX",
-        };
+fn encode(command: &Command, buffer: &mut Vec<u8>) {
+    buffer.extend_from_slice(format!("{};", command).as_bytes());
+}
+
+/// Sends commands to a KX3 through [`manage_rig_thread`].
+#[derive(Clone, Debug)]
+pub struct Kx3 {
+    commands: mpsc::Sender<Command>,
+}
 
-        f.write_str(code)
+impl Kx3 {
+    pub fn new(commands: mpsc::Sender<Command>) -> Self {
+        Self { commands }
+    }
+
+    /// Sets the output power, in whole watts. See [`Command::SetPower`].
+    pub async fn set_power(&self, watts: u16) -> color_eyre::Result<()> {
+        Ok(self.commands.send(Command::SetPower(watts)).await?)
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum Update<'a> {
-    Filler(&'a str)
-}
-
-fn line(data: &[u8]) -> IResult<&[u8], &[u8]> {
-    nom::sequence::terminated(nom::bytes::streaming::take_until1(";"), nom::bytes::streaming::tag(";"))(data)
-}
-
-#[tracing::instrument(skip(rx, stream), err)]
-pub async fn manage_rig_thread<D: AsyncRead + AsyncWrite + Unpin + 'static>(mut rx: mpsc::Receiver<Command>, /*tx: broadcast::Sender<Update<'static>>, */mut stream: D) -> color_eyre::Result<()> {
-    let mut cmd_buffer = String::with_capacity(32);
-    let mut upd_buffer = bytes::BytesMut::with_capacity(32);
-    let mut command_active = true;
-
-    while command_active {
-        tokio::select!(
-            recv = rx.recv() => {
-                if let Some(command) = recv {
-                    cmd_buffer.clear();
-                    write!(&mut cmd_buffer, "{};", command).unwrap();
-                    tracing::trace!(command = cmd_buffer.as_str(), "sending command");
-                    stream.write_all(cmd_buffer.as_bytes()).await?;
-                } else {
-                    command_active = false
-                }
-            },
-            result = stream.read_buf(&mut upd_buffer) => {
-                match result {
-                    Err(err) => return Err(err.into()),
-                    Ok(bytes) => {
-                        tracing::trace!(bytes, "received bytes from command port");
-                    }
-                }
-                let retain_after = {
-                    let mut data = upd_buffer.as_bytes();
-                    while data.len() > 0 {
-                        match line(&data) {
-                            Ok((remaining, line)) => {
-                                tracing::trace!(line = std::str::from_utf8(line).unwrap(), remaining = std::str::from_utf8(remaining).unwrap(), "received complete line");
-                                data = remaining;
-                                //
-                                // match nom::combinator::all_consuming(update)(line).map_err(|e| e.map_input(|i| String::from_utf8(i.into()).unwrap())).finish() {
-                                //     Ok((_ , update)) => {
-                                //         tracing::debug!(?update, "received update");
-                                //         match update {
-                                //             Update::CommandResult(result) => {
-                                //                 if result == CommandResult::Ok {
-                                //                     to_acknowledge.push(Ok(()))
-                                //                 } else {
-                                //                     to_acknowledge.push(Err(()))
-                                //                 }
-                                //             }
-                                //             Update::Heartbeat => {
-                                //                 tracing::debug!("received heartbeat");
-                                //             }
-                                //             Update::TransceiverControl(control) => {
-                                //                 //tracing::debug!("")
-                                //             }
-                                //             _ => {}
-                                //         }
-                                //     }
-                                //     Err(err) => {
-                                //         return Err(err.into());
-                                //     }
-                                // }
-                            },
-                            Err(err) if err.is_incomplete() => {
-                                tracing::trace!(buffer = std::str::from_utf8(data).unwrap(), "incomplete");
-                                break
-                            },
-                            Err(err) => {
-                                return Err(err.to_owned().into())
-                            },
-                        }
-                    }
-                    upd_buffer.len() - data.len()
-                };
-                if retain_after == upd_buffer.len() {
-                  upd_buffer.clear();
-                } else if retain_after > 0 {
-                    let new = upd_buffer.split_off(retain_after);
-                    upd_buffer = new;
-                    tracing::trace!(bytes = upd_buffer.len(), "retained incomplete parts");
+impl Rig for Kx3 {
+    type Command = Command;
+
+    fn encode_command(&self, command: &Command, buffer: &mut Vec<u8>) {
+        encode(command, buffer)
+    }
+
+    async fn set_transmit_state(&self, state: TransmitState) -> color_eyre::Result<()> {
+        Ok(self.commands.send(Command::SetTransmitState(state)).await?)
+    }
+
+    async fn set_frequency(&self, hz: u64) -> color_eyre::Result<()> {
+        Ok(self.commands.send(Command::SetFrequency(hz)).await?)
+    }
+
+    async fn set_mode(&self, mode: Mode) -> color_eyre::Result<()> {
+        Ok(self.commands.send(Command::SetMode(mode)).await?)
+    }
+}
+
+fn frequency(data: &[u8]) -> IResult<&[u8], Update> {
+    nom::sequence::preceded(
+        nom::bytes::complete::tag("FA"),
+        nom::combinator::map_res(
+            nom::bytes::complete::take_while_m_n(11, 11, nom::character::is_digit),
+            |x: &[u8]| unsafe { std::str::from_utf8_unchecked(x) }.parse().map(Update::Frequency),
+        ),
+    )(data)
+}
+
+fn mode(data: &[u8]) -> IResult<&[u8], Update> {
+    nom::sequence::preceded(
+        nom::bytes::complete::tag("MD"),
+        nom::combinator::map_opt(nom::character::complete::u8, |code| mode_from_code(code).map(Update::Mode)),
+    )(data)
+}
+
+fn transmit_state(data: &[u8]) -> IResult<&[u8], Update> {
+    nom::branch::alt((
+        nom::combinator::value(Update::TransmitState(TransmitState::Transmit), nom::branch::alt((nom::bytes::complete::tag("TX"), nom::bytes::complete::tag("TQ1")))),
+        nom::combinator::value(Update::TransmitState(TransmitState::Receive), nom::branch::alt((nom::bytes::complete::tag("RX"), nom::bytes::complete::tag("TQ0")))),
+    ))(data)
+}
+
+/// Parses a response line, without its `;` terminator.
+pub fn update(data: &[u8]) -> IResult<&[u8], Update> {
+    nom::branch::alt((frequency, mode, transmit_state))(data)
+}
+
+/// How often [`manage_rig_thread`] asks the rig whether it is transmitting.
+pub const TRANSMIT_STATE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+impl Update {
+    pub fn transmit_state(&self) -> Option<TransmitState> {
+        match self {
+            Self::TransmitState(state) => Some(*state),
+            _ => None,
+        }
+    }
+}
+
+#[tracing::instrument(skip(rx, tx, stream), err)]
+pub async fn manage_rig_thread<D: AsyncRead + AsyncWrite + Unpin + 'static>(rx: mpsc::Receiver<Command>, tx: broadcast::Sender<Update>, stream: D) -> color_eyre::Result<()> {
+    crate::rig::manage_cat_thread(rx, tx, stream, encode, update, Some((TRANSMIT_STATE_POLL_INTERVAL, Command::ReadTransmitState))).await
+}
+
+#[cfg(test)]
+mod tests {
+    use test_log::test;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use super::*;
+
+    #[test]
+    fn command_serialization() {
+        assert_eq!(Command::SetFrequency(14112000).to_string(), "FA00014112000");
+        assert_eq!(Command::SetFrequency(7_040_000).to_string(), "FA00007040000");
+        assert_eq!(Command::SetMode(Mode::Usb).to_string(), "MD2");
+        assert_eq!(Command::SetMode(Mode::Data).to_string(), "MD6");
+        assert_eq!(Command::SetTransmitState(TransmitState::Transmit).to_string(), "TX");
+        assert_eq!(Command::ReadTransmitState.to_string(), "TQ");
+        assert_eq!(Command::SetPower(5).to_string(), "PC005");
+        assert_eq!(Command::SetPower(0).to_string(), "PC000");
+        assert_eq!(Command::SetPower(15).to_string(), "PC015");
+        assert_eq!(Command::SetPower(50).to_string(), "PC015");
+
+        let mut buffer = Vec::new();
+        encode(&Command::SetMode(Mode::Lsb), &mut buffer);
+        assert_eq!(buffer, b"MD1;");
+    }
+
+    #[test]
+    fn update_parsing() -> color_eyre::Result<()> {
+        for (input, expected) in [
+            (&b"FA00014112000;"[..], Update::Frequency(14_112_000)),
+            (b"MD2;", Update::Mode(Mode::Usb)),
+            (b"TX;", Update::TransmitState(TransmitState::Transmit)),
+            (b"RX;", Update::TransmitState(TransmitState::Receive)),
+            (b"TQ1;", Update::TransmitState(TransmitState::Transmit)),
+            (b"TQ0;", Update::TransmitState(TransmitState::Receive)),
+        ] {
+            let (_, response) = crate::rig::line(input)?;
+            let (_, parsed) = nom::combinator::all_consuming(update)(response)?;
+            assert_eq!(parsed, expected);
+        }
+
+        assert!(nom::combinator::all_consuming(update)(&b"MD4"[..]).is_err());
+        assert!(nom::combinator::all_consuming(update)(&b"FA140"[..]).is_err());
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn rig_thread_broadcasts_updates() -> color_eyre::Result<()> {
+        let (rig, mut radio) = tokio::io::duplex(64);
+        let (cmd_tx, cmd_rx) = mpsc::channel(1);
+        let (update_tx, mut update_rx) = broadcast::channel(4);
+        let thread = tokio::spawn(manage_rig_thread(cmd_rx, update_tx, rig));
+
+        Kx3::new(cmd_tx.clone()).set_frequency(14_112_000).await?;
+        let mut received = [0; 14];
+        radio.read_exact(&mut received).await?;
+        assert_eq!(&received, b"FA00014112000;");
+
+        radio.write_all(b"ID017;FA00014112000;MD").await?;
+        assert_eq!(update_rx.recv().await?, Update::Frequency(14_112_000));
+        radio.write_all(b"6;").await?;
+        assert_eq!(update_rx.recv().await?, Update::Mode(Mode::Data));
+
+        drop(cmd_tx);
+        thread.await??;
+        Ok(())
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn unresponsive_rig_ends_thread() -> color_eyre::Result<()> {
+        // The far end stays open but never reads or answers, like a wedged adapter.
+        let (rig, _radio) = tokio::io::duplex(64);
+        let (_cmd_tx, cmd_rx) = mpsc::channel(1);
+        let (update_tx, _update_rx) = broadcast::channel(4);
+
+        let result = tokio::time::timeout(Duration::from_secs(60), manage_rig_thread(cmd_rx, update_tx, rig)).await?;
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test(tokio::test(start_paused = true))]
+    async fn rig_ignoring_transmit_is_noticed() -> color_eyre::Result<()> {
+        let (rig, radio) = tokio::io::duplex(64);
+        let (cmd_tx, cmd_rx) = mpsc::channel(1);
+        let (update_tx, mut update_rx) = broadcast::channel(4);
+        let thread = tokio::spawn(manage_rig_thread(cmd_rx, update_tx, rig));
+
+        // A rig that never leaves receive, whatever it is told.
+        let mock = tokio::spawn(async move {
+            let (mut reader, mut writer) = tokio::io::split(radio);
+            let mut commands = tokio::io::BufReader::new(&mut reader);
+            let mut command = Vec::new();
+            while tokio::io::AsyncBufReadExt::read_until(&mut commands, b';', &mut command).await? > 0 {
+                if command == b"TQ;" {
+                    writer.write_all(b"TQ0;").await?;
                 }
+                command.clear();
+            }
+            color_eyre::Result::<_, color_eyre::Report>::Ok(())
+        });
+
+        let kx3 = Kx3::new(cmd_tx);
+        kx3.set_transmit_state(TransmitState::Transmit).await?;
+        let mut monitor = crate::rig::PttMonitor::new(Duration::from_millis(750));
+        monitor.request(TransmitState::Transmit, tokio::time::Instant::now());
+
+        let desync = loop {
+            let reported = update_rx.recv().await?.transmit_state();
+            let now = tokio::time::Instant::now();
+            monitor.report(reported.expect("only polls are answered"), now);
+            if let Some(desync) = monitor.check(now) {
+                break desync;
             }
-        );
+        };
+        assert_eq!(desync.requested, TransmitState::Transmit);
+        assert_eq!(desync.reported, TransmitState::Receive);
+        assert!(desync.duration > Duration::from_millis(750));
+
+        drop(kx3);
+        thread.await??;
+        mock.abort();
+        Ok(())
     }
-    tracing::info!("exiting command loop");
-    Ok(())
 }
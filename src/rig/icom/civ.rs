@@ -0,0 +1,378 @@
+//! Icom's CI-V protocol. Each frame is `FE FE <to> <from> <command> [data] FD`, with
+//! frequencies written as five bytes of BCD, least significant first.
+//!
+//! CI-V is a shared bus, so the rig also hears its own commands echoed back; frames not
+//! addressed to the controller are ignored.
+
+use nom::{AsBytes, IResult};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc};
+use crate::rig::Rig;
+
+pub use crate::rig::{Mode, TransmitState};
+
+/// The address conventionally used by the controlling computer.
+pub const CONTROLLER_ADDRESS: u8 = 0xE0;
+
+const PREAMBLE: [u8; 2] = [0xFE, 0xFE];
+const END_OF_MESSAGE: u8 = 0xFD;
+const OK: u8 = 0xFB;
+const NOT_GOOD: u8 = 0xFA;
+
+const TRANSCEIVE_FREQUENCY: u8 = 0x00;
+const TRANSCEIVE_MODE: u8 = 0x01;
+const READ_FREQUENCY: u8 = 0x03;
+const READ_MODE: u8 = 0x04;
+const SET_FREQUENCY: u8 = 0x05;
+const SET_MODE: u8 = 0x06;
+const DATA_MODE: [u8; 2] = [0x1A, 0x06];
+const TRANSMIT_STATE: [u8; 2] = [0x1C, 0x00];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    SetTransmitState(TransmitState),
+    /// Tunes the operating VFO, in hertz.
+    SetFrequency(u64),
+    SetMode(Mode),
+    ReadFrequency,
+    ReadMode,
+    ReadTransmitState,
+}
+
+fn mode_code(mode: Mode) -> u8 {
+    match mode {
+        Mode::Lsb => 0x00,
+        Mode::Usb | Mode::Data => 0x01,
+        Mode::Cw => 0x03,
+    }
+}
+
+fn mode_from_code(code: u8) -> Option<Mode> {
+    match code {
+        0x00 => Some(Mode::Lsb),
+        0x01 => Some(Mode::Usb),
+        0x03 => Some(Mode::Cw),
+        _ => None,
+    }
+}
+
+fn encode_bcd(mut hz: u64, buffer: &mut Vec<u8>) {
+    for _ in 0..5 {
+        let low = (hz % 10) as u8;
+        let high = (hz / 10 % 10) as u8;
+        buffer.push(high << 4 | low);
+        hz /= 100;
+    }
+}
+
+fn decode_bcd(bytes: &[u8]) -> Option<u64> {
+    bytes.iter().rev().try_fold(0u64, |hz, &b| {
+        let (high, low) = (b >> 4, b & 0x0F);
+        (high < 10 && low < 10).then(|| hz * 100 + u64::from(high) * 10 + u64::from(low))
+    })
+}
+
+fn encode_frame(to: u8, from: u8, body: &[u8], buffer: &mut Vec<u8>) {
+    buffer.extend_from_slice(&PREAMBLE);
+    buffer.push(to);
+    buffer.push(from);
+    buffer.extend_from_slice(body);
+    buffer.push(END_OF_MESSAGE);
+}
+
+/// Writes the frames for a command. Setting [`Mode::Data`] selects USB and then switches
+/// on the data mode; other modes switch it off.
+fn encode(radio: u8, controller: u8, command: &Command, buffer: &mut Vec<u8>) {
+    match *command {
+        Command::SetTransmitState(state) => {
+            let on = u8::from(state == TransmitState::Transmit);
+            encode_frame(radio, controller, &[TRANSMIT_STATE[0], TRANSMIT_STATE[1], on], buffer)
+        }
+        Command::SetFrequency(hz) => {
+            let mut body = vec![SET_FREQUENCY];
+            encode_bcd(hz, &mut body);
+            encode_frame(radio, controller, &body, buffer)
+        }
+        Command::SetMode(mode) => {
+            encode_frame(radio, controller, &[SET_MODE, mode_code(mode)], buffer);
+            let data = u8::from(mode == Mode::Data);
+            encode_frame(radio, controller, &[DATA_MODE[0], DATA_MODE[1], data, data], buffer)
+        }
+        Command::ReadFrequency => encode_frame(radio, controller, &[READ_FREQUENCY], buffer),
+        Command::ReadMode => encode_frame(radio, controller, &[READ_MODE], buffer),
+        Command::ReadTransmitState => encode_frame(radio, controller, &TRANSMIT_STATE, buffer),
+    }
+}
+
+/// Sends commands to an Icom radio through [`manage_rig_thread`](Civ::manage_rig_thread).
+#[derive(Clone, Debug)]
+pub struct Civ {
+    address: u8,
+    commands: mpsc::Sender<Command>,
+}
+
+impl Civ {
+    /// `address` is the radio's CI-V address, such as `0x94` for an IC-7300.
+    pub fn new(address: u8, commands: mpsc::Sender<Command>) -> Self {
+        Self { address, commands }
+    }
+
+    /// Talks to the radio at this handle's address over `stream`, sending what arrives on
+    /// `rx` and broadcasting what the radio reports on `tx`.
+    pub fn manage_rig_thread<D: AsyncRead + AsyncWrite + Unpin + 'static>(&self, rx: mpsc::Receiver<Command>, tx: broadcast::Sender<Update>, stream: D) -> impl std::future::Future<Output = color_eyre::Result<()>> {
+        manage_rig_thread(self.address, rx, tx, stream)
+    }
+
+    pub async fn read_frequency(&self) -> color_eyre::Result<()> {
+        Ok(self.commands.send(Command::ReadFrequency).await?)
+    }
+
+    pub async fn read_transmit_state(&self) -> color_eyre::Result<()> {
+        Ok(self.commands.send(Command::ReadTransmitState).await?)
+    }
+}
+
+impl Rig for Civ {
+    type Command = Command;
+
+    fn encode_command(&self, command: &Command, buffer: &mut Vec<u8>) {
+        encode(self.address, CONTROLLER_ADDRESS, command, buffer)
+    }
+
+    async fn set_transmit_state(&self, state: TransmitState) -> color_eyre::Result<()> {
+        Ok(self.commands.send(Command::SetTransmitState(state)).await?)
+    }
+
+    async fn set_frequency(&self, hz: u64) -> color_eyre::Result<()> {
+        Ok(self.commands.send(Command::SetFrequency(hz)).await?)
+    }
+
+    async fn set_mode(&self, mode: Mode) -> color_eyre::Result<()> {
+        Ok(self.commands.send(Command::SetMode(mode)).await?)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub to: u8,
+    pub from: u8,
+    /// The command byte and any data, without the terminator.
+    pub body: &'a [u8],
+}
+
+pub fn frame(data: &[u8]) -> IResult<&[u8], Frame<'_>> {
+    let (rest, (_, to, from, body)) = nom::sequence::tuple((
+        nom::bytes::streaming::tag(&PREAMBLE[..]),
+        nom::number::streaming::u8,
+        nom::number::streaming::u8,
+        nom::sequence::terminated(
+            nom::bytes::streaming::take_till1(|b| b == END_OF_MESSAGE),
+            nom::bytes::streaming::tag(&[END_OF_MESSAGE][..]),
+        ),
+    ))(data)?;
+    Ok((rest, Frame { to, from, body }))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Update {
+    /// The operating VFO, in hertz.
+    Frequency(u64),
+    Mode(Mode),
+    TransmitState(TransmitState),
+    Accepted,
+    Rejected,
+}
+
+fn frequency(data: &[u8]) -> IResult<&[u8], Update> {
+    nom::sequence::preceded(
+        nom::branch::alt((
+            nom::bytes::complete::tag(&[TRANSCEIVE_FREQUENCY][..]),
+            nom::bytes::complete::tag(&[READ_FREQUENCY][..]),
+        )),
+        nom::combinator::map_opt(nom::bytes::complete::take(5usize), |bcd| decode_bcd(bcd).map(Update::Frequency)),
+    )(data)
+}
+
+fn mode(data: &[u8]) -> IResult<&[u8], Update> {
+    nom::sequence::terminated(
+        nom::sequence::preceded(
+            nom::branch::alt((
+                nom::bytes::complete::tag(&[TRANSCEIVE_MODE][..]),
+                nom::bytes::complete::tag(&[READ_MODE][..]),
+            )),
+            nom::combinator::map_opt(nom::number::complete::u8, |code| mode_from_code(code).map(Update::Mode)),
+        ),
+        nom::combinator::opt(nom::number::complete::u8),
+    )(data)
+}
+
+fn transmit_state(data: &[u8]) -> IResult<&[u8], Update> {
+    nom::sequence::preceded(
+        nom::bytes::complete::tag(&TRANSMIT_STATE[..]),
+        nom::combinator::map(nom::number::complete::u8, |on| {
+            Update::TransmitState(if on == 0 { TransmitState::Receive } else { TransmitState::Transmit })
+        }),
+    )(data)
+}
+
+/// Parses the body of a frame from the radio.
+pub fn update(data: &[u8]) -> IResult<&[u8], Update> {
+    nom::branch::alt((
+        nom::combinator::value(Update::Accepted, nom::bytes::complete::tag(&[OK][..])),
+        nom::combinator::value(Update::Rejected, nom::bytes::complete::tag(&[NOT_GOOD][..])),
+        frequency,
+        mode,
+        transmit_state,
+    ))(data)
+}
+
+#[tracing::instrument(skip(rx, tx, stream), err)]
+async fn manage_rig_thread<D: AsyncRead + AsyncWrite + Unpin + 'static>(address: u8, mut rx: mpsc::Receiver<Command>, tx: broadcast::Sender<Update>, mut stream: D) -> color_eyre::Result<()> {
+    let mut cmd_buffer = Vec::with_capacity(32);
+    let mut upd_buffer = bytes::BytesMut::with_capacity(32);
+    let mut command_active = true;
+
+    while command_active {
+        tokio::select!(
+            recv = rx.recv() => {
+                if let Some(command) = recv {
+                    cmd_buffer.clear();
+                    encode(address, CONTROLLER_ADDRESS, &command, &mut cmd_buffer);
+                    tracing::trace!(?command, bytes = ?cmd_buffer, "sending command");
+                    stream.write_all(&cmd_buffer).await?;
+                } else {
+                    command_active = false
+                }
+            },
+            result = stream.read_buf(&mut upd_buffer) => {
+                match result {
+                    Err(err) => return Err(err.into()),
+                    Ok(bytes) => {
+                        tracing::trace!(bytes, "received bytes from command port");
+                    }
+                }
+                let retain_after = {
+                    let mut data = upd_buffer.as_bytes();
+                    while !data.is_empty() {
+                        match frame(data) {
+                            Ok((remaining, frame)) => {
+                                tracing::trace!(?frame, "received complete frame");
+                                data = remaining;
+                                if frame.to != CONTROLLER_ADDRESS || frame.from != address {
+                                    continue;
+                                }
+                                match nom::combinator::all_consuming(update)(frame.body) {
+                                    Ok((_, update)) => {
+                                        tracing::debug!(?update, "received update");
+                                        let _ = tx.send(update);
+                                    }
+                                    Err(_) => {
+                                        tracing::debug!(body = ?frame.body, "ignoring unrecognized frame");
+                                    }
+                                }
+                            },
+                            Err(err) if err.is_incomplete() => {
+                                tracing::trace!(buffer = ?data, "incomplete");
+                                break
+                            },
+                            Err(_) => {
+                                tracing::debug!(byte = data[0], "skipping byte outside of a frame");
+                                data = &data[1..];
+                            },
+                        }
+                    }
+                    upd_buffer.len() - data.len()
+                };
+                if retain_after == upd_buffer.len() {
+                  upd_buffer.clear();
+                } else if retain_after > 0 {
+                    let new = upd_buffer.split_off(retain_after);
+                    upd_buffer = new;
+                    tracing::trace!(bytes = upd_buffer.len(), "retained incomplete parts");
+                }
+            }
+        );
+    }
+    tracing::info!("exiting command loop");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use test_log::test;
+    use super::*;
+
+    const IC_7300: u8 = 0x94;
+
+    #[test]
+    fn set_frequency_frame() {
+        let mut buffer = Vec::new();
+        encode(IC_7300, CONTROLLER_ADDRESS, &Command::SetFrequency(14_074_000), &mut buffer);
+        assert_eq!(buffer, [0xFE, 0xFE, 0x94, 0xE0, 0x05, 0x00, 0x40, 0x07, 0x14, 0x00, 0xFD]);
+    }
+
+    #[test]
+    fn command_frames() {
+        let mut buffer = Vec::new();
+        encode(IC_7300, CONTROLLER_ADDRESS, &Command::SetTransmitState(TransmitState::Transmit), &mut buffer);
+        assert_eq!(buffer, [0xFE, 0xFE, 0x94, 0xE0, 0x1C, 0x00, 0x01, 0xFD]);
+
+        buffer.clear();
+        encode(IC_7300, CONTROLLER_ADDRESS, &Command::SetMode(Mode::Data), &mut buffer);
+        assert_eq!(buffer, [
+            0xFE, 0xFE, 0x94, 0xE0, 0x06, 0x01, 0xFD,
+            0xFE, 0xFE, 0x94, 0xE0, 0x1A, 0x06, 0x01, 0x01, 0xFD,
+        ]);
+
+        buffer.clear();
+        encode(IC_7300, CONTROLLER_ADDRESS, &Command::ReadFrequency, &mut buffer);
+        assert_eq!(buffer, [0xFE, 0xFE, 0x94, 0xE0, 0x03, 0xFD]);
+    }
+
+    #[test]
+    fn bcd_round_trip() {
+        for hz in [0, 7_040_000, 14_074_000, 1_296_123_450] {
+            let mut bcd = Vec::new();
+            encode_bcd(hz, &mut bcd);
+            assert_eq!(decode_bcd(&bcd), Some(hz));
+        }
+        assert_eq!(decode_bcd(&[0x0A, 0, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn frame_parsing() -> color_eyre::Result<()> {
+        let input: &'static [u8] = &[0xFE, 0xFE, 0xE0, 0x94, 0x03, 0x00, 0x40, 0x07, 0x14, 0x00, 0xFD, 0xFE, 0xFE, 0xE0];
+        let (rest, parsed) = frame(input)?;
+        assert_eq!(parsed, Frame { to: 0xE0, from: 0x94, body: &[0x03, 0x00, 0x40, 0x07, 0x14, 0x00] });
+        assert_eq!(update(parsed.body)?.1, Update::Frequency(14_074_000));
+        assert!(frame(rest).unwrap_err().is_incomplete());
+
+        assert_eq!(update(&[0x04, 0x03, 0x01])?.1, Update::Mode(Mode::Cw));
+        assert_eq!(update(&[0x1C, 0x00, 0x01])?.1, Update::TransmitState(TransmitState::Transmit));
+        assert_eq!(update(&[0xFA])?.1, Update::Rejected);
+        Ok(())
+    }
+
+    #[test(tokio::test)]
+    async fn rig_thread_ignores_echoes() -> color_eyre::Result<()> {
+        let (rig, mut radio) = tokio::io::duplex(64);
+        let (cmd_tx, cmd_rx) = mpsc::channel(1);
+        let (update_tx, mut update_rx) = broadcast::channel(4);
+        let civ = Civ::new(IC_7300, cmd_tx);
+        let thread = tokio::spawn(civ.manage_rig_thread(cmd_rx, update_tx, rig));
+
+        civ.read_frequency().await?;
+        let mut received = [0; 6];
+        radio.read_exact(&mut received).await?;
+        assert_eq!(received, [0xFE, 0xFE, 0x94, 0xE0, 0x03, 0xFD]);
+
+        radio.write_all(&[0xFE, 0xFE, 0x94, 0xE0, 0x03, 0xFD]).await?;
+        radio.write_all(&[0xFE, 0xFE, 0xE0, 0x94, 0x03, 0x00, 0x40, 0x07]).await?;
+        radio.write_all(&[0x14, 0x00, 0xFD]).await?;
+        assert_eq!(update_rx.recv().await?, Update::Frequency(14_074_000));
+
+        drop(civ);
+        thread.await??;
+        Ok(())
+    }
+}
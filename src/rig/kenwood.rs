@@ -0,0 +1,165 @@
+//! Kenwood CAT, as spoken by the TS-590 and TS-890, and by Yaesu's newer radios in their
+//! Kenwood-compatible form. Framing matches the KX3: ASCII commands terminated by `;`.
+//!
+//! Mode numbers mostly agree with Elecraft's (`1` LSB, `2` USB, `3` CW), but Kenwood's
+//! `MD6` is FSK rather than data. Data is instead USB with the separate data mode switched
+//! on with `DA1`.
+
+use std::io::Write;
+use nom::IResult;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{broadcast, mpsc};
+use crate::rig::Rig;
+
+pub use crate::rig::{Mode, TransmitState};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    SetTransmitState(TransmitState),
+    /// Tunes VFO A, in hertz.
+    SetFrequency(u64),
+    SetMode(Mode),
+    ReadFrequency,
+    ReadMode,
+}
+
+fn mode_code(mode: Mode) -> u8 {
+    match mode {
+        Mode::Lsb => 1,
+        Mode::Usb | Mode::Data => 2,
+        Mode::Cw => 3,
+    }
+}
+
+fn mode_from_code(code: u8) -> Option<Mode> {
+    match code {
+        1 => Some(Mode::Lsb),
+        2 => Some(Mode::Usb),
+        3 => Some(Mode::Cw),
+        _ => None,
+    }
+}
+
+fn encode(command: &Command, buffer: &mut Vec<u8>) {
+    match *command {
+        Command::SetTransmitState(state) => write!(buffer, "{};", state),
+        Command::SetFrequency(hz) => write!(buffer, "FA{:011};", hz),
+        Command::SetMode(mode) => write!(buffer, "MD{};DA{};", mode_code(mode), u8::from(mode == Mode::Data)),
+        Command::ReadFrequency => write!(buffer, "FA;"),
+        Command::ReadMode => write!(buffer, "MD;"),
+    }.expect("writing to a Vec cannot fail")
+}
+
+/// Sends commands to a Kenwood radio through [`manage_rig_thread`].
+#[derive(Clone, Debug)]
+pub struct Kenwood {
+    commands: mpsc::Sender<Command>,
+}
+
+impl Kenwood {
+    pub fn new(commands: mpsc::Sender<Command>) -> Self {
+        Self { commands }
+    }
+}
+
+impl Rig for Kenwood {
+    type Command = Command;
+
+    fn encode_command(&self, command: &Command, buffer: &mut Vec<u8>) {
+        encode(command, buffer)
+    }
+
+    async fn set_transmit_state(&self, state: TransmitState) -> color_eyre::Result<()> {
+        Ok(self.commands.send(Command::SetTransmitState(state)).await?)
+    }
+
+    async fn set_frequency(&self, hz: u64) -> color_eyre::Result<()> {
+        Ok(self.commands.send(Command::SetFrequency(hz)).await?)
+    }
+
+    async fn set_mode(&self, mode: Mode) -> color_eyre::Result<()> {
+        Ok(self.commands.send(Command::SetMode(mode)).await?)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Update {
+    /// VFO A, in hertz.
+    Frequency(u64),
+    Mode(Mode),
+    DataMode(bool),
+}
+
+fn frequency(data: &[u8]) -> IResult<&[u8], Update> {
+    nom::sequence::preceded(
+        nom::bytes::complete::tag("FA"),
+        nom::combinator::map_res(
+            nom::bytes::complete::take_while_m_n(11, 11, nom::character::is_digit),
+            |x: &[u8]| unsafe { std::str::from_utf8_unchecked(x) }.parse().map(Update::Frequency),
+        ),
+    )(data)
+}
+
+fn mode(data: &[u8]) -> IResult<&[u8], Update> {
+    nom::sequence::preceded(
+        nom::bytes::complete::tag("MD"),
+        nom::combinator::map_opt(nom::character::complete::u8, |code| mode_from_code(code).map(Update::Mode)),
+    )(data)
+}
+
+fn data_mode(data: &[u8]) -> IResult<&[u8], Update> {
+    nom::sequence::preceded(
+        nom::bytes::complete::tag("DA"),
+        nom::branch::alt((
+            nom::combinator::value(Update::DataMode(false), nom::bytes::complete::tag("0")),
+            nom::combinator::value(Update::DataMode(true), nom::bytes::complete::tag("1")),
+        )),
+    )(data)
+}
+
+/// Parses a response line, without its `;` terminator.
+pub fn update(data: &[u8]) -> IResult<&[u8], Update> {
+    nom::branch::alt((frequency, mode, data_mode))(data)
+}
+
+#[tracing::instrument(skip(rx, tx, stream), err)]
+pub async fn manage_rig_thread<D: AsyncRead + AsyncWrite + Unpin + 'static>(rx: mpsc::Receiver<Command>, tx: broadcast::Sender<Update>, stream: D) -> color_eyre::Result<()> {
+    crate::rig::manage_cat_thread(rx, tx, stream, encode, update, None).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(command: Command) -> String {
+        let mut buffer = Vec::new();
+        encode(&command, &mut buffer);
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn command_serialization() {
+        assert_eq!(encoded(Command::SetFrequency(14_074_000)), "FA00014074000;");
+        assert_eq!(encoded(Command::SetMode(Mode::Usb)), "MD2;DA0;");
+        assert_eq!(encoded(Command::SetMode(Mode::Data)), "MD2;DA1;");
+        assert_eq!(encoded(Command::SetMode(Mode::Cw)), "MD3;DA0;");
+        assert_eq!(encoded(Command::SetTransmitState(TransmitState::Transmit)), "TX;");
+        assert_eq!(encoded(Command::ReadFrequency), "FA;");
+    }
+
+    #[test]
+    fn update_parsing() -> color_eyre::Result<()> {
+        for (input, expected) in [
+            (&b"FA00014074000;"[..], Update::Frequency(14_074_000)),
+            (b"MD1;", Update::Mode(Mode::Lsb)),
+            (b"DA1;", Update::DataMode(true)),
+        ] {
+            let (_, response) = crate::rig::line(input)?;
+            let (_, parsed) = nom::combinator::all_consuming(update)(response)?;
+            assert_eq!(parsed, expected);
+        }
+
+        assert!(nom::combinator::all_consuming(update)(&b"MD6"[..]).is_err());
+        Ok(())
+    }
+}
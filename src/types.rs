@@ -5,40 +5,275 @@ use nom::error::VerboseError;
 use regex::Regex;
 use thiserror::Error;
 
-static STATION: Lazy<Regex> = lazy_regex!(r#"([0-9]?[A-Za-z]+)([0-9]+)([A-Za-z][A-Za-z0-9]*)"#);
+static STATION: Lazy<Regex> = lazy_regex!(r#"^([0-9]?[A-Za-z]+)([0-9]+)([A-Za-z][A-Za-z0-9]*)((?:/[A-Za-z0-9]+)*)(?:-(1[0-5]|[0-9]))?$"#);
+static DECORATIONS: Lazy<Regex> = lazy_regex!(r#"^(?:/[A-Za-z0-9]+)*(?:-(?:1[0-5]|[0-9]))?$"#);
 
 #[derive(Debug, Error)]
 #[error("invalid station identity")]
 pub struct InvalidStationId;//(#[from] nom::Err<nom::error::Error<String>>);
 
-#[braid(normalizer)]
+#[cfg_attr(feature = "serde", braid(serde, normalizer))]
+#[cfg_attr(not(feature = "serde"), braid(normalizer))]
 pub struct StationId;
 
-impl aliri_braid::Normalizer for StationId {
-    type Error = InvalidStationId;
+/// How strictly a station identity is checked before it is accepted.
+///
+/// At every level, the call may be followed by `/`-separated suffixes, such as `W1AW/4`
+/// or `KC1GSL/P`, and then an SSID from 0 to 15, such as `KC1GSL-7`.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub enum ValidationLevel {
+    /// Any run of ASCII letters and digits containing at least one of each.
+    Loose,
+    /// A prefix, a call area digit, and a suffix, as in `KC1GSL` or `2E0ABC`.
+    #[default]
+    Structural,
+    /// Structural, and the prefix falls within an ITU-allocated call sign series.
+    ItuAllocated,
+}
 
-    fn normalize(s: &str) -> Result<Cow<str>, Self::Error> {
-        // let (rest, cs) = nom::combinator::all_consuming(callsign)(s).map_err(|e| e.to_owned())?;
-        // Ok(cs)
-        if STATION.is_match(s) {
-            if s.as_bytes().iter().any(|&b| b'a' <= b && b <= b'z') {
-                Ok(Cow::Owned(s.to_ascii_uppercase()))
-            } else {
-                Ok(Cow::Borrowed(s))
+impl ValidationLevel {
+    fn accepts(self, s: &str) -> bool {
+        match self {
+            Self::Loose => {
+                let (base, decorations) = s.split_at(base_len(s));
+                base.bytes().any(|b| b.is_ascii_digit())
+                    && base.bytes().any(|b| b.is_ascii_alphabetic())
+                    && base.bytes().all(|b| b.is_ascii_alphanumeric())
+                    && DECORATIONS.is_match(decorations)
             }
+            Self::Structural => STATION.is_match(s),
+            Self::ItuAllocated => STATION.is_match(s) && is_itu_allocated(s),
+        }
+    }
+}
+
+/// Checks the first two characters against the ITU table of call sign series
+/// allocations. Series starting with `Q`, `0`, or `1` are never allocated.
+fn is_itu_allocated(s: &str) -> bool {
+    let bytes = s.as_bytes();
+    if bytes.len() < 2 {
+        return false;
+    }
+
+    match (bytes[0].to_ascii_uppercase(), bytes[1].to_ascii_uppercase()) {
+        (b'Q' | b'0' | b'1', _) => false,
+        (b'2'..=b'9', b'A'..=b'Z') => true,
+        (b'A'..=b'Z', b'A'..=b'Z') => true,
+        (b'B' | b'F' | b'G' | b'I' | b'K' | b'M' | b'N' | b'R' | b'W', _) => true,
+        (b'A' | b'C' | b'D' | b'L' | b'P' | b'Y', b'2'..=b'9') => true,
+        (b'E', b'2'..=b'7') => true,
+        (b'H', b'2'..=b'4' | b'6'..=b'9') => true,
+        (b'J' | b'T' | b'V', b'2'..=b'8') => true,
+        (b'S', b'2' | b'3' | b'5'..=b'9') => true,
+        (b'Z', b'2' | b'3' | b'8') => true,
+        _ => false,
+    }
+}
+
+/// The country or entity a call sign series is allocated to. Only common entities are
+/// distinguished; everything else is `Other`.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Country {
+    Argentina,
+    Australia,
+    Austria,
+    Belgium,
+    Brazil,
+    Canada,
+    Chile,
+    China,
+    Croatia,
+    CzechRepublic,
+    Denmark,
+    Finland,
+    France,
+    Germany,
+    Greece,
+    Hungary,
+    Iceland,
+    India,
+    Indonesia,
+    Ireland,
+    Israel,
+    Italy,
+    Japan,
+    Mexico,
+    Netherlands,
+    NewZealand,
+    Norway,
+    Philippines,
+    Poland,
+    Portugal,
+    Romania,
+    Russia,
+    SouthAfrica,
+    SouthKorea,
+    Spain,
+    Sweden,
+    Switzerland,
+    Thailand,
+    Turkey,
+    Ukraine,
+    UnitedKingdom,
+    UnitedStates,
+    Other,
+}
+
+/// Inclusive ranges of call sign series, compared against the leading characters of the
+/// prefix and call area digit. Two-character series come first so they take precedence.
+static SERIES: &[(&str, &str, Country)] = &[
+    ("2A", "2Z", Country::UnitedKingdom),
+    ("3G", "3G", Country::Chile),
+    ("3Z", "3Z", Country::Poland),
+    ("4A", "4C", Country::Mexico),
+    ("4D", "4I", Country::Philippines),
+    ("4X", "4X", Country::Israel),
+    ("4Z", "4Z", Country::Israel),
+    ("5P", "5Q", Country::Denmark),
+    ("6D", "6J", Country::Mexico),
+    ("6K", "6N", Country::SouthKorea),
+    ("7A", "7I", Country::Indonesia),
+    ("7J", "7N", Country::Japan),
+    ("7S", "7S", Country::Sweden),
+    ("8A", "8I", Country::Indonesia),
+    ("8J", "8N", Country::Japan),
+    ("8S", "8S", Country::Sweden),
+    ("8T", "8Y", Country::India),
+    ("9A", "9A", Country::Croatia),
+    ("AA", "AL", Country::UnitedStates),
+    ("AM", "AO", Country::Spain),
+    ("AT", "AW", Country::India),
+    ("AX", "AX", Country::Australia),
+    ("AY", "AZ", Country::Argentina),
+    ("CA", "CE", Country::Chile),
+    ("CQ", "CU", Country::Portugal),
+    ("CY", "CZ", Country::Canada),
+    ("DA", "DR", Country::Germany),
+    ("DS", "DT", Country::SouthKorea),
+    ("DU", "DZ", Country::Philippines),
+    ("E2", "E2", Country::Thailand),
+    ("EA", "EH", Country::Spain),
+    ("EI", "EJ", Country::Ireland),
+    ("EM", "EO", Country::Ukraine),
+    ("HA", "HA", Country::Hungary),
+    ("HB", "HB", Country::Switzerland),
+    ("HG", "HG", Country::Hungary),
+    ("HL", "HL", Country::SouthKorea),
+    ("HS", "HS", Country::Thailand),
+    ("J4", "J4", Country::Greece),
+    ("JA", "JS", Country::Japan),
+    ("L2", "L9", Country::Argentina),
+    ("LA", "LN", Country::Norway),
+    ("LO", "LW", Country::Argentina),
+    ("OE", "OE", Country::Austria),
+    ("OF", "OJ", Country::Finland),
+    ("OK", "OL", Country::CzechRepublic),
+    ("ON", "OT", Country::Belgium),
+    ("OU", "OZ", Country::Denmark),
+    ("PA", "PI", Country::Netherlands),
+    ("PK", "PO", Country::Indonesia),
+    ("PP", "PY", Country::Brazil),
+    ("SA", "SM", Country::Sweden),
+    ("SN", "SR", Country::Poland),
+    ("SV", "SZ", Country::Greece),
+    ("TA", "TC", Country::Turkey),
+    ("TF", "TF", Country::Iceland),
+    ("UA", "UI", Country::Russia),
+    ("UR", "UZ", Country::Ukraine),
+    ("VA", "VG", Country::Canada),
+    ("VH", "VN", Country::Australia),
+    ("VO", "VO", Country::Canada),
+    ("VT", "VW", Country::India),
+    ("VY", "VY", Country::Canada),
+    ("XA", "XI", Country::Mexico),
+    ("XJ", "XO", Country::Canada),
+    ("XQ", "XR", Country::Chile),
+    ("YB", "YH", Country::Indonesia),
+    ("YM", "YM", Country::Turkey),
+    ("YO", "YR", Country::Romania),
+    ("ZK", "ZM", Country::NewZealand),
+    ("ZR", "ZU", Country::SouthAfrica),
+    ("ZV", "ZZ", Country::Brazil),
+    ("B", "B", Country::China),
+    ("F", "F", Country::France),
+    ("G", "G", Country::UnitedKingdom),
+    ("I", "I", Country::Italy),
+    ("K", "K", Country::UnitedStates),
+    ("M", "M", Country::UnitedKingdom),
+    ("N", "N", Country::UnitedStates),
+    ("R", "R", Country::Russia),
+    ("W", "W", Country::UnitedStates),
+];
+
+fn base_len(s: &str) -> usize {
+    s.find(['/', '-']).unwrap_or(s.len())
+}
+
+fn normalize_with(s: &str, level: ValidationLevel) -> Result<Cow<'_, str>, InvalidStationId> {
+    if level.accepts(s) {
+        if s.as_bytes().iter().any(u8::is_ascii_lowercase) {
+            Ok(Cow::Owned(s.to_ascii_uppercase()))
         } else {
-            Err(InvalidStationId)
+            Ok(Cow::Borrowed(s))
         }
+    } else {
+        Err(InvalidStationId)
+    }
+}
+
+impl aliri_braid::Normalizer for StationId {
+    type Error = InvalidStationId;
+
+    fn normalize(s: &str) -> Result<Cow<'_, str>, Self::Error> {
+        normalize_with(s, ValidationLevel::Structural)
+    }
+}
+
+impl StationId {
+    /// Validates at the given strictness rather than the default of [`ValidationLevel::Structural`].
+    ///
+    /// Identities accepted as [`ValidationLevel::Loose`] may not pass `StationId::new`.
+    pub fn new_with<S: AsRef<str>>(s: S, level: ValidationLevel) -> Result<Self, InvalidStationId> {
+        let normalized = normalize_with(s.as_ref(), level)?.into_owned();
+        Ok(unsafe { Self::new_unchecked(normalized) })
+    }
+}
+
+impl StationIdRef {
+    /// The call without any suffixes or SSID, as in `KC1GSL` for `KC1GSL/P-7`.
+    pub fn base_call(&self) -> &str {
+        let s = self.as_str();
+        &s[..base_len(s)]
+    }
+
+    /// The country the call's prefix is allocated to, as in [`Country::UnitedStates`] for
+    /// `KC1GSL`. Calls without a recognizable prefix, as some accepted at
+    /// [`ValidationLevel::Loose`], have none.
+    pub fn prefix_country(&self) -> Option<Country> {
+        let captures = STATION.captures(self.as_str())?;
+        let (prefix, digits) = (captures.get(1)?.as_str(), captures.get(2)?.as_str());
+        let series = format!("{}{}", prefix, &digits[..1]);
+
+        let country = SERIES.iter()
+            .find(|(start, end, _)| series.get(..start.len()).is_some_and(|s| *start <= s && s <= *end))
+            .map_or(Country::Other, |&(_, _, country)| country);
+        Some(country)
+    }
+
+    /// The secondary station identifier, as in `7` for `KC1GSL-7`.
+    pub fn ssid(&self) -> Option<u8> {
+        let (_, ssid) = self.as_str().rsplit_once('-')?;
+        ssid.parse().ok()
     }
 }
 
+/// Parses an uppercase station identity, as the TNC reports it, held to the same
+/// structure as [`StationId::new`].
 pub fn callsign(s: &[u8]) -> nom::IResult<&[u8], &StationIdRef, VerboseError<&[u8]>> {
-    let (rest, result) = nom::bytes::complete::take_while_m_n(3,7, |c: u8| c.is_ascii_uppercase() || c.is_ascii_digit())(s)?;
-    // let cow = if result.iter().any(|&b| b'a' <= b && b <= b'z') {
-    //     Cow::Owned(unsafe { String::from_utf8_unchecked(result.to_ascii_uppercase()) })
-    // } else {
-    //     Cow::Borrowed(unsafe { std::str::from_utf8_unchecked(result) })
-    // };
+    let (rest, result) = nom::combinator::verify(
+        nom::bytes::complete::take_while1(|c: u8| c.is_ascii_uppercase() || c.is_ascii_digit() || c == b'/' || c == b'-'),
+        |call: &[u8]| ValidationLevel::Structural.accepts(unsafe { std::str::from_utf8_unchecked(call) }),
+    )(s)?;
 
     Ok((rest, unsafe { StationIdRef::from_str_unchecked(std::str::from_utf8_unchecked(result)) }))
 }
@@ -61,4 +296,90 @@ mod tests {
         assert_eq!(x.into_owned(), StationId::new("KC1GSL")?);
         Ok(())
     }
+
+    #[test]
+    fn validation_levels() {
+        let cases = [
+            // input, loose, structural, itu allocated
+            ("kc1gsl", true, true, true),
+            ("2E0ABC", true, true, true),
+            ("Q1ABC", true, true, false),
+            ("0A1ABC", true, true, false),
+            ("ABC123", true, false, false),
+            ("KC1GSL!", false, false, false),
+            ("XKC1GSL KW1U", false, false, false),
+            ("kc1gsl-7", true, true, true),
+            ("W1AW/4", true, true, true),
+            ("KC1GSL/P-15", true, true, true),
+            ("ABC123-1", true, false, false),
+            ("KC1GSL-16", false, false, false),
+            ("KC1GSL-", false, false, false),
+            ("KC1GSL/", false, false, false),
+            ("KC1GSL-7/P", false, false, false),
+            ("", false, false, false),
+        ];
+
+        for (input, loose, structural, itu) in cases {
+            assert_eq!(StationId::new_with(input, ValidationLevel::Loose).is_ok(), loose, "{:?} at loose", input);
+            assert_eq!(StationId::new_with(input, ValidationLevel::Structural).is_ok(), structural, "{:?} at structural", input);
+            assert_eq!(StationId::new_with(input, ValidationLevel::ItuAllocated).is_ok(), itu, "{:?} at ITU allocated", input);
+            assert_eq!(StationId::new(input).is_ok(), structural, "{:?} by default", input);
+        }
+    }
+
+    #[test]
+    fn ssid_and_suffixes() -> color_eyre::Result<()> {
+        let plain = StationId::new("KC1GSL")?;
+        assert_eq!(plain.base_call(), "KC1GSL");
+        assert_eq!(plain.ssid(), None);
+
+        let packet = StationId::new("kc1gsl-7")?;
+        assert_eq!(packet.as_str(), "KC1GSL-7");
+        assert_eq!(packet.base_call(), "KC1GSL");
+        assert_eq!(packet.ssid(), Some(7));
+
+        let portable = StationId::new("W1AW/4")?;
+        assert_eq!(portable.as_str(), "W1AW/4");
+        assert_eq!(portable.base_call(), "W1AW");
+        assert_eq!(portable.ssid(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_countries() -> color_eyre::Result<()> {
+        let cases = [
+            ("KC1GSL", Country::UnitedStates),
+            ("W1AW/4", Country::UnitedStates),
+            ("AA1A", Country::UnitedStates),
+            ("G0ABC", Country::UnitedKingdom),
+            ("M0ABC-7", Country::UnitedKingdom),
+            ("2E0ABC", Country::UnitedKingdom),
+            ("VE3ABC", Country::Canada),
+            ("DL1ABC", Country::Germany),
+            ("JA1ABC", Country::Japan),
+            ("E21ABC", Country::Thailand),
+            ("EA4ABC", Country::Spain),
+            ("LU1ABC", Country::Argentina),
+            ("ZS6ABC", Country::SouthAfrica),
+            ("A61ABC", Country::Other),
+        ];
+        for (input, country) in cases {
+            assert_eq!(StationId::new(input)?.prefix_country(), Some(country), "{:?}", input);
+        }
+
+        assert_eq!(StationId::new_with("ABC123", ValidationLevel::Loose)?.prefix_country(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn callsign_matches_normalizer() {
+        for input in ["KC1GSL", "KC1GSL-7", "W1AW/4", "1ABC", "12", "ABC123", "KC1GSL-16"] {
+            let parsed = nom::combinator::all_consuming(callsign)(input.as_bytes()).is_ok();
+            assert_eq!(parsed, StationId::new(input).is_ok(), "{:?}", input);
+        }
+
+        let (rest, call) = callsign(b"KC1GSL KW1U").unwrap();
+        assert_eq!(call.as_str(), "KC1GSL");
+        assert_eq!(rest, b" KW1U");
+    }
 }